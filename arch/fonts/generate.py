@@ -0,0 +1,422 @@
+"""Rasterize DejaVu Sans Mono into the PSF fonts embedded in the kernel.
+
+    python generate.py /usr/share/fonts/truetype/dejavu
+
+writes `dejavu-mono-8x16.psfu` (PSF2 with a unicode table) and
+`dejavu-mono-cp437-8x16.psf` (PSF1 with a unicode table, in code page 437
+order) next to this script.
+
+Glyphs are rasterized unhinted by sampling each pixel on a grid, the outlines
+come straight from the `glyf` table (quadratic curves, composites.) Without
+hinting the regular weight's one pixel stems come out uneven at 8x16, so
+both fonts use the bold weight.
+"""
+
+import os
+import struct
+import sys
+
+WIDTH = 8
+HEIGHT = 16
+
+# Samples per pixel along each axis, and how much of a pixel must be covered.
+SAMPLES = 5
+THRESHOLD = 0.5
+
+# -- TrueType
+
+
+class Font:
+    def __init__(self, path):
+        with open(path, "rb") as f:
+            self.data = f.read()
+
+        self.tables = {}
+        (count,) = struct.unpack_from(">H", self.data, 4)
+
+        for index in range(count):
+            tag, _, offset, length = struct.unpack_from(">4sIII", self.data, 12 + 16 * index)
+            self.tables[tag.decode()] = (offset, length)
+
+        head = self.tables["head"][0]
+        (self.units_per_em,) = struct.unpack_from(">H", self.data, head + 18)
+        (self.long_loca,) = struct.unpack_from(">h", self.data, head + 50)
+
+        hhea = self.tables["hhea"][0]
+        self.ascender, self.descender = struct.unpack_from(">hh", self.data, hhea + 4)
+        (self.metrics,) = struct.unpack_from(">H", self.data, hhea + 34)
+
+        (self.glyphs,) = struct.unpack_from(">H", self.data, self.tables["maxp"][0] + 4)
+
+        self.cmap = self._parse_cmap()
+
+    def _parse_cmap(self):
+        cmap = self.tables["cmap"][0]
+        (count,) = struct.unpack_from(">H", self.data, cmap + 2)
+
+        for index in range(count):
+            platform, encoding, offset = struct.unpack_from(">HHI", self.data, cmap + 4 + 8 * index)
+
+            if (platform, encoding) == (3, 1):
+                return self._parse_format_4(cmap + offset)
+
+        raise ValueError("No unicode BMP cmap")
+
+    def _parse_format_4(self, offset):
+        (segments,) = struct.unpack_from(">H", self.data, offset + 6)
+        segments //= 2
+
+        ends = offset + 14
+        starts = ends + 2 * segments + 2
+        deltas = starts + 2 * segments
+        ranges = deltas + 2 * segments
+
+        mapping = {}
+
+        for segment in range(segments):
+            (end,) = struct.unpack_from(">H", self.data, ends + 2 * segment)
+            (start,) = struct.unpack_from(">H", self.data, starts + 2 * segment)
+            (delta,) = struct.unpack_from(">h", self.data, deltas + 2 * segment)
+            (range_offset,) = struct.unpack_from(">H", self.data, ranges + 2 * segment)
+
+            for code in range(start, end + 1):
+                if code == 0xFFFF:
+                    continue
+
+                if range_offset == 0:
+                    glyph = (code + delta) & 0xFFFF
+                else:
+                    address = ranges + 2 * segment + range_offset + 2 * (code - start)
+                    (glyph,) = struct.unpack_from(">H", self.data, address)
+
+                    if glyph != 0:
+                        glyph = (glyph + delta) & 0xFFFF
+
+                if glyph != 0:
+                    mapping[code] = glyph
+
+        return mapping
+
+    def advance(self, glyph):
+        hmtx = self.tables["hmtx"][0]
+        (advance,) = struct.unpack_from(">H", self.data, hmtx + 4 * min(glyph, self.metrics - 1))
+        return advance
+
+    def _glyph_range(self, glyph):
+        loca = self.tables["loca"][0]
+
+        if self.long_loca:
+            start, end = struct.unpack_from(">II", self.data, loca + 4 * glyph)
+        else:
+            start, end = struct.unpack_from(">HH", self.data, loca + 2 * glyph)
+            start, end = start * 2, end * 2
+
+        return self.tables["glyf"][0] + start, end - start
+
+    def contours(self, glyph):
+        """The outline of `glyph` as lists of (x, y, on_curve) points."""
+        offset, length = self._glyph_range(glyph)
+
+        if length == 0:
+            return []
+
+        (count,) = struct.unpack_from(">h", self.data, offset)
+
+        if count >= 0:
+            return self._simple(offset, count)
+
+        return self._composite(offset)
+
+    def _simple(self, offset, count):
+        ends = struct.unpack_from(">%dH" % count, self.data, offset + 10)
+        points = ends[-1] + 1 if ends else 0
+
+        cursor = offset + 10 + 2 * count
+        (instructions,) = struct.unpack_from(">H", self.data, cursor)
+        cursor += 2 + instructions
+
+        flags = []
+
+        while len(flags) < points:
+            flag = self.data[cursor]
+            cursor += 1
+            flags.append(flag)
+
+            if flag & 0x08:
+                repeat = self.data[cursor]
+                cursor += 1
+                flags.extend([flag] * repeat)
+
+        def coordinates(short, same):
+            nonlocal cursor
+            values, value = [], 0
+
+            for flag in flags:
+                if flag & short:
+                    delta = self.data[cursor]
+                    cursor += 1
+                    value += delta if flag & same else -delta
+                elif not flag & same:
+                    (delta,) = struct.unpack_from(">h", self.data, cursor)
+                    cursor += 2
+                    value += delta
+
+                values.append(value)
+
+            return values
+
+        xs = coordinates(0x02, 0x10)
+        ys = coordinates(0x04, 0x20)
+
+        contours, start = [], 0
+
+        for end in ends:
+            contours.append([(xs[i], ys[i], bool(flags[i] & 1)) for i in range(start, end + 1)])
+            start = end + 1
+
+        return contours
+
+    def _composite(self, offset):
+        cursor = offset + 10
+        contours = []
+
+        while True:
+            flags, glyph = struct.unpack_from(">HH", self.data, cursor)
+            cursor += 4
+
+            if flags & 0x0001:
+                dx, dy = struct.unpack_from(">hh", self.data, cursor)
+                cursor += 4
+            else:
+                dx, dy = struct.unpack_from(">bb", self.data, cursor)
+                cursor += 2
+
+            if not flags & 0x0002:
+                # Point matching isn't used by DejaVu.
+                dx, dy = 0, 0
+
+            a, b, c, d = 1.0, 0.0, 0.0, 1.0
+
+            if flags & 0x0008:
+                (a,) = struct.unpack_from(">h", self.data, cursor)
+                a = d = a / 16384
+                cursor += 2
+            elif flags & 0x0040:
+                a, d = (value / 16384 for value in struct.unpack_from(">hh", self.data, cursor))
+                cursor += 4
+            elif flags & 0x0080:
+                a, b, c, d = (value / 16384 for value in struct.unpack_from(">hhhh", self.data, cursor))
+                cursor += 8
+
+            for contour in self.contours(glyph):
+                contours.append([(a * x + c * y + dx, b * x + d * y + dy, on) for x, y, on in contour])
+
+            if not flags & 0x0020:
+                return contours
+
+
+def flatten(contour, steps=8):
+    """Quadratic contour to a closed polygon."""
+    if not contour:
+        return []
+
+    # Make sure we start on an on-curve point.
+    if not contour[0][2]:
+        if contour[-1][2]:
+            contour = contour[-1:] + contour[:-1]
+        else:
+            first, last = contour[0], contour[-1]
+            contour = [((first[0] + last[0]) / 2, (first[1] + last[1]) / 2, True)] + contour
+
+    # Insert the implicit on-curve points between two off-curve ones.
+    points = []
+
+    for index, point in enumerate(contour):
+        previous = contour[index - 1]
+
+        if index > 0 and not point[2] and not previous[2]:
+            points.append(((point[0] + previous[0]) / 2, (point[1] + previous[1]) / 2, True))
+
+        points.append(point)
+
+    if not points[-1][2]:
+        points.append(points[0])
+
+    polygon = [(points[0][0], points[0][1])]
+    index = 1
+
+    while index < len(points):
+        point = points[index % len(points)]
+
+        if point[2]:
+            polygon.append((point[0], point[1]))
+            index += 1
+            continue
+
+        start = polygon[-1]
+        end = points[(index + 1) % len(points)]
+
+        for step in range(1, steps + 1):
+            t = step / steps
+            x = (1 - t) ** 2 * start[0] + 2 * (1 - t) * t * point[0] + t * t * end[0]
+            y = (1 - t) ** 2 * start[1] + 2 * (1 - t) * t * point[1] + t * t * end[1]
+            polygon.append((x, y))
+
+        index += 2
+
+    return polygon
+
+
+def winding(polygons, x, y):
+    total = 0
+
+    for polygon in polygons:
+        for index in range(len(polygon)):
+            x0, y0 = polygon[index - 1]
+            x1, y1 = polygon[index]
+
+            if y0 <= y < y1 or y1 <= y < y0:
+                crossing = x0 + (y - y0) * (x1 - x0) / (y1 - y0)
+
+                if crossing > x:
+                    total += 1 if y1 > y0 else -1
+
+    return total
+
+
+def rasterize(font, code):
+    """The rows of `code`'s glyph, one byte per row (MSB is the leftmost pixel.)"""
+    glyph = font.cmap.get(code)
+
+    if glyph is None:
+        return None
+
+    scale = WIDTH / font.advance(glyph)
+    vertical = scale
+
+    # Box drawing characters have to touch the cells above and below.
+    if 0x2500 <= code < 0x2580:
+        vertical = HEIGHT / (font.ascender - font.descender)
+
+    height = (font.ascender - font.descender) * vertical
+    baseline = (HEIGHT - height) / 2 + font.ascender * vertical
+
+    polygons = [flatten(contour) for contour in font.contours(glyph)]
+    rows = []
+
+    for row in range(HEIGHT):
+        bits = 0
+
+        for column in range(WIDTH):
+            covered = 0
+
+            for sy in range(SAMPLES):
+                for sx in range(SAMPLES):
+                    x = (column + (sx + 0.5) / SAMPLES) / scale
+                    y = (baseline - (row + (sy + 0.5) / SAMPLES)) / vertical
+
+                    if winding(polygons, x, y) != 0:
+                        covered += 1
+
+            if covered >= THRESHOLD * SAMPLES * SAMPLES:
+                bits |= 0x80 >> column
+
+        rows.append(bits)
+
+    return bytes(rows)
+
+
+# -- Character sets
+
+REPLACEMENT = 0xFFFD
+
+# Code page 437, 0x01-0x1F and 0x7F are the graphic characters.
+CP437_CONTROL = "\u0000☺☻♥♦♣♠•◘○◙♂♀♪♫☼►◄↕‼¶§▬↨↑↓→←∟↔▲▼"
+
+
+def cp437():
+    characters = list(CP437_CONTROL)
+    characters += [chr(code) for code in range(0x20, 0x7F)]
+    characters.append("⌂")
+    characters += list(bytes(range(0x80, 0x100)).decode("cp437"))
+    return [ord(character) for character in characters]
+
+
+def unicode_set():
+    codes = [REPLACEMENT]
+    codes += range(0x20, 0x7F)
+    codes += range(0xA0, 0x100)
+    codes += [0x2013, 0x2014, 0x2018, 0x2019, 0x201C, 0x201D, 0x2022, 0x2026, 0x20AC, 0x2122]
+    codes += range(0x2190, 0x2196)
+    codes += range(0x2500, 0x25A0)
+    codes += [0x25A0, 0x25A1, 0x25B2, 0x25B6, 0x25BA, 0x25BC, 0x25C0, 0x25C4, 0x25C6, 0x25CB, 0x25CF]
+    return codes
+
+
+# -- PSF
+
+
+def write_psf2(path, font, codes):
+    glyphs, table = [], bytearray()
+
+    for code in codes:
+        bitmap = rasterize(font, code)
+
+        if bitmap is None:
+            continue
+
+        glyphs.append(bitmap)
+        table += chr(code).encode("utf-8") + b"\xff"
+
+    header = struct.pack(
+        "<IIIIIIII",
+        0x864AB572,  # magic
+        0,  # version
+        32,  # header size
+        0x01,  # flags: has a unicode table
+        len(glyphs),
+        HEIGHT,  # bytes per glyph
+        HEIGHT,
+        WIDTH,
+    )
+
+    with open(path, "wb") as f:
+        f.write(header + b"".join(glyphs) + bytes(table))
+
+    return len(glyphs)
+
+
+def write_psf1(path, font, codes):
+    glyphs, table = [], bytearray()
+
+    for index, code in enumerate(codes):
+        # Glyph 0 is the fallback.
+        code = REPLACEMENT if index == 0 else code
+        bitmap = rasterize(font, code) or bytes(HEIGHT)
+
+        glyphs.append(bitmap)
+        table += struct.pack("<HH", code, 0xFFFF)
+
+    assert len(glyphs) == 256
+
+    # Magic, mode (has a unicode table), character size.
+    header = struct.pack("<BBBB", 0x36, 0x04, 0x02, HEIGHT)
+
+    with open(path, "wb") as f:
+        f.write(header + b"".join(glyphs) + bytes(table))
+
+
+def main():
+    directory = sys.argv[1] if len(sys.argv) > 1 else "/usr/share/fonts/truetype/dejavu"
+    here = os.path.dirname(os.path.abspath(__file__))
+
+    font = Font(os.path.join(directory, "DejaVuSansMono-Bold.ttf"))
+
+    count = write_psf2(os.path.join(here, "dejavu-mono-8x16.psfu"), font, unicode_set())
+    write_psf1(os.path.join(here, "dejavu-mono-cp437-8x16.psf"), font, cp437())
+
+    print("%d + 256 glyphs" % count)
+
+
+if __name__ == "__main__":
+    main()
@@ -2,6 +2,8 @@
 #![feature(global_asm)]
 #![feature(asm)]
 #![feature(abi_x86_interrupt)]
+#![feature(const_in_array_repeat_expressions)]
+#![feature(min_const_generics)]
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "x86-64")] {
@@ -0,0 +1,266 @@
+//! Frame pointer based stack unwinding and symbolization.
+//!
+//! Every frame starts with the caller's RBP followed by the return address
+//! so walking the chain is enough, as long as everything was compiled with
+//! frame pointers (`x.py --frame-pointers`.) The bootstrap code zeroes RBP
+//! before calling into Rust which terminates the chain.
+//!
+//! Symbols come from the kernel's `.symtab` and `.strtab`, which GRUB loads
+//! along with the other ELF sections (so `x.py` only strips debug info.)
+
+use core::{
+    cell::Cell,
+    fmt,
+    mem::size_of,
+    slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use multiboot2::{BootInformation, ElfSection, ElfSectionType};
+use rustc_demangle::demangle;
+use spin::Once;
+
+use super::{interrupts::diagnostics::is_range_mapped, memory::IDENTITY_MAPPED_LIMIT, percpu};
+
+/// The deepest backtrace we collect.
+pub const MAX_FRAMES: usize = 32;
+
+crate::percpu! {
+    /// Set once this processor started logging a panic backtrace.
+    static PANICKED: Cell<bool> = Cell::new(false);
+}
+
+/// `PANICKED` for panics before the per-CPU data is set up.
+static BOOT_PANICKED: AtomicBool = AtomicBool::new(false);
+
+/// Should a panic on the executing processor log a backtrace?
+///
+/// Only the first one does, a panic while unwinding would just recurse.
+/// Panics on the other processors still get theirs.
+pub(crate) fn first_panic() -> bool {
+    if percpu::is_ready() {
+        !PANICKED.get().replace(true)
+    } else {
+        !BOOT_PANICKED.swap(true, Ordering::SeqCst)
+    }
+}
+
+/// `Elf64_Sym`
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ElfSymbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    section: u16,
+    value: u64,
+    size: u64,
+}
+
+const STT_FUNC: u8 = 2;
+
+struct SymbolTable {
+    symbols: &'static [ElfSymbol],
+    strings: &'static [u8],
+}
+
+static SYMBOL_TABLE: Once<Option<SymbolTable>> = Once::new();
+
+/// The `.symtab` and `.strtab` sections of the kernel, if GRUB loaded them.
+pub(super) fn symbol_sections(info: &BootInformation) -> Option<(ElfSection, ElfSection)> {
+    let sections = info.elf_sections_tag()?;
+
+    let symtab = sections
+        .sections()
+        .find(|section| section.section_type() == ElfSectionType::LinkerSymbolTable)?;
+
+    let strtab = sections.sections().find(|section| section.name() == ".strtab")?;
+
+    Some((symtab, strtab))
+}
+
+/// Find the kernel's symbol table, backtraces are unsymbolized without one.
+pub(super) fn initialize(info: &BootInformation) {
+    let table = SYMBOL_TABLE.call_once(|| {
+        let (symtab, strtab) = symbol_sections(info)?;
+
+        if symtab.end_address() > IDENTITY_MAPPED_LIMIT as u64 || strtab.end_address() > IDENTITY_MAPPED_LIMIT as u64 {
+            log::warn!("(BACKTRACE) The symbol table isn't identity mapped");
+            return None;
+        }
+
+        unsafe {
+            Some(SymbolTable {
+                symbols: slice::from_raw_parts(
+                    symtab.start_address() as *const ElfSymbol,
+                    symtab.size() as usize / size_of::<ElfSymbol>(),
+                ),
+                strings: slice::from_raw_parts(strtab.start_address() as *const u8, strtab.size() as usize),
+            })
+        }
+    });
+
+    match table {
+        Some(table) => log::info!("(BACKTRACE) Loaded {:?} symbols", table.symbols.len()),
+        None => log::warn!("(BACKTRACE) No symbol table, backtraces won't be symbolized"),
+    }
+}
+
+impl SymbolTable {
+    fn name(&self, symbol: &ElfSymbol) -> &'static str {
+        let start = symbol.name as usize;
+
+        let bytes = match self.strings.get(start..) {
+            Some(bytes) => bytes,
+            None => return "",
+        };
+
+        let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+
+        core::str::from_utf8(&bytes[..end]).unwrap_or("")
+    }
+
+    fn lookup(&self, address: u64) -> Option<(&'static str, u64)> {
+        self.symbols
+            .iter()
+            .filter(|symbol| symbol.info & 0xF == STT_FUNC)
+            .find(|symbol| symbol.value <= address && address < symbol.value + symbol.size.max(1))
+            .map(|symbol| (self.name(symbol), address - symbol.value))
+    }
+}
+
+/// The function containing `address` (and the offset into it.)
+pub fn symbolize(address: u64) -> Option<(&'static str, u64)> {
+    SYMBOL_TABLE.r#try()?.as_ref()?.lookup(address)
+}
+
+// -- Backtrace
+
+/// Return addresses collected by walking the frame pointer chain.
+#[derive(Clone, Copy)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+
+    /// The first frame is an instruction pointer rather than a return address.
+    exact_first: bool,
+}
+
+impl Backtrace {
+    /// Walk the chain starting at the frame `rbp`, `first` is reported as the innermost frame.
+    pub fn from_frame(first: Option<u64>, mut rbp: u64) -> Self {
+        let mut backtrace = Self {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+            exact_first: first.is_some(),
+        };
+
+        if let Some(rip) = first {
+            backtrace.push(rip);
+        }
+
+        while rbp != 0 && rbp % 8 == 0 && backtrace.len < MAX_FRAMES {
+            if !is_range_mapped(rbp, 16) {
+                break;
+            }
+
+            let (next, return_address) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+
+            if return_address == 0 {
+                break;
+            }
+
+            backtrace.push(return_address);
+
+            // Frames only ever move up the stack.
+            if next <= rbp {
+                break;
+            }
+
+            rbp = next;
+        }
+
+        backtrace
+    }
+
+    /// The backtrace of the code an exception handler interrupted.
+    ///
+    /// `handler_rbp` is the handler's own frame, it links to the interrupted one.
+    #[inline]
+    pub fn interrupted(rip: u64, handler_rbp: u64) -> Self {
+        let rbp = if handler_rbp % 8 == 0 && is_range_mapped(handler_rbp, 8) {
+            unsafe { *(handler_rbp as *const u64) }
+        } else {
+            0
+        };
+
+        Self::from_frame(Some(rip), rbp)
+    }
+
+    /// The backtrace of the caller.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        Self::from_frame(None, current_frame())
+    }
+
+    #[inline]
+    fn push(&mut self, address: u64) {
+        self.frames[self.len] = address;
+        self.len += 1;
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+
+    /// Log every frame at error level.
+    pub fn log(&self) {
+        log::error!("(BACKTRACE) Stack backtrace:");
+
+        for index in 0..self.len {
+            log::error!("    {:>2}: {}", index, self.frame(index));
+        }
+    }
+
+    fn frame(&self, index: usize) -> Frame {
+        Frame {
+            address: self.frames[index],
+            exact: index == 0 && self.exact_first,
+        }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for index in 0..self.len {
+            writeln!(f, "{:>4}: {}", index, self.frame(index))?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Frame {
+    address: u64,
+    exact: bool,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Return addresses point after the call, look up the call itself.
+        let adjust = if self.exact { 0 } else { 1 };
+
+        match symbolize(self.address.saturating_sub(adjust)) {
+            Some((name, offset)) => write!(f, "{:#018x} - {:#} + {:#x}", self.address, demangle(name), offset + adjust),
+            None => write!(f, "{:#018x} - <unknown>", self.address),
+        }
+    }
+}
+
+/// The RBP of the calling function.
+#[inline(always)]
+pub fn current_frame() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
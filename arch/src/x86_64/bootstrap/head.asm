@@ -162,6 +162,9 @@ __start:
     mov fs, ax
     mov gs, ax
 
+    ; Terminate the frame pointer chain for backtraces
+    xor rbp, rbp
+
     ; Call into Rust
     call __kmain
 
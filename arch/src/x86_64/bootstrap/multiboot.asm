@@ -7,7 +7,16 @@ header_start:
     ; checksum
     dd 0x100000000 - (0xe85250d6 + 0 + (header_end - header_start))
 
-    ; insert optional multiboot tags here
+    ; framebuffer tag, optional (we fall back to VGA text mode)
+    dw 5    ; type
+    dw 1    ; flags
+    dd 20   ; size
+    dd 1024 ; width
+    dd 768  ; height
+    dd 32   ; depth
+
+    ; tags are 8 byte aligned
+    align 8
 
     ; required end tag
     dw 0    ; type
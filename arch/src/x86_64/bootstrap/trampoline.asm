@@ -0,0 +1,121 @@
+; Application processor trampoline.
+;
+; This is never executed in place, `smp.rs` copies everything between
+; `ap_trampoline_start` and `ap_trampoline_end` to `AP_TRAMPOLINE` (0x8000)
+; and patches `ap_trampoline_params` before sending the startup IPIs.
+;
+; APs start executing at 0x8000 in real mode, switch to protected mode using
+; a small GDT of their own, enable PAE + long mode with the boot processor's
+; PML4 and finally jump into Rust on a stack set up by the boot processor.
+
+section .rodata
+
+%define AP_TRAMPOLINE 0x8000
+
+; The address of `label` once the trampoline has been copied.
+%define REL(label) (AP_TRAMPOLINE + (label - ap_trampoline_start))
+
+global ap_trampoline_start
+global ap_trampoline_end
+global ap_trampoline_params
+
+align 16
+
+bits 16
+
+ap_trampoline_start:
+    cli
+    cld
+
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    o32 lgdt [REL(ap_trampoline_gdt.ptr)]
+
+    ; Protected mode.
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+
+    jmp ap_trampoline_gdt.code32:REL(ap_trampoline_32)
+
+bits 32
+
+ap_trampoline_32:
+    mov ax, ap_trampoline_gdt.data32
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    ; Set PAE bit in CR4
+    mov eax, cr4
+    or eax, (1 << 5)
+    mov cr4, eax
+
+    ; Share the boot processor's page tables.
+    mov eax, [REL(ap_trampoline_params.cr3)]
+    mov cr3, eax
+
+    ; Set LM enable bit in the EFER MSR
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, (1 << 8)
+    wrmsr
+
+    ; enable paging in the CR0 register
+    mov eax, cr0
+    or eax, (1 << 31)
+    mov cr0, eax
+
+    jmp ap_trampoline_gdt.code64:REL(ap_trampoline_64)
+
+bits 64
+
+ap_trampoline_64:
+    xor ax, ax
+    mov ss, ax
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, [REL(ap_trampoline_params.stack)]
+    mov rdi, [REL(ap_trampoline_params.arg)]
+    mov rax, [REL(ap_trampoline_params.entry)]
+
+    ; Terminate the frame pointer chain for backtraces
+    xor rbp, rbp
+
+    ; Call into Rust (never returns)
+    call rax
+
+.spin:
+    hlt
+    jmp .spin
+
+align 8
+
+ap_trampoline_gdt:
+    dq 0 ; zero entry
+.code32: equ $ - ap_trampoline_gdt
+    dq 0x00CF9A000000FFFF ; 32-bit code segment
+.data32: equ $ - ap_trampoline_gdt
+    dq 0x00CF92000000FFFF ; 32-bit data segment
+.code64: equ $ - ap_trampoline_gdt
+    dq (1<<43) | (1<<44) | (1<<47) | (1<<53) ; code segment
+.ptr:
+    dw $ - ap_trampoline_gdt - 1
+    dd REL(ap_trampoline_gdt)
+
+align 8
+
+; Patched by the boot processor, see `TrampolineParams` in `smp.rs`.
+ap_trampoline_params:
+.cr3: dq 0
+.stack: dq 0
+.entry: dq 0
+.arg: dq 0
+
+ap_trampoline_end:
@@ -0,0 +1,188 @@
+//! The local APIC timer as a (per-CPU) clock event.
+//!
+//! It counts down from an initial count at the (calibrated) bus frequency
+//! divided by `DIVIDE_BY`, either once or periodically. When the CPU supports
+//! it one-shot events use TSC-deadline mode instead, which is programmed with
+//! an absolute TSC value.
+
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
+
+use x86_64::registers::model_specific::Msr;
+
+use super::{tsc, ClockError, ClockEvent, ClockEventFeatures};
+use crate::x86_64::{
+    device::apic::{local_apic, LocalApic, Register},
+    interrupts::irq::{self, IrqContext, IrqReturn},
+};
+
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// Divide configuration value for dividing by 16.
+const DIVIDE_BY_16: u32 = 0b0011;
+const DIVIDE_BY: u64 = 16;
+
+const LVT_MASKED: u32 = 1 << 16;
+
+/// Timer modes of the LVT timer entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TimerMode {
+    OneShot = 0b00 << 17,
+    Periodic = 0b01 << 17,
+    TscDeadline = 0b10 << 17,
+}
+
+pub struct ApicTimer {
+    /// Timer counts per second (after dividing.)
+    frequency: AtomicU64,
+    vector: AtomicU8,
+}
+
+pub static APIC_TIMER: ApicTimer = ApicTimer {
+    frequency: AtomicU64::new(0),
+    vector: AtomicU8::new(0),
+};
+
+/// Can the timer be programmed in TSC-deadline mode? (and is the TSC frequency known?)
+fn tsc_deadline_supported() -> bool {
+    let leaf = unsafe { __cpuid(1) };
+    leaf.ecx & (1 << 24) != 0 && tsc::frequency() != 0
+}
+
+impl ApicTimer {
+    fn apic(&self) -> &'static LocalApic {
+        local_apic().expect("The APIC timer needs a local APIC")
+    }
+
+    fn program(&self, mode: TimerMode) -> &'static LocalApic {
+        let apic = self.apic();
+
+        apic.write(Register::TimerDivide, DIVIDE_BY_16);
+        apic.write(Register::LvtTimer, mode as u32 | self.vector.load(Ordering::Relaxed) as u32);
+
+        apic
+    }
+
+    fn count(&self, duration: Duration) -> Result<u32, ClockError> {
+        let count = (self.frequency.load(Ordering::Relaxed) as u128 * duration.as_nanos()) / super::NANOS_PER_SEC as u128;
+
+        if count == 0 || count > u32::MAX as u128 {
+            return Err(ClockError::OutOfRange);
+        }
+
+        Ok(count as u32)
+    }
+
+    /// Fire once at the absolute TSC value `deadline`.
+    pub fn set_deadline(&self, deadline: u64) -> Result<(), ClockError> {
+        if !tsc_deadline_supported() {
+            return Err(ClockError::Unsupported);
+        }
+
+        self.program(TimerMode::TscDeadline);
+
+        unsafe { Msr::new(IA32_TSC_DEADLINE).write(deadline) };
+
+        Ok(())
+    }
+}
+
+impl ClockEvent for ApicTimer {
+    fn name(&self) -> &'static str {
+        "apic-timer"
+    }
+
+    fn rating(&self) -> u32 {
+        if tsc_deadline_supported() {
+            300
+        } else {
+            200
+        }
+    }
+
+    fn features(&self) -> ClockEventFeatures {
+        ClockEventFeatures {
+            oneshot: true,
+            periodic: true,
+            per_cpu: true,
+        }
+    }
+
+    fn set_periodic(&self, period: Duration) -> Result<(), ClockError> {
+        let count = self.count(period)?;
+
+        self.program(TimerMode::Periodic).write(Register::TimerInitialCount, count);
+
+        Ok(())
+    }
+
+    fn set_oneshot(&self, delta: Duration) -> Result<(), ClockError> {
+        if tsc_deadline_supported() {
+            let cycles = (tsc::frequency() as u128 * delta.as_nanos()) / super::NANOS_PER_SEC as u128;
+            return self.set_deadline(unsafe { _rdtsc() }.saturating_add(cycles as u64));
+        }
+
+        let count = self.count(delta)?;
+
+        self.program(TimerMode::OneShot).write(Register::TimerInitialCount, count);
+
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        let apic = self.apic();
+
+        apic.write(Register::LvtTimer, LVT_MASKED);
+        apic.write(Register::TimerInitialCount, 0);
+
+        if tsc_deadline_supported() {
+            unsafe { Msr::new(IA32_TSC_DEADLINE).write(0) };
+        }
+    }
+}
+
+fn on_timer(_context: &IrqContext) -> IrqReturn {
+    super::event_fired();
+    IrqReturn::Handled
+}
+
+/// Calibrate the timer of the executing (boot) processor and register it.
+pub(super) fn register() {
+    let apic = match local_apic() {
+        Some(apic) => apic,
+        None => return,
+    };
+
+    // Count down from the top with the timer masked.
+    apic.write(Register::TimerDivide, DIVIDE_BY_16);
+    apic.write(Register::LvtTimer, LVT_MASKED);
+    apic.write(Register::TimerInitialCount, u32::MAX);
+
+    let frequency = super::calibrate(|| (u32::MAX - apic.read(Register::TimerCurrentCount)) as u64);
+
+    apic.write(Register::TimerInitialCount, 0);
+
+    let handle = match irq::request_vector("APIC timer", on_timer, 0) {
+        Ok(handle) => handle,
+        Err(err) => {
+            log::warn!("(APIC) No vector for the APIC timer: {:?}", err);
+            return;
+        }
+    };
+
+    APIC_TIMER.frequency.store(frequency, Ordering::Relaxed);
+    APIC_TIMER.vector.store(handle.vector(), Ordering::Relaxed);
+
+    log::info!(
+        "(APIC) Timer runs at {:?}Hz (bus {:?}Hz), TSC-deadline: {}",
+        frequency,
+        frequency * DIVIDE_BY,
+        tsc_deadline_supported()
+    );
+
+    super::register_event(&APIC_TIMER);
+}
@@ -0,0 +1,317 @@
+//! High Precision Event Timer (HPET) clock source and comparator clock events.
+//!
+//! The HPET is found through the ACPI HPET table, its main counter runs at
+//! (at least) 10MHz and every comparator can raise an interrupt when the
+//! counter reaches it. Comparator interrupts are routed through the I/O APIC.
+
+use core::{
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
+
+use spin::Once;
+
+use super::{ClockError, ClockEvent, ClockEventFeatures, ClockSource};
+use crate::x86_64::{
+    device::ioapic::{self, Polarity, TriggerMode},
+    interrupts::irq::{self, IrqContext, IrqReturn},
+};
+
+const CAPABILITIES: u64 = 0x000;
+const CONFIGURATION: u64 = 0x010;
+const INTERRUPT_STATUS: u64 = 0x020;
+const MAIN_COUNTER: u64 = 0x0F0;
+
+const fn timer_configuration(timer: u64) -> u64 {
+    0x100 + 0x20 * timer
+}
+
+const fn timer_comparator(timer: u64) -> u64 {
+    0x108 + 0x20 * timer
+}
+
+/// Bits of `CAPABILITIES`.
+const CAP_COUNTER_64: u64 = 1 << 13;
+
+/// Bits of `CONFIGURATION`.
+const CONFIG_ENABLE: u64 = 1 << 0;
+
+/// Bits of the timer configuration registers.
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_SET_VALUE: u64 = 1 << 6;
+const TIMER_32_BIT: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+// -- Hpet
+
+pub struct Hpet {
+    base: u64,
+
+    /// Main counter ticks per second.
+    frequency: u64,
+    mask: u64,
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+impl Hpet {
+    #[inline]
+    fn read(&self, register: u64) -> u64 {
+        unsafe { read_volatile((self.base + register) as *const u64) }
+    }
+
+    #[inline]
+    fn write(&self, register: u64, value: u64) {
+        unsafe { write_volatile((self.base + register) as *mut u64, value) }
+    }
+
+    #[inline]
+    fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER) & self.mask
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn mask(&self) -> u64 {
+        self.mask
+    }
+
+    fn read(&self) -> u64 {
+        self.counter()
+    }
+}
+
+/// Busy wait on the main counter, returns `false` (immediately) if there is no HPET.
+pub(super) fn wait_micros(micros: u64) -> bool {
+    let hpet = match HPET.r#try() {
+        Some(hpet) => hpet,
+        None => return false,
+    };
+
+    let ticks = (hpet.frequency as u128 * micros as u128 / 1_000_000) as u64;
+    let start = hpet.counter();
+
+    while hpet.counter().wrapping_sub(start) & hpet.mask < ticks {
+        core::sync::atomic::spin_loop_hint();
+    }
+
+    true
+}
+
+// -- HpetComparator
+
+/// One of the HPET's comparators, as a clock event.
+pub struct HpetComparator {
+    timer: u64,
+    periodic_capable: bool,
+    armed: AtomicBool,
+    vector: AtomicU8,
+    period: AtomicU64,
+}
+
+impl HpetComparator {
+    fn hpet(&self) -> &'static Hpet {
+        HPET.r#try().expect("HPET comparator without an HPET")
+    }
+
+    fn ticks(&self, duration: Duration) -> Result<u64, ClockError> {
+        let hpet = self.hpet();
+        let ticks = (hpet.frequency as u128 * duration.as_nanos()) / super::NANOS_PER_SEC as u128;
+
+        if ticks == 0 || ticks > hpet.mask as u128 {
+            return Err(ClockError::OutOfRange);
+        }
+
+        Ok(ticks as u64)
+    }
+}
+
+impl ClockEvent for HpetComparator {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        150
+    }
+
+    fn features(&self) -> ClockEventFeatures {
+        ClockEventFeatures {
+            oneshot: true,
+            periodic: self.periodic_capable,
+            per_cpu: false,
+        }
+    }
+
+    fn set_periodic(&self, period: Duration) -> Result<(), ClockError> {
+        if !self.periodic_capable {
+            return Err(ClockError::Unsupported);
+        }
+
+        let hpet = self.hpet();
+        let ticks = self.ticks(period)?;
+
+        let config = hpet.read(timer_configuration(self.timer));
+
+        // The first write sets the comparator, the second the period (with `TIMER_SET_VALUE`.)
+        hpet.write(
+            timer_configuration(self.timer),
+            config | TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC | TIMER_SET_VALUE,
+        );
+        hpet.write(timer_comparator(self.timer), hpet.counter().wrapping_add(ticks) & hpet.mask);
+        hpet.write(timer_comparator(self.timer), ticks);
+
+        self.period.store(ticks, Ordering::SeqCst);
+        self.armed.store(true, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    fn set_oneshot(&self, delta: Duration) -> Result<(), ClockError> {
+        let hpet = self.hpet();
+        let ticks = self.ticks(delta)?;
+
+        let config = hpet.read(timer_configuration(self.timer)) & !TIMER_PERIODIC;
+
+        hpet.write(timer_configuration(self.timer), config | TIMER_INTERRUPT_ENABLE);
+        hpet.write(timer_comparator(self.timer), hpet.counter().wrapping_add(ticks) & hpet.mask);
+
+        self.period.store(0, Ordering::SeqCst);
+        self.armed.store(true, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        let hpet = self.hpet();
+        let config = hpet.read(timer_configuration(self.timer));
+
+        hpet.write(timer_configuration(self.timer), config & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC));
+        self.armed.store(false, Ordering::SeqCst);
+    }
+}
+
+static COMPARATOR: Once<HpetComparator> = Once::new();
+
+fn on_comparator(_context: &IrqContext) -> IrqReturn {
+    let comparator = match COMPARATOR.r#try() {
+        Some(comparator) if comparator.armed.load(Ordering::Relaxed) => comparator,
+        _ => return IrqReturn::NotMine,
+    };
+
+    // Edge triggered, but clear the status bit anyway in case the firmware left it level.
+    comparator.hpet().write(INTERRUPT_STATUS, 1 << comparator.timer);
+
+    if comparator.period.load(Ordering::Relaxed) == 0 {
+        comparator.armed.store(false, Ordering::Relaxed);
+    }
+
+    super::event_fired();
+
+    IrqReturn::Handled
+}
+
+/// Set up comparator 0 as a clock event, if it can be routed through an I/O APIC.
+fn register_comparator(hpet: &'static Hpet) {
+    let config = hpet.read(timer_configuration(0));
+
+    // Leave the ISA IRQs alone.
+    let routes = (config >> 32) & !0xFFFF;
+
+    if routes == 0 {
+        log::warn!("(HPET) Comparator 0 can't be routed above the ISA IRQs");
+        return;
+    }
+
+    let gsi = routes.trailing_zeros();
+
+    let handle = match irq::request_vector("HPET", on_comparator, 0) {
+        Ok(handle) => handle,
+        Err(err) => {
+            log::warn!("(HPET) No vector for comparator 0: {:?}", err);
+            return;
+        }
+    };
+
+    let destination = crate::prelude::local_apic_id().unwrap_or(0) as u8;
+
+    if !ioapic::route_gsi(gsi, handle.vector(), destination, Polarity::ActiveHigh, TriggerMode::Edge) {
+        log::warn!("(HPET) No I/O APIC handles GSI {:?}", gsi);
+        irq::free(handle);
+        return;
+    }
+
+    let config = (config & !(TIMER_32_BIT | (0x1F << TIMER_ROUTE_SHIFT))) | ((gsi as u64) << TIMER_ROUTE_SHIFT);
+
+    hpet.write(timer_configuration(0), config & !TIMER_INTERRUPT_ENABLE);
+    ioapic::unmask(gsi);
+
+    let comparator = COMPARATOR.call_once(|| HpetComparator {
+        timer: 0,
+        periodic_capable: config & TIMER_PERIODIC_CAPABLE != 0,
+        armed: AtomicBool::new(false),
+        vector: AtomicU8::new(handle.vector()),
+        period: AtomicU64::new(0),
+    });
+
+    log::info!(
+        "(HPET) Comparator 0 on GSI {:?} (vector {:#x})",
+        gsi,
+        comparator.vector.load(Ordering::Relaxed)
+    );
+
+    super::register_event(comparator);
+}
+
+/// Enable the HPET at `address` and register it.
+pub(super) fn register(address: u64) {
+    crate::x86_64::device::map_mmio(address, "HPET");
+
+    let hpet = HPET.call_once(|| {
+        let capabilities = unsafe { read_volatile((address + CAPABILITIES) as *const u64) };
+        let period = capabilities >> 32;
+
+        Hpet {
+            base: address,
+            frequency: FEMTOS_PER_SEC / period.max(1),
+            mask: if capabilities & CAP_COUNTER_64 != 0 { u64::MAX } else { u32::MAX as u64 },
+        }
+    });
+
+    let capabilities = hpet.read(CAPABILITIES);
+
+    log::info!(
+        "(HPET) {:?} comparators, {:?}Hz, {}-bit counter",
+        ((capabilities >> 8) & 0x1F) + 1,
+        hpet.frequency,
+        if hpet.mask == u64::MAX { 64 } else { 32 }
+    );
+
+    // Disable every comparator before starting the main counter.
+    for timer in 0..(((capabilities >> 8) & 0x1F) + 1) {
+        let config = hpet.read(timer_configuration(timer));
+        hpet.write(timer_configuration(timer), config & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC));
+    }
+
+    hpet.write(CONFIGURATION, hpet.read(CONFIGURATION) | CONFIG_ENABLE);
+
+    super::register_source(hpet);
+    register_comparator(hpet);
+}
@@ -0,0 +1,346 @@
+//! Timekeeping: clock sources and clock event devices.
+//!
+//! A `ClockSource` is a free running counter (PIT ticks, the HPET main
+//! counter, the TSC), the one with the best rating backs `monotonic_nanos`.
+//! A `ClockEvent` raises interrupts in the future, one-shot or periodically
+//! (the PIT, HPET comparators and the local APIC timer.)
+//!
+//! Readers never lock: the active source and the conversion from its cycles
+//! to nanoseconds live behind a sequence counter that is only ever written
+//! when switching sources or folding in a wrapping counter.
+
+use core::{
+    cell::UnsafeCell,
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub mod apic_timer;
+pub mod hpet;
+pub mod pit;
+pub mod realtime;
+pub mod tsc;
+
+/// The most clock sources (or events) that can be registered.
+const MAX_CLOCKS: usize = 8;
+
+/// How long calibrations measure for.
+const CALIBRATION_MICROS: u64 = 10_000;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// A free running counter.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Higher is better, the best registered source is used for timekeeping.
+    fn rating(&self) -> u32;
+
+    /// Counts per second.
+    fn frequency(&self) -> u64;
+
+    /// The valid bits of `read` (the counter wraps after `mask`.)
+    fn mask(&self) -> u64;
+
+    fn read(&self) -> u64;
+}
+
+/// The modes a `ClockEvent` can be programmed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockEventFeatures {
+    pub oneshot: bool,
+    pub periodic: bool,
+
+    /// Is the device local to every processor (i.e. the APIC timer)?
+    pub per_cpu: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockError {
+    /// The device can't be programmed in that mode.
+    Unsupported,
+
+    /// The interval is too short or too long for the device.
+    OutOfRange,
+}
+
+/// Something that can interrupt us in the future.
+///
+/// Expiries call the handler installed with `set_event_handler`.
+pub trait ClockEvent: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Higher is better.
+    fn rating(&self) -> u32;
+
+    fn features(&self) -> ClockEventFeatures;
+
+    /// Fire every `period` until shut down.
+    fn set_periodic(&self, period: Duration) -> Result<(), ClockError>;
+
+    /// Fire once, `delta` from now.
+    fn set_oneshot(&self, delta: Duration) -> Result<(), ClockError>;
+
+    /// Stop firing.
+    fn shutdown(&self);
+}
+
+// -- Registry
+
+static SOURCES: Mutex<[Option<&'static dyn ClockSource>; MAX_CLOCKS]> = Mutex::new([None; MAX_CLOCKS]);
+static EVENTS: Mutex<[Option<&'static dyn ClockEvent>; MAX_CLOCKS]> = Mutex::new([None; MAX_CLOCKS]);
+
+/// The handler called whenever a clock event fires, as a `fn()`.
+static EVENT_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Register a clock source and switch to it if it's the best one.
+pub fn register_source(source: &'static dyn ClockSource) {
+    {
+        let mut sources = SOURCES.lock();
+
+        match sources.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(source),
+            None => {
+                log::warn!("(CLOCK) No room for clock source {:?}", source.name());
+                return;
+            }
+        }
+    }
+
+    log::info!(
+        "(CLOCK) Clock source {:?} ({:?}Hz, rating {:?})",
+        source.name(),
+        source.frequency(),
+        source.rating()
+    );
+
+    let current = TIMEKEEPER.read().source.map_or(0, |current| current.rating());
+
+    if source.rating() > current {
+        TIMEKEEPER.switch(source);
+        log::info!("(CLOCK) Switched to clock source {:?}", source.name());
+    }
+}
+
+/// Register a clock event device.
+pub fn register_event(event: &'static dyn ClockEvent) {
+    let mut events = EVENTS.lock();
+
+    match events.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(event);
+
+            log::info!(
+                "(CLOCK) Clock event {:?} ({:?}, rating {:?})",
+                event.name(),
+                event.features(),
+                event.rating()
+            );
+        }
+
+        None => log::warn!("(CLOCK) No room for clock event {:?}", event.name()),
+    }
+}
+
+/// The best clock event that supports `features` (e.g. only `oneshot`.)
+pub fn best_event(features: ClockEventFeatures) -> Option<&'static dyn ClockEvent> {
+    let events = EVENTS.lock();
+
+    events
+        .iter()
+        .flatten()
+        .filter(|event| {
+            let has = event.features();
+            (has.oneshot || !features.oneshot) && (has.periodic || !features.periodic) && (has.per_cpu || !features.per_cpu)
+        })
+        .max_by_key(|event| event.rating())
+        .copied()
+}
+
+/// The clock source in use.
+pub fn current_source() -> Option<&'static dyn ClockSource> {
+    TIMEKEEPER.read().source
+}
+
+/// Call `handler` (in interrupt context) whenever a clock event fires.
+pub fn set_event_handler(handler: Option<fn()>) {
+    EVENT_HANDLER.store(handler.map_or(0, |handler| handler as usize), Ordering::Release);
+}
+
+/// Called by the clock event devices when they fire.
+fn event_fired() {
+    let handler = EVENT_HANDLER.load(Ordering::Acquire);
+
+    if handler != 0 {
+        // SAFETY: Only ever set from a `fn()` in `set_event_handler`.
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+}
+
+// -- Timekeeper
+
+#[derive(Clone, Copy)]
+struct Timebase {
+    source: Option<&'static dyn ClockSource>,
+    base_cycles: u64,
+    base_nanos: u64,
+
+    /// `nanos = (cycles * mult) >> 32`
+    mult: u64,
+    mask: u64,
+}
+
+impl Timebase {
+    const EMPTY: Self = Self {
+        source: None,
+        base_cycles: 0,
+        base_nanos: 0,
+        mult: 0,
+        mask: 0,
+    };
+
+    #[inline]
+    fn nanos(&self) -> u64 {
+        let source = match self.source {
+            Some(source) => source,
+            None => return self.base_nanos,
+        };
+
+        let delta = source.read().wrapping_sub(self.base_cycles) & self.mask;
+
+        self.base_nanos + ((delta as u128 * self.mult as u128) >> 32) as u64
+    }
+}
+
+/// A sequence counter protected `Timebase`, kept twice so readers never wait for a writer.
+///
+/// Even sequence numbers mean the first copy is current, odd ones the
+/// second: writers update one copy while readers use the other. That keeps
+/// reads working in an NMI that interrupted a write on the same CPU (e.g.
+/// logging, which timestamps every record.)
+struct Timekeeper {
+    sequence: AtomicU64,
+    timebase: [UnsafeCell<Timebase>; 2],
+
+    /// Serialises writers.
+    writer: Mutex<()>,
+}
+
+// SAFETY: `timebase` is only written under `writer` and readers retry torn reads.
+unsafe impl Sync for Timekeeper {}
+
+static TIMEKEEPER: Timekeeper = Timekeeper {
+    sequence: AtomicU64::new(0),
+    timebase: [UnsafeCell::new(Timebase::EMPTY), UnsafeCell::new(Timebase::EMPTY)],
+    writer: Mutex::new(()),
+};
+
+impl Timekeeper {
+    #[inline]
+    fn read(&self) -> Timebase {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+
+            let timebase = unsafe { read_volatile(self.timebase[(before & 1) as usize].get()) };
+
+            // Only retried if a writer moved on to the copy we were reading.
+            if self.sequence.load(Ordering::Acquire) == before {
+                return timebase;
+            }
+        }
+    }
+
+    fn write(&self, f: impl FnOnce(Timebase) -> Timebase) {
+        without_interrupts(|| {
+            let _writer = self.writer.lock();
+
+            let timebase = f(unsafe { read_volatile(self.timebase[0].get()) });
+
+            // Send readers to the second copy while the first one changes, then back.
+            for copy in self.timebase.iter() {
+                self.sequence.fetch_add(1, Ordering::AcqRel);
+
+                unsafe { write_volatile(copy.get(), timebase) };
+            }
+        })
+    }
+
+    /// Continue counting from `source`, without jumping back.
+    fn switch(&self, source: &'static dyn ClockSource) {
+        self.write(|timebase| Timebase {
+            source: Some(source),
+            base_nanos: timebase.nanos(),
+            base_cycles: source.read(),
+            mult: ((NANOS_PER_SEC as u128) << 32) as u64 / source.frequency().max(1),
+            mask: source.mask(),
+        })
+    }
+
+    /// Fold the cycles counted so far into the base (so narrow counters can't wrap twice.)
+    fn accumulate(&self) {
+        self.write(|timebase| Timebase {
+            base_nanos: timebase.nanos(),
+            base_cycles: timebase.source.map_or(0, |source| source.read()),
+            ..timebase
+        })
+    }
+}
+
+/// Nanoseconds since the first clock source was registered, never goes back.
+#[inline]
+pub fn monotonic_nanos() -> u64 {
+    TIMEKEEPER.read().nanos()
+}
+
+/// `monotonic_nanos` as a `Duration`.
+#[inline]
+pub fn monotonic() -> Duration {
+    Duration::from_nanos(monotonic_nanos())
+}
+
+/// Called on every system tick.
+pub(crate) fn tick() {
+    let timebase = TIMEKEEPER.read();
+
+    // 64-bit counters don't wrap in practice.
+    if timebase.source.is_some() && timebase.mask != u64::MAX {
+        TIMEKEEPER.accumulate();
+    }
+}
+
+// -- Calibration
+
+/// Wait for about `micros` using the best reference we have that doesn't need interrupts.
+fn calibration_wait(micros: u64) {
+    if !hpet::wait_micros(micros) {
+        unsafe { super::device::pic::ProgrammableIntervalTimer::spin_wait_micros(micros) };
+    }
+}
+
+/// Count how many times `counter` ticks per second.
+fn calibrate(mut counter: impl FnMut() -> u64) -> u64 {
+    let start = counter();
+    calibration_wait(CALIBRATION_MICROS);
+    let end = counter();
+
+    end.wrapping_sub(start) * (1_000_000 / CALIBRATION_MICROS)
+}
+
+/// Register every clock source and event device we can find.
+///
+/// `hpet` is the physical address of the HPET registers (from the ACPI HPET table.)
+pub(crate) fn initialize(hpet: Option<u64>) {
+    pit::register();
+
+    if let Some(address) = hpet {
+        hpet::register(address);
+    }
+
+    tsc::register();
+    apic_timer::register();
+}
@@ -0,0 +1,101 @@
+//! The PIT as a clock source (the system tick's uptime) and periodic clock event.
+
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use super::{ClockError, ClockEvent, ClockEventFeatures, ClockSource};
+use crate::x86_64::{
+    device::pic::{PIT_MIN_FREQ, PIT_OSC_FREQ},
+    timer,
+};
+
+/// Counts nanoseconds of uptime, with tick granularity.
+pub struct PitClockSource;
+
+impl ClockSource for PitClockSource {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn frequency(&self) -> u64 {
+        super::NANOS_PER_SEC
+    }
+
+    fn mask(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn read(&self) -> u64 {
+        timer::uptime().as_nanos() as u64
+    }
+}
+
+/// Channel 0 is the system tick so it can only ever be periodic.
+pub struct PitClockEvent {
+    armed: AtomicBool,
+}
+
+impl ClockEvent for PitClockEvent {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn features(&self) -> ClockEventFeatures {
+        ClockEventFeatures {
+            oneshot: false,
+            periodic: true,
+            per_cpu: false,
+        }
+    }
+
+    fn set_periodic(&self, period: Duration) -> Result<(), ClockError> {
+        let nanos = period.as_nanos().max(1);
+        let freq = (super::NANOS_PER_SEC as u128 / nanos) as usize;
+
+        if !(PIT_MIN_FREQ..=PIT_OSC_FREQ).contains(&freq) {
+            return Err(ClockError::OutOfRange);
+        }
+
+        timer::set_frequency(freq);
+        self.armed.store(true, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    fn set_oneshot(&self, _delta: Duration) -> Result<(), ClockError> {
+        Err(ClockError::Unsupported)
+    }
+
+    fn shutdown(&self) {
+        // The tick keeps going, we just stop reporting it.
+        self.armed.store(false, Ordering::SeqCst);
+    }
+}
+
+pub static PIT_CLOCK_SOURCE: PitClockSource = PitClockSource;
+
+pub static PIT_CLOCK_EVENT: PitClockEvent = PitClockEvent {
+    armed: AtomicBool::new(false),
+};
+
+/// Called by the system tick.
+pub(crate) fn on_tick() {
+    if PIT_CLOCK_EVENT.armed.load(Ordering::Relaxed) {
+        super::event_fired();
+    }
+}
+
+pub(super) fn register() {
+    super::register_source(&PIT_CLOCK_SOURCE);
+    super::register_event(&PIT_CLOCK_EVENT);
+}
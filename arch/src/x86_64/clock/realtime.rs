@@ -0,0 +1,101 @@
+//! Wall-clock time: the RTC read once at boot plus the monotonic clock.
+//!
+//! The RTC only has second granularity and is slow to read so it only sets
+//! the offset between `monotonic_nanos` and the UNIX epoch, `SystemTime::now`
+//! is the monotonic clock plus that offset.
+
+use core::{
+    fmt,
+    ops::{Add, Sub},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::x86_64::device::rtc::{self, DateTime};
+
+/// Nanoseconds since the UNIX epoch at `monotonic_nanos() == 0`.
+static EPOCH_OFFSET_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// A point in wall-clock time, like `std::time::SystemTime`.
+///
+/// Unlike `monotonic` it can go backwards, whenever the time is `set`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(Duration);
+
+/// 1970-01-01T00:00:00Z
+pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::from_secs(0));
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = UNIX_EPOCH;
+
+    pub fn now() -> Self {
+        let nanos = EPOCH_OFFSET_NANOS.load(Ordering::Relaxed) + super::monotonic_nanos();
+        Self(Duration::from_nanos(nanos))
+    }
+
+    /// How long after `earlier` this is, `Err` with the difference if it's before.
+    pub fn duration_since(&self, earlier: SystemTime) -> Result<Duration, Duration> {
+        if self.0 >= earlier.0 {
+            Ok(self.0 - earlier.0)
+        } else {
+            Err(earlier.0 - self.0)
+        }
+    }
+
+    /// How long ago this was.
+    pub fn elapsed(&self) -> Result<Duration, Duration> {
+        Self::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_add(duration).map(SystemTime)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_sub(duration).map(SystemTime)
+    }
+
+    /// The calendar date and time (UTC, truncated to the second.)
+    pub fn datetime(&self) -> DateTime {
+        DateTime::from_unix(self.0.as_secs())
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn add(self, duration: Duration) -> SystemTime {
+        self.checked_add(duration).expect("Overflow when adding a duration to a SystemTime")
+    }
+}
+
+impl Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn sub(self, duration: Duration) -> SystemTime {
+        self.checked_sub(duration).expect("Overflow when subtracting a duration from a SystemTime")
+    }
+}
+
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.datetime().fmt(f)
+    }
+}
+
+/// Set the wall-clock time (the RTC isn't written.)
+pub fn set(time: SystemTime) {
+    let offset = (time.0.as_nanos() as u64).saturating_sub(super::monotonic_nanos());
+    EPOCH_OFFSET_NANOS.store(offset, Ordering::Relaxed);
+}
+
+/// Read the RTC and start keeping wall-clock time from it.
+pub(crate) fn initialize(century_register: Option<u8>) {
+    rtc::initialize(century_register);
+
+    let now = rtc::read();
+
+    set(SystemTime(Duration::from_secs(now.to_unix())));
+
+    log::info!("(CLOCK) Wall-clock time is {}", now);
+}
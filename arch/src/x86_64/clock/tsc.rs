@@ -0,0 +1,69 @@
+//! The time stamp counter as a clock source.
+//!
+//! Only an invariant TSC (one that ticks at a constant rate regardless of
+//! power states) is used, its frequency is calibrated against the HPET or
+//! the PIT.
+
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use super::ClockSource;
+
+pub struct Tsc {
+    frequency: AtomicU64,
+}
+
+pub static TSC: Tsc = Tsc {
+    frequency: AtomicU64::new(0),
+};
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        300
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency.load(Ordering::Relaxed)
+    }
+
+    fn mask(&self) -> u64 {
+        u64::MAX
+    }
+
+    #[inline]
+    fn read(&self) -> u64 {
+        unsafe { _rdtsc() }
+    }
+}
+
+/// Does the CPU have an invariant TSC?
+fn is_invariant() -> bool {
+    unsafe {
+        __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+    }
+}
+
+/// The calibrated TSC frequency, 0 if it isn't usable.
+#[inline]
+pub fn frequency() -> u64 {
+    TSC.frequency()
+}
+
+pub(super) fn register() {
+    if !is_invariant() {
+        log::warn!("(TSC) Not invariant, not using it for timekeeping");
+        return;
+    }
+
+    let frequency = super::calibrate(|| unsafe { _rdtsc() });
+
+    TSC.frequency.store(frequency, Ordering::Relaxed);
+
+    super::register_source(&TSC);
+}
@@ -0,0 +1,286 @@
+//! Local APIC driver (xAPIC and x2APIC.)
+//!
+//! Every processor has its own local APIC, in xAPIC mode its registers are
+//! memory mapped (at the same physical address on every CPU) and in x2APIC
+//! mode they're accessed through MSRs instead. x2APIC is preferred whenever
+//! the CPU supports it.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use spin::Once;
+use x86_64::{
+    registers::model_specific::Msr,
+    structures::idt::InterruptStackFrame,
+};
+
+use crate::x86_64::{interrupts::irq, percpu::KernelGs};
+
+use super::pic::{CHIP_8259, DEFAULT_PIC_MASTER_OFFSET, DEFAULT_PIC_SLAVE_OFFSET};
+
+/// The vector spurious interrupts are delivered to (the low nibble must be set.)
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// The vector APIC errors are delivered to through the LVT error entry.
+pub const ERROR_VECTOR: u8 = 0xFE;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+
+/// The APIC is enabled (`IA32_APIC_BASE`.)
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// The APIC is in x2APIC mode (`IA32_APIC_BASE`.)
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// x2APIC registers are MSRs starting here, register `offset` is `X2APIC_MSR_BASE + (offset >> 4)`.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Software enable bit of the spurious interrupt vector register.
+const SVR_ENABLE: u32 = 1 << 8;
+
+/// Masks an LVT entry.
+const LVT_MASKED: u32 = 1 << 16;
+
+/// NMI delivery mode for an LVT entry.
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+
+/// Delivery status bit of the (xAPIC) interrupt command register.
+const ICR_SEND_PENDING: u32 = 1 << 12;
+
+/// INIT delivery mode (level assert) for `LocalApic::send_ipi`.
+pub const IPI_INIT: u32 = (0b101 << 8) | (1 << 14);
+
+/// Startup delivery mode for `LocalApic::send_ipi`, or the page number of the entry point.
+pub const IPI_STARTUP: u32 = (0b110 << 8) | (1 << 14);
+
+/// An NMI to every processor but the sender for `LocalApic::send_ipi` (the destination is ignored.)
+pub const IPI_NMI_OTHERS: u32 = (0b100 << 8) | (1 << 14) | (0b11 << 18);
+
+/// Local APIC register offsets (relative to the xAPIC MMIO base.)
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+pub enum Register {
+    Id = 0x20,
+    Version = 0x30,
+    TaskPriority = 0x80,
+    EndOfInterrupt = 0xB0,
+    SpuriousVector = 0xF0,
+    ErrorStatus = 0x280,
+    InterruptCommandLow = 0x300,
+    InterruptCommandHigh = 0x310,
+    LvtTimer = 0x320,
+    LvtThermal = 0x330,
+    LvtPerformance = 0x340,
+    LvtLint0 = 0x350,
+    LvtLint1 = 0x360,
+    LvtError = 0x370,
+    TimerInitialCount = 0x380,
+    TimerCurrentCount = 0x390,
+    TimerDivide = 0x3E0,
+}
+
+// -- LocalApic
+
+/// How the local APIC registers are accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalApic {
+    /// Memory mapped registers at `base` (identity mapped.)
+    XApic { base: u64 },
+
+    /// Registers are MSRs.
+    X2Apic,
+}
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+
+/// The local APIC, `None` until `initialize` has been called.
+#[inline]
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.r#try()
+}
+
+impl LocalApic {
+    #[inline]
+    pub fn read(&self, register: Register) -> u32 {
+        let offset = register as u32;
+
+        match self {
+            LocalApic::XApic { base } => unsafe { read_volatile((base + offset as u64) as *const u32) },
+            LocalApic::X2Apic => unsafe { Msr::new(X2APIC_MSR_BASE + (offset >> 4)).read() as u32 },
+        }
+    }
+
+    #[inline]
+    pub fn write(&self, register: Register, value: u32) {
+        let offset = register as u32;
+
+        match self {
+            LocalApic::XApic { base } => unsafe { write_volatile((base + offset as u64) as *mut u32, value) },
+            LocalApic::X2Apic => unsafe { Msr::new(X2APIC_MSR_BASE + (offset >> 4)).write(value as u64) },
+        }
+    }
+
+    /// The APIC ID of the executing processor.
+    #[inline]
+    pub fn id(&self) -> u32 {
+        match self {
+            LocalApic::XApic { .. } => self.read(Register::Id) >> 24,
+            LocalApic::X2Apic => self.read(Register::Id),
+        }
+    }
+
+    /// Signal the end of the interrupt currently being serviced.
+    #[inline]
+    pub fn end_of_interrupt(&self) {
+        self.write(Register::EndOfInterrupt, 0);
+    }
+
+    /// Send an inter-processor interrupt to the local APIC `destination`.
+    ///
+    /// `command` is the low half of the interrupt command register (vector,
+    /// delivery mode, level...), this waits until the IPI has been sent.
+    pub fn send_ipi(&self, destination: u32, command: u32) {
+        match self {
+            LocalApic::XApic { .. } => {
+                self.write(Register::InterruptCommandHigh, destination << 24);
+                self.write(Register::InterruptCommandLow, command);
+
+                while self.read(Register::InterruptCommandLow) & ICR_SEND_PENDING != 0 {
+                    core::sync::atomic::spin_loop_hint();
+                }
+            }
+
+            // The x2APIC ICR is a single 64-bit MSR and there is no send pending bit.
+            LocalApic::X2Apic => unsafe {
+                let value = ((destination as u64) << 32) | command as u64;
+                Msr::new(X2APIC_MSR_BASE + (Register::InterruptCommandLow as u32 >> 4)).write(value);
+            },
+        }
+    }
+
+    /// Read (and clear) the error status register.
+    pub fn error_status(&self) -> u32 {
+        // The ESR has to be written to before it's read to latch the errors.
+        self.write(Register::ErrorStatus, 0);
+        self.read(Register::ErrorStatus)
+    }
+
+    /// Enable the local APIC of the executing processor.
+    ///
+    /// Every LVT entry starts out masked except for LINT1 (NMI) and errors.
+    ///
+    /// # Safety
+    ///
+    /// The IDT must have handlers for `SPURIOUS_VECTOR` and `ERROR_VECTOR`.
+    pub unsafe fn enable(&self) {
+        let mut base = Msr::new(IA32_APIC_BASE);
+        let mut value = base.read() | APIC_BASE_ENABLE;
+
+        if *self == LocalApic::X2Apic {
+            value |= APIC_BASE_X2APIC;
+        }
+
+        base.write(value);
+
+        self.write(Register::TaskPriority, 0);
+
+        self.write(Register::LvtTimer, LVT_MASKED);
+        self.write(Register::LvtThermal, LVT_MASKED);
+        self.write(Register::LvtPerformance, LVT_MASKED);
+
+        // LINT0 is wired to the (masked) 8259, LINT1 to NMI.
+        self.write(Register::LvtLint0, LVT_MASKED);
+        self.write(Register::LvtLint1, LVT_DELIVERY_NMI);
+
+        self.write(Register::LvtError, ERROR_VECTOR as u32);
+        let _ = self.error_status();
+
+        self.write(Register::SpuriousVector, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+        // Drop anything that was pending before the APIC was set up.
+        self.end_of_interrupt();
+    }
+}
+
+/// Does the executing processor have a local APIC? (and does it support x2APIC?)
+fn apic_support() -> (bool, bool) {
+    let leaf = unsafe { core::arch::x86_64::__cpuid(1) };
+
+    (leaf.edx & (1 << 9) != 0, leaf.ecx & (1 << 21) != 0)
+}
+
+/// Mask the legacy PIC and enable the local APIC of the boot processor.
+///
+/// The PIC is remapped first so that any (spurious) interrupts it still
+/// raises don't land on CPU exception vectors.
+pub(crate) fn initialize() {
+    let (present, x2apic) = apic_support();
+
+    assert!(present, "(APIC) No local APIC present!");
+
+    unsafe {
+        CHIP_8259.remap(DEFAULT_PIC_SLAVE_OFFSET, DEFAULT_PIC_MASTER_OFFSET);
+        CHIP_8259.pic.lock().initialize();
+        CHIP_8259.mask_all();
+    }
+
+    let apic = LOCAL_APIC.call_once(|| {
+        if x2apic {
+            return LocalApic::X2Apic;
+        }
+
+        let base = unsafe { Msr::new(IA32_APIC_BASE).read() } & APIC_BASE_ADDRESS_MASK;
+
+        super::map_mmio(base, "local APIC");
+
+        LocalApic::XApic { base }
+    });
+
+    unsafe { apic.enable() };
+
+    irq::set_apic_active();
+
+    log::info!(
+        "(APIC) Local APIC {:?} enabled in {:?} mode (version {:#x})",
+        apic.id(),
+        apic,
+        apic.read(Register::Version) & 0xFF
+    );
+}
+
+// -- Interrupt handlers
+
+/// Spurious interrupts must not be acknowledged.
+pub(crate) extern "x86-interrupt" fn spurious_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+
+    irq::note_spurious("local APIC");
+}
+
+pub(crate) extern "x86-interrupt" fn error_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+
+    let apic = local_apic().expect("APIC error without a local APIC?");
+
+    let status = apic.error_status();
+
+    const ERRORS: [&str; 8] = [
+        "send checksum",
+        "receive checksum",
+        "send accept",
+        "receive accept",
+        "redirectable IPI",
+        "send illegal vector",
+        "receive illegal vector",
+        "illegal register address",
+    ];
+
+    for (bit, error) in ERRORS.iter().enumerate() {
+        if status & (1 << bit) != 0 {
+            log::error!("(APIC) Local APIC {:?} error: {}", apic.id(), error);
+        }
+    }
+
+    apic.end_of_interrupt();
+}
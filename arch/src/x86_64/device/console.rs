@@ -0,0 +1,461 @@
+//! The kernel console, a terminal drawn on whichever `Screen` is the output.
+//!
+//! The console starts out on the VGA text buffer and is moved over to the
+//! linear framebuffer on graphical boots (see `framebuffer/mod.rs`), the cursor,
+//! colours and escape sequence state live here so that every screen behaves
+//! the same.
+//!
+//! A subset of ANSI escape sequences is understood:
+//!
+//! * `ESC[<n>m` - SGR: 0 (reset), 1 (bright), 30-37/90-97 and 40-47/100-107 (colours), 39/49 (default)
+//! * `ESC[<row>;<col>H` / `ESC[<row>;<col>f` - move the cursor (1-based)
+//! * `ESC[<n>A/B/C/D` - move the cursor up/down/right/left
+//! * `ESC[<n>J` - clear the screen (2) or from the cursor to the end (0)
+//! * `ESC[<n>K` - clear the line (2) or from the cursor to its end (0)
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use log::{Level, LevelFilter};
+use spin::Mutex;
+use vga::colors::Color16;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{framebuffer::FramebufferConsole, vga_text::VgaText};
+use crate::x86_64::logger::{LogRecord, Sink};
+
+const TAB_WIDTH: usize = 8;
+
+/// The most numeric parameters of an escape sequence we keep.
+const MAX_PARAMS: usize = 4;
+
+pub const DEFAULT_FOREGROUND: Color16 = Color16::LightGrey;
+pub const DEFAULT_BACKGROUND: Color16 = Color16::Black;
+
+/// ANSI colour numbers (0-7) to VGA colours.
+const ANSI_COLORS: [Color16; 8] = [
+    Color16::Black,
+    Color16::Red,
+    Color16::Green,
+    Color16::Brown,
+    Color16::Blue,
+    Color16::Magenta,
+    Color16::Cyan,
+    Color16::LightGrey,
+];
+
+/// The bright version of `color` (VGA colour 8 and up.)
+fn bright(color: Color16) -> Color16 {
+    match color {
+        Color16::Black => Color16::DarkGrey,
+        Color16::Blue => Color16::LightBlue,
+        Color16::Green => Color16::LightGreen,
+        Color16::Cyan => Color16::LightCyan,
+        Color16::Red => Color16::LightRed,
+        Color16::Magenta => Color16::Pink,
+        Color16::Brown => Color16::Yellow,
+        Color16::LightGrey => Color16::White,
+        other => other,
+    }
+}
+
+// -- Screen
+
+/// A character and its colours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub character: char,
+    pub foreground: Color16,
+    pub background: Color16,
+}
+
+impl Cell {
+    pub const BLANK: Self = Self {
+        character: ' ',
+        foreground: DEFAULT_FOREGROUND,
+        background: DEFAULT_BACKGROUND,
+    };
+}
+
+/// A grid of character cells the console can draw on.
+pub trait Screen {
+    /// The size in (rows, columns).
+    fn size(&self) -> (usize, usize);
+
+    fn draw(&mut self, row: usize, column: usize, cell: Cell);
+
+    /// Move every row up by one and fill the bottom row with `blank`.
+    fn scroll(&mut self, blank: Cell);
+
+    fn move_cursor(&mut self, row: usize, column: usize);
+}
+
+/// The screen the console draws on.
+pub enum Output {
+    Vga(VgaText),
+    Framebuffer(FramebufferConsole),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+
+    /// Got `ESC`.
+    Started,
+
+    /// Inside `ESC[`, collecting parameters.
+    Csi {
+        params: [u16; MAX_PARAMS],
+        count: usize,
+    },
+}
+
+// -- Console
+
+pub struct Console {
+    output: Output,
+    row: usize,
+    column: usize,
+    foreground: Color16,
+    background: Color16,
+    bold: bool,
+    escape: Escape,
+}
+
+impl Console {
+    const fn new() -> Self {
+        Self {
+            output: Output::Vga(VgaText),
+            row: 0,
+            column: 0,
+            foreground: DEFAULT_FOREGROUND,
+            background: DEFAULT_BACKGROUND,
+            bold: false,
+            escape: Escape::None,
+        }
+    }
+
+    #[inline]
+    fn screen(&mut self) -> &mut dyn Screen {
+        match &mut self.output {
+            Output::Vga(screen) => screen,
+            Output::Framebuffer(screen) => screen,
+        }
+    }
+
+    /// The size in (rows, columns).
+    pub fn size(&self) -> (usize, usize) {
+        match &self.output {
+            Output::Vga(screen) => screen.size(),
+            Output::Framebuffer(screen) => screen.size(),
+        }
+    }
+
+    /// Draw on `output` from now on, starting with a clear screen.
+    pub(crate) fn set_output(&mut self, output: Output) {
+        self.output = output;
+        self.escape = Escape::None;
+        self.clear();
+    }
+
+    fn cell(&self, character: char) -> Cell {
+        Cell {
+            character,
+            foreground: if self.bold { bright(self.foreground) } else { self.foreground },
+            background: self.background,
+        }
+    }
+
+    /// Blank the cells from `start` up to `end`, counted from the top left.
+    fn clear_cells(&mut self, start: usize, end: usize) {
+        let blank = self.cell(' ');
+        let (_, columns) = self.size();
+        let screen = self.screen();
+
+        for index in start..end {
+            screen.draw(index / columns, index % columns, blank);
+        }
+    }
+
+    fn newline(&mut self) {
+        let (rows, _) = self.size();
+
+        self.column = 0;
+        self.row += 1;
+
+        if self.row == rows {
+            let blank = self.cell(' ');
+
+            self.screen().scroll(blank);
+            self.row = rows - 1;
+        }
+    }
+
+    /// Put `character` at the cursor and advance it.
+    fn put(&mut self, character: char) {
+        let (_, columns) = self.size();
+
+        if self.column == columns {
+            self.newline();
+        }
+
+        let (row, column, cell) = (self.row, self.column, self.cell(character));
+
+        self.screen().draw(row, column, cell);
+        self.column += 1;
+    }
+
+    pub fn clear(&mut self) {
+        let (rows, columns) = self.size();
+
+        self.clear_cells(0, rows * columns);
+        self.row = 0;
+        self.column = 0;
+        self.update_cursor();
+    }
+
+    pub fn set_colors(&mut self, foreground: Color16, background: Color16) {
+        self.foreground = foreground;
+        self.background = background;
+        self.bold = false;
+    }
+
+    pub fn reset_colors(&mut self) {
+        self.set_colors(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND);
+    }
+
+    /// Move the cursor to (`row`, `column`), clamped to the screen.
+    pub fn set_position(&mut self, row: usize, column: usize) {
+        let (rows, columns) = self.size();
+
+        self.row = row.min(rows - 1);
+        self.column = column.min(columns - 1);
+        self.update_cursor();
+    }
+
+    pub fn position(&self) -> (usize, usize) {
+        (self.row, self.column)
+    }
+
+    fn update_cursor(&mut self) {
+        let (_, columns) = self.size();
+        let (row, column) = (self.row, self.column.min(columns - 1));
+
+        self.screen().move_cursor(row, column);
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        // `ESC[m` is a reset.
+        if params.is_empty() {
+            self.reset_colors();
+        }
+
+        for &param in params {
+            match param {
+                0 => self.reset_colors(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                30..=37 => self.foreground = ANSI_COLORS[(param - 30) as usize],
+                39 => self.foreground = DEFAULT_FOREGROUND,
+                40..=47 => self.background = ANSI_COLORS[(param - 40) as usize],
+                49 => self.background = DEFAULT_BACKGROUND,
+                90..=97 => self.foreground = bright(ANSI_COLORS[(param - 90) as usize]),
+                100..=107 => self.background = bright(ANSI_COLORS[(param - 100) as usize]),
+                _ => {}
+            }
+        }
+    }
+
+    /// Run the escape sequence ending in `command`.
+    fn control_sequence(&mut self, command: char, params: &[u16]) {
+        let (rows, columns) = self.size();
+
+        // Movement counts default to (and are at least) 1.
+        let count = params.first().copied().unwrap_or(1).max(1) as usize;
+        let mode = params.first().copied().unwrap_or(0);
+
+        match command {
+            'm' => self.select_graphic_rendition(params),
+
+            'H' | 'f' => {
+                let row = params.first().copied().unwrap_or(1).max(1) as usize;
+                let column = params.get(1).copied().unwrap_or(1).max(1) as usize;
+                self.set_position(row - 1, column - 1);
+            }
+
+            'A' => self.row = self.row.saturating_sub(count),
+            'B' => self.row = (self.row + count).min(rows - 1),
+            'C' => self.column = (self.column + count).min(columns - 1),
+            'D' => self.column = self.column.saturating_sub(count),
+
+            'J' => match mode {
+                0 => self.clear_cells(self.row * columns + self.column, rows * columns),
+                2 | 3 => self.clear(),
+                _ => {}
+            },
+
+            'K' => match mode {
+                0 => self.clear_cells(self.row * columns + self.column, (self.row + 1) * columns),
+                2 => self.clear_cells(self.row * columns, (self.row + 1) * columns),
+                _ => {}
+            },
+
+            _ => {}
+        }
+    }
+
+    /// Write `character`, running it through the escape sequence parser.
+    pub fn write_char(&mut self, character: char) {
+        match self.escape {
+            Escape::None => match character {
+                '\x1B' => self.escape = Escape::Started,
+                '\n' => self.newline(),
+                '\r' => self.column = 0,
+                '\t' => {
+                    let (_, columns) = self.size();
+                    let next = (self.column / TAB_WIDTH + 1) * TAB_WIDTH;
+
+                    while self.column < next.min(columns) {
+                        self.put(' ');
+                    }
+                }
+                '\x08' => self.column = self.column.saturating_sub(1),
+                character => self.put(character),
+            },
+
+            Escape::Started => {
+                self.escape = if character == '[' {
+                    Escape::Csi {
+                        params: [0; MAX_PARAMS],
+                        count: 0,
+                    }
+                } else {
+                    Escape::None
+                };
+            }
+
+            Escape::Csi { mut params, mut count } => match character {
+                '0'..='9' => {
+                    if count == 0 {
+                        count = 1;
+                    }
+
+                    if count <= MAX_PARAMS {
+                        let param = &mut params[count - 1];
+                        *param = param.saturating_mul(10).saturating_add(character as u16 - '0' as u16);
+                    }
+
+                    self.escape = Escape::Csi { params, count };
+                }
+
+                ';' => {
+                    // An empty parameter is a 0.
+                    self.escape = Escape::Csi {
+                        params,
+                        count: count.max(1) + 1,
+                    };
+                }
+
+                '\x40'..='\x7E' => {
+                    self.escape = Escape::None;
+                    self.control_sequence(character, &params[..count.min(MAX_PARAMS)]);
+                }
+
+                // Anything else aborts the sequence.
+                _ => self.escape = Escape::None,
+            },
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for character in s.chars() {
+            self.write_char(character);
+        }
+
+        self.update_cursor();
+
+        Ok(())
+    }
+}
+
+pub static CONSOLE: Mutex<Console> = Mutex::new(Console::new());
+
+/// Write `args` to the console.
+pub fn print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    without_interrupts(|| {
+        let _ = CONSOLE.lock().write_fmt(args);
+    });
+}
+
+// -- ConsoleLogger
+
+/// The most verbose level shown on the console, as a `LevelFilter`.
+static CONSOLE_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+
+/// A log sink for the console, every record in its level's colour.
+pub(crate) struct ConsoleLogger;
+
+pub(crate) static CONSOLE_LOGGER: ConsoleLogger = ConsoleLogger;
+
+fn level_color(level: Level) -> Color16 {
+    match level {
+        Level::Error => Color16::LightRed,
+        Level::Warn => Color16::Yellow,
+        Level::Info => Color16::White,
+        Level::Debug => Color16::LightCyan,
+        Level::Trace => Color16::DarkGrey,
+    }
+}
+
+/// Only show records up to `level` on the console.
+pub fn set_level(level: LevelFilter) {
+    CONSOLE_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+impl Sink for ConsoleLogger {
+    fn enabled(&self, record: &LogRecord) -> bool {
+        record.level() as usize <= CONSOLE_LEVEL.load(Ordering::Relaxed)
+    }
+
+    fn write(&self, record: &LogRecord) -> bool {
+        use core::fmt::Write;
+
+        // Busy printing (maybe on this processor), try again on the next drain.
+        let mut console = match CONSOLE.try_lock() {
+            Some(console) => console,
+            None => return false,
+        };
+
+        console.set_colors(level_color(record.level()), DEFAULT_BACKGROUND);
+        let _ = write!(console, "[{}] ", record.level());
+        console.reset_colors();
+        let _ = write!(console, "{}", record.message());
+
+        if record.is_truncated() {
+            let _ = write!(console, " [...]");
+        }
+
+        let _ = writeln!(console);
+
+        true
+    }
+}
+
+/// `print!` to the console.
+#[macro_export]
+macro_rules! kprint {
+    ($($arg:tt)*) => ($crate::prelude::console_print(format_args!($($arg)*)));
+}
+
+/// `println!` to the console.
+#[macro_export]
+macro_rules! kprintln {
+    () => ($crate::kprint!("\n"));
+    ($($arg:tt)*) => ($crate::kprint!("{}\n", format_args!($($arg)*)));
+}
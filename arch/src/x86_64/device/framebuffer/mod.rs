@@ -0,0 +1,329 @@
+//! A console screen on the linear framebuffer set up by the bootloader.
+//!
+//! `multiboot.asm` asks GRUB for a 1024x768x32 framebuffer, when we get a
+//! direct colour one it's mapped write-combining and takes over the console
+//! from the VGA text buffer. Text is drawn with the embedded PSF fonts (see
+//! `arch/fonts`), characters missing from the main font come from the code
+//! page 437 one and anything else is drawn as U+FFFD.
+//!
+//! Reading video memory back is slow, so the cells on screen are kept in a
+//! shadow buffer: scrolling shifts the shadow buffer and only redraws the
+//! cells that changed, the cursor is drawn as an inverted cell.
+
+use core::ptr::write_volatile;
+
+use multiboot2::{BootInformation, FramebufferType};
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{mapper::MapToError, PageTableFlags},
+};
+
+use super::console::{Cell, Output, Screen, CONSOLE};
+use crate::x86_64::memory::{IDENTITY_MAPPED_LIMIT, WRITE_COMBINING};
+
+mod psf;
+
+pub use psf::{Font, PsfError};
+
+/// DejaVu Sans Mono 8x16, PSF2 with a unicode table.
+static FONT: &[u8] = include_bytes!("../../../../fonts/dejavu-mono-8x16.psfu");
+
+/// The same in code page 437 order, PSF1 with a unicode table.
+static FALLBACK_FONT: &[u8] = include_bytes!("../../../../fonts/dejavu-mono-cp437-8x16.psf");
+
+/// The largest console, in cells, the shadow buffer has room for.
+const MAX_ROWS: usize = 96;
+const MAX_COLUMNS: usize = 240;
+
+const PAGE_SIZE: usize = 0x1000;
+
+/// The standard VGA palette as (red, green, blue), indexed by `Color16`.
+const VGA_PALETTE: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00),
+    (0x00, 0x00, 0xAA),
+    (0x00, 0xAA, 0x00),
+    (0x00, 0xAA, 0xAA),
+    (0xAA, 0x00, 0x00),
+    (0xAA, 0x00, 0xAA),
+    (0xAA, 0x55, 0x00),
+    (0xAA, 0xAA, 0xAA),
+    (0x55, 0x55, 0x55),
+    (0x55, 0x55, 0xFF),
+    (0x55, 0xFF, 0x55),
+    (0x55, 0xFF, 0xFF),
+    (0xFF, 0x55, 0x55),
+    (0xFF, 0x55, 0xFF),
+    (0xFF, 0xFF, 0x55),
+    (0xFF, 0xFF, 0xFF),
+];
+
+/// The cells on screen, handed to the console by `initialize`.
+static mut SHADOW: [Cell; MAX_ROWS * MAX_COLUMNS] = [Cell::BLANK; MAX_ROWS * MAX_COLUMNS];
+
+/// Where a colour channel sits in a pixel, as (position, size) in bits.
+type Channel = (u8, u8);
+
+#[inline]
+fn encode(value: u8, (position, size): Channel) -> u32 {
+    ((value as u32) >> (8 - size.min(8))) << position
+}
+
+// -- Framebuffer
+
+struct Framebuffer {
+    address: usize,
+    pitch: usize,
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+
+    /// `VGA_PALETTE` in the pixel format.
+    palette: [u32; 16],
+}
+
+impl Framebuffer {
+    #[inline]
+    fn put_pixel(&self, x: usize, y: usize, pixel: u32) {
+        let offset = self.address + y * self.pitch + x * self.bytes_per_pixel;
+
+        unsafe {
+            match self.bytes_per_pixel {
+                4 => write_volatile(offset as *mut u32, pixel),
+                2 => write_volatile(offset as *mut u16, pixel as u16),
+                3 => {
+                    write_volatile(offset as *mut u8, pixel as u8);
+                    write_volatile((offset + 1) as *mut u8, (pixel >> 8) as u8);
+                    write_volatile((offset + 2) as *mut u8, (pixel >> 16) as u8);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn fill(&self, pixel: u32) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                self.put_pixel(x, y, pixel);
+            }
+        }
+    }
+}
+
+// -- FramebufferConsole
+
+pub struct FramebufferConsole {
+    framebuffer: Framebuffer,
+    font: Font<'static>,
+
+    /// Searched for characters `font` doesn't have, always the same size.
+    fallback: Option<Font<'static>>,
+
+    /// The glyph of `font` drawn for characters neither font has.
+    replacement: usize,
+
+    cells: &'static mut [Cell],
+    rows: usize,
+    columns: usize,
+    cursor: (usize, usize),
+}
+
+impl FramebufferConsole {
+    /// The font and glyph drawing `character`.
+    fn glyph(&self, character: char) -> (&Font<'static>, usize) {
+        if let Some(glyph) = self.font.lookup(character) {
+            return (&self.font, glyph);
+        }
+
+        match self.fallback.as_ref().and_then(|font| Some((font, font.lookup(character)?))) {
+            Some(found) => found,
+            None => (&self.font, self.replacement),
+        }
+    }
+
+    /// Draw the cell at (`row`, `column`) from the shadow buffer.
+    fn render(&self, row: usize, column: usize) {
+        let cell = self.cells[row * self.columns + column];
+        let palette = &self.framebuffer.palette;
+
+        let (mut foreground, mut background) = (palette[cell.foreground as usize], palette[cell.background as usize]);
+
+        if (row, column) == self.cursor {
+            core::mem::swap(&mut foreground, &mut background);
+        }
+
+        let (font, index) = self.glyph(cell.character);
+        let bitmap = font.glyph(index);
+        let stride = font.bytes_per_row();
+        let (width, height) = font.size();
+        let (left, top) = (column * width, row * height);
+
+        for (y, line) in bitmap.chunks_exact(stride).take(height).enumerate() {
+            for x in 0..width {
+                let set = line[x / 8] & (0x80 >> (x % 8)) != 0;
+                self.framebuffer.put_pixel(left + x, top + y, if set { foreground } else { background });
+            }
+        }
+    }
+}
+
+impl Screen for FramebufferConsole {
+    fn size(&self) -> (usize, usize) {
+        (self.rows, self.columns)
+    }
+
+    fn draw(&mut self, row: usize, column: usize, cell: Cell) {
+        let index = row * self.columns + column;
+
+        if self.cells[index] != cell {
+            self.cells[index] = cell;
+            self.render(row, column);
+        }
+    }
+
+    fn scroll(&mut self, blank: Cell) {
+        let columns = self.columns;
+
+        for index in 0..(self.rows * columns) {
+            let below = self.cells.get(index + columns).copied().unwrap_or(blank);
+
+            if self.cells[index] != below {
+                self.cells[index] = below;
+                self.render(index / columns, index % columns);
+            }
+        }
+    }
+
+    fn move_cursor(&mut self, row: usize, column: usize) {
+        let (old_row, old_column) = core::mem::replace(&mut self.cursor, (row, column));
+
+        self.render(old_row, old_column);
+        self.render(row, column);
+    }
+}
+
+/// Identity map `length` bytes of video memory at `address` write-combined.
+fn map(address: usize, length: usize) {
+    use mem::MemoryManager;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | WRITE_COMBINING;
+    let manager = unsafe { crate::prelude::memory_manager_ref() };
+
+    for page in ((address & !(PAGE_SIZE - 1))..(address + length)).step_by(PAGE_SIZE) {
+        // The bootstrap code already mapped it (write-back, the MTRRs decide.)
+        if page < IDENTITY_MAPPED_LIMIT {
+            continue;
+        }
+
+        match manager.identity_map(page, flags.bits()) {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::PageAlreadyMapped(_)) => {}
+            Err(err) => panic!("Failed to map the framebuffer at {:#x}: {:?}", page, err),
+        }
+    }
+}
+
+/// Move the console onto the framebuffer, if the bootloader set up a direct colour one.
+///
+/// Needs the memory manager and the PAT (`memory::load_pat`.)
+pub(crate) fn initialize(info: &BootInformation) {
+    let tag = match info.framebuffer_tag() {
+        Some(tag) => tag,
+        None => {
+            log::info!("(FB) No framebuffer, staying in VGA text mode");
+            return;
+        }
+    };
+
+    let (red, green, blue) = match tag.buffer_type {
+        FramebufferType::RGB { red, green, blue } => (
+            (red.position, red.size),
+            (green.position, green.size),
+            (blue.position, blue.size),
+        ),
+
+        FramebufferType::Text => {
+            log::info!("(FB) The framebuffer is VGA text, keeping it");
+            return;
+        }
+
+        FramebufferType::Indexed { .. } => {
+            log::warn!("(FB) Indexed framebuffers aren't supported, staying in VGA text mode");
+            return;
+        }
+    };
+
+    let bytes_per_pixel = match tag.bpp {
+        15 | 16 => 2,
+        24 => 3,
+        32 => 4,
+        bpp => {
+            log::warn!("(FB) Unsupported depth {:?}, staying in VGA text mode", bpp);
+            return;
+        }
+    };
+
+    let mut palette = [0u32; 16];
+
+    for (pixel, &(r, g, b)) in palette.iter_mut().zip(VGA_PALETTE.iter()) {
+        *pixel = encode(r, red) | encode(g, green) | encode(b, blue);
+    }
+
+    let framebuffer = Framebuffer {
+        address: tag.address as usize,
+        pitch: tag.pitch as usize,
+        width: tag.width as usize,
+        height: tag.height as usize,
+        bytes_per_pixel,
+        palette,
+    };
+
+    map(framebuffer.address, framebuffer.pitch * framebuffer.height);
+
+    let font = Font::parse(FONT).expect("The embedded font is broken.");
+    let fallback = Font::parse(FALLBACK_FONT).ok().filter(|fallback| fallback.size() == font.size());
+
+    let replacement = font
+        .lookup(core::char::REPLACEMENT_CHARACTER)
+        .or_else(|| font.lookup('?'))
+        .unwrap_or(0);
+
+    let (glyph_width, glyph_height) = font.size();
+    let rows = (framebuffer.height / glyph_height).min(MAX_ROWS);
+    let columns = (framebuffer.width / glyph_width).min(MAX_COLUMNS);
+
+    if rows == 0 || columns == 0 {
+        log::warn!("(FB) The framebuffer is too small for the font, staying in VGA text mode");
+        return;
+    }
+
+    // The shadow buffer starts out blank, so must the screen.
+    framebuffer.fill(palette[Cell::BLANK.background as usize]);
+
+    // SAFETY: `boot` runs once, nothing else touches the shadow buffer.
+    let cells = unsafe { &mut SHADOW[..rows * columns] };
+
+    let console = FramebufferConsole {
+        framebuffer,
+        font,
+        fallback,
+        replacement,
+        cells,
+        rows,
+        columns,
+        cursor: (0, 0),
+    };
+
+    let (width, height, address) = (console.framebuffer.width, console.framebuffer.height, console.framebuffer.address);
+
+    without_interrupts(|| CONSOLE.lock().set_output(Output::Framebuffer(console)));
+
+    log::info!(
+        "(FB) {:?}x{:?}x{:?} framebuffer at {:#x}, {:?}x{:?} console",
+        width,
+        height,
+        tag.bpp,
+        address,
+        columns,
+        rows
+    );
+}
@@ -0,0 +1,264 @@
+//! PC Screen Font (PSF1 and PSF2) bitmap fonts.
+//!
+//! Glyph rows are `ceil(width / 8)` bytes with the leftmost pixel in the most
+//! significant bit. Both versions can carry a unicode table saying which
+//! characters every glyph draws: PSF1 as little endian UCS-2 terminated by
+//! `0xFFFF`, PSF2 as UTF-8 terminated by `0xFF`. Either one starts combining
+//! sequences (which we skip) with its `0xFFFE` or `0xFE` separator.
+
+use core::convert::TryInto;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HASTAB: u8 = 0x02;
+const PSF1_MODE_SEQ: u8 = 0x04;
+const PSF1_HEADER_SIZE: usize = 4;
+const PSF1_SEPARATOR: u16 = 0xFFFE;
+const PSF1_TERMINATOR: u16 = 0xFFFF;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_HEADER_SIZE: usize = 32;
+const PSF2_SEPARATOR: u8 = 0xFE;
+const PSF2_TERMINATOR: u8 = 0xFF;
+
+/// Characters below this are looked up in a table instead of the unicode table.
+const FAST_MAP_LENGTH: usize = 256;
+
+/// Marks a character of the fast map without a glyph.
+const NO_GLYPH: u16 = u16::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsfError {
+    /// Not a PSF1 or PSF2 font.
+    BadMagic,
+
+    /// The header or glyphs run past the end of the data.
+    Truncated,
+
+    /// The header describes something we can't draw.
+    BadHeader,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnicodeTable<'a> {
+    None,
+    Psf1(&'a [u8]),
+    Psf2(&'a [u8]),
+}
+
+impl<'a> UnicodeTable<'a> {
+    /// Call `f` with every (glyph, character) pair of the table.
+    fn for_each(&self, mut f: impl FnMut(usize, char)) {
+        match *self {
+            UnicodeTable::None => {}
+
+            UnicodeTable::Psf1(table) => {
+                let mut glyph = 0;
+                let mut in_sequence = false;
+
+                for entry in table.chunks_exact(2) {
+                    match u16::from_le_bytes([entry[0], entry[1]]) {
+                        PSF1_TERMINATOR => {
+                            glyph += 1;
+                            in_sequence = false;
+                        }
+
+                        PSF1_SEPARATOR => in_sequence = true,
+
+                        code if !in_sequence => {
+                            if let Some(character) = core::char::from_u32(code as u32) {
+                                f(glyph, character);
+                            }
+                        }
+
+                        _ => {}
+                    }
+                }
+            }
+
+            UnicodeTable::Psf2(table) => {
+                for (glyph, entry) in table.split(|&byte| byte == PSF2_TERMINATOR).enumerate() {
+                    let singles = entry.split(|&byte| byte == PSF2_SEPARATOR).next().unwrap_or(&[]);
+
+                    if let Ok(characters) = core::str::from_utf8(singles) {
+                        characters.chars().for_each(|character| f(glyph, character));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// -- Font
+
+pub struct Font<'a> {
+    glyphs: &'a [u8],
+    glyph_count: usize,
+    bytes_per_glyph: usize,
+    width: usize,
+    height: usize,
+    unicode: UnicodeTable<'a>,
+
+    /// Glyphs of the characters below `FAST_MAP_LENGTH`.
+    fast_map: [u16; FAST_MAP_LENGTH],
+}
+
+#[inline]
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+impl<'a> Font<'a> {
+    /// Parse a PSF1 or PSF2 font.
+    pub fn parse(data: &'a [u8]) -> Result<Self, PsfError> {
+        let mut font = if data.starts_with(&PSF2_MAGIC) {
+            Self::parse_psf2(data)?
+        } else if data.starts_with(&PSF1_MAGIC) {
+            Self::parse_psf1(data)?
+        } else {
+            return Err(PsfError::BadMagic);
+        };
+
+        let mut fast_map = [NO_GLYPH; FAST_MAP_LENGTH];
+
+        match font.unicode {
+            // Without a table glyphs are in character order.
+            UnicodeTable::None => {
+                for (character, glyph) in fast_map.iter_mut().enumerate().take(font.glyph_count) {
+                    *glyph = character as u16;
+                }
+            }
+
+            // Tables can have more entries than the font has glyphs, those are skipped.
+            table => table.for_each(|glyph, character| {
+                if glyph >= font.glyph_count {
+                    return;
+                }
+
+                if let Some(slot) = fast_map.get_mut(character as usize) {
+                    if *slot == NO_GLYPH {
+                        *slot = glyph as u16;
+                    }
+                }
+            }),
+        }
+
+        font.fast_map = fast_map;
+
+        Ok(font)
+    }
+
+    fn parse_psf1(data: &'a [u8]) -> Result<Self, PsfError> {
+        if data.len() < PSF1_HEADER_SIZE {
+            return Err(PsfError::Truncated);
+        }
+
+        let mode = data[2];
+        let height = data[3] as usize;
+        let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let end = PSF1_HEADER_SIZE + glyph_count * height;
+
+        if height == 0 {
+            return Err(PsfError::BadHeader);
+        }
+
+        if data.len() < end {
+            return Err(PsfError::Truncated);
+        }
+
+        let unicode = if mode & (PSF1_MODE_HASTAB | PSF1_MODE_SEQ) != 0 {
+            UnicodeTable::Psf1(&data[end..])
+        } else {
+            UnicodeTable::None
+        };
+
+        Ok(Self {
+            glyphs: &data[PSF1_HEADER_SIZE..end],
+            glyph_count,
+            bytes_per_glyph: height,
+            width: 8,
+            height,
+            unicode,
+            fast_map: [NO_GLYPH; FAST_MAP_LENGTH],
+        })
+    }
+
+    fn parse_psf2(data: &'a [u8]) -> Result<Self, PsfError> {
+        if data.len() < PSF2_HEADER_SIZE {
+            return Err(PsfError::Truncated);
+        }
+
+        let header_size = u32_at(data, 8) as usize;
+        let flags = u32_at(data, 12);
+        let glyph_count = u32_at(data, 16) as usize;
+        let bytes_per_glyph = u32_at(data, 20) as usize;
+        let height = u32_at(data, 24) as usize;
+        let width = u32_at(data, 28) as usize;
+
+        if width == 0 || height == 0 || glyph_count == 0 || bytes_per_glyph < height * ((width + 7) / 8) {
+            return Err(PsfError::BadHeader);
+        }
+
+        let end = glyph_count
+            .checked_mul(bytes_per_glyph)
+            .and_then(|size| size.checked_add(header_size))
+            .ok_or(PsfError::BadHeader)?;
+
+        if header_size < PSF2_HEADER_SIZE || data.len() < end {
+            return Err(PsfError::Truncated);
+        }
+
+        let unicode = if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            UnicodeTable::Psf2(&data[end..])
+        } else {
+            UnicodeTable::None
+        };
+
+        Ok(Self {
+            glyphs: &data[header_size..end],
+            glyph_count,
+            bytes_per_glyph,
+            width,
+            height,
+            unicode,
+            fast_map: [NO_GLYPH; FAST_MAP_LENGTH],
+        })
+    }
+
+    /// The size of a glyph in (width, height) pixels.
+    #[inline]
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Bytes in every row of a glyph.
+    #[inline]
+    pub fn bytes_per_row(&self) -> usize {
+        (self.width + 7) / 8
+    }
+
+    /// The index of the glyph drawing `character`, if the font has one.
+    pub fn lookup(&self, character: char) -> Option<usize> {
+        if let Some(&glyph) = self.fast_map.get(character as usize) {
+            return if glyph == NO_GLYPH { None } else { Some(glyph as usize) };
+        }
+
+        let mut found = None;
+
+        self.unicode.for_each(|glyph, candidate| {
+            if found.is_none() && candidate == character {
+                found = Some(glyph);
+            }
+        });
+
+        found.filter(|&glyph| glyph < self.glyph_count)
+    }
+
+    /// The bitmap of glyph `index`.
+    #[inline]
+    pub fn glyph(&self, index: usize) -> &'a [u8] {
+        let start = index * self.bytes_per_glyph;
+        &self.glyphs[start..start + self.bytes_per_glyph]
+    }
+}
@@ -0,0 +1,117 @@
+//! Queues of input events, filled by interrupt handlers and drained by tasks.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+struct Ring<T, const N: usize> {
+    events: [Option<T>; N],
+    head: usize,
+    length: usize,
+    dropped: u64,
+}
+
+/// A bounded queue of `N` events, newer events are dropped when it's full.
+pub struct InputQueue<T: Copy, const N: usize> {
+    ring: Mutex<Ring<T, N>>,
+
+    /// The task waiting in `next`.
+    waker: Mutex<Option<Waker>>,
+}
+
+impl<T: Copy, const N: usize> InputQueue<T, N> {
+    pub fn new() -> Self {
+        Self {
+            ring: Mutex::new(Ring {
+                events: [None; N],
+                head: 0,
+                length: 0,
+                dropped: 0,
+            }),
+            waker: Mutex::new(None),
+        }
+    }
+
+    /// Queue `event` and wake the waiting task (from interrupt context.)
+    pub fn push(&self, event: T) {
+        {
+            let mut ring = self.ring.lock();
+
+            if ring.length == N {
+                ring.dropped += 1;
+                return;
+            }
+
+            let tail = (ring.head + ring.length) % N;
+
+            ring.events[tail] = Some(event);
+            ring.length += 1;
+        }
+
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+    }
+
+    /// The oldest queued event, if there is one.
+    pub fn pop(&self) -> Option<T> {
+        without_interrupts(|| {
+            let mut ring = self.ring.lock();
+
+            if ring.length == 0 {
+                return None;
+            }
+
+            let head = ring.head;
+            let event = ring.events[head].take();
+
+            ring.head = (head + 1) % N;
+            ring.length -= 1;
+
+            event
+        })
+    }
+
+    /// How many events were dropped because nobody read them in time.
+    pub fn dropped(&self) -> u64 {
+        without_interrupts(|| self.ring.lock().dropped)
+    }
+
+    /// Wait for the next event.
+    pub fn next_event(&self) -> NextInput<'_, T, N> {
+        NextInput(self)
+    }
+}
+
+impl<T: Copy, const N: usize> Default for InputQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Resolves to the next event of an `InputQueue`.
+pub struct NextInput<'a, T: Copy, const N: usize>(&'a InputQueue<T, N>);
+
+impl<'a, T: Copy, const N: usize> Future for NextInput<'a, T, N> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let queue = self.0;
+
+        without_interrupts(|| {
+            if let Some(event) = queue.pop() {
+                return Poll::Ready(event);
+            }
+
+            // Interrupts are off, so a push can't slip in before the waker is registered.
+            *queue.waker.lock() = Some(cx.waker().clone());
+
+            Poll::Pending
+        })
+    }
+}
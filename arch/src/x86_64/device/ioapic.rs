@@ -0,0 +1,279 @@
+//! I/O APIC driver.
+//!
+//! I/O APICs route external interrupts, identified by their global system
+//! interrupt (GSI) number, to local APICs. Which I/O APICs exist (and how
+//! the legacy ISA IRQs are wired to them) is described by the MADT, the
+//! kernel hands that information to `initialize`.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use spin::Mutex;
+
+use crate::x86_64::interrupts::index::InterruptIndex;
+
+/// The most I/O APICs we keep track of.
+const MAX_IO_APICS: usize = 8;
+
+/// The (legacy) ISA IRQs.
+const ISA_IRQS: usize = 16;
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+
+/// Redirection entries start here, two registers each.
+const IOREDTBL: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// An I/O APIC as described by the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+/// An ISA interrupt source override from the MADT.
+///
+/// `None` polarity or trigger mode means "same as the bus" (i.e. ISA, which
+/// is active high and edge triggered.)
+#[derive(Debug, Clone, Copy)]
+pub struct IsaOverride {
+    pub isa_source: u8,
+    pub gsi: u32,
+    pub polarity: Option<Polarity>,
+    pub trigger_mode: Option<TriggerMode>,
+}
+
+/// Where (and how) an ISA IRQ is wired.
+#[derive(Debug, Clone, Copy)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+// -- IoApic
+
+#[derive(Debug, Clone, Copy)]
+struct IoApic {
+    id: u8,
+    base: u64,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    #[inline]
+    unsafe fn read(&self, register: u32) -> u32 {
+        write_volatile((self.base + IOREGSEL) as *mut u32, register);
+        read_volatile((self.base + IOWIN) as *const u32)
+    }
+
+    #[inline]
+    unsafe fn write(&self, register: u32, value: u32) {
+        write_volatile((self.base + IOREGSEL) as *mut u32, register);
+        write_volatile((self.base + IOWIN) as *mut u32, value);
+    }
+
+    #[inline]
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..(self.gsi_base + self.entries)).contains(&gsi)
+    }
+
+    unsafe fn redirection(&self, gsi: u32) -> u64 {
+        let register = IOREDTBL + 2 * (gsi - self.gsi_base);
+        (self.read(register) as u64) | ((self.read(register + 1) as u64) << 32)
+    }
+
+    unsafe fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOREDTBL + 2 * (gsi - self.gsi_base);
+
+        // Mask first so a half written entry never fires.
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+// -- IoApics
+
+struct IoApics {
+    apics: [Option<IoApic>; MAX_IO_APICS],
+    isa: [IsaRoute; ISA_IRQS],
+}
+
+static IO_APICS: Mutex<IoApics> = Mutex::new(IoApics {
+    apics: [None; MAX_IO_APICS],
+    isa: [IsaRoute {
+        gsi: 0,
+        polarity: Polarity::ActiveHigh,
+        trigger_mode: TriggerMode::Edge,
+    }; ISA_IRQS],
+});
+
+impl IoApics {
+    fn find(&self, gsi: u32) -> Option<&IoApic> {
+        self.apics.iter().flatten().find(|apic| apic.handles(gsi))
+    }
+}
+
+/// Configure every I/O APIC from the MADT.
+///
+/// All entries are masked, then every ISA IRQ is routed (still masked) to its
+/// `InterruptIndex::vector` on the executing processor with the polarity and
+/// trigger mode from the interrupt source overrides. Use `unmask` once there
+/// is a handler installed.
+pub fn initialize(io_apics: &[IoApicInfo], overrides: &[IsaOverride]) {
+    let mut state = IO_APICS.lock();
+
+    for (slot, info) in state.apics.iter_mut().zip(io_apics.iter()) {
+        super::map_mmio(info.address as u64, "I/O APIC");
+
+        let mut apic = IoApic {
+            id: info.id,
+            base: info.address as u64,
+            gsi_base: info.gsi_base,
+            entries: 0,
+        };
+
+        unsafe {
+            apic.entries = ((apic.read(IOAPICVER) >> 16) & 0xFF) + 1;
+
+            for gsi in apic.gsi_base..(apic.gsi_base + apic.entries) {
+                apic.set_redirection(gsi, REDIRECTION_MASKED);
+            }
+
+            log::info!(
+                "(IOAPIC) I/O APIC {:?} (hardware id {:?}) at {:#x} handles GSIs {:?}..{:?}",
+                apic.id,
+                (apic.read(IOAPICID) >> 24) & 0xF,
+                apic.base,
+                apic.gsi_base,
+                apic.gsi_base + apic.entries
+            );
+        }
+
+        *slot = Some(apic);
+    }
+
+    if io_apics.len() > MAX_IO_APICS {
+        log::warn!("(IOAPIC) Ignoring {:?} I/O APICs", io_apics.len() - MAX_IO_APICS);
+    }
+
+    // ISA IRQs are identity mapped to GSIs unless overridden.
+    for (irq, route) in state.isa.iter_mut().enumerate() {
+        *route = IsaRoute {
+            gsi: irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+        };
+    }
+
+    for over in overrides.iter() {
+        if let Some(route) = state.isa.get_mut(over.isa_source as usize) {
+            log::debug!("(IOAPIC) ISA override: {:?}", over);
+
+            *route = IsaRoute {
+                gsi: over.gsi,
+                polarity: over.polarity.unwrap_or(Polarity::ActiveHigh),
+                trigger_mode: over.trigger_mode.unwrap_or(TriggerMode::Edge),
+            };
+        }
+    }
+
+    let destination = crate::prelude::local_apic_id().unwrap_or(0) as u8;
+    let isa = state.isa;
+
+    drop(state);
+
+    for index in InterruptIndex::ALL.iter().filter(|index| **index != InterruptIndex::Cascade) {
+        let route = isa[index.irq() as usize];
+
+        if !route_gsi(route.gsi, index.vector(), destination, route.polarity, route.trigger_mode) {
+            log::warn!("(IOAPIC) No I/O APIC handles {:?} (GSI {:?})", index, route.gsi);
+        }
+    }
+}
+
+/// Where the ISA IRQ `irq` is wired to.
+pub fn isa_route(irq: InterruptIndex) -> IsaRoute {
+    IO_APICS.lock().isa[irq.irq() as usize]
+}
+
+/// Route `gsi` to `vector` on the local APIC `destination`, the entry is left masked.
+///
+/// Returns `false` if no I/O APIC handles `gsi`.
+pub fn route_gsi(gsi: u32, vector: u8, destination: u8, polarity: Polarity, trigger_mode: TriggerMode) -> bool {
+    let state = IO_APICS.lock();
+
+    let apic = match state.find(gsi) {
+        Some(apic) => apic,
+        None => return false,
+    };
+
+    let mut entry = (vector as u64) | ((destination as u64) << 56) | REDIRECTION_MASKED;
+
+    if polarity == Polarity::ActiveLow {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+
+    if trigger_mode == TriggerMode::Level {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+
+    unsafe { apic.set_redirection(gsi, entry) };
+
+    true
+}
+
+/// Set (or clear) the mask bit of `gsi`, returns `false` if no I/O APIC handles it.
+fn set_masked(gsi: u32, masked: bool) -> bool {
+    let state = IO_APICS.lock();
+
+    let apic = match state.find(gsi) {
+        Some(apic) => apic,
+        None => return false,
+    };
+
+    unsafe {
+        let entry = apic.redirection(gsi);
+
+        let entry = if masked {
+            entry | REDIRECTION_MASKED
+        } else {
+            entry & !REDIRECTION_MASKED
+        };
+
+        apic.set_redirection(gsi, entry);
+    }
+
+    true
+}
+
+/// Stop `gsi` from being delivered.
+pub fn mask(gsi: u32) -> bool {
+    set_masked(gsi, true)
+}
+
+/// Start delivering `gsi` (to wherever it was routed.)
+pub fn unmask(gsi: u32) -> bool {
+    set_masked(gsi, false)
+}
@@ -1,3 +1,30 @@
 //! Architecture specific hardware/device code.
 
+use x86_64::structures::paging::{mapper::MapToError, PageTableFlags};
+
+pub mod apic;
+pub mod console;
+pub mod framebuffer;
+pub mod input;
+pub mod ioapic;
 pub mod pic;
+pub mod ps2;
+pub mod rtc;
+pub mod uart;
+pub mod vga_text;
+
+/// Identity map (uncached) the register page of a memory mapped device.
+pub(crate) fn map_mmio(address: u64, device: &str) {
+    use mem::MemoryManager;
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+
+    match unsafe { crate::prelude::memory_manager_ref() }.identity_map(address as usize, flags.bits()) {
+        Ok(flush) => flush.flush(),
+        Err(MapToError::PageAlreadyMapped(_)) => {}
+        Err(err) => panic!("Failed to map the {} registers at {:#x}: {:?}", device, address, err),
+    }
+}
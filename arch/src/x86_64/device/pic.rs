@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use pic8259_simple::ChainedPics;
 use spin::Mutex;
 
@@ -41,6 +43,51 @@ impl Chip8259 {
         pt!(0x21).write(0xFF);
     }
 
+    /// Unmask `irq` (and the cascade if it's on the secondary PIC.)
+    pub unsafe fn unmask(&self, irq: u8) {
+        if irq >= 8 {
+            self.set_masked(2, false);
+        }
+
+        self.set_masked(irq, false);
+    }
+
+    /// Mask `irq`.
+    pub unsafe fn mask(&self, irq: u8) {
+        self.set_masked(irq, true);
+    }
+
+    unsafe fn set_masked(&self, irq: u8, masked: bool) {
+        let port = if irq < 8 { 0x21 } else { 0xA1 };
+        let bit = 1 << (irq % 8);
+
+        let mask = pt!(port).read();
+        pt!(port).write(if masked { mask | bit } else { mask & !bit });
+    }
+
+    /// Signal the end of `irq` without going through the `pic` lock (for interrupt handlers.)
+    pub unsafe fn end_of_interrupt(&self, irq: u8) {
+        if irq >= 8 {
+            pt!(0xA0).write(0x20);
+        }
+
+        pt!(0x20).write(0x20);
+    }
+
+    /// The in-service register of both PICs (the secondary's in the high byte.)
+    pub unsafe fn in_service(&self) -> u16 {
+        // OCW3: read the ISR on the next read of the command port.
+        pt!(0x20).write(0x0B);
+        pt!(0xA0).write(0x0B);
+
+        (pt!(0x20).read() as u16) | ((pt!(0xA0).read() as u16) << 8)
+    }
+
+    /// Reprogram channel 0 of the PIT to interrupt at (about) `freq` hertz.
+    pub fn set_timer_frequency(&self, freq: usize) {
+        unsafe { self.pit.lock().set_frequency(freq) }
+    }
+
     pub unsafe fn setup(&self, pic_slave_offset: u8) {
         let mut pic = self.pic.lock();
         pic.initialize();
@@ -53,11 +100,25 @@ impl Chip8259 {
 // -- ProgrammableIntervalTimer (PIT)
 
 const CHAN_0_DATA: u16 = 0x40;
+const CHAN_2_DATA: u16 = 0x42;
+const CHAN_2_GATE: u16 = 0x61;
 const MDE_CMD_REG: u16 = 0x43;
 
 /// The frequency (in hertz) of the (8253/8254) PIT Oscillator.
 pub const PIT_OSC_FREQ: usize = 1193180;
 
+/// The lowest frequency channel 0 can be programmed to (the divisor is 16 bits.)
+pub const PIT_MIN_FREQ: usize = PIT_OSC_FREQ / 0xFFFF + 1;
+
+/// The period channel 0 was last programmed with (readable without the `pit` lock.)
+static CHAN_0_PERIOD_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// How long (in nanoseconds) there is between timer interrupts, 0 if never configured.
+#[inline]
+pub fn timer_period_nanos() -> u64 {
+    CHAN_0_PERIOD_NANOS.load(Ordering::Relaxed)
+}
+
 /// A (P)rogrammable (I)nterval (Timer) for the 825x oscillator.
 pub struct ProgrammableIntervalTimer {
     /// The frequency (in hertz) that the PIT should interrupt at.
@@ -75,9 +136,33 @@ impl ProgrammableIntervalTimer {
         Self { freq }
     }
 
+    /// The channel 0 divisor for our frequency.
+    #[inline]
+    fn divisor(&self) -> usize {
+        PIT_OSC_FREQ / self.freq
+    }
+
+    /// How long (in nanoseconds) channel 0 actually takes per interrupt.
+    #[inline]
+    pub fn period_nanos(&self) -> u64 {
+        (self.divisor() as u64 * 1_000_000_000) / PIT_OSC_FREQ as u64
+    }
+
+    /// Change our frequency and reconfigure the PIT with it.
+    pub unsafe fn set_frequency(&mut self, freq: usize) {
+        assert!(
+            (PIT_MIN_FREQ..=PIT_OSC_FREQ).contains(&freq),
+            "PIT frequency {:?}Hz is out of range",
+            freq
+        );
+
+        self.freq = freq;
+        self.reconfigure();
+    }
+
     /// Reconfigure the PIT with our frequency.
     pub unsafe fn reconfigure(&mut self) {
-        let divisor = PIT_OSC_FREQ / self.freq;
+        let divisor = self.divisor();
 
         // 0x36 is the command byte.
         pt!(MDE_CMD_REG).write(0x36);
@@ -93,6 +178,39 @@ impl ProgrammableIntervalTimer {
         self.io_wait();
         chan_0_data.write(h);
         self.io_wait();
+
+        CHAN_0_PERIOD_NANOS.store(self.period_nanos(), Ordering::Relaxed);
+    }
+
+    /// Busy wait for (at least) `micros` microseconds.
+    ///
+    /// This uses channel 2 (the one wired to the PC speaker) in one-shot mode
+    /// and polls its output, so it works before (and without) any interrupts.
+    pub unsafe fn spin_wait_micros(micros: u64) {
+        let mut remaining = (PIT_OSC_FREQ as u64 * micros) / 1_000_000;
+
+        while remaining > 0 {
+            let count = remaining.min(0xFFFF);
+            remaining -= count;
+
+            // Gate channel 2 on, speaker off.
+            let gate = pt!(CHAN_2_GATE).read();
+            pt!(CHAN_2_GATE).write((gate & !0x02) | 0x01);
+
+            // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count.)
+            pt!(MDE_CMD_REG).write(0xB0);
+            pt!(CHAN_2_DATA).write((count & 0xFF) as u8);
+            pt!(CHAN_2_DATA).write(((count >> 8) & 0xFF) as u8);
+
+            // Restart the count by toggling the gate.
+            let gate = pt!(CHAN_2_GATE).read() & !0x01;
+            pt!(CHAN_2_GATE).write(gate);
+            pt!(CHAN_2_GATE).write(gate | 0x01);
+
+            while pt!(CHAN_2_GATE).read() & 0x20 == 0 {
+                core::sync::atomic::spin_loop_hint();
+            }
+        }
     }
 
     /// Estimate the end time from a base uptime after some milis.
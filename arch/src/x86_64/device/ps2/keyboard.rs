@@ -0,0 +1,376 @@
+//! The PS/2 keyboard on the first port of the i8042.
+//!
+//! Scancodes (set 1 or 2, the controller's translation is off) are decoded
+//! with `pc-keyboard` in interrupt context and the resulting key events are
+//! queued for tasks to read with `read_event` or `next_event`.
+
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, HandleControl, KeyEvent, ScancodeSet1, ScancodeSet2};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{Port, Ps2Error};
+use crate::x86_64::device::input::{InputQueue, NextInput};
+
+pub use pc_keyboard::{DecodedKey, KeyCode, KeyState};
+
+/// Keyboard commands.
+const CMD_SET_LEDS: u8 = 0xED;
+const CMD_SCANCODE_SET: u8 = 0xF0;
+const CMD_SET_TYPEMATIC: u8 = 0xF3;
+const CMD_ENABLE_SCANNING: u8 = 0xF4;
+const CMD_DISABLE_SCANNING: u8 = 0xF5;
+const CMD_RESET: u8 = 0xFF;
+
+const SELF_TEST_PASSED: u8 = 0xAA;
+
+/// How many key events are kept for tasks, newer ones are dropped when it's full.
+const QUEUE_LENGTH: usize = 128;
+
+// -- Configuration
+
+/// The keyboard layouts `pc-keyboard` knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us104,
+    Uk105,
+    Jis109,
+    Azerty,
+    Dvorak104,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1 = 1,
+    Set2 = 2,
+}
+
+/// How long a key is held before it repeats and how often it repeats after that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Typematic {
+    /// Rounded to 250, 500, 750 or 1000ms.
+    pub delay: Duration,
+
+    /// Rounded to the nearest supported period (between ~33ms and 500ms.)
+    pub period: Duration,
+}
+
+impl Typematic {
+    /// The typematic byte: the delay in bits 5-6 and the rate in bits 0-4.
+    fn encode(&self) -> u8 {
+        let delay = ((self.delay.as_millis() + 125) / 250).max(1).min(4) as u8 - 1;
+
+        // period = (8 + low 3 bits) * 2^(bits 3-4) * 4.17ms
+        let period_micros = |rate: u8| (8 + (rate & 7) as u64) * (1 << (rate >> 3)) * 4170;
+        let target = self.period.as_micros() as u64;
+
+        let rate = (0..32)
+            .min_by_key(|&rate| (period_micros(rate) as i64 - target as i64).abs())
+            .unwrap_or(0);
+
+        (delay << 5) | rate
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyboardConfig {
+    pub layout: Layout,
+    pub scancode_set: ScancodeSet,
+
+    /// `None` leaves the keyboard's default (usually 500ms and ~10.9 keys per second.)
+    pub typematic: Option<Typematic>,
+}
+
+impl Default for KeyboardConfig {
+    fn default() -> Self {
+        Self {
+            layout: Layout::Us104,
+            scancode_set: ScancodeSet::Set2,
+            typematic: None,
+        }
+    }
+}
+
+/// The lock LEDs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Leds {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+impl Leds {
+    fn encode(&self) -> u8 {
+        (self.scroll_lock as u8) | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+}
+
+// -- Decoder
+
+/// A `pc_keyboard::Keyboard` for every layout, they're generic over the layout.
+macro_rules! decoder {
+    ($($layout:ident => $ty:ident),* $(,)?) => {
+        enum LayoutDecoder<S: pc_keyboard::ScancodeSet> {
+            $($layout(pc_keyboard::Keyboard<layouts::$ty, S>),)*
+        }
+
+        impl<S: pc_keyboard::ScancodeSet> LayoutDecoder<S> {
+            fn new(layout: Layout, set: S) -> Self {
+                match layout {
+                    $(Layout::$layout => {
+                        Self::$layout(pc_keyboard::Keyboard::new(layouts::$ty, set, HandleControl::MapLettersToUnicode))
+                    })*
+                }
+            }
+
+            fn add_byte(&mut self, byte: u8) -> Result<Option<KeyEvent>, pc_keyboard::Error> {
+                match self {
+                    $(Self::$layout(keyboard) => keyboard.add_byte(byte),)*
+                }
+            }
+
+            fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+                match self {
+                    $(Self::$layout(keyboard) => keyboard.process_keyevent(event),)*
+                }
+            }
+        }
+    };
+}
+
+decoder! {
+    Us104 => Us104Key,
+    Uk105 => Uk105Key,
+    Jis109 => Jis109Key,
+    Azerty => Azerty,
+    Dvorak104 => Dvorak104Key,
+}
+
+enum Decoder {
+    Set1(LayoutDecoder<ScancodeSet1>),
+    Set2(LayoutDecoder<ScancodeSet2>),
+}
+
+impl Decoder {
+    fn new(layout: Layout, set: ScancodeSet) -> Self {
+        match set {
+            ScancodeSet::Set1 => Self::Set1(LayoutDecoder::new(layout, ScancodeSet1)),
+            ScancodeSet::Set2 => Self::Set2(LayoutDecoder::new(layout, ScancodeSet2)),
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Result<Option<KeyEvent>, pc_keyboard::Error> {
+        match self {
+            Self::Set1(decoder) => decoder.add_byte(byte),
+            Self::Set2(decoder) => decoder.add_byte(byte),
+        }
+    }
+
+    fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        match self {
+            Self::Set1(decoder) => decoder.process_keyevent(event),
+            Self::Set2(decoder) => decoder.process_keyevent(event),
+        }
+    }
+}
+
+// -- Events
+
+/// A key going down or up, and what it means in the current layout (if anything.)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyboardEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    pub key: Option<DecodedKey>,
+}
+
+struct KeyboardState {
+    decoder: Decoder,
+    layout: Layout,
+    scancode_set: ScancodeSet,
+    leds: Leds,
+}
+
+static STATE: Mutex<Option<KeyboardState>> = Mutex::new(None);
+
+lazy_static! {
+    static ref QUEUE: InputQueue<KeyboardEvent, QUEUE_LENGTH> = InputQueue::new();
+}
+
+/// The LEDs changed but couldn't be sent yet (the controller was busy.)
+static LEDS_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Send the current LEDs to the keyboard, unless the controller is in use.
+fn flush_leds() {
+    let leds = match &*STATE.lock() {
+        Some(state) => state.leds.encode(),
+        None => return,
+    };
+
+    let sent = super::try_with_controller(|controller| controller.command(Port::First, &[CMD_SET_LEDS, leds], &mut []));
+
+    match sent {
+        Ok(Ok(())) => LEDS_PENDING.store(false, Ordering::Relaxed),
+        Ok(Err(err)) => {
+            LEDS_PENDING.store(false, Ordering::Relaxed);
+            log::warn!("(KEYBOARD) Failed to set the LEDs: {:?}", err);
+        }
+        Err(_) => {}
+    }
+}
+
+/// Byte handler of the first port.
+fn on_byte(byte: u8) {
+    let event = {
+        let mut state = STATE.lock();
+
+        let state = match &mut *state {
+            Some(state) => state,
+            None => return,
+        };
+
+        let event = match state.decoder.add_byte(byte) {
+            Ok(Some(event)) => event,
+            Ok(None) => return,
+            Err(err) => {
+                log::trace!("(KEYBOARD) Bad scancode {:#x}: {:?}", byte, err);
+                return;
+            }
+        };
+
+        if event.state == KeyState::Down {
+            let led = match event.code {
+                KeyCode::CapsLock => Some(&mut state.leds.caps_lock),
+                KeyCode::NumpadLock => Some(&mut state.leds.num_lock),
+                KeyCode::ScrollLock => Some(&mut state.leds.scroll_lock),
+                _ => None,
+            };
+
+            if let Some(led) = led {
+                *led = !*led;
+                LEDS_PENDING.store(true, Ordering::Relaxed);
+            }
+        }
+
+        KeyboardEvent {
+            code: event.code,
+            state: event.state,
+            key: state.decoder.process_keyevent(event),
+        }
+    };
+
+    QUEUE.push(event);
+
+    if LEDS_PENDING.load(Ordering::Relaxed) {
+        flush_leds();
+    }
+}
+
+/// The next queued key event, if there is one.
+pub fn read_event() -> Option<KeyboardEvent> {
+    QUEUE.pop()
+}
+
+/// How many key events were dropped because nobody read them in time.
+pub fn dropped_events() -> u64 {
+    QUEUE.dropped()
+}
+
+/// Wait for the next key event.
+pub fn next_event() -> NextInput<'static, KeyboardEvent, QUEUE_LENGTH> {
+    QUEUE.next_event()
+}
+
+// -- Configuration
+
+/// Decode with `layout` from now on.
+pub fn set_layout(layout: Layout) {
+    without_interrupts(|| {
+        if let Some(state) = &mut *STATE.lock() {
+            state.decoder = Decoder::new(layout, state.scancode_set);
+            state.layout = layout;
+        }
+    })
+}
+
+/// The current layout, `None` if there is no keyboard.
+pub fn layout() -> Option<Layout> {
+    without_interrupts(|| STATE.lock().as_ref().map(|state| state.layout))
+}
+
+/// Light the lock LEDs (the lock keys toggle them too.)
+pub fn set_leds(leds: Leds) -> Result<(), Ps2Error> {
+    without_interrupts(|| {
+        if let Some(state) = &mut *STATE.lock() {
+            state.leds = leds;
+        }
+    });
+
+    super::with_controller(|controller| controller.command(Port::First, &[CMD_SET_LEDS, leds.encode()], &mut []))
+}
+
+pub fn leds() -> Leds {
+    without_interrupts(|| STATE.lock().as_ref().map_or(Leds::default(), |state| state.leds))
+}
+
+/// Configure the key repeat delay and rate.
+pub fn set_typematic(typematic: Typematic) -> Result<(), Ps2Error> {
+    super::with_controller(|controller| {
+        controller.command(Port::First, &[CMD_SET_TYPEMATIC, typematic.encode()], &mut [])
+    })
+}
+
+/// Reset the keyboard on the first port and start decoding its scancodes.
+pub(crate) fn initialize(config: KeyboardConfig) -> Result<(), Ps2Error> {
+    if !super::is_available(Port::First) {
+        return Err(Ps2Error::NoPort(Port::First));
+    }
+
+    let scancode_set = super::with_controller(|controller| {
+        controller.send(Port::First, CMD_DISABLE_SCANNING)?;
+
+        let mut result = [0u8; 1];
+        controller.command(Port::First, &[CMD_RESET], &mut result)?;
+
+        if result[0] != SELF_TEST_PASSED {
+            return Err(Ps2Error::Unexpected(Port::First, result[0]));
+        }
+
+        let scancode_set = match controller.command(Port::First, &[CMD_SCANCODE_SET, config.scancode_set as u8], &mut []) {
+            Ok(()) => config.scancode_set,
+            Err(err) => {
+                log::warn!("(KEYBOARD) Can't switch to {:?} ({:?}), staying on set 2", config.scancode_set, err);
+                ScancodeSet::Set2
+            }
+        };
+
+        if let Some(typematic) = config.typematic {
+            controller.command(Port::First, &[CMD_SET_TYPEMATIC, typematic.encode()], &mut [])?;
+        }
+
+        controller.command(Port::First, &[CMD_SET_LEDS, 0], &mut [])?;
+
+        Ok(scancode_set)
+    })?;
+
+    without_interrupts(|| {
+        *STATE.lock() = Some(KeyboardState {
+            decoder: Decoder::new(config.layout, scancode_set),
+            layout: config.layout,
+            scancode_set,
+            leds: Leds::default(),
+        })
+    });
+
+    super::set_handler(Port::First, Some(on_byte));
+    super::with_controller(|controller| controller.send(Port::First, CMD_ENABLE_SCANNING))?;
+
+    log::info!("(KEYBOARD) {:?} layout, scancode {:?}", config.layout, scancode_set);
+
+    Ok(())
+}
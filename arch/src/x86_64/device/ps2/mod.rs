@@ -0,0 +1,360 @@
+//! The i8042 PS/2 controller.
+//!
+//! The controller has (up to) two ports, the first usually has a keyboard
+//! and the second a mouse. Both share one data port, the status register
+//! says which device a byte came from, so every byte is read here and handed
+//! to the handler of its port (see `set_handler`.)
+//!
+//! Commands to the devices are sent with interrupts disabled and their
+//! responses polled for, bytes from the other port that arrive meanwhile are
+//! still delivered to its handler.
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::x86_64::{
+    interrupts::{
+        index::InterruptIndex,
+        irq::{self, IrqContext, IrqReturn},
+    },
+    timer,
+};
+
+pub mod keyboard;
+pub mod mouse;
+
+const DATA: u16 = 0x60;
+const STATUS_COMMAND: u16 = 0x64;
+
+/// Bits of the status register.
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+const STATUS_SECOND_PORT: u8 = 1 << 5;
+
+/// Controller commands.
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_SECOND: u8 = 0xA7;
+const CMD_ENABLE_SECOND: u8 = 0xA8;
+const CMD_TEST_SECOND: u8 = 0xA9;
+const CMD_SELF_TEST: u8 = 0xAA;
+const CMD_TEST_FIRST: u8 = 0xAB;
+const CMD_DISABLE_FIRST: u8 = 0xAD;
+const CMD_ENABLE_FIRST: u8 = 0xAE;
+const CMD_WRITE_SECOND: u8 = 0xD4;
+
+/// Bits of the configuration byte.
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+/// Device responses.
+pub const ACK: u8 = 0xFA;
+pub const RESEND: u8 = 0xFE;
+
+/// How often a command is resent before giving up.
+const MAX_RESENDS: usize = 3;
+
+/// How long to wait for the controller or a device, device self tests are slow.
+const TIMEOUT: Duration = Duration::from_millis(500);
+const POLL_INTERVAL: Duration = Duration::from_micros(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    First = 0,
+    Second = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// The controller or device didn't respond in time.
+    Timeout,
+
+    /// The controller self test returned this instead of 0x55.
+    SelfTest(u8),
+
+    /// The interface test of a port returned this instead of 0x00.
+    PortTest(Port, u8),
+
+    /// The port doesn't exist or failed its test.
+    NoPort(Port),
+
+    /// The device kept asking for the byte to be resent.
+    Resend(Port),
+
+    /// The device answered something other than an acknowledgement.
+    Unexpected(Port, u8),
+
+    /// The controller is in use (i.e. by the code that interrupted us.)
+    Busy,
+}
+
+/// The byte handlers of both ports as `fn(u8)`s, 0 when there isn't one.
+static HANDLERS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+/// Ports that exist and passed their interface test.
+static AVAILABLE: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+/// Does the controller have a second port? (otherwise the second port status bit is meaningless.)
+static DUAL_CHANNEL: AtomicBool = AtomicBool::new(false);
+
+static CONTROLLER: Mutex<Controller> = Mutex::new(Controller);
+
+#[inline]
+unsafe fn status() -> u8 {
+    cpuio::inb(STATUS_COMMAND)
+}
+
+/// Which port the byte in the output buffer is from.
+#[inline]
+fn source(status: u8) -> Port {
+    if DUAL_CHANNEL.load(Ordering::Relaxed) && status & STATUS_SECOND_PORT != 0 {
+        Port::Second
+    } else {
+        Port::First
+    }
+}
+
+/// Hand a byte from `port` to its handler.
+fn deliver(port: Port, byte: u8) {
+    let handler = HANDLERS[port as usize].load(Ordering::Acquire);
+
+    if handler != 0 {
+        // SAFETY: Only ever set from a `fn(u8)` in `set_handler`.
+        let handler: fn(u8) = unsafe { core::mem::transmute(handler) };
+        handler(byte);
+    }
+}
+
+// -- Controller
+
+/// Exclusive access to the controller, see `with_controller`.
+pub struct Controller;
+
+impl Controller {
+    /// Poll `ready` with a timeout.
+    fn wait(&self, mut ready: impl FnMut() -> bool) -> Result<(), Ps2Error> {
+        let mut waited = Duration::from_secs(0);
+
+        while !ready() {
+            if waited >= TIMEOUT {
+                return Err(Ps2Error::Timeout);
+            }
+
+            timer::delay(POLL_INTERVAL);
+            waited += POLL_INTERVAL;
+        }
+
+        Ok(())
+    }
+
+    fn write_command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.wait(|| unsafe { status() } & STATUS_INPUT_FULL == 0)?;
+        unsafe { cpuio::outb(command, STATUS_COMMAND) };
+        Ok(())
+    }
+
+    fn write_data(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        self.wait(|| unsafe { status() } & STATUS_INPUT_FULL == 0)?;
+        unsafe { cpuio::outb(byte, DATA) };
+        Ok(())
+    }
+
+    /// Read the next byte from the controller itself (a command response.)
+    fn read_data(&mut self) -> Result<u8, Ps2Error> {
+        self.wait(|| unsafe { status() } & STATUS_OUTPUT_FULL != 0)?;
+        Ok(unsafe { cpuio::inb(DATA) })
+    }
+
+    fn command_response(&mut self, command: u8) -> Result<u8, Ps2Error> {
+        self.write_command(command)?;
+        self.read_data()
+    }
+
+    fn read_config(&mut self) -> Result<u8, Ps2Error> {
+        self.command_response(CMD_READ_CONFIG)
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), Ps2Error> {
+        self.write_command(CMD_WRITE_CONFIG)?;
+        self.write_data(config)
+    }
+
+    /// Throw away whatever is in the output buffer.
+    fn flush(&mut self) {
+        while unsafe { status() } & STATUS_OUTPUT_FULL != 0 {
+            unsafe { cpuio::inb(DATA) };
+        }
+    }
+
+    /// Read the next byte from the device on `port`, delivering bytes from the other one.
+    pub fn read(&mut self, port: Port) -> Result<u8, Ps2Error> {
+        loop {
+            self.wait(|| unsafe { status() } & STATUS_OUTPUT_FULL != 0)?;
+
+            let from = source(unsafe { status() });
+            let byte = unsafe { cpuio::inb(DATA) };
+
+            if from == port {
+                return Ok(byte);
+            }
+
+            deliver(from, byte);
+        }
+    }
+
+    /// Send `byte` to the device on `port` and wait for it to be acknowledged.
+    pub fn send(&mut self, port: Port, byte: u8) -> Result<(), Ps2Error> {
+        if !AVAILABLE[port as usize].load(Ordering::Relaxed) {
+            return Err(Ps2Error::NoPort(port));
+        }
+
+        for _ in 0..MAX_RESENDS {
+            if port == Port::Second {
+                self.write_command(CMD_WRITE_SECOND)?;
+            }
+
+            self.write_data(byte)?;
+
+            match self.read(port)? {
+                ACK => return Ok(()),
+                RESEND => continue,
+                other => return Err(Ps2Error::Unexpected(port, other)),
+            }
+        }
+
+        Err(Ps2Error::Resend(port))
+    }
+
+    /// Send every byte of `command` and then read `response.len()` bytes back.
+    pub fn command(&mut self, port: Port, command: &[u8], response: &mut [u8]) -> Result<(), Ps2Error> {
+        for &byte in command {
+            self.send(port, byte)?;
+        }
+
+        for slot in response.iter_mut() {
+            *slot = self.read(port)?;
+        }
+
+        Ok(())
+    }
+
+    fn initialize(&mut self) -> Result<(), Ps2Error> {
+        // Quiet both ports while we're setting things up.
+        self.write_command(CMD_DISABLE_FIRST)?;
+        self.write_command(CMD_DISABLE_SECOND)?;
+        self.flush();
+
+        // No interrupts or translation (to scancode set 1) yet.
+        let config = self.read_config()? & !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ | CONFIG_TRANSLATION);
+        self.write_config(config)?;
+
+        match self.command_response(CMD_SELF_TEST)? {
+            SELF_TEST_PASSED => {}
+            other => return Err(Ps2Error::SelfTest(other)),
+        }
+
+        // The self test resets the controller on some machines.
+        self.write_config(config)?;
+
+        // Enabling the second port clears its clock disabled bit, if there is one.
+        self.write_command(CMD_ENABLE_SECOND)?;
+        let dual_channel = self.read_config()? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+        DUAL_CHANNEL.store(dual_channel, Ordering::Relaxed);
+        self.write_command(CMD_DISABLE_SECOND)?;
+
+        let mut config = self.read_config()?;
+
+        for &(port, test, enable, irq) in &[
+            (Port::First, CMD_TEST_FIRST, CMD_ENABLE_FIRST, CONFIG_FIRST_IRQ),
+            (Port::Second, CMD_TEST_SECOND, CMD_ENABLE_SECOND, CONFIG_SECOND_IRQ),
+        ] {
+            if port == Port::Second && !dual_channel {
+                continue;
+            }
+
+            match self.command_response(test)? {
+                PORT_TEST_PASSED => {
+                    self.write_command(enable)?;
+                    AVAILABLE[port as usize].store(true, Ordering::Relaxed);
+                    config |= irq;
+                }
+
+                other => log::warn!("(PS2) {:?}", Ps2Error::PortTest(port, other)),
+            }
+        }
+
+        self.flush();
+        self.write_config(config)
+    }
+}
+
+/// Run `f` with the controller, interrupts disabled.
+pub fn with_controller<T>(f: impl FnOnce(&mut Controller) -> T) -> T {
+    without_interrupts(|| f(&mut CONTROLLER.lock()))
+}
+
+/// `with_controller`, unless it's already in use (for interrupt handlers.)
+pub fn try_with_controller<T>(f: impl FnOnce(&mut Controller) -> T) -> Result<T, Ps2Error> {
+    without_interrupts(|| match CONTROLLER.try_lock() {
+        Some(mut controller) => Ok(f(&mut controller)),
+        None => Err(Ps2Error::Busy),
+    })
+}
+
+/// Is there a working port `port`?
+pub fn is_available(port: Port) -> bool {
+    AVAILABLE[port as usize].load(Ordering::Relaxed)
+}
+
+/// Call `handler` (in interrupt context) with every byte the device on `port` sends.
+pub fn set_handler(port: Port, handler: Option<fn(u8)>) {
+    HANDLERS[port as usize].store(handler.map_or(0, |handler| handler as usize), Ordering::Release);
+}
+
+fn on_interrupt(_context: &IrqContext) -> IrqReturn {
+    loop {
+        let status = unsafe { status() };
+
+        if status & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+
+        deliver(source(status), unsafe { cpuio::inb(DATA) });
+    }
+
+    // Even with nothing to read, the byte was just taken by a poller.
+    IrqReturn::Handled
+}
+
+/// Initialise and test the controller, enable its ports and take IRQ 1 and 12.
+pub(crate) fn initialize() -> Result<(), Ps2Error> {
+    with_controller(|controller| controller.initialize())?;
+
+    for &(port, index) in &[(Port::First, InterruptIndex::PS2Keyboard), (Port::Second, InterruptIndex::PS2Mouse)] {
+        if !is_available(port) {
+            continue;
+        }
+
+        if let Err(err) = irq::request_irq(index, "i8042", on_interrupt, port as usize) {
+            log::warn!("(PS2) Can't take the IRQ of the {:?} port: {:?}", port, err);
+        }
+    }
+
+    log::info!(
+        "(PS2) Controller ready, first port: {:?}, second port: {:?}",
+        is_available(Port::First),
+        is_available(Port::Second)
+    );
+
+    Ok(())
+}
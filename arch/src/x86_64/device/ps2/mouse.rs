@@ -0,0 +1,271 @@
+//! The PS/2 mouse on the second port of the i8042.
+//!
+//! A plain PS/2 mouse sends 3 byte packets (buttons and X/Y motion). The
+//! IntelliMouse extensions are unlocked with "magic" sample rate sequences:
+//! 200, 100, 80 turns on the scroll wheel (device id 3) and then 200, 200, 80
+//! the fourth and fifth buttons (device id 4), both add a fourth byte.
+
+use core::{
+    sync::atomic::{AtomicU8, Ordering},
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::{Port, Ps2Error};
+use crate::x86_64::{
+    device::input::{InputQueue, NextInput},
+    timer,
+};
+
+/// Mouse commands.
+const CMD_GET_ID: u8 = 0xF2;
+const CMD_SET_SAMPLE_RATE: u8 = 0xF3;
+const CMD_ENABLE_REPORTING: u8 = 0xF4;
+const CMD_DISABLE_REPORTING: u8 = 0xF5;
+const CMD_SET_DEFAULTS: u8 = 0xF6;
+const CMD_RESET: u8 = 0xFF;
+
+const SELF_TEST_PASSED: u8 = 0xAA;
+
+/// Device ids.
+const ID_STANDARD: u8 = 0;
+const ID_WHEEL: u8 = 3;
+const ID_FIVE_BUTTONS: u8 = 4;
+
+/// Bits of the first byte of a packet.
+const PACKET_LEFT: u8 = 1 << 0;
+const PACKET_RIGHT: u8 = 1 << 1;
+const PACKET_MIDDLE: u8 = 1 << 2;
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+
+/// Bits of the fourth byte of a 5 button packet.
+const PACKET_FOURTH: u8 = 1 << 4;
+const PACKET_FIFTH: u8 = 1 << 5;
+
+/// Reports per second.
+const SAMPLE_RATE: u8 = 100;
+
+/// The bytes of a packet arrive back to back, a gap this long means we lost sync.
+const PACKET_TIMEOUT: Duration = Duration::from_millis(50);
+
+const QUEUE_LENGTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+    pub fourth: bool,
+    pub fifth: bool,
+}
+
+/// One packet: relative motion, the buttons held and the wheel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// Right is positive.
+    pub dx: i16,
+
+    /// Down is positive (like screen coordinates, PS/2 has up positive.)
+    pub dy: i16,
+
+    /// Towards the user is positive.
+    pub wheel: i8,
+
+    pub buttons: MouseButtons,
+}
+
+struct Decoder {
+    packet: [u8; 4],
+    received: usize,
+    last_byte: Duration,
+}
+
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder {
+    packet: [0; 4],
+    received: 0,
+    last_byte: Duration::from_secs(0),
+});
+
+/// The device id, which decides the packet format.
+static DEVICE_ID: AtomicU8 = AtomicU8::new(ID_STANDARD);
+
+lazy_static! {
+    static ref QUEUE: InputQueue<MouseEvent, QUEUE_LENGTH> = InputQueue::new();
+}
+
+fn packet_length() -> usize {
+    match DEVICE_ID.load(Ordering::Relaxed) {
+        ID_WHEEL | ID_FIVE_BUTTONS => 4,
+        _ => 3,
+    }
+}
+
+/// Sign extend a 9-bit motion value.
+#[inline]
+fn motion(value: u8, negative: bool) -> i16 {
+    if negative {
+        value as i16 - 0x100
+    } else {
+        value as i16
+    }
+}
+
+fn decode(packet: &[u8; 4]) -> MouseEvent {
+    let flags = packet[0];
+
+    // The motion is garbage when it overflowed.
+    let (dx, dy) = if flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) != 0 {
+        (0, 0)
+    } else {
+        (motion(packet[1], flags & PACKET_X_SIGN != 0), -motion(packet[2], flags & PACKET_Y_SIGN != 0))
+    };
+
+    let mut buttons = MouseButtons {
+        left: flags & PACKET_LEFT != 0,
+        right: flags & PACKET_RIGHT != 0,
+        middle: flags & PACKET_MIDDLE != 0,
+        ..MouseButtons::default()
+    };
+
+    let wheel = match DEVICE_ID.load(Ordering::Relaxed) {
+        ID_WHEEL => packet[3] as i8,
+
+        ID_FIVE_BUTTONS => {
+            buttons.fourth = packet[3] & PACKET_FOURTH != 0;
+            buttons.fifth = packet[3] & PACKET_FIFTH != 0;
+
+            // A 4-bit two's complement value.
+            ((packet[3] << 4) as i8) >> 4
+        }
+
+        _ => 0,
+    };
+
+    MouseEvent { dx, dy, wheel, buttons }
+}
+
+/// Byte handler of the second port.
+fn on_byte(byte: u8) {
+    let event = {
+        let mut decoder = DECODER.lock();
+        let now = timer::uptime();
+
+        if decoder.received > 0 && now - decoder.last_byte > PACKET_TIMEOUT {
+            decoder.received = 0;
+        }
+
+        decoder.last_byte = now;
+
+        // Resynchronise on a byte that can start a packet.
+        if decoder.received == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+            return;
+        }
+
+        let index = decoder.received;
+
+        decoder.packet[index] = byte;
+        decoder.received += 1;
+
+        if decoder.received < packet_length() {
+            return;
+        }
+
+        decoder.received = 0;
+        decode(&decoder.packet)
+    };
+
+    QUEUE.push(event);
+}
+
+/// The next queued mouse event, if there is one.
+pub fn read_event() -> Option<MouseEvent> {
+    QUEUE.pop()
+}
+
+/// How many mouse events were dropped because nobody read them in time.
+pub fn dropped_events() -> u64 {
+    QUEUE.dropped()
+}
+
+/// Wait for the next mouse event.
+pub fn next_event() -> NextInput<'static, MouseEvent, QUEUE_LENGTH> {
+    QUEUE.next_event()
+}
+
+/// Does the mouse have a scroll wheel?
+pub fn has_wheel() -> bool {
+    DEVICE_ID.load(Ordering::Relaxed) != ID_STANDARD
+}
+
+/// Does the mouse have fourth and fifth buttons?
+pub fn has_five_buttons() -> bool {
+    DEVICE_ID.load(Ordering::Relaxed) == ID_FIVE_BUTTONS
+}
+
+/// Send a sample rate sequence and read back the device id.
+fn knock(controller: &mut super::Controller, rates: &[u8]) -> Result<u8, Ps2Error> {
+    for &rate in rates {
+        controller.command(Port::Second, &[CMD_SET_SAMPLE_RATE, rate], &mut [])?;
+    }
+
+    let mut id = [0u8; 1];
+    controller.command(Port::Second, &[CMD_GET_ID], &mut id)?;
+
+    Ok(id[0])
+}
+
+/// Reset the mouse on the second port, negotiate the IntelliMouse modes and enable reporting.
+pub(crate) fn initialize() -> Result<(), Ps2Error> {
+    if !super::is_available(Port::Second) {
+        return Err(Ps2Error::NoPort(Port::Second));
+    }
+
+    let id = super::with_controller(|controller| {
+        controller.send(Port::Second, CMD_DISABLE_REPORTING)?;
+
+        // Self test result and the device id.
+        let mut result = [0u8; 2];
+        controller.command(Port::Second, &[CMD_RESET], &mut result)?;
+
+        if result[0] != SELF_TEST_PASSED {
+            return Err(Ps2Error::Unexpected(Port::Second, result[0]));
+        }
+
+        controller.send(Port::Second, CMD_SET_DEFAULTS)?;
+
+        let mut id = knock(controller, &[200, 100, 80])?;
+
+        if id == ID_WHEEL {
+            id = knock(controller, &[200, 200, 80])?;
+        }
+
+        controller.command(Port::Second, &[CMD_SET_SAMPLE_RATE, SAMPLE_RATE], &mut [])?;
+
+        Ok(id)
+    })?;
+
+    let id = match id {
+        ID_WHEEL | ID_FIVE_BUTTONS => id,
+        _ => ID_STANDARD,
+    };
+
+    DEVICE_ID.store(id, Ordering::Relaxed);
+
+    super::set_handler(Port::Second, Some(on_byte));
+    super::with_controller(|controller| controller.send(Port::Second, CMD_ENABLE_REPORTING))?;
+
+    log::info!(
+        "(MOUSE) Device id {:?}, wheel: {:?}, five buttons: {:?}",
+        id,
+        has_wheel(),
+        has_five_buttons()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,383 @@
+//! The CMOS real-time clock (the MC146818 and its descendants.)
+//!
+//! The date and time registers may be BCD or binary and the hours 12 or 24
+//! hour depending on status register B. The RTC updates them once a second
+//! and reads during an update can be torn, so we wait for the update in
+//! progress flag to clear and read until two reads agree.
+//!
+//! The century register isn't standard, the FADT says where (if anywhere) it is.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::x86_64::interrupts::{
+    index::InterruptIndex,
+    irq::{self, IrqContext, IrqReturn},
+};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+/// Keep NMIs masked while an index is selected.
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_ALARM_SECONDS: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_ALARM_MINUTES: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_ALARM_HOURS: u8 = 0x05;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+const REG_STATUS_C: u8 = 0x0C;
+
+/// Bits of status register A.
+const A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const A_RATE_MASK: u8 = 0x0F;
+
+/// Bits of status register B.
+const B_24_HOUR: u8 = 1 << 1;
+const B_BINARY: u8 = 1 << 2;
+const B_ALARM_INTERRUPT: u8 = 1 << 5;
+const B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+
+/// Bits of status register C (reading it acknowledges the interrupt.)
+const C_INTERRUPT: u8 = 1 << 7;
+const C_PERIODIC: u8 = 1 << 6;
+const C_ALARM: u8 = 1 << 5;
+
+/// The PM flag of the hours register in 12 hour mode.
+const HOURS_PM: u8 = 1 << 7;
+
+/// Alarm registers with this value match any value.
+const ALARM_ANY: u8 = 0xC0;
+
+/// The base frequency the periodic interrupt rate divides.
+const RTC_BASE_FREQ: u32 = 32768;
+
+/// Serialises index/data port pairs.
+static CMOS: Mutex<()> = Mutex::new(());
+
+/// The CMOS index of the century register, 0 if there isn't one.
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+/// The periodic and alarm handlers as `fn()`s, 0 when there isn't one.
+static PERIODIC_HANDLER: AtomicUsize = AtomicUsize::new(0);
+static ALARM_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Read CMOS register `index`, the `CMOS` lock must be held.
+unsafe fn read_register(index: u8) -> u8 {
+    cpuio::outb(NMI_DISABLE | index, CMOS_ADDRESS);
+    let value = cpuio::inb(CMOS_DATA);
+    cpuio::outb(0, CMOS_ADDRESS);
+    value
+}
+
+/// Write CMOS register `index`, the `CMOS` lock must be held.
+unsafe fn write_register(index: u8, value: u8) {
+    cpuio::outb(NMI_DISABLE | index, CMOS_ADDRESS);
+    cpuio::outb(value, CMOS_DATA);
+    cpuio::outb(0, CMOS_ADDRESS);
+}
+
+fn with_cmos<T>(f: impl FnOnce() -> T) -> T {
+    without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        f()
+    })
+}
+
+#[inline]
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+#[inline]
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+// -- DateTime
+
+/// A (UTC) calendar date and time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since the UNIX epoch (1970-01-01T00:00:00Z.)
+    pub fn to_unix(&self) -> u64 {
+        // Days from the civil date, years start in March so the leap day is last.
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        let seconds = days * 86_400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+
+        seconds.max(0) as u64
+    }
+
+    /// The calendar date and time `seconds` after the UNIX epoch.
+    pub fn from_unix(seconds: u64) -> Self {
+        let days = (seconds / 86_400) as i64 + 719_468;
+        let time = seconds % 86_400;
+
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// -- Reading
+
+/// The raw date and time registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+impl Registers {
+    /// Read once the current update (if any) has finished, the `CMOS` lock must be held.
+    unsafe fn read(century_register: u8) -> Self {
+        while read_register(REG_STATUS_A) & A_UPDATE_IN_PROGRESS != 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+
+        Self {
+            second: read_register(REG_SECONDS),
+            minute: read_register(REG_MINUTES),
+            hour: read_register(REG_HOURS),
+            day: read_register(REG_DAY),
+            month: read_register(REG_MONTH),
+            year: read_register(REG_YEAR),
+            century: if century_register != 0 { read_register(century_register) } else { 0 },
+        }
+    }
+
+    fn decode(self, status_b: u8) -> DateTime {
+        let binary = status_b & B_BINARY != 0;
+        let decode = |value: u8| if binary { value } else { from_bcd(value) };
+
+        let pm = status_b & B_24_HOUR == 0 && self.hour & HOURS_PM != 0;
+        let mut hour = decode(self.hour & !HOURS_PM);
+
+        if status_b & B_24_HOUR == 0 {
+            // 12AM is midnight, 12PM is noon.
+            hour %= 12;
+
+            if pm {
+                hour += 12;
+            }
+        }
+
+        let year = decode(self.year) as u16;
+
+        let year = if self.century != 0 {
+            decode(self.century) as u16 * 100 + year
+        } else {
+            // Without a century register, assume we're somewhere in 1970..2070.
+            if year < 70 {
+                2000 + year
+            } else {
+                1900 + year
+            }
+        };
+
+        DateTime {
+            year,
+            month: decode(self.month),
+            day: decode(self.day),
+            hour,
+            minute: decode(self.minute),
+            second: decode(self.second),
+        }
+    }
+}
+
+/// Read the current date and time from the RTC.
+pub fn read() -> DateTime {
+    let century_register = CENTURY_REGISTER.load(Ordering::Relaxed);
+
+    let (registers, status_b) = with_cmos(|| unsafe {
+        let mut last = Registers::read(century_register);
+
+        // An update could have started between the flag check and the reads.
+        loop {
+            let current = Registers::read(century_register);
+
+            if current == last {
+                break (current, read_register(REG_STATUS_B));
+            }
+
+            last = current;
+        }
+    });
+
+    registers.decode(status_b)
+}
+
+// -- Interrupts
+
+fn on_interrupt(_context: &IrqContext) -> IrqReturn {
+    // Reading C acknowledges the interrupt, the RTC won't raise another until we do.
+    let flags = with_cmos(|| unsafe { read_register(REG_STATUS_C) });
+
+    if flags & C_INTERRUPT == 0 {
+        return IrqReturn::NotMine;
+    }
+
+    for &(flag, handler) in &[(C_PERIODIC, &PERIODIC_HANDLER), (C_ALARM, &ALARM_HANDLER)] {
+        let handler = handler.load(Ordering::Acquire);
+
+        if flags & flag != 0 && handler != 0 {
+            // SAFETY: Only ever set from a `fn()` in `set_handler`.
+            let handler: fn() = unsafe { core::mem::transmute(handler) };
+            handler();
+        }
+    }
+
+    IrqReturn::Handled
+}
+
+/// Install `handler` in `slot` and enable (or disable, with `None`) the interrupt in register B.
+fn set_handler(slot: &AtomicUsize, enable: u8, handler: Option<fn()>) {
+    slot.store(handler.map_or(0, |handler| handler as usize), Ordering::Release);
+
+    with_cmos(|| unsafe {
+        let status_b = read_register(REG_STATUS_B);
+        write_register(
+            REG_STATUS_B,
+            if handler.is_some() { status_b | enable } else { status_b & !enable },
+        );
+
+        // Throw away anything that was pending.
+        read_register(REG_STATUS_C);
+    });
+}
+
+/// Call `handler` (in interrupt context) at `freq` hertz, a power of two between 2 and 8192.
+///
+/// `None` stops the periodic interrupt.
+pub fn set_periodic(freq: u32, handler: Option<fn()>) {
+    if handler.is_some() {
+        assert!(
+            freq.is_power_of_two() && (2..=8192).contains(&freq),
+            "The RTC's periodic interrupt must be a power of two between 2 and 8192 hertz."
+        );
+
+        // freq = 32768 >> (rate - 1)
+        let rate = (RTC_BASE_FREQ / freq).trailing_zeros() as u8 + 1;
+
+        with_cmos(|| unsafe {
+            let status_a = read_register(REG_STATUS_A);
+            write_register(REG_STATUS_A, (status_a & !A_RATE_MASK) | rate);
+        });
+    }
+
+    set_handler(&PERIODIC_HANDLER, B_PERIODIC_INTERRUPT, handler);
+}
+
+/// Call `handler` (in interrupt context) every day at `hour:minute:second`.
+///
+/// `None` for a field matches any value, i.e. `(None, None, Some(0))` fires every minute.
+pub fn set_alarm(hour: Option<u8>, minute: Option<u8>, second: Option<u8>, handler: fn()) {
+    with_cmos(|| unsafe {
+        let status_b = read_register(REG_STATUS_B);
+        let encode = |value: u8| if status_b & B_BINARY != 0 { value } else { to_bcd(value) };
+
+        let hour = hour.map_or(ALARM_ANY, |hour| {
+            if status_b & B_24_HOUR != 0 {
+                encode(hour)
+            } else {
+                let twelve = if hour % 12 == 0 { 12 } else { hour % 12 };
+                encode(twelve) | if hour >= 12 { HOURS_PM } else { 0 }
+            }
+        });
+
+        write_register(REG_ALARM_HOURS, hour);
+        write_register(REG_ALARM_MINUTES, minute.map_or(ALARM_ANY, encode));
+        write_register(REG_ALARM_SECONDS, second.map_or(ALARM_ANY, encode));
+    });
+
+    set_handler(&ALARM_HANDLER, B_ALARM_INTERRUPT, Some(handler));
+}
+
+/// Stop the alarm.
+pub fn clear_alarm() {
+    set_handler(&ALARM_HANDLER, B_ALARM_INTERRUPT, None);
+}
+
+/// Remember where the century register is and take IRQ 8.
+///
+/// `century_register` is the CMOS index from the FADT (0 or `None` if there isn't one.)
+pub(crate) fn initialize(century_register: Option<u8>) {
+    CENTURY_REGISTER.store(century_register.unwrap_or(0), Ordering::Relaxed);
+
+    // Start with the periodic and alarm interrupts off.
+    with_cmos(|| unsafe {
+        let status_b = read_register(REG_STATUS_B);
+        write_register(REG_STATUS_B, status_b & !(B_PERIODIC_INTERRUPT | B_ALARM_INTERRUPT));
+        read_register(REG_STATUS_C);
+    });
+
+    if let Err(err) = irq::request_irq(InterruptIndex::CMOS, "RTC", on_interrupt, 0) {
+        log::warn!("(RTC) Can't take IRQ 8, no periodic or alarm interrupts: {:?}", err);
+    }
+
+    let status_b = with_cmos(|| unsafe { read_register(REG_STATUS_B) });
+
+    log::info!(
+        "(RTC) {} {}, century register: {:?}",
+        if status_b & B_BINARY != 0 { "Binary" } else { "BCD" },
+        if status_b & B_24_HOUR != 0 { "24h" } else { "12h" },
+        century_register
+    );
+}
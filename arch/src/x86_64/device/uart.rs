@@ -0,0 +1,824 @@
+//! 16550 UARTs on the legacy COM ports.
+//!
+//! The I/O bases of COM1-COM4 are looked up in the BIOS data area (falling
+//! back to the usual 0x3F8, 0x2F8, 0x3E8 and 0x2E8) and every port is checked
+//! with the scratch register and a loopback test before it's used.
+//!
+//! Until `enable_interrupts` runs (and whenever interrupts are disabled, e.g.
+//! while panicking) writes are polled out. Afterwards received bytes are read
+//! into a ring buffer by the interrupt handler and writes are queued in
+//! another one that the transmitter interrupt drains, COM1/COM3 share IRQ 4
+//! and COM2/COM4 IRQ 3.
+
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    ptr::read_volatile,
+    sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+use crate::x86_64::interrupts::{
+    index::InterruptIndex,
+    irq::{self, IrqContext, IrqReturn},
+};
+
+/// Registers, relative to the I/O base.
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const INTERRUPT_ID_FIFO: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+const SCRATCH: u16 = 7;
+
+/// The divisor latch, while `LCR_DLAB` is set.
+const DIVISOR_LOW: u16 = 0;
+const DIVISOR_HIGH: u16 = 1;
+
+/// Bits of the interrupt enable register.
+const IER_RX: u8 = 1 << 0;
+const IER_TX: u8 = 1 << 1;
+const IER_LINE_STATUS: u8 = 1 << 2;
+
+/// The interrupt identification register.
+const IIR_NONE_PENDING: u8 = 1 << 0;
+const IIR_ID_MASK: u8 = 0b1110;
+const IIR_MODEM_STATUS: u8 = 0b0000;
+const IIR_TX_EMPTY: u8 = 0b0010;
+const IIR_RX_DATA: u8 = 0b0100;
+const IIR_LINE_STATUS: u8 = 0b0110;
+const IIR_RX_TIMEOUT: u8 = 0b1100;
+const IIR_FIFO_ENABLED: u8 = 0b1100_0000;
+
+/// Bits of the FIFO control register.
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+
+/// Bits of the line control register.
+const LCR_TWO_STOP_BITS: u8 = 1 << 2;
+const LCR_PARITY_ENABLE: u8 = 1 << 3;
+const LCR_EVEN_PARITY: u8 = 1 << 4;
+const LCR_STICK_PARITY: u8 = 1 << 5;
+const LCR_DLAB: u8 = 1 << 7;
+
+/// Bits of the modem control register.
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+/// Bits of the line status register.
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_TX_EMPTY: u8 = 1 << 5;
+
+/// The UART clock over 16, i.e. the baud rate at divisor 1.
+const MAX_BAUD: u32 = 115_200;
+
+/// The (real mode) address of the I/O bases of COM1-COM4 in the BIOS data area.
+const BDA_COM_PORTS: usize = 0x400;
+
+const DEFAULT_BASES: [u16; 4] = [0x3F8, 0x2F8, 0x3E8, 0x2E8];
+
+const LOOPBACK_TEST_BYTE: u8 = 0xAE;
+
+/// Bytes written into the transmit FIFO at once, 1 without a FIFO.
+const FIFO_SIZE: usize = 16;
+
+const RING_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1 = 0,
+    Com2 = 1,
+    Com3 = 2,
+    Com4 = 3,
+}
+
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    /// The ISA IRQ of the port (shared by COM1/COM3 and COM2/COM4.)
+    pub fn irq(self) -> InterruptIndex {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => InterruptIndex::COM1,
+            ComPort::Com2 | ComPort::Com4 => InterruptIndex::COM2,
+        }
+    }
+
+    /// The port called `name`, i.e. `com1` to `com4` (in any case.)
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|port| {
+            let expected = [b'c', b'o', b'm', b'1' + *port as u8];
+            name.as_bytes().eq_ignore_ascii_case(&expected)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartError {
+    /// Nothing answered on the port.
+    NotPresent(ComPort),
+
+    /// Not 115200 divided by a whole number.
+    InvalidBaud(u32),
+
+    /// Only 5 to 8 data bits work.
+    InvalidDataBits(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+
+    /// Always 1.
+    Mark,
+
+    /// Always 0.
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+
+    /// 1.5 with 5 data bits.
+    Two,
+}
+
+/// How many received bytes raise an interrupt, `Disabled` turns the FIFOs off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FifoTrigger {
+    Disabled,
+    Bytes1,
+    Bytes4,
+    Bytes8,
+    Bytes14,
+}
+
+impl FifoTrigger {
+    fn control(self) -> u8 {
+        let level = match self {
+            FifoTrigger::Disabled => return 0,
+            FifoTrigger::Bytes1 => 0b00,
+            FifoTrigger::Bytes4 => 0b01,
+            FifoTrigger::Bytes8 => 0b10,
+            FifoTrigger::Bytes14 => 0b11,
+        };
+
+        level << 6 | FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX
+    }
+}
+
+/// Line settings, the default is 115200 baud 8N1 with the FIFOs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UartConfig {
+    pub baud: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub fifo: FifoTrigger,
+}
+
+impl Default for UartConfig {
+    fn default() -> Self {
+        Self {
+            baud: MAX_BAUD,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            fifo: FifoTrigger::Bytes14,
+        }
+    }
+}
+
+impl UartConfig {
+    fn divisor(&self) -> Result<u16, UartError> {
+        if self.baud == 0 || MAX_BAUD % self.baud != 0 || MAX_BAUD / self.baud > u16::MAX as u32 {
+            return Err(UartError::InvalidBaud(self.baud));
+        }
+
+        Ok((MAX_BAUD / self.baud) as u16)
+    }
+
+    fn line_control(&self) -> Result<u8, UartError> {
+        let mut control = match self.data_bits {
+            5..=8 => self.data_bits - 5,
+            bits => return Err(UartError::InvalidDataBits(bits)),
+        };
+
+        if self.stop_bits == StopBits::Two {
+            control |= LCR_TWO_STOP_BITS;
+        }
+
+        control |= match self.parity {
+            Parity::None => 0,
+            Parity::Odd => LCR_PARITY_ENABLE,
+            Parity::Even => LCR_PARITY_ENABLE | LCR_EVEN_PARITY,
+            Parity::Mark => LCR_PARITY_ENABLE | LCR_STICK_PARITY,
+            Parity::Space => LCR_PARITY_ENABLE | LCR_EVEN_PARITY | LCR_STICK_PARITY,
+        };
+
+        Ok(control)
+    }
+}
+
+// -- ByteRing
+
+struct ByteRing {
+    buffer: [u8; RING_SIZE],
+    head: usize,
+    length: usize,
+}
+
+impl ByteRing {
+    const EMPTY: Self = Self {
+        buffer: [0; RING_SIZE],
+        head: 0,
+        length: 0,
+    };
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    #[inline]
+    fn free(&self) -> usize {
+        RING_SIZE - self.length
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.length == RING_SIZE {
+            return false;
+        }
+
+        self.buffer[(self.head + self.length) % RING_SIZE] = byte;
+        self.length += 1;
+
+        true
+    }
+
+    #[inline]
+    fn front(&self) -> Option<u8> {
+        if self.length == 0 {
+            None
+        } else {
+            Some(self.buffer[self.head])
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.length == 0 {
+            return None;
+        }
+
+        let byte = self.buffer[self.head];
+
+        self.head = (self.head + 1) % RING_SIZE;
+        self.length -= 1;
+
+        Some(byte)
+    }
+}
+
+// -- Uart
+
+pub struct Uart {
+    port: ComPort,
+
+    /// The I/O base, 0 when there's no UART on the port.
+    base: AtomicU16,
+
+    /// Has the port a (working) FIFO?
+    fifo: AtomicBool,
+
+    /// Are received bytes and the transmitter serviced by the interrupt handler?
+    interrupt_driven: AtomicBool,
+
+    rx: Mutex<ByteRing>,
+    tx: Mutex<ByteRing>,
+
+    /// The tasks waiting in `read` and `write`.
+    rx_waker: Mutex<Option<Waker>>,
+    tx_waker: Mutex<Option<Waker>>,
+
+    /// Received bytes lost because the ring buffer (or the UART) overflowed.
+    overruns: AtomicU64,
+
+    /// Called by the interrupt handler after receiving, set while the GDB stub owns the port.
+    break_in: Mutex<Option<fn(&Uart)>>,
+}
+
+static PORTS: [Uart; 4] = [
+    Uart::new(ComPort::Com1),
+    Uart::new(ComPort::Com2),
+    Uart::new(ComPort::Com3),
+    Uart::new(ComPort::Com4),
+];
+
+/// The UART on `port`, if there is one.
+pub fn port(port: ComPort) -> Option<&'static Uart> {
+    let uart = &PORTS[port as usize];
+
+    if uart.is_present() {
+        Some(uart)
+    } else {
+        None
+    }
+}
+
+impl Uart {
+    const fn new(port: ComPort) -> Self {
+        Self {
+            port,
+            base: AtomicU16::new(0),
+            fifo: AtomicBool::new(false),
+            interrupt_driven: AtomicBool::new(false),
+            rx: Mutex::new(ByteRing::EMPTY),
+            tx: Mutex::new(ByteRing::EMPTY),
+            rx_waker: Mutex::new(None),
+            tx_waker: Mutex::new(None),
+            overruns: AtomicU64::new(0),
+            break_in: Mutex::new(None),
+        }
+    }
+
+    #[inline]
+    fn is_present(&self) -> bool {
+        self.base.load(Ordering::Relaxed) != 0
+    }
+
+    #[inline]
+    fn read_register(&self, register: u16) -> u8 {
+        unsafe { cpuio::inb(self.base.load(Ordering::Relaxed) + register) }
+    }
+
+    #[inline]
+    fn write_register(&self, register: u16, value: u8) {
+        unsafe { cpuio::outb(value, self.base.load(Ordering::Relaxed) + register) }
+    }
+
+    pub fn com_port(&self) -> ComPort {
+        self.port
+    }
+
+    /// The I/O base of the UART.
+    pub fn base(&self) -> u16 {
+        self.base.load(Ordering::Relaxed)
+    }
+
+    /// Received bytes that were lost because nobody read them in time.
+    pub fn overruns(&self) -> u64 {
+        self.overruns.load(Ordering::Relaxed)
+    }
+
+    /// Is there a UART answering at `base`? Leaves it in loopback mode.
+    fn probe(base: u16) -> bool {
+        unsafe {
+            cpuio::outb(0x5A, base + SCRATCH);
+
+            if cpuio::inb(base + SCRATCH) != 0x5A {
+                return false;
+            }
+
+            cpuio::outb(0, base + INTERRUPT_ENABLE);
+            cpuio::outb(MCR_LOOPBACK | MCR_OUT1 | MCR_OUT2 | MCR_RTS, base + MODEM_CONTROL);
+            cpuio::outb(LOOPBACK_TEST_BYTE, base + DATA);
+
+            cpuio::inb(base + DATA) == LOOPBACK_TEST_BYTE
+        }
+    }
+
+    /// Change the line settings (waits for queued bytes to go out first.)
+    pub fn configure(&self, config: UartConfig) -> Result<(), UartError> {
+        if !self.is_present() {
+            return Err(UartError::NotPresent(self.port));
+        }
+
+        let divisor = config.divisor()?;
+        let line_control = config.line_control()?;
+
+        self.flush();
+
+        without_interrupts(|| {
+            let enabled = self.read_register(INTERRUPT_ENABLE);
+
+            self.write_register(INTERRUPT_ENABLE, 0);
+            self.write_register(LINE_CONTROL, LCR_DLAB);
+            self.write_register(DIVISOR_LOW, divisor as u8);
+            self.write_register(DIVISOR_HIGH, (divisor >> 8) as u8);
+            self.write_register(LINE_CONTROL, line_control);
+            self.write_register(INTERRUPT_ID_FIFO, config.fifo.control());
+
+            // A 16450 (or 8250) has no FIFO, whatever we asked for.
+            let fifo = self.read_register(INTERRUPT_ID_FIFO) & IIR_FIFO_ENABLED == IIR_FIFO_ENABLED;
+            self.fifo.store(fifo, Ordering::Relaxed);
+
+            self.write_register(MODEM_CONTROL, MCR_DTR | MCR_RTS | MCR_OUT2);
+            self.write_register(INTERRUPT_ENABLE, enabled);
+        });
+
+        Ok(())
+    }
+
+    /// Move whatever the UART received into the ring buffer.
+    fn receive(&self) {
+        let mut rx = self.rx.lock();
+        let mut received = false;
+
+        loop {
+            let status = self.read_register(LINE_STATUS);
+
+            if status & LSR_OVERRUN != 0 {
+                self.overruns.fetch_add(1, Ordering::Relaxed);
+            }
+
+            if status & LSR_DATA_READY == 0 {
+                break;
+            }
+
+            if !rx.push(self.read_register(DATA)) {
+                self.overruns.fetch_add(1, Ordering::Relaxed);
+            }
+
+            received = true;
+        }
+
+        drop(rx);
+
+        if received {
+            if let Some(waker) = self.rx_waker.lock().take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Refill the (empty) transmitter from `tx`, the transmitter interrupt is
+    /// only left enabled while there's more to send.
+    fn transmit(&self, tx: &mut ByteRing) {
+        if self.read_register(LINE_STATUS) & LSR_TX_EMPTY != 0 {
+            let burst = if self.fifo.load(Ordering::Relaxed) { FIFO_SIZE } else { 1 };
+
+            for _ in 0..burst {
+                match tx.pop() {
+                    Some(byte) => self.write_register(DATA, byte),
+                    None => break,
+                }
+            }
+        }
+
+        let enabled = if tx.is_empty() { IER_RX | IER_LINE_STATUS } else { IER_RX | IER_LINE_STATUS | IER_TX };
+        self.write_register(INTERRUPT_ENABLE, enabled);
+    }
+
+    /// Send everything in `tx`, spinning on the transmitter.
+    fn transmit_polled(&self, tx: &mut ByteRing) {
+        while let Some(byte) = tx.pop() {
+            while self.read_register(LINE_STATUS) & LSR_TX_EMPTY == 0 {
+                core::sync::atomic::spin_loop_hint();
+            }
+
+            self.write_register(DATA, byte);
+        }
+    }
+
+    /// Should writes be polled out (nobody is going to take the transmitter interrupt)?
+    #[inline]
+    fn polled(&self) -> bool {
+        !self.interrupt_driven.load(Ordering::Acquire) || !interrupts::are_enabled()
+    }
+
+    /// Queue as much of `data` as fits and start sending, returns how much was queued.
+    ///
+    /// Interrupts have to be disabled.
+    fn queue(&self, data: &[u8], polled: bool) -> usize {
+        let mut tx = self.tx.lock();
+        let queued = data.len().min(tx.free());
+
+        for &byte in &data[..queued] {
+            tx.push(byte);
+        }
+
+        if polled {
+            self.transmit_polled(&mut tx);
+        } else {
+            self.transmit(&mut tx);
+        }
+
+        queued
+    }
+
+    /// Queue as much of `data` as fits, returns how much that was.
+    pub fn try_write(&self, data: &[u8]) -> usize {
+        if !self.is_present() {
+            return data.len();
+        }
+
+        let polled = self.polled();
+
+        without_interrupts(|| self.queue(data, polled))
+    }
+
+    /// Queue all of `data`, spinning while the ring buffer is full.
+    pub fn write_blocking(&self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let queued = self.try_write(data);
+
+            if queued == 0 {
+                core::sync::atomic::spin_loop_hint();
+            }
+
+            data = &data[queued..];
+        }
+    }
+
+    /// Wait for all of `data` to be queued.
+    pub fn write<'a>(&'a self, data: &'a [u8]) -> UartWrite<'a> {
+        UartWrite { uart: self, data }
+    }
+
+    /// Spin until every queued byte went out.
+    pub fn flush(&self) {
+        while without_interrupts(|| !self.tx.lock().is_empty()) {
+            // Doesn't queue anything, just pushes the queue along when polled.
+            self.try_write(&[]);
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+
+    /// Take received bytes into `buffer`, returns how many.
+    pub fn try_read(&self, buffer: &mut [u8]) -> usize {
+        if !self.is_present() {
+            return 0;
+        }
+
+        without_interrupts(|| {
+            if !self.interrupt_driven.load(Ordering::Acquire) {
+                self.receive();
+            }
+
+            let mut rx = self.rx.lock();
+            let mut count = 0;
+
+            for slot in buffer.iter_mut() {
+                match rx.pop() {
+                    Some(byte) => *slot = byte,
+                    None => break,
+                }
+
+                count += 1;
+            }
+
+            count
+        })
+    }
+
+    /// Wait for (at least one) received byte, resolves to how many were read into `buffer`.
+    pub fn read<'a>(&'a self, buffer: &'a mut [u8]) -> UartRead<'a> {
+        UartRead { uart: self, buffer }
+    }
+
+    /// Drop received bytes up to the first one `f` accepts, is there one?
+    pub(crate) fn discard_until(&self, f: impl Fn(u8) -> bool) -> bool {
+        without_interrupts(|| {
+            let mut rx = self.rx.lock();
+
+            while let Some(byte) = rx.front() {
+                if f(byte) {
+                    return true;
+                }
+
+                rx.pop();
+            }
+
+            false
+        })
+    }
+
+    /// Take a received byte if there is one, for the GDB stub.
+    ///
+    /// Works with interrupts disabled and doesn't wait for the ring buffer
+    /// lock, the stub can stop the kernel while it's held.
+    pub(crate) fn poll_byte(&self) -> Option<u8> {
+        if let Some(byte) = self.rx.try_lock().and_then(|mut rx| rx.pop()) {
+            return Some(byte);
+        }
+
+        if self.read_register(LINE_STATUS) & LSR_DATA_READY != 0 {
+            Some(self.read_register(DATA))
+        } else {
+            None
+        }
+    }
+
+    /// Send `data` spinning on the transmitter, past the ring buffer (see `poll_byte`.)
+    pub(crate) fn write_polled(&self, data: &[u8]) {
+        for &byte in data {
+            while self.read_register(LINE_STATUS) & LSR_TX_EMPTY == 0 {
+                core::sync::atomic::spin_loop_hint();
+            }
+
+            self.write_register(DATA, byte);
+        }
+    }
+
+    /// Hand received bytes to `break_in` (from the interrupt handler), or stop doing so.
+    pub(crate) fn set_break_in(&self, break_in: Option<fn(&Uart)>) {
+        without_interrupts(|| *self.break_in.lock() = break_in);
+    }
+
+    /// Is the GDB stub using the port?
+    pub(crate) fn has_break_in(&self) -> bool {
+        without_interrupts(|| self.break_in.lock().is_some())
+    }
+
+    /// A `fmt::Write` that writes (blocking) to the port.
+    pub fn writer(&self) -> UartWriter<'_> {
+        UartWriter(self)
+    }
+
+    /// Service the interrupt of this port, `false` if it didn't raise it.
+    fn on_interrupt(&self) -> bool {
+        let mut handled = false;
+
+        loop {
+            let id = self.read_register(INTERRUPT_ID_FIFO);
+
+            if id & IIR_NONE_PENDING != 0 {
+                break;
+            }
+
+            handled = true;
+
+            match id & IIR_ID_MASK {
+                IIR_RX_DATA | IIR_RX_TIMEOUT | IIR_LINE_STATUS => {
+                    self.receive();
+
+                    // Not under the lock, the stub stops the kernel in there.
+                    let break_in = *self.break_in.lock();
+
+                    if let Some(break_in) = break_in {
+                        break_in(self);
+                    }
+                }
+
+                IIR_TX_EMPTY => {
+                    self.transmit(&mut self.tx.lock());
+
+                    if let Some(waker) = self.tx_waker.lock().take() {
+                        waker.wake();
+                    }
+                }
+
+                IIR_MODEM_STATUS => {
+                    self.read_register(MODEM_STATUS);
+                }
+
+                _ => break,
+            }
+        }
+
+        handled
+    }
+}
+
+pub struct UartWriter<'a>(&'a Uart);
+
+impl<'a> fmt::Write for UartWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_blocking(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Resolves once all of its data was queued, see `Uart::write`.
+pub struct UartWrite<'a> {
+    uart: &'a Uart,
+    data: &'a [u8],
+}
+
+impl<'a> Future for UartWrite<'a> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let uart = self.uart;
+
+        if !uart.is_present() {
+            return Poll::Ready(());
+        }
+
+        let polled = uart.polled();
+
+        without_interrupts(|| {
+            let queued = uart.queue(self.data, polled);
+            self.data = &self.data[queued..];
+
+            if self.data.is_empty() {
+                return Poll::Ready(());
+            }
+
+            // Interrupts are off, so the transmitter can't drain before the waker is registered.
+            *uart.tx_waker.lock() = Some(cx.waker().clone());
+
+            if polled {
+                cx.waker().wake_by_ref();
+            }
+
+            Poll::Pending
+        })
+    }
+}
+
+/// Resolves to how many bytes were read, see `Uart::read`.
+pub struct UartRead<'a> {
+    uart: &'a Uart,
+    buffer: &'a mut [u8],
+}
+
+impl<'a> Future for UartRead<'a> {
+    type Output = usize;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let uart = this.uart;
+
+        without_interrupts(|| {
+            let count = uart.try_read(this.buffer);
+
+            if count > 0 || this.buffer.is_empty() {
+                return Poll::Ready(count);
+            }
+
+            *uart.rx_waker.lock() = Some(cx.waker().clone());
+
+            // Without the interrupt only polling brings new bytes in.
+            if !uart.interrupt_driven.load(Ordering::Acquire) {
+                cx.waker().wake_by_ref();
+            }
+
+            Poll::Pending
+        })
+    }
+}
+
+fn on_interrupt(context: &IrqContext) -> IrqReturn {
+    if PORTS[context.data].on_interrupt() {
+        IrqReturn::Handled
+    } else {
+        IrqReturn::NotMine
+    }
+}
+
+/// Find the UARTs on COM1-COM4 and set them up with the default config (polled.)
+pub(crate) fn probe() {
+    for port in ComPort::ALL.iter().copied() {
+        let bda = unsafe { read_volatile((BDA_COM_PORTS + 2 * port as usize) as *const u16) };
+        let base = if bda != 0 { bda } else { DEFAULT_BASES[port as usize] };
+
+        if !Uart::probe(base) {
+            continue;
+        }
+
+        let uart = &PORTS[port as usize];
+
+        uart.base.store(base, Ordering::Relaxed);
+
+        // The default config is always valid.
+        let _ = uart.configure(UartConfig::default());
+    }
+}
+
+/// Hand the ports `probe` found over to their interrupt handler.
+pub(crate) fn enable_interrupts() {
+    for uart in PORTS.iter().filter(|uart| uart.is_present()) {
+        let port = uart.port;
+
+        if let Err(err) = irq::request_irq(port.irq(), "16550", on_interrupt, port as usize) {
+            log::warn!("(UART) Can't take {:?} of {:?}: {:?}", port.irq(), port, err);
+            continue;
+        }
+
+        without_interrupts(|| {
+            uart.interrupt_driven.store(true, Ordering::Release);
+            uart.write_register(INTERRUPT_ENABLE, IER_RX | IER_LINE_STATUS);
+        });
+
+        log::info!(
+            "(UART) {:?} at {:#x}, FIFO: {:?}",
+            port,
+            uart.base(),
+            uart.fifo.load(Ordering::Relaxed)
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! The 80x25 VGA text-mode screen of the console.
+//!
+//! Cells go straight to the text buffer at 0xB8000 (left in text mode by
+//! the bootloader) and the cursor is the hardware cursor of the CRTC.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::console::{Cell, Screen, CONSOLE};
+
+pub const WIDTH: usize = 80;
+pub const HEIGHT: usize = 25;
+
+const TEXT_BUFFER: usize = 0xB8000;
+
+const CRTC_ADDRESS: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+const CRTC_CURSOR_START: u8 = 0x0A;
+const CRTC_CURSOR_END: u8 = 0x0B;
+const CRTC_CURSOR_HIGH: u8 = 0x0E;
+const CRTC_CURSOR_LOW: u8 = 0x0F;
+
+/// Code page 437 "■", stands in for everything outside of ASCII.
+const FALLBACK_GLYPH: u8 = 0xFE;
+
+// -- VgaText
+
+pub struct VgaText;
+
+impl VgaText {
+    #[inline]
+    fn cell(row: usize, column: usize) -> *mut u16 {
+        (TEXT_BUFFER as *mut u16).wrapping_add(row * WIDTH + column)
+    }
+
+    /// Show the hardware cursor as an underline.
+    pub fn enable_cursor(&self) {
+        unsafe {
+            cpuio::outb(CRTC_CURSOR_START, CRTC_ADDRESS);
+            cpuio::outb((cpuio::inb(CRTC_DATA) & 0xC0) | 13, CRTC_DATA);
+            cpuio::outb(CRTC_CURSOR_END, CRTC_ADDRESS);
+            cpuio::outb((cpuio::inb(CRTC_DATA) & 0xE0) | 14, CRTC_DATA);
+        }
+    }
+
+    pub fn disable_cursor(&self) {
+        unsafe {
+            cpuio::outb(CRTC_CURSOR_START, CRTC_ADDRESS);
+            cpuio::outb(0x20, CRTC_DATA);
+        }
+    }
+}
+
+impl Screen for VgaText {
+    fn size(&self) -> (usize, usize) {
+        (HEIGHT, WIDTH)
+    }
+
+    fn draw(&mut self, row: usize, column: usize, cell: Cell) {
+        // The VGA font is code page 437, only ASCII maps one to one.
+        let byte = if cell.character.is_ascii() { cell.character as u8 } else { FALLBACK_GLYPH };
+        let attribute = (cell.background as u16) << 4 | cell.foreground as u16;
+
+        unsafe { write_volatile(Self::cell(row, column), attribute << 8 | byte as u16) };
+    }
+
+    fn scroll(&mut self, blank: Cell) {
+        for index in WIDTH..(WIDTH * HEIGHT) {
+            unsafe { write_volatile(Self::cell(0, index - WIDTH), read_volatile(Self::cell(0, index))) };
+        }
+
+        for column in 0..WIDTH {
+            self.draw(HEIGHT - 1, column, blank);
+        }
+    }
+
+    fn move_cursor(&mut self, row: usize, column: usize) {
+        let position = (row * WIDTH + column) as u16;
+
+        unsafe {
+            cpuio::outb(CRTC_CURSOR_LOW, CRTC_ADDRESS);
+            cpuio::outb(position as u8, CRTC_DATA);
+            cpuio::outb(CRTC_CURSOR_HIGH, CRTC_ADDRESS);
+            cpuio::outb((position >> 8) as u8, CRTC_DATA);
+        }
+    }
+}
+
+/// Clear the screen and show the cursor.
+pub(crate) fn initialize() {
+    without_interrupts(|| {
+        let mut console = CONSOLE.lock();
+
+        console.reset_colors();
+        console.clear();
+
+        VgaText.enable_cursor();
+    });
+}
@@ -0,0 +1,1002 @@
+//! A GDB remote serial protocol stub on one of the COM ports.
+//!
+//! GDB (`target remote` on the serial line) can read and write registers
+//! and memory, place software breakpoints (`int3`, reported through #BP),
+//! single-step (the trap flag, reported through #DB) and use the four debug
+//! registers for hardware breakpoints and watchpoints.
+//!
+//! The stub is all-stop: the processor that trapped becomes the master and
+//! talks to GDB, the others are stopped with an NMI and park until it lets
+//! them go.
+//!
+//! Threads are the executor's tasks (see `task.rs`) and the processors that
+//! aren't running one (thread `n + 1` is CPU `n`.) Tasks are futures, they
+//! only have registers while a processor is polling them: the others are
+//! listed but their registers are unavailable.
+//!
+//! The stub owns its port: nothing is logged to it and, once the port's
+//! interrupt is enabled, a `^C` (or GDB connecting) breaks into the kernel.
+
+use core::{
+    fmt,
+    ptr::{null_mut, read_volatile, write_volatile},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    registers::control::{Cr0, Cr0Flags},
+};
+
+use super::{
+    device::{
+        apic::{local_apic, IPI_NMI_OTHERS},
+        uart::{self, ComPort, Uart, UartError},
+    },
+    interrupts::{diagnostics::is_range_mapped, trap::TrapFrame},
+    percpu::{self, current_cpu},
+    smp::{self, MAX_CPUS},
+    task::{self, MAX_TASKS},
+};
+
+mod packet;
+mod registers;
+mod watchpoint;
+
+use packet::{Response, PACKET_SIZE};
+use watchpoint::{DebugRegisters, Kind, Watchpoint, DR6_SINGLE_STEP};
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const INT3: u8 = 0xCC;
+
+/// The trap (single-step) and resume flags in RFLAGS.
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_RF: u64 = 1 << 16;
+
+/// How many software breakpoints can be placed at once.
+const MAX_BREAKPOINTS: usize = 64;
+
+/// `PORT` while the stub is disabled.
+const NO_PORT: usize = usize::MAX;
+
+/// `MASTER` while the kernel runs.
+const NO_MASTER: usize = usize::MAX;
+
+/// Thread IDs of tasks come after those of the processors.
+const TASK_THREADS: u64 = MAX_CPUS as u64 + 1;
+
+/// How long (in spins) the master waits for the other processors to stop.
+const STOP_TIMEOUT: usize = 10_000_000;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// A `ComPort` as `usize`, or `NO_PORT`.
+static PORT: AtomicUsize = AtomicUsize::new(NO_PORT);
+
+/// The processor talking to GDB.
+static MASTER: AtomicUsize = AtomicUsize::new(NO_MASTER);
+
+/// How many processors wait in `park`.
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+const NO_FRAME: AtomicPtr<TrapFrame> = AtomicPtr::new(null_mut());
+const NOT_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// The saved registers of every stopped processor.
+static FRAMES: [AtomicPtr<TrapFrame>; MAX_CPUS] = [NO_FRAME; MAX_CPUS];
+
+/// Set for every processor the master sent an NMI to stop it.
+static STOP_PENDING: [AtomicBool; MAX_CPUS] = [NOT_PENDING; MAX_CPUS];
+
+/// Set by `break_in`, so that the stop is reported as an interrupt.
+static BREAK_IN: AtomicBool = AtomicBool::new(false);
+
+/// Has GDB talked to us? Stops aren't reported until it has.
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+/// Loaded by every processor as it's resumed.
+static DEBUG_REGISTERS: Mutex<DebugRegisters> = Mutex::new(DebugRegisters::EMPTY);
+
+static STUB: Mutex<Stub> = Mutex::new(Stub::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum GdbError<'a> {
+    UnknownPort(&'a str),
+    UnknownOption(&'a str),
+    Uart(UartError),
+}
+
+impl fmt::Display for GdbError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GdbError::UnknownPort(port) => write!(f, "unknown port {:?} (expected com1 to com4)", port),
+            GdbError::UnknownOption(option) => write!(f, "unknown option {:?}", option),
+            GdbError::Uart(err) => write!(f, "{:?}", err),
+        }
+    }
+}
+
+/// The options of the `gdb=` argument of the kernel command line.
+pub(crate) struct Options {
+    /// Stop for GDB at the end of `boot`.
+    pub(crate) wait: bool,
+}
+
+/// The registers of `cpu` if it's stopped.
+///
+/// # Safety
+///
+/// Only the master may look at them, nobody else can while they're stopped.
+#[inline]
+unsafe fn frame_of<'a>(cpu: usize) -> Option<&'a mut TrapFrame> {
+    FRAMES.get(cpu)?.load(Ordering::Acquire).as_mut()
+}
+
+/// Is all of `address..address + length` mapped? (Every page is checked.)
+fn is_mapped(address: u64, length: u64) -> bool {
+    let end = match address.checked_add(length) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    let mut page = address & !(PAGE_SIZE - 1);
+
+    while page < end {
+        if !is_range_mapped(page.max(address), 1) {
+            return false;
+        }
+
+        page = match page.checked_add(PAGE_SIZE) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    true
+}
+
+/// Write `bytes` to `address`, read-only pages (i.e. kernel code) included.
+fn poke(address: u64, bytes: impl Iterator<Item = u8>) {
+    let cr0 = Cr0::read();
+
+    unsafe {
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+
+        for (offset, byte) in bytes.enumerate() {
+            write_volatile((address + offset as u64) as *mut u8, byte);
+        }
+
+        Cr0::write(cr0);
+    }
+}
+
+// -- Thread
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Thread {
+    /// A processor that isn't running a task.
+    Cpu(usize),
+
+    /// The task in a slot of `task.rs`.
+    Task(usize),
+}
+
+impl Thread {
+    /// The thread running on `cpu`.
+    fn of(cpu: usize) -> Self {
+        match task::slot_of(percpu::task_of(cpu)) {
+            Some(slot) => Thread::Task(slot),
+            None => Thread::Cpu(cpu),
+        }
+    }
+
+    /// `0` and `-1` (any and all) aren't a thread of their own.
+    fn from_id(id: u64) -> Option<Self> {
+        match id {
+            0 => None,
+            id if id < TASK_THREADS => Some(Thread::Cpu(id as usize - 1)),
+            id => Some(Thread::Task((id - TASK_THREADS) as usize)),
+        }
+    }
+
+    #[inline]
+    fn id(self) -> u64 {
+        match self {
+            Thread::Cpu(cpu) => cpu as u64 + 1,
+            Thread::Task(slot) => TASK_THREADS + slot as u64,
+        }
+    }
+
+    /// The stopped processor running the thread, whose registers are the thread's.
+    fn cpu(self) -> Option<usize> {
+        let stopped = |cpu: &usize| unsafe { frame_of(*cpu) }.is_some();
+
+        match self {
+            Thread::Cpu(cpu) => Some(cpu).filter(stopped).filter(|&cpu| Thread::of(cpu) == self),
+            Thread::Task(_) => (0..MAX_CPUS).filter(stopped).find(|&cpu| Thread::of(cpu) == self),
+        }
+    }
+
+    fn is_alive(self) -> bool {
+        match self {
+            Thread::Cpu(_) => self.cpu().is_some(),
+            Thread::Task(slot) => !task::task_in(slot).is_null(),
+        }
+    }
+}
+
+// -- Session
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+
+    /// The byte the `int3` replaced.
+    original: u8,
+}
+
+#[derive(Clone, Copy)]
+enum StopReason {
+    /// A break-in or an `int3` that isn't ours.
+    Signal(u8),
+    Breakpoint,
+    HardwareBreakpoint,
+    Watchpoint(Watchpoint),
+    Step,
+}
+
+enum Action {
+    Reply,
+    Resume { step: bool },
+    Detach,
+
+    /// Like `Detach`, but GDB doesn't wait for a reply.
+    Kill,
+}
+
+struct Session {
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+
+    /// The processor that stopped the kernel, and why.
+    master: usize,
+    reason: StopReason,
+
+    /// The processors `Hg` and `Hc` selected (register accesses and stepping),
+    /// none for a task that isn't running.
+    registers_of: Option<usize>,
+    step_of: usize,
+}
+
+impl Session {
+    const fn new() -> Self {
+        Self {
+            breakpoints: [None; MAX_BREAKPOINTS],
+            master: 0,
+            reason: StopReason::Signal(SIGTRAP),
+            registers_of: None,
+            step_of: 0,
+        }
+    }
+
+    #[inline]
+    fn has_breakpoint(&self, address: u64) -> bool {
+        self.breakpoints.iter().flatten().any(|breakpoint| breakpoint.address == address)
+    }
+
+    fn insert_breakpoint(&mut self, address: u64) -> bool {
+        if self.has_breakpoint(address) {
+            return true;
+        }
+
+        if !is_mapped(address, 1) {
+            return false;
+        }
+
+        let slot = match self.breakpoints.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => slot,
+            None => return false,
+        };
+
+        let original = unsafe { read_volatile(address as *const u8) };
+
+        poke(address, core::iter::once(INT3));
+        *slot = Some(Breakpoint { address, original });
+
+        true
+    }
+
+    fn remove_breakpoint(&mut self, address: u64) -> bool {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(breakpoint) = slot.filter(|breakpoint| breakpoint.address == address) {
+                poke(breakpoint.address, core::iter::once(breakpoint.original));
+                *slot = None;
+
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Take out every breakpoint and watchpoint, GDB is gone.
+    fn detach(&mut self) {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(breakpoint) = slot.take() {
+                poke(breakpoint.address, core::iter::once(breakpoint.original));
+            }
+        }
+
+        DEBUG_REGISTERS.lock().clear();
+        ATTACHED.store(false, Ordering::Release);
+    }
+
+    /// The thread with ID `thread` if it's alive. `0` and `-1` (any and all) are the master's.
+    fn thread(&self, thread: Option<u64>) -> Option<Thread> {
+        match thread.and_then(Thread::from_id) {
+            None => Some(Thread::of(self.master)),
+            Some(thread) => Some(thread).filter(|thread| thread.is_alive()),
+        }
+    }
+
+    fn stop_reply(&self, reply: &mut Response) {
+        use fmt::Write;
+
+        let signal = match self.reason {
+            StopReason::Signal(signal) => signal,
+            _ => SIGTRAP,
+        };
+
+        let _ = write!(reply, "T{:02x}thread:{:x};", signal, Thread::of(self.master).id());
+
+        match self.reason {
+            StopReason::Breakpoint => reply.push_str("swbreak:;"),
+            StopReason::HardwareBreakpoint => reply.push_str("hwbreak:;"),
+
+            StopReason::Watchpoint(watchpoint) => {
+                let name = if watchpoint.kind == Kind::Write { "watch" } else { "awatch" };
+                let _ = write!(reply, "{}:{:x};", name, watchpoint.address);
+            }
+
+            StopReason::Signal(_) | StopReason::Step => {}
+        }
+    }
+
+    fn handle(&mut self, packet: &[u8], reply: &mut Response) -> Action {
+        reply.clear();
+
+        let (&command, arguments) = match packet.split_first() {
+            Some(split) => split,
+            None => return Action::Reply,
+        };
+
+        match command {
+            b'?' => self.stop_reply(reply),
+
+            b'g' => self.read_registers(reply),
+            b'G' => self.write_registers(arguments, reply),
+            b'p' => self.read_register(arguments, reply),
+            b'P' => self.write_register(arguments, reply),
+
+            b'm' => self.read_memory(arguments, reply),
+            b'M' => self.write_memory(arguments, reply),
+
+            b'c' | b's' | b'C' | b'S' => {
+                // The signal of `C` and `S` means nothing to the kernel.
+                let address = match command {
+                    b'C' | b'S' => packet::split(arguments, b';').1,
+                    _ => arguments,
+                };
+
+                if !address.is_empty() {
+                    match (packet::parse_hex(address), unsafe { frame_of(self.step_of) }) {
+                        (Some(address), Some(frame)) => frame.rip = address,
+                        _ => {
+                            reply.error(1);
+                            return Action::Reply;
+                        }
+                    }
+                }
+
+                return Action::Resume {
+                    step: command == b's' || command == b'S',
+                };
+            }
+
+            b'H' => self.set_thread(arguments, reply),
+
+            b'T' => match packet::parse_thread(arguments).and_then(|thread| self.thread(thread)) {
+                Some(_) => reply.ok(),
+                None => reply.error(1),
+            },
+
+            b'q' => self.query(arguments, reply),
+
+            b'Z' | b'z' => self.breakpoint(command == b'Z', arguments, reply),
+
+            b'D' => return Action::Detach,
+            b'k' => return Action::Kill,
+
+            // Anything else isn't supported, which is an empty reply.
+            _ => {}
+        }
+
+        Action::Reply
+    }
+
+    /// The registers `Hg` selected, `None` for a task that isn't running.
+    #[inline]
+    fn frame(&self) -> Option<&'static mut TrapFrame> {
+        self.registers_of.and_then(|cpu| unsafe { frame_of(cpu) })
+    }
+
+    fn read_registers(&self, reply: &mut Response) {
+        let frame = self.frame();
+
+        for number in 0..registers::COUNT {
+            let value = frame.as_ref().and_then(|frame| registers::read(frame, number));
+            reply.register(value, registers::size(number));
+        }
+    }
+
+    fn write_registers(&mut self, mut hex: &[u8], reply: &mut Response) {
+        let frame = match self.frame() {
+            Some(frame) => frame,
+            None => return reply.error(1),
+        };
+
+        for number in 0..registers::COUNT {
+            let digits = registers::size(number) * 2;
+
+            if hex.len() < digits {
+                break;
+            }
+
+            // Registers GDB doesn't have (`xx`) or can't be written are left alone.
+            if let Some(value) = packet::decode_register(&hex[..digits], registers::size(number)) {
+                registers::write(frame, number, value);
+            }
+
+            hex = &hex[digits..];
+        }
+
+        reply.ok();
+    }
+
+    fn read_register(&self, arguments: &[u8], reply: &mut Response) {
+        let number = match packet::parse_hex(arguments) {
+            Some(number) => number as usize,
+            None => return reply.error(1),
+        };
+
+        // GDB falls back to `g` for the rest.
+        if number >= registers::COUNT {
+            return;
+        }
+
+        let value = self.frame().and_then(|frame| registers::read(frame, number));
+        reply.register(value, registers::size(number));
+    }
+
+    fn write_register(&mut self, arguments: &[u8], reply: &mut Response) {
+        let (number, value) = packet::split(arguments, b'=');
+
+        let number = match packet::parse_hex(number) {
+            Some(number) if (number as usize) < registers::COUNT => number as usize,
+            _ => return reply.error(1),
+        };
+
+        let written = match (packet::decode_register(value, registers::size(number)), self.frame()) {
+            (Some(value), Some(frame)) => registers::write(frame, number, value),
+            _ => false,
+        };
+
+        if written {
+            reply.ok()
+        } else {
+            reply.error(1)
+        }
+    }
+
+    fn read_memory(&self, arguments: &[u8], reply: &mut Response) {
+        let (address, length) = packet::split(arguments, b',');
+
+        let (address, length) = match (packet::parse_hex(address), packet::parse_hex(length)) {
+            (Some(address), Some(length)) => (address, length.min(PACKET_SIZE as u64 / 2)),
+            _ => return reply.error(1),
+        };
+
+        // EFAULT.
+        if !is_mapped(address, length) {
+            return reply.error(14);
+        }
+
+        for offset in 0..length {
+            let byte = unsafe { read_volatile((address + offset) as *const u8) };
+            reply.hex(&[byte]);
+        }
+    }
+
+    fn write_memory(&mut self, arguments: &[u8], reply: &mut Response) {
+        let (arguments, hex) = packet::split(arguments, b':');
+        let (address, length) = packet::split(arguments, b',');
+
+        let (address, length) = match (packet::parse_hex(address), packet::parse_hex(length)) {
+            (Some(address), Some(length)) if hex.len() as u64 == length * 2 => (address, length),
+            _ => return reply.error(1),
+        };
+
+        if !hex.iter().all(|&digit| packet::hex_digit(digit).is_some()) {
+            return reply.error(1);
+        }
+
+        if !is_mapped(address, length) {
+            return reply.error(14);
+        }
+
+        let bytes = hex.chunks_exact(2).map(|pair| {
+            let high = packet::hex_digit(pair[0]).unwrap_or(0);
+            let low = packet::hex_digit(pair[1]).unwrap_or(0);
+
+            (high << 4) | low
+        });
+
+        poke(address, bytes);
+
+        reply.ok();
+    }
+
+    fn set_thread(&mut self, arguments: &[u8], reply: &mut Response) {
+        let (&operation, thread) = match arguments.split_first() {
+            Some(split) => split,
+            None => return reply.error(1),
+        };
+
+        let thread = match packet::parse_thread(thread).and_then(|thread| self.thread(thread)) {
+            Some(thread) => thread,
+            None => return reply.error(1),
+        };
+
+        match (operation, thread.cpu()) {
+            (b'g', cpu) => self.registers_of = cpu,
+
+            // Only a running thread can be stepped.
+            (b'c', Some(cpu)) => self.step_of = cpu,
+            _ => return reply.error(1),
+        }
+
+        reply.ok();
+    }
+
+    fn query(&self, query: &[u8], reply: &mut Response) {
+        use fmt::Write;
+
+        const THREAD_EXTRA_INFO: &[u8] = b"ThreadExtraInfo,";
+
+        if query.starts_with(b"Supported") {
+            let _ = write!(reply, "PacketSize={:x};swbreak+;hwbreak+", PACKET_SIZE);
+        } else if query == b"Attached" {
+            reply.push_str("1");
+        } else if query == b"C" {
+            let _ = write!(reply, "QC{:x}", Thread::of(self.master).id());
+        } else if query == b"fThreadInfo" {
+            // They all fit in the first reply: what the stopped processors run, then the other tasks.
+            let running = (0..MAX_CPUS)
+                .filter(|&cpu| unsafe { frame_of(cpu) }.is_some())
+                .map(Thread::of);
+
+            let waiting = (0..MAX_TASKS)
+                .map(Thread::Task)
+                .filter(|thread| thread.is_alive() && thread.cpu().is_none());
+
+            for (index, thread) in running.chain(waiting).enumerate() {
+                reply.push(if index == 0 { b'm' } else { b',' });
+                let _ = write!(reply, "{:x}", thread.id());
+            }
+        } else if query == b"sThreadInfo" {
+            reply.push(b'l');
+        } else if query.starts_with(THREAD_EXTRA_INFO) {
+            let thread = packet::parse_thread(&query[THREAD_EXTRA_INFO.len()..]);
+
+            match thread.and_then(|thread| self.thread(thread)) {
+                Some(Thread::Cpu(cpu)) => reply.hex_fmt(format_args!("CPU {}, idle", cpu)),
+
+                Some(Thread::Task(slot)) => {
+                    let task = task::task_in(slot);
+
+                    match Thread::Task(slot).cpu() {
+                        Some(cpu) => reply.hex_fmt(format_args!("Task {:p}, running on CPU {}", task, cpu)),
+                        None => reply.hex_fmt(format_args!("Task {:p}, waiting", task)),
+                    }
+                }
+
+                None => reply.error(1),
+            }
+        } else if query.starts_with(b"Symbol") {
+            reply.ok();
+        }
+    }
+
+    fn breakpoint(&mut self, insert: bool, arguments: &[u8], reply: &mut Response) {
+        // Conditions (after a `;`) are evaluated by GDB.
+        let (arguments, _) = packet::split(arguments, b';');
+        let (kind, arguments) = packet::split(arguments, b',');
+        let (address, length) = packet::split(arguments, b',');
+
+        let (address, length) = match (packet::parse_hex(address), packet::parse_hex(length)) {
+            (Some(address), Some(length)) => (address, length),
+            _ => return reply.error(1),
+        };
+
+        let kind = match kind {
+            b"0" => {
+                let done = if insert {
+                    self.insert_breakpoint(address)
+                } else {
+                    self.remove_breakpoint(address)
+                };
+
+                return if done { reply.ok() } else { reply.error(1) };
+            }
+
+            b"1" => Kind::Execute,
+            b"2" => Kind::Write,
+            b"4" => Kind::Access,
+
+            // x86 can't watch for reads only (`3`), that's unsupported.
+            _ => return,
+        };
+
+        let watchpoint = Watchpoint { address, length, kind };
+        let mut debug_registers = DEBUG_REGISTERS.lock();
+
+        let done = if insert {
+            debug_registers.insert(watchpoint)
+        } else {
+            debug_registers.remove(watchpoint)
+        };
+
+        if done {
+            reply.ok()
+        } else {
+            reply.error(1)
+        }
+    }
+}
+
+// -- Stub
+
+struct Stub {
+    input: [u8; PACKET_SIZE],
+    response: Response,
+    session: Session,
+}
+
+impl Stub {
+    const fn new() -> Self {
+        Self {
+            input: [0; PACKET_SIZE],
+            response: Response::EMPTY,
+            session: Session::new(),
+        }
+    }
+
+    /// Talk to GDB (on `uart`) until it resumes the kernel, on the master.
+    fn run(&mut self, uart: &Uart, master: usize, reason: StopReason) {
+        let Stub {
+            input,
+            response,
+            session,
+        } = self;
+
+        session.master = master;
+        session.reason = reason;
+        session.registers_of = Some(master);
+        session.step_of = master;
+
+        // GDB asks with `?` when it connects.
+        if ATTACHED.load(Ordering::Acquire) {
+            response.clear();
+            session.stop_reply(response);
+            packet::send(uart, response.as_bytes());
+        }
+
+        loop {
+            let length = packet::receive(uart, input);
+
+            ATTACHED.store(true, Ordering::Release);
+
+            match session.handle(&input[..length], response) {
+                Action::Reply => packet::send(uart, response.as_bytes()),
+
+                Action::Resume { step } => {
+                    if let Some(frame) = unsafe { frame_of(session.step_of) }.filter(|_| step) {
+                        frame.rflags |= RFLAGS_TF;
+                    }
+
+                    return;
+                }
+
+                Action::Detach => {
+                    packet::send(uart, b"OK");
+                    session.detach();
+                    return;
+                }
+
+                Action::Kill => {
+                    session.detach();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// -- Stopping and resuming
+
+/// The UART of the stub, if it's enabled.
+#[inline]
+fn stub_port() -> Option<&'static Uart> {
+    // The stub runs on whatever processor traps, it needs per-CPU data.
+    if !percpu::is_ready() {
+        return None;
+    }
+
+    ComPort::ALL
+        .get(PORT.load(Ordering::Acquire))
+        .copied()
+        .and_then(uart::port)
+}
+
+/// Wait while another processor is the master, GDB can look at (and change) `frame` meanwhile.
+fn park(cpu: usize, frame: &mut TrapFrame) {
+    FRAMES[cpu].store(frame, Ordering::Release);
+    PARKED.fetch_add(1, Ordering::AcqRel);
+
+    while MASTER.load(Ordering::Acquire) != NO_MASTER {
+        core::sync::atomic::spin_loop_hint();
+    }
+
+    PARKED.fetch_sub(1, Ordering::AcqRel);
+    FRAMES[cpu].store(null_mut(), Ordering::Release);
+
+    DEBUG_REGISTERS.lock().load();
+}
+
+/// NMI the other processors and wait (for a while) until they're parked.
+fn stop_others(cpu: usize) {
+    let online = smp::cpus_online();
+
+    let apic = match local_apic() {
+        Some(apic) if online > 1 => apic,
+        _ => return,
+    };
+
+    // The ones already parked (they trapped too) would park twice.
+    for other in (0..online).filter(|&other| other != cpu) {
+        if FRAMES[other].load(Ordering::Acquire).is_null() {
+            STOP_PENDING[other].store(true, Ordering::Release);
+        }
+    }
+
+    apic.send_ipi(0, IPI_NMI_OTHERS);
+
+    for _ in 0..STOP_TIMEOUT {
+        if PARKED.load(Ordering::Acquire) + 1 >= online {
+            return;
+        }
+
+        core::sync::atomic::spin_loop_hint();
+    }
+
+    log::warn!(
+        "(GDB) Only {:?} of {:?} other processors stopped",
+        PARKED.load(Ordering::Acquire),
+        online - 1
+    );
+}
+
+/// Become the master (parking while another processor is) and stop the others.
+fn stop_kernel(cpu: usize, frame: &mut TrapFrame) {
+    while MASTER
+        .compare_exchange(NO_MASTER, cpu, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        park(cpu, frame);
+    }
+
+    FRAMES[cpu].store(frame, Ordering::Release);
+
+    stop_others(cpu);
+}
+
+/// Let the parked processors go, from the master.
+fn resume_kernel(cpu: usize) {
+    DEBUG_REGISTERS.lock().load();
+
+    FRAMES[cpu].store(null_mut(), Ordering::Release);
+    MASTER.store(NO_MASTER, Ordering::Release);
+}
+
+/// #BP, `false` if it's not the debugger's.
+pub(crate) fn on_breakpoint(frame: &mut TrapFrame) -> bool {
+    let uart = match stub_port() {
+        Some(uart) => uart,
+        None => return false,
+    };
+
+    let cpu = current_cpu();
+
+    stop_kernel(cpu, frame);
+
+    let mut stub = STUB.lock();
+
+    // RIP is past the `int3`.
+    let address = frame.rip.wrapping_sub(1);
+
+    let reason = if stub.session.has_breakpoint(address) {
+        frame.rip = address;
+        Some(StopReason::Breakpoint)
+    } else if BREAK_IN.swap(false, Ordering::AcqRel) {
+        Some(StopReason::Signal(SIGINT))
+    } else if is_mapped(address, 1) && unsafe { read_volatile(address as *const u8) } == INT3 {
+        Some(StopReason::Signal(SIGTRAP))
+    } else {
+        // GDB took the breakpoint out while we waited to be the master, run what's there now.
+        frame.rip = address;
+        None
+    };
+
+    if let Some(reason) = reason {
+        stub.run(uart, cpu, reason);
+    }
+
+    drop(stub);
+
+    resume_kernel(cpu);
+
+    true
+}
+
+/// #DB, `false` if it's not the debugger's.
+pub(crate) fn on_debug(frame: &mut TrapFrame) -> bool {
+    let dr6 = watchpoint::take_dr6();
+
+    let uart = match stub_port() {
+        Some(uart) => uart,
+
+        // Watchpoints left behind by a disabled stub.
+        None if dr6 & 0b1111 != 0 => {
+            DEBUG_REGISTERS.lock().load();
+            return true;
+        }
+
+        None => return false,
+    };
+
+    let cpu = current_cpu();
+
+    stop_kernel(cpu, frame);
+
+    let reason = if dr6 & DR6_SINGLE_STEP != 0 {
+        frame.rflags &= !RFLAGS_TF;
+        StopReason::Step
+    } else {
+        match DEBUG_REGISTERS.lock().hit(dr6) {
+            Some(watchpoint) if watchpoint.kind == Kind::Execute => {
+                // Don't hit it again straight away.
+                frame.rflags |= RFLAGS_RF;
+                StopReason::HardwareBreakpoint
+            }
+
+            Some(watchpoint) => StopReason::Watchpoint(watchpoint),
+            None => StopReason::Signal(SIGTRAP),
+        }
+    };
+
+    STUB.lock().run(uart, cpu, reason);
+
+    resume_kernel(cpu);
+
+    true
+}
+
+/// NMI, `true` if it was the master stopping this processor.
+pub(crate) fn on_nmi(frame: &mut TrapFrame) -> bool {
+    if !percpu::is_ready() {
+        return false;
+    }
+
+    let cpu = current_cpu();
+
+    if !STOP_PENDING[cpu].swap(false, Ordering::AcqRel) {
+        return false;
+    }
+
+    if FRAMES[cpu].load(Ordering::Acquire).is_null() {
+        park(cpu, frame);
+    }
+
+    true
+}
+
+/// Received something on the stub's port while the kernel runs.
+fn break_in(uart: &Uart) {
+    // A ^C, or a packet from GDB connecting.
+    if uart.discard_until(|byte| byte == 0x03 || byte == b'$') {
+        BREAK_IN.store(true, Ordering::Release);
+        breakpoint();
+    }
+}
+
+/// Stop in the debugger (if the stub is enabled), as if a breakpoint was hit.
+#[inline(always)]
+pub fn breakpoint() {
+    x86_64::instructions::interrupts::int3();
+}
+
+/// Run the stub on the UART on `port`, instead of the one it's on.
+pub fn enable(port: ComPort) -> Result<(), UartError> {
+    let uart = uart::port(port).ok_or(UartError::NotPresent(port))?;
+
+    disable();
+
+    uart.set_break_in(Some(break_in));
+    PORT.store(port as usize, Ordering::Release);
+
+    log::info!("(GDB) Stub on {:?} at {:#x}", port, uart.base());
+
+    Ok(())
+}
+
+/// Stop running the stub, taking out every breakpoint and watchpoint.
+pub fn disable() {
+    let port = PORT.swap(NO_PORT, Ordering::AcqRel);
+
+    if let Some(uart) = ComPort::ALL.get(port).copied().and_then(uart::port) {
+        uart.set_break_in(None);
+
+        without_interrupts(|| {
+            STUB.lock().session.detach();
+            DEBUG_REGISTERS.lock().load();
+        });
+    }
+}
+
+/// Load the hardware breakpoints and watchpoints, for processors coming online.
+pub(crate) fn load_debug_registers() {
+    DEBUG_REGISTERS.lock().load();
+}
+
+/// Enable the stub as the `gdb=` argument of `command_line` says, if it has one.
+///
+/// The argument is a port and options, e.g. `gdb=com2` or `gdb=com2,wait`.
+pub(crate) fn configure_from_command_line(command_line: &str) -> Result<Option<Options>, GdbError<'_>> {
+    let spec = match command_line
+        .split_whitespace()
+        .filter_map(|argument| argument.strip_prefix("gdb="))
+        .last()
+    {
+        Some(spec) => spec,
+        None => return Ok(None),
+    };
+
+    let mut parts = spec.split(',');
+    let name = parts.next().unwrap_or("");
+    let port = ComPort::from_name(name).ok_or(GdbError::UnknownPort(name))?;
+
+    let mut options = Options { wait: false };
+
+    for option in parts {
+        match option {
+            "wait" => options.wait = true,
+            _ => return Err(GdbError::UnknownOption(option)),
+        }
+    }
+
+    enable(port).map_err(GdbError::Uart)?;
+
+    Ok(Some(options))
+}
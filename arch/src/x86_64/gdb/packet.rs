@@ -0,0 +1,242 @@
+//! Remote serial protocol packets, `$<data>#<checksum>`, and their hex encoding.
+
+use core::fmt;
+
+use crate::x86_64::device::uart::Uart;
+
+/// The largest packet we take or send, advertised in `qSupported`.
+pub(super) const PACKET_SIZE: usize = 0x1000;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+#[inline]
+fn read_byte(uart: &Uart) -> u8 {
+    loop {
+        if let Some(byte) = uart.poll_byte() {
+            return byte;
+        }
+
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+#[inline]
+pub(super) fn hex_digit(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// A (big-endian) hex number, e.g. an address.
+pub(super) fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+
+    digits
+        .iter()
+        .try_fold(0u64, |value, &digit| Some((value << 4) | hex_digit(digit)? as u64))
+}
+
+/// A thread ID, `-1` (all threads) is `None`.
+pub(super) fn parse_thread(digits: &[u8]) -> Option<Option<u64>> {
+    if digits == b"-1" {
+        Some(None)
+    } else {
+        parse_hex(digits).map(Some)
+    }
+}
+
+/// Decode hex pairs into `bytes`, `None` unless `hex` is exactly that long.
+pub(super) fn decode_hex(hex: &[u8], bytes: &mut [u8]) -> Option<()> {
+    if hex.len() != bytes.len() * 2 {
+        return None;
+    }
+
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks_exact(2)) {
+        *byte = (hex_digit(pair[0])? << 4) | hex_digit(pair[1])?;
+    }
+
+    Some(())
+}
+
+/// A register value, `size` bytes in target (little-endian) order.
+pub(super) fn decode_register(hex: &[u8], size: usize) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+
+    decode_hex(hex, &mut bytes[..size])?;
+
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Split at the first `separator`, the rest is empty if there's none.
+pub(super) fn split(data: &[u8], separator: u8) -> (&[u8], &[u8]) {
+    match data.iter().position(|&byte| byte == separator) {
+        Some(idx) => (&data[..idx], &data[(idx + 1)..]),
+        None => (data, &[]),
+    }
+}
+
+/// Wait for the next packet (acknowledging it) and read it into `buffer`, returns its length.
+pub(super) fn receive(uart: &Uart, buffer: &mut [u8; PACKET_SIZE]) -> usize {
+    'packet: loop {
+        // Anything outside a packet (acknowledgements, ^C) doesn't matter while stopped.
+        while read_byte(uart) != b'$' {}
+
+        let mut length = 0;
+        let mut checksum = 0u8;
+
+        loop {
+            let byte = read_byte(uart);
+
+            match byte {
+                b'#' => break,
+
+                // GDB gave up on the packet and started over.
+                b'$' => {
+                    length = 0;
+                    checksum = 0;
+                    continue;
+                }
+
+                _ if length == PACKET_SIZE => {
+                    uart.write_polled(b"-");
+                    continue 'packet;
+                }
+
+                _ => {
+                    buffer[length] = byte;
+                    length += 1;
+                    checksum = checksum.wrapping_add(byte);
+                }
+            }
+        }
+
+        let high = hex_digit(read_byte(uart));
+        let low = hex_digit(read_byte(uart));
+
+        match (high, low) {
+            (Some(high), Some(low)) if (high << 4) | low == checksum => {
+                uart.write_polled(b"+");
+                return length;
+            }
+
+            _ => uart.write_polled(b"-"),
+        }
+    }
+}
+
+/// Send `data` as a packet until GDB acknowledges it.
+pub(super) fn send(uart: &Uart, data: &[u8]) {
+    let checksum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    let trailer = [
+        b'#',
+        HEX_DIGITS[(checksum >> 4) as usize],
+        HEX_DIGITS[(checksum & 0xF) as usize],
+    ];
+
+    loop {
+        uart.write_polled(b"$");
+        uart.write_polled(data);
+        uart.write_polled(&trailer);
+
+        loop {
+            match read_byte(uart) {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+// -- Response
+
+/// The data of the packet to send back, anything past `PACKET_SIZE` is dropped.
+pub(super) struct Response {
+    buffer: [u8; PACKET_SIZE],
+    length: usize,
+}
+
+impl Response {
+    pub(super) const EMPTY: Self = Self {
+        buffer: [0; PACKET_SIZE],
+        length: 0,
+    };
+
+    #[inline]
+    pub(super) fn clear(&mut self) {
+        self.length = 0;
+    }
+
+    #[inline]
+    pub(super) fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.length]
+    }
+
+    #[inline]
+    pub(super) fn push(&mut self, byte: u8) {
+        // `$`, `#` and `}` would have to be escaped, nothing we send has them.
+        if self.length < PACKET_SIZE {
+            self.buffer[self.length] = byte;
+            self.length += 1;
+        }
+    }
+
+    pub(super) fn push_str(&mut self, s: &str) {
+        s.bytes().for_each(|byte| self.push(byte));
+    }
+
+    pub(super) fn hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(HEX_DIGITS[(byte >> 4) as usize]);
+            self.push(HEX_DIGITS[(byte & 0xF) as usize]);
+        }
+    }
+
+    /// Formatted text, hex encoded.
+    pub(super) fn hex_fmt(&mut self, args: fmt::Arguments) {
+        struct Hex<'a>(&'a mut Response);
+
+        impl fmt::Write for Hex<'_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                self.0.hex(s.as_bytes());
+                Ok(())
+            }
+        }
+
+        let _ = fmt::write(&mut Hex(self), args);
+    }
+
+    /// A register value, `size` bytes in target (little-endian) order.
+    pub(super) fn register(&mut self, value: Option<u64>, size: usize) {
+        match value {
+            Some(value) => self.hex(&value.to_le_bytes()[..size]),
+
+            // The register isn't available.
+            None => (0..(size * 2)).for_each(|_| self.push(b'x')),
+        }
+    }
+
+    /// `E<nn>`, for errors.
+    pub(super) fn error(&mut self, code: u8) {
+        self.clear();
+        self.push(b'E');
+        self.hex(&[code]);
+    }
+
+    pub(super) fn ok(&mut self) {
+        self.clear();
+        self.push_str("OK");
+    }
+}
+
+impl fmt::Write for Response {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+//! GDB's numbering of the amd64 registers (without a target description.)
+//!
+//! `g` packets hold rax to r15, rip and eflags to gs in this order. The x87
+//! and SSE registers after them aren't saved on traps, a short `g` packet
+//! tells GDB they're unavailable.
+
+use crate::x86_64::interrupts::trap::TrapFrame;
+
+/// How many registers we know, rax (0) to gs (23.)
+pub(super) const COUNT: usize = 24;
+
+const RIP: usize = 16;
+const EFLAGS: usize = 17;
+
+/// The size of register `number` in bytes.
+#[inline]
+pub(super) fn size(number: usize) -> usize {
+    if number <= RIP {
+        8
+    } else {
+        4
+    }
+}
+
+/// The value of register `number`, `None` if the frame doesn't have it.
+pub(super) fn read(frame: &TrapFrame, number: usize) -> Option<u64> {
+    let value = match number {
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.rsp,
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        RIP => frame.rip,
+        EFLAGS => frame.rflags,
+        18 => frame.cs,
+        19 => frame.ss,
+
+        // ds, es, fs and gs aren't saved.
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+/// Change register `number`, `false` if it can't be changed.
+///
+/// The segment registers can't, the kernel only has the one code and data segment.
+pub(super) fn write(frame: &mut TrapFrame, number: usize, value: u64) -> bool {
+    let slot = match number {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        RIP => &mut frame.rip,
+        EFLAGS => &mut frame.rflags,
+        _ => return false,
+    };
+
+    *slot = value;
+
+    true
+}
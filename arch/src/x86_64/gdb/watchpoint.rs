@@ -0,0 +1,158 @@
+//! Hardware breakpoints and watchpoints in the debug registers.
+//!
+//! DR0-DR3 hold up to four addresses, DR7 enables them and says what
+//! triggers each (executing it, writing or any access) and how many bytes
+//! it covers. The debug registers are per processor: every processor loads
+//! the same ones when the debugger resumes it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Kind {
+    /// An instruction breakpoint, `Z1`.
+    Execute,
+
+    /// `Z2`.
+    Write,
+
+    /// Reads or writes, `Z4` (x86 can't watch for reads only.)
+    Access,
+}
+
+impl Kind {
+    /// The R/W bits of DR7.
+    #[inline]
+    fn condition(self) -> u64 {
+        match self {
+            Kind::Execute => 0b00,
+            Kind::Write => 0b01,
+            Kind::Access => 0b11,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Watchpoint {
+    pub(super) address: u64,
+    pub(super) length: u64,
+    pub(super) kind: Kind,
+}
+
+impl Watchpoint {
+    /// The LEN bits of DR7.
+    #[inline]
+    fn length_bits(&self) -> u64 {
+        match self.length {
+            2 => 0b01,
+            8 => 0b10,
+            4 => 0b11,
+            _ => 0b00,
+        }
+    }
+}
+
+/// Set in DR6 by single-steps.
+pub(super) const DR6_SINGLE_STEP: u64 = 1 << 14;
+
+/// What DR6 reads as when nothing happened.
+const DR6_CLEAR: u64 = 0xFFFF_0FF0;
+
+/// Read (and reset) DR6, the cause of the #DB being handled.
+pub(super) fn take_dr6() -> u64 {
+    let dr6: u64;
+
+    unsafe {
+        asm!("mov {}, dr6", out(reg) dr6, options(nomem, nostack, preserves_flags));
+        asm!("mov dr6, {}", in(reg) DR6_CLEAR, options(nomem, nostack, preserves_flags));
+    }
+
+    dr6
+}
+
+// -- DebugRegisters
+
+pub(super) struct DebugRegisters {
+    slots: [Option<Watchpoint>; 4],
+}
+
+impl DebugRegisters {
+    pub(super) const EMPTY: Self = Self { slots: [None; 4] };
+
+    /// Take a free debug register for `watchpoint`, `false` if there is none
+    /// (or the CPU can't watch it.)
+    pub(super) fn insert(&mut self, watchpoint: Watchpoint) -> bool {
+        let valid = match watchpoint.kind {
+            Kind::Execute => watchpoint.length == 1,
+            Kind::Write | Kind::Access => {
+                [1, 2, 4, 8].contains(&watchpoint.length) && watchpoint.address % watchpoint.length == 0
+            }
+        };
+
+        if !valid {
+            return false;
+        }
+
+        if self.slots.contains(&Some(watchpoint)) {
+            return true;
+        }
+
+        match self.slots.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(watchpoint);
+                true
+            }
+
+            None => false,
+        }
+    }
+
+    /// Free the debug register holding `watchpoint`, `false` if none does.
+    pub(super) fn remove(&mut self, watchpoint: Watchpoint) -> bool {
+        match self.slots.iter_mut().find(|slot| **slot == Some(watchpoint)) {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+
+            None => false,
+        }
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.slots = [None; 4];
+    }
+
+    /// The watchpoint that triggered the #DB with `dr6`, if one did.
+    pub(super) fn hit(&self, dr6: u64) -> Option<Watchpoint> {
+        (0..4)
+            .filter(|&index| dr6 & (1 << index) != 0)
+            .find_map(|index| self.slots[index])
+    }
+
+    /// Load DR0-DR3 and DR7 of the executing processor.
+    pub(super) fn load(&self) {
+        let mut dr7 = 0u64;
+        let mut addresses = [0u64; 4];
+
+        for (index, watchpoint) in self.slots.iter().enumerate() {
+            if let Some(watchpoint) = watchpoint {
+                addresses[index] = watchpoint.address;
+
+                // Locally enabled, with its condition and length.
+                dr7 |= 1 << (index * 2);
+                dr7 |= watchpoint.kind.condition() << (16 + index * 4);
+                dr7 |= watchpoint.length_bits() << (18 + index * 4);
+            }
+        }
+
+        unsafe {
+            // Disable them all while the addresses change.
+            asm!("mov dr7, {}", in(reg) 0u64, options(nomem, nostack, preserves_flags));
+
+            asm!("mov dr0, {}", in(reg) addresses[0], options(nomem, nostack, preserves_flags));
+            asm!("mov dr1, {}", in(reg) addresses[1], options(nomem, nostack, preserves_flags));
+            asm!("mov dr2, {}", in(reg) addresses[2], options(nomem, nostack, preserves_flags));
+            asm!("mov dr3, {}", in(reg) addresses[3], options(nomem, nostack, preserves_flags));
+
+            asm!("mov dr7, {}", in(reg) dr7, options(nomem, nostack, preserves_flags));
+        }
+    }
+}
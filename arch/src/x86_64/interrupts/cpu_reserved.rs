@@ -1,30 +1,229 @@
 use x86_64::{
     registers::control::Cr2,
-    structures::idt::{InterruptStackFrame, PageFaultErrorCode},
-    structures::{idt::InterruptDescriptorTable, tss::TaskStateSegment},
+    structures::idt::{InterruptDescriptorTable, PageFaultErrorCode},
 };
 
-// CPU reserved routines.
+use crate::x86_64::{
+    backtrace::{current_frame, Backtrace},
+    gdb,
+    percpu::KernelGs,
+};
+
+use super::{
+    diagnostics::{dump, dump_machine_check, PageFaultReason, SelectorErrorCode},
+    trap::{self, TrapFrame},
+};
+
+/// IST entries (indices into `TaskStateSegment::interrupt_stack_table`.)
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// Point every architectural exception at its handler.
+///
+/// They all go through `trap_entry.asm` so that the dumps (and the debugger)
+/// see every register.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(trap::divide_error());
+    idt.debug.set_handler_fn(trap::debug());
+    idt.breakpoint.set_handler_fn(trap::breakpoint());
+    idt.overflow.set_handler_fn(trap::overflow());
+    idt.bound_range_exceeded.set_handler_fn(trap::bound_range_exceeded());
+    idt.invalid_opcode.set_handler_fn(trap::invalid_opcode());
+    idt.device_not_available.set_handler_fn(trap::device_not_available());
+    idt.invalid_tss.set_handler_fn(trap::invalid_tss());
+    idt.segment_not_present.set_handler_fn(trap::segment_not_present());
+    idt.stack_segment_fault.set_handler_fn(trap::stack_segment_fault());
+    idt.general_protection_fault.set_handler_fn(trap::general_protection_fault());
+    idt.page_fault.set_handler_fn(trap::page_fault());
+    idt.x87_floating_point.set_handler_fn(trap::x87_floating_point());
+    idt.alignment_check.set_handler_fn(trap::alignment_check());
+    idt.simd_floating_point.set_handler_fn(trap::simd_floating_point());
+    idt.virtualization.set_handler_fn(trap::virtualization());
+    idt.security_exception.set_handler_fn(trap::security_exception());
+
+    // These can hit at any time (even with a broken stack) so they get their own.
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(trap::double_fault())
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+
+        idt.non_maskable_interrupt
+            .set_handler_fn(trap::nmi())
+            .set_stack_index(NMI_IST_INDEX);
+
+        idt.machine_check
+            .set_handler_fn(trap::machine_check())
+            .set_stack_index(MACHINE_CHECK_IST_INDEX);
+    }
+}
+
+/// Log the backtrace of the interrupted code (must be expanded in the handler itself.)
+macro_rules! interrupted_backtrace {
+    ($stack_frame:expr) => {
+        Backtrace::interrupted($stack_frame.instruction_pointer.as_u64(), current_frame()).log()
+    };
+}
+
+/// Dump and panic, for exceptions we can't recover from (yet.)
+macro_rules! fatal {
+    ($frame:expr, $name:expr) => {{
+        dump($name, $frame);
+        interrupted_backtrace!($frame.stack_frame());
+        panic!("{}!", $name);
+    }};
+
+    ($frame:expr, $name:expr, $($detail:tt)+) => {{
+        dump($name, $frame);
+        log::error!($($detail)+);
+        interrupted_backtrace!($frame.stack_frame());
+        panic!("{}!", $name);
+    }};
+}
+
+// CPU reserved routines, called by `trap_entry.asm`.
+
+#[no_mangle]
+extern "C" fn divide_error_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+    fatal!(frame, "Divide error (#DE)");
+}
+
+#[no_mangle]
+extern "C" fn debug_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+
+    if !gdb::on_debug(frame) {
+        log::warn!("Debug exception (#DB) at {:#x}", frame.rip);
+    }
+}
+
+#[no_mangle]
+extern "C" fn nmi_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+
+    // The debugger stops the other processors with NMIs.
+    if gdb::on_nmi(frame) {
+        return;
+    }
+
+    dump("Non-maskable interrupt (NMI)", frame);
+    interrupted_backtrace!(frame.stack_frame());
+}
+
+#[no_mangle]
+extern "C" fn breakpoint_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+
+    if !gdb::on_breakpoint(frame) {
+        log::error!("Breakpoint!\n{:#?}", frame);
+    }
+}
+
+#[no_mangle]
+extern "C" fn overflow_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+    fatal!(frame, "Overflow (#OF)");
+}
+
+#[no_mangle]
+extern "C" fn bound_range_exceeded_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+    fatal!(frame, "Bound range exceeded (#BR)");
+}
+
+#[no_mangle]
+extern "C" fn invalid_opcode_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+    fatal!(frame, "Invalid opcode (#UD)");
+}
+
+#[no_mangle]
+extern "C" fn device_not_available_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+    fatal!(frame, "Device not available (#NM)");
+}
 
-pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    log::error!("Breakpoint!\n{:#?}", stack_frame);
+#[no_mangle]
+extern "C" fn double_fault_trap(frame: &mut TrapFrame) -> ! {
+    let _gs = KernelGs::enter(frame.stack_frame());
+    fatal!(frame, "Double fault (#DF)");
 }
 
-pub extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("Double fault!\n{:#?}", stack_frame);
+#[no_mangle]
+extern "C" fn invalid_tss_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+    fatal!(frame, "Invalid TSS (#TS)", "    error code: {}", SelectorErrorCode(frame.error_code));
 }
 
-pub extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    panic!(
-        "Page fault!\nAccessed Address: {:?}\nError Code: {:?}, {:#?}",
+#[no_mangle]
+extern "C" fn segment_not_present_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+    fatal!(frame, "Segment not present (#NP)", "    error code: {}", SelectorErrorCode(frame.error_code));
+}
+
+#[no_mangle]
+extern "C" fn stack_segment_fault_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+    fatal!(frame, "Stack segment fault (#SS)", "    error code: {}", SelectorErrorCode(frame.error_code));
+}
+
+#[no_mangle]
+extern "C" fn general_protection_fault_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+    fatal!(frame, "General protection fault (#GP)", "    error code: {}", SelectorErrorCode(frame.error_code));
+}
+
+#[no_mangle]
+extern "C" fn page_fault_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+
+    fatal!(
+        frame,
+        "Page fault (#PF)",
+        "    accessed address: {:?}, {}",
         Cr2::read(),
-        error_code,
-        stack_frame
+        PageFaultReason(PageFaultErrorCode::from_bits_truncate(frame.error_code))
     );
 }
+
+#[no_mangle]
+extern "C" fn x87_floating_point_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+    fatal!(frame, "x87 floating point exception (#MF)");
+}
+
+#[no_mangle]
+extern "C" fn alignment_check_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+    fatal!(frame, "Alignment check (#AC)", "    error code: {:#x}", frame.error_code);
+}
+
+#[no_mangle]
+extern "C" fn machine_check_trap(frame: &mut TrapFrame) -> ! {
+    let _gs = KernelGs::enter(frame.stack_frame());
+
+    dump("Machine check (#MC)", frame);
+    dump_machine_check();
+    interrupted_backtrace!(frame.stack_frame());
+
+    panic!("Machine check (#MC)!");
+}
+
+#[no_mangle]
+extern "C" fn simd_floating_point_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+    fatal!(frame, "SIMD floating point exception (#XM)");
+}
+
+#[no_mangle]
+extern "C" fn virtualization_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+    fatal!(frame, "Virtualization exception (#VE)");
+}
+
+#[no_mangle]
+extern "C" fn security_exception_trap(frame: &mut TrapFrame) {
+    let _gs = KernelGs::enter(frame.stack_frame());
+    fatal!(frame, "Security exception (#SX)", "    error code: {:#x}", frame.error_code);
+}
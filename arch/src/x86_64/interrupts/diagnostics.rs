@@ -0,0 +1,255 @@
+//! Decoding and dumping CPU state for the exception handlers.
+//!
+//! Exceptions are entered through `trap_entry.asm`, which saves the general
+//! purpose registers along with the interrupt stack frame (see `trap.rs`.)
+
+use core::fmt;
+
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr2, Cr3, Cr4},
+        model_specific::Msr,
+    },
+    structures::idt::PageFaultErrorCode,
+};
+
+use crate::x86_64::{memory::IDENTITY_MAPPED_LIMIT, percpu};
+
+use super::trap::TrapFrame;
+
+const IA32_EFER: u32 = 0xC000_0080;
+const IA32_FS_BASE: u32 = 0xC000_0100;
+const IA32_GS_BASE: u32 = 0xC000_0101;
+const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MC0_STATUS: u32 = 0x401;
+
+/// How many bytes at RIP get dumped.
+const CODE_BYTES: usize = 16;
+
+/// How many quadwords at RSP get dumped.
+const STACK_WORDS: usize = 8;
+
+// -- SelectorErrorCode
+
+/// The error code of #TS, #NP, #SS and #GP.
+#[derive(Clone, Copy)]
+pub struct SelectorErrorCode(pub u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.0;
+
+        if code == 0 {
+            return write!(f, "{:#x} (not selector related)", code);
+        }
+
+        let table = match (code >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        };
+
+        write!(f, "{:#x} ({} index {:?}", code, table, (code >> 3) & 0x1FFF)?;
+
+        if code & 1 != 0 {
+            write!(f, ", external event")?;
+        }
+
+        write!(f, ")")
+    }
+}
+
+// -- PageFaultReason
+
+/// A page fault error code in words.
+#[derive(Clone, Copy)]
+pub struct PageFaultReason(pub PageFaultErrorCode);
+
+impl fmt::Display for PageFaultReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.0;
+
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        };
+
+        let mode = if code.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "supervisor"
+        };
+
+        let cause = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "protection violation"
+        } else {
+            "page not present"
+        };
+
+        write!(f, "{} {} ({})", mode, access, cause)?;
+
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", reserved bit set in a page table")?;
+        }
+
+        // Not every version of `PageFaultErrorCode` knows about these.
+        if code.bits() & (1 << 5) != 0 {
+            write!(f, ", protection key")?;
+        }
+
+        if code.bits() & (1 << 6) != 0 {
+            write!(f, ", shadow stack")?;
+        }
+
+        Ok(())
+    }
+}
+
+// -- Page table walk
+
+/// Is `address` mapped in the active page tables?
+///
+/// The tables are read through the identity map, anything that isn't in it
+/// is treated as unmapped so that dumping never faults itself.
+fn is_mapped(address: u64) -> bool {
+    let (pml4, _) = Cr3::read();
+    let mut table = pml4.start_address().as_u64();
+
+    for level in (0..4).rev() {
+        if table >= IDENTITY_MAPPED_LIMIT as u64 {
+            return false;
+        }
+
+        let index = (address >> (12 + 9 * level)) & 0x1FF;
+        let entry = unsafe { *((table + index * 8) as *const u64) };
+
+        // Present.
+        if entry & 1 == 0 {
+            return false;
+        }
+
+        // Huge page (1GiB or 2MiB.)
+        if level != 0 && level != 3 && entry & (1 << 7) != 0 {
+            return true;
+        }
+
+        table = entry & 0x000F_FFFF_FFFF_F000;
+    }
+
+    true
+}
+
+/// Is all of `address..address + len` mapped?
+pub(crate) fn is_range_mapped(address: u64, len: u64) -> bool {
+    let last = match address.checked_add(len - 1) {
+        Some(last) => last,
+        None => return false,
+    };
+
+    is_mapped(address) && is_mapped(last)
+}
+
+// -- Dumps
+
+/// Log the saved registers, control registers and the bytes at RIP and RSP.
+pub fn dump(name: &str, frame: &TrapFrame) {
+    let rip = frame.rip;
+    let rsp = frame.rsp;
+
+    log::error!("(EXCEPTION) {} on CPU {:?}", name, percpu::current_cpu());
+
+    log::error!("    RIP={:#018x} CS={:#06x} RFLAGS={:#010x}", rip, frame.cs, frame.rflags);
+    log::error!("    RSP={:#018x} SS={:#06x}", rsp, frame.ss);
+
+    log::error!(
+        "    RAX={:#018x} RBX={:#018x} RCX={:#018x} RDX={:#018x}",
+        frame.rax,
+        frame.rbx,
+        frame.rcx,
+        frame.rdx
+    );
+
+    log::error!(
+        "    RSI={:#018x} RDI={:#018x} RBP={:#018x} R8 ={:#018x}",
+        frame.rsi,
+        frame.rdi,
+        frame.rbp,
+        frame.r8
+    );
+
+    log::error!(
+        "    R9 ={:#018x} R10={:#018x} R11={:#018x} R12={:#018x}",
+        frame.r9,
+        frame.r10,
+        frame.r11,
+        frame.r12
+    );
+
+    log::error!("    R13={:#018x} R14={:#018x} R15={:#018x}", frame.r13, frame.r14, frame.r15);
+
+    unsafe {
+        log::error!(
+            "    CR0={:#018x} CR2={:#018x} CR3={:#018x} CR4={:#018x}",
+            Cr0::read_raw(),
+            Cr2::read().as_u64(),
+            Cr3::read().0.start_address().as_u64(),
+            Cr4::read_raw()
+        );
+
+        log::error!(
+            "    EFER={:#x} FS_BASE={:#x} GS_BASE={:#x} KERNEL_GS_BASE={:#x}",
+            Msr::new(IA32_EFER).read(),
+            Msr::new(IA32_FS_BASE).read(),
+            Msr::new(IA32_GS_BASE).read(),
+            Msr::new(IA32_KERNEL_GS_BASE).read()
+        );
+    }
+
+    if is_range_mapped(rip, CODE_BYTES as u64) {
+        let code = unsafe { &*(rip as *const [u8; CODE_BYTES]) };
+        log::error!("    code: {:02x?}", code);
+    } else {
+        log::error!("    code: <RIP is not mapped>");
+    }
+
+    if rsp % 8 == 0 && is_range_mapped(rsp, (STACK_WORDS * 8) as u64) {
+        let stack = unsafe { &*(rsp as *const [u64; STACK_WORDS]) };
+
+        for (index, word) in stack.iter().enumerate() {
+            log::error!("    [rsp+{:#04x}] {:#018x}", index * 8, word);
+        }
+    } else {
+        log::error!("    stack: <RSP is not mapped (or misaligned)>");
+    }
+}
+
+/// Log every machine check bank that holds a valid error.
+pub fn dump_machine_check() {
+    unsafe {
+        let status = Msr::new(IA32_MCG_STATUS).read();
+        let banks = Msr::new(IA32_MCG_CAP).read() & 0xFF;
+
+        // RIPV, EIPV and MCIP.
+        log::error!(
+            "    MCG_STATUS={:#x} (restart IP valid: {}, error IP valid: {}, in progress: {})",
+            status,
+            status & 1 != 0,
+            status & 2 != 0,
+            status & 4 != 0
+        );
+
+        for bank in 0..banks as u32 {
+            let status = Msr::new(IA32_MC0_STATUS + bank * 4).read();
+
+            if status & (1 << 63) != 0 {
+                log::error!("    MC{:?}_STATUS={:#018x}", bank, status);
+            }
+        }
+    }
+}
@@ -1,5 +1,7 @@
+use crate::x86_64::device::pic::DEFAULT_PIC_SLAVE_OFFSET;
+
 /// InterruptIndex enum that is used for mapping out pic interrupt vectors.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum InterruptIndex {
     /// Programmable Interrupt Timer (PIT) Interrupt vector,
@@ -50,3 +52,44 @@ pub enum InterruptIndex {
     /// Secondary ATA Hard Disk.
     SecondaryATA,
 }
+
+impl InterruptIndex {
+    /// Every legacy (ISA) IRQ, in order.
+    pub const ALL: [InterruptIndex; 16] = [
+        InterruptIndex::Timer,
+        InterruptIndex::PS2Keyboard,
+        InterruptIndex::Cascade,
+        InterruptIndex::COM2,
+        InterruptIndex::COM1,
+        InterruptIndex::LPT2,
+        InterruptIndex::FloppyDisk,
+        InterruptIndex::LPT1,
+        InterruptIndex::CMOS,
+        InterruptIndex::NIC1,
+        InterruptIndex::NIC2,
+        InterruptIndex::NIC3,
+        InterruptIndex::PS2Mouse,
+        InterruptIndex::FPUCoprocessor,
+        InterruptIndex::PrimaryATA,
+        InterruptIndex::SecondaryATA,
+    ];
+
+    /// The ISA IRQ number.
+    #[inline]
+    pub const fn irq(self) -> u8 {
+        self as u8
+    }
+
+    /// The vector the IRQ is delivered to (the same with the PIC or the I/O APIC.)
+    #[inline]
+    pub const fn vector(self) -> u8 {
+        DEFAULT_PIC_SLAVE_OFFSET + self as u8
+    }
+
+    /// The ISA IRQ delivered to `vector`, if any.
+    #[inline]
+    pub fn from_vector(vector: u8) -> Option<Self> {
+        let irq = vector.checked_sub(DEFAULT_PIC_SLAVE_OFFSET)?;
+        Self::ALL.get(irq as usize).copied()
+    }
+}
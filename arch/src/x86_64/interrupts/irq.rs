@@ -0,0 +1,408 @@
+//! Runtime interrupt handler registration and dispatch.
+//!
+//! Every vector from `FIRST_VECTOR` up has a generated stub in the IDT that
+//! calls `dispatch`, which runs the handlers registered for it and then
+//! signals the end of the interrupt to whichever controller delivered it
+//! (the 8259 PIC until the local APIC is enabled, the APIC after that.)
+//!
+//! Vectors can be shared by up to `MAX_SHARED` handlers (e.g. level triggered
+//! PCI lines), all of them are called and report whether the interrupt was
+//! theirs. Interrupts nobody claims are counted and reported.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
+
+use crate::x86_64::{
+    device::{apic, ioapic, pic::CHIP_8259},
+    percpu::KernelGs,
+};
+
+use super::index::InterruptIndex;
+
+/// The first vector that isn't a CPU exception.
+pub const FIRST_VECTOR: u8 = 0x20;
+
+/// Vectors handed out by `request_vector` (above the ISA IRQs, below the system vectors.)
+const DYNAMIC_VECTORS: core::ops::Range<u8> = 0x30..0xF0;
+
+/// The most handlers sharing a vector.
+pub const MAX_SHARED: usize = 4;
+
+/// Did the handler deal with the interrupt?
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+
+    /// The device of this handler didn't raise it (on shared lines.)
+    NotMine,
+}
+
+/// What an interrupt handler gets called with.
+pub struct IrqContext<'a> {
+    pub vector: u8,
+
+    /// The value passed in when the handler was registered.
+    pub data: usize,
+
+    pub stack_frame: &'a InterruptStackFrame,
+}
+
+/// An interrupt handler, non-capturing closures work too.
+pub type IrqHandler = fn(&IrqContext) -> IrqReturn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The vector already has `MAX_SHARED` handlers (or was handed out by `request_vector`.)
+    Busy,
+
+    /// Every dynamic vector is taken.
+    NoFreeVector,
+
+    /// The IRQ line can't be delivered (i.e. the cascade.)
+    NotRoutable,
+}
+
+/// A registered handler, pass it to `free` to unregister.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandle {
+    vector: u8,
+    slot: usize,
+}
+
+impl IrqHandle {
+    #[inline]
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+// -- Vectors
+
+#[derive(Clone, Copy)]
+struct Slot {
+    handler: IrqHandler,
+    data: usize,
+    name: &'static str,
+}
+
+struct Vector {
+    slots: Mutex<[Option<Slot>; MAX_SHARED]>,
+
+    /// Handed out by `request_vector`, nobody else may register on it.
+    exclusive: AtomicBool,
+
+    count: AtomicU64,
+    unhandled: AtomicU64,
+}
+
+impl Vector {
+    const EMPTY: Self = Self {
+        slots: Mutex::new([None; MAX_SHARED]),
+        exclusive: AtomicBool::new(false),
+        count: AtomicU64::new(0),
+        unhandled: AtomicU64::new(0),
+    };
+
+    /// Register in the first free slot, returns whether it's the first handler.
+    fn register(&self, vector: u8, slot: Slot) -> Result<(IrqHandle, bool), IrqError> {
+        without_interrupts(|| {
+            let mut slots = self.slots.lock();
+
+            let first = slots.iter().all(Option::is_none);
+
+            let (index, free) = slots
+                .iter_mut()
+                .enumerate()
+                .find(|(_, free)| free.is_none())
+                .ok_or(IrqError::Busy)?;
+
+            *free = Some(slot);
+
+            Ok((IrqHandle { vector, slot: index }, first))
+        })
+    }
+}
+
+static VECTORS: [Vector; 256] = [Vector::EMPTY; 256];
+
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+/// Is the local APIC (rather than the PIC) delivering interrupts?
+static APIC_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Switch end of interrupt signalling (and IRQ masking) over to the (I/O) APIC.
+pub(crate) fn set_apic_active() {
+    APIC_ACTIVE.store(true, Ordering::SeqCst);
+}
+
+/// Unmask (or mask) the ISA IRQ `irq` at whichever controller is active.
+fn set_isa_masked(irq: InterruptIndex, masked: bool) {
+    if APIC_ACTIVE.load(Ordering::SeqCst) {
+        let gsi = ioapic::isa_route(irq).gsi;
+
+        let found = if masked { ioapic::mask(gsi) } else { ioapic::unmask(gsi) };
+
+        if !found {
+            log::warn!("(IRQ) {:?} (GSI {:?}) isn't wired to any I/O APIC", irq, gsi);
+        }
+    } else {
+        unsafe {
+            if masked {
+                CHIP_8259.mask(irq.irq())
+            } else {
+                CHIP_8259.unmask(irq.irq())
+            }
+        }
+    }
+}
+
+/// Register `handler` for the ISA IRQ `irq` and unmask it.
+pub fn request_irq(irq: InterruptIndex, name: &'static str, handler: IrqHandler, data: usize) -> Result<IrqHandle, IrqError> {
+    if irq == InterruptIndex::Cascade {
+        return Err(IrqError::NotRoutable);
+    }
+
+    let (handle, first) = request(irq.vector(), name, handler, data)?;
+
+    if first {
+        set_isa_masked(irq, false);
+    }
+
+    Ok(handle)
+}
+
+/// Register `handler` on `vector` (which may already have handlers.)
+///
+/// Nothing is unmasked, routing the interrupt to `vector` is up to the caller.
+pub fn request_shared(vector: u8, name: &'static str, handler: IrqHandler, data: usize) -> Result<IrqHandle, IrqError> {
+    request(vector, name, handler, data).map(|(handle, _)| handle)
+}
+
+/// Register `handler` on a free vector of its own (e.g. for MSIs or IPIs.)
+pub fn request_vector(name: &'static str, handler: IrqHandler, data: usize) -> Result<IrqHandle, IrqError> {
+    for vector in DYNAMIC_VECTORS {
+        let entry = &VECTORS[vector as usize];
+
+        let claimed = entry
+            .exclusive
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err();
+
+        if claimed {
+            continue;
+        }
+
+        match entry.register(vector, Slot { handler, data, name }) {
+            Ok((handle, true)) => {
+                log::debug!("(IRQ) {:?} was given vector {:#x}", name, vector);
+                return Ok(handle);
+            }
+
+            // Somebody registered on it through `request_shared`.
+            Ok((handle, false)) => {
+                free(handle);
+                entry.exclusive.store(false, Ordering::SeqCst);
+            }
+
+            Err(_) => entry.exclusive.store(false, Ordering::SeqCst),
+        }
+    }
+
+    Err(IrqError::NoFreeVector)
+}
+
+fn request(vector: u8, name: &'static str, handler: IrqHandler, data: usize) -> Result<(IrqHandle, bool), IrqError> {
+    assert!(vector >= FIRST_VECTOR, "(IRQ) Vector {:#x} is a CPU exception", vector);
+
+    let entry = &VECTORS[vector as usize];
+
+    if entry.exclusive.load(Ordering::SeqCst) {
+        return Err(IrqError::Busy);
+    }
+
+    let registered = entry.register(vector, Slot { handler, data, name })?;
+
+    log::debug!("(IRQ) Registered {:?} on vector {:#x}", name, vector);
+
+    Ok(registered)
+}
+
+/// Unregister a handler, the ISA IRQ is masked again once its last handler is gone.
+pub fn free(handle: IrqHandle) {
+    let entry = &VECTORS[handle.vector as usize];
+
+    let last = without_interrupts(|| {
+        let mut slots = entry.slots.lock();
+
+        if let Some(slot) = slots[handle.slot].take() {
+            log::debug!("(IRQ) Freed {:?} on vector {:#x}", slot.name, handle.vector);
+        }
+
+        slots.iter().all(Option::is_none)
+    });
+
+    if last {
+        entry.exclusive.store(false, Ordering::SeqCst);
+
+        if let Some(irq) = InterruptIndex::from_vector(handle.vector) {
+            set_isa_masked(irq, true);
+        }
+    }
+}
+
+/// How many times `vector` has been dispatched (on any processor.)
+pub fn count(vector: u8) -> u64 {
+    VECTORS[vector as usize].count.load(Ordering::Relaxed)
+}
+
+/// How many spurious interrupts (PIC or APIC) have been seen.
+pub fn spurious_count() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// Count a spurious interrupt.
+pub(crate) fn note_spurious(source: &str) {
+    let count = SPURIOUS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    if count.is_power_of_two() {
+        log::debug!("(IRQ) Spurious interrupt from the {} ({:?} so far)", source, count);
+    }
+}
+
+/// Log the counters of every vector that has fired.
+pub fn log_statistics() {
+    for (vector, entry) in VECTORS.iter().enumerate().skip(FIRST_VECTOR as usize) {
+        let count = entry.count.load(Ordering::Relaxed);
+
+        if count == 0 {
+            continue;
+        }
+
+        let slots = without_interrupts(|| *entry.slots.lock());
+        let mut names = slots.iter().flatten().map(|slot| slot.name);
+
+        log::info!(
+            "(IRQ) {:#04x}: {:>10} ({:?} unhandled) {}",
+            vector,
+            count,
+            entry.unhandled.load(Ordering::Relaxed),
+            names.next().unwrap_or("-")
+        );
+
+        for name in names {
+            log::info!("(IRQ)       {:>10}                 {}", "", name);
+        }
+    }
+
+    log::info!("(IRQ) spurious: {:?}", spurious_count());
+}
+
+// -- Dispatch
+
+fn end_of_interrupt(vector: u8) {
+    if APIC_ACTIVE.load(Ordering::Relaxed) {
+        if let Some(apic) = apic::local_apic() {
+            apic.end_of_interrupt();
+        }
+    } else if let Some(irq) = InterruptIndex::from_vector(vector) {
+        unsafe { CHIP_8259.end_of_interrupt(irq.irq()) };
+    }
+}
+
+/// IRQ 7 and 15 are raised by the PIC when an IRQ goes away before it's acknowledged.
+fn is_pic_spurious(vector: u8) -> bool {
+    if APIC_ACTIVE.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    let irq = match InterruptIndex::from_vector(vector) {
+        Some(irq @ InterruptIndex::LPT1) | Some(irq @ InterruptIndex::SecondaryATA) => irq.irq(),
+        _ => return false,
+    };
+
+    if unsafe { CHIP_8259.in_service() } & (1 << irq) != 0 {
+        return false;
+    }
+
+    // The primary PIC did see the cascade, it still wants its EOI.
+    if irq >= 8 {
+        unsafe { CHIP_8259.end_of_interrupt(InterruptIndex::Cascade.irq()) };
+    }
+
+    true
+}
+
+fn dispatch(vector: u8, stack_frame: &InterruptStackFrame) {
+    let _gs = KernelGs::enter(stack_frame);
+
+    if is_pic_spurious(vector) {
+        note_spurious("PIC");
+        return;
+    }
+
+    let entry = &VECTORS[vector as usize];
+
+    entry.count.fetch_add(1, Ordering::Relaxed);
+
+    // Registration disables interrupts while holding the lock, so this can't deadlock.
+    let slots = *entry.slots.lock();
+
+    let mut handled = false;
+
+    for slot in slots.iter().flatten() {
+        let context = IrqContext {
+            vector,
+            data: slot.data,
+            stack_frame,
+        };
+
+        handled |= (slot.handler)(&context) == IrqReturn::Handled;
+    }
+
+    if !handled {
+        let unhandled = entry.unhandled.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if unhandled.is_power_of_two() {
+            log::warn!("(IRQ) Unhandled interrupt on vector {:#x} ({:?} so far)", vector, unhandled);
+        }
+    }
+
+    end_of_interrupt(vector);
+}
+
+extern "x86-interrupt" fn stub<const VECTOR: u8>(stack_frame: &mut InterruptStackFrame) {
+    dispatch(VECTOR, stack_frame);
+}
+
+macro_rules! install_stubs {
+    ($idt:ident; $($row:literal)*) => {
+        $(
+            $idt[$row + 0x0].set_handler_fn(stub::<{ $row + 0x0 }>);
+            $idt[$row + 0x1].set_handler_fn(stub::<{ $row + 0x1 }>);
+            $idt[$row + 0x2].set_handler_fn(stub::<{ $row + 0x2 }>);
+            $idt[$row + 0x3].set_handler_fn(stub::<{ $row + 0x3 }>);
+            $idt[$row + 0x4].set_handler_fn(stub::<{ $row + 0x4 }>);
+            $idt[$row + 0x5].set_handler_fn(stub::<{ $row + 0x5 }>);
+            $idt[$row + 0x6].set_handler_fn(stub::<{ $row + 0x6 }>);
+            $idt[$row + 0x7].set_handler_fn(stub::<{ $row + 0x7 }>);
+            $idt[$row + 0x8].set_handler_fn(stub::<{ $row + 0x8 }>);
+            $idt[$row + 0x9].set_handler_fn(stub::<{ $row + 0x9 }>);
+            $idt[$row + 0xA].set_handler_fn(stub::<{ $row + 0xA }>);
+            $idt[$row + 0xB].set_handler_fn(stub::<{ $row + 0xB }>);
+            $idt[$row + 0xC].set_handler_fn(stub::<{ $row + 0xC }>);
+            $idt[$row + 0xD].set_handler_fn(stub::<{ $row + 0xD }>);
+            $idt[$row + 0xE].set_handler_fn(stub::<{ $row + 0xE }>);
+            $idt[$row + 0xF].set_handler_fn(stub::<{ $row + 0xF }>);
+        )*
+    };
+}
+
+/// Point every vector from `FIRST_VECTOR` up at its dispatch stub.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    install_stubs!(idt; 0x20 0x30 0x40 0x50 0x60 0x70 0x80 0x90 0xA0 0xB0 0xC0 0xD0 0xE0 0xF0);
+}
@@ -4,15 +4,13 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::{VirtAddr, registers::control::Cr2, structures::idt::{InterruptStackFrame, PageFaultErrorCode}, structures::{gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector}, idt::InterruptDescriptorTable, tss::TaskStateSegment}};
 
-mod cpu_reserved;
-mod index;
+use super::device::apic;
 
-/// Each pic interrupt must be met with an end of interrupt.
-macro_rules! eoi {
-    ($base:expr, $pics:expr, $irq:expr) => {
-        unsafe { $pics.notify_end_of_interrupt($base + ($irq as u8)) }
-    };
-}
+pub(crate) mod cpu_reserved;
+pub(crate) mod diagnostics;
+pub(crate) mod index;
+pub(crate) mod irq;
+pub(crate) mod trap;
 
 pub struct Selectors {
     pub code_selector: SegmentSelector,
@@ -23,19 +21,24 @@ lazy_static! {
     pub(crate) static ref INTERRUPT_DESCRIPTOR_TABLE: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
 
-        use cpu_reserved::*;
-
         // Set CPU routines.
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        cpu_reserved::install(&mut idt);
+
+        // Everything else goes through `irq::dispatch`.
+        irq::install(&mut idt);
+
+        // Local APIC routines.
+        idt[apic::ERROR_VECTOR as usize].set_handler_fn(apic::error_handler);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(apic::spurious_handler);
 
         idt
     };
 
     pub static ref TSS: TaskStateSegment = {
+            use cpu_reserved::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX};
+
             let mut tss = TaskStateSegment::new();
-            tss.interrupt_stack_table[0] = {
+            tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
                 const STACK_SIZE: usize = 4096 * 5;
                 static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
@@ -43,6 +46,18 @@ lazy_static! {
                 let stack_end = stack_start + STACK_SIZE;
                 stack_end
             };
+            tss.interrupt_stack_table[NMI_IST_INDEX as usize] = {
+                const STACK_SIZE: usize = 4096 * 4;
+                static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+                VirtAddr::from_ptr(unsafe { &STACK }) + STACK_SIZE
+            };
+            tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = {
+                const STACK_SIZE: usize = 4096 * 4;
+                static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+                VirtAddr::from_ptr(unsafe { &STACK }) + STACK_SIZE
+            };
             tss
         };
 
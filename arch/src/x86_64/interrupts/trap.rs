@@ -0,0 +1,93 @@
+//! Exceptions entered through `trap_entry.asm`, with every register saved.
+
+use x86_64::structures::idt::{
+    DivergingHandlerFunc, DivergingHandlerFuncWithErrCode, HandlerFunc, HandlerFuncWithErrCode, InterruptStackFrame,
+    PageFaultHandlerFunc,
+};
+
+/// The interrupted state as `trap_entry.asm` leaves it on the stack, lowest address first.
+///
+/// Whatever the handler changes in here is what the interrupted code resumes with.
+#[derive(Debug)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    /// Pushed by the CPU for some exceptions, `0` for the others.
+    pub error_code: u64,
+
+    // Pushed by the CPU.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// The part the CPU pushed, for the code that works with `x86-interrupt` handlers.
+    #[inline]
+    pub fn stack_frame(&self) -> &InterruptStackFrame {
+        // SAFETY: `InterruptStackFrame` is the same five quadwords (`repr(C)`.)
+        unsafe { &*(&self.rip as *const u64 as *const InterruptStackFrame) }
+    }
+}
+
+/// Declare the entry points of `trap_entry.asm` as handlers the IDT takes (they're never called as one.)
+macro_rules! entries {
+    ($($(#[$attr:meta])* fn $name:ident() -> $ty:ty = $entry:ident;)*) => {
+        extern "C" {
+            $(static $entry: u8;)*
+        }
+
+        $(
+            $(#[$attr])*
+            pub(super) fn $name() -> $ty {
+                unsafe { core::mem::transmute(&$entry as *const u8) }
+            }
+        )*
+    };
+}
+
+entries! {
+    /// Calls `cpu_reserved::breakpoint_trap`.
+    fn breakpoint() -> HandlerFunc = breakpoint_entry;
+
+    /// Calls `cpu_reserved::debug_trap`.
+    fn debug() -> HandlerFunc = debug_entry;
+
+    /// Calls `cpu_reserved::nmi_trap`.
+    fn nmi() -> HandlerFunc = nmi_entry;
+
+    fn divide_error() -> HandlerFunc = divide_error_entry;
+    fn overflow() -> HandlerFunc = overflow_entry;
+    fn bound_range_exceeded() -> HandlerFunc = bound_range_exceeded_entry;
+    fn invalid_opcode() -> HandlerFunc = invalid_opcode_entry;
+    fn device_not_available() -> HandlerFunc = device_not_available_entry;
+    fn double_fault() -> DivergingHandlerFuncWithErrCode = double_fault_entry;
+    fn invalid_tss() -> HandlerFuncWithErrCode = invalid_tss_entry;
+    fn segment_not_present() -> HandlerFuncWithErrCode = segment_not_present_entry;
+    fn stack_segment_fault() -> HandlerFuncWithErrCode = stack_segment_fault_entry;
+    fn general_protection_fault() -> HandlerFuncWithErrCode = general_protection_fault_entry;
+    fn page_fault() -> PageFaultHandlerFunc = page_fault_entry;
+    fn x87_floating_point() -> HandlerFunc = x87_floating_point_entry;
+    fn alignment_check() -> HandlerFuncWithErrCode = alignment_check_entry;
+    fn machine_check() -> DivergingHandlerFunc = machine_check_entry;
+    fn simd_floating_point() -> HandlerFunc = simd_floating_point_entry;
+    fn virtualization() -> HandlerFunc = virtualization_entry;
+    fn security_exception() -> HandlerFuncWithErrCode = security_exception_entry;
+}
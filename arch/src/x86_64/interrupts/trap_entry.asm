@@ -0,0 +1,138 @@
+; Exception entry points that save every general purpose register.
+;
+; The `x86-interrupt` ABI only hands handlers the interrupt stack frame, the
+; debugger (and the exception dumps) have to see the rest of the interrupted
+; state too. The entry points below push the registers under the frame the
+; CPU pushed, call the Rust handler with a pointer to the whole thing (a
+; `trap::TrapFrame`) and restore whatever it left there.
+;
+; Some exceptions push an error code, the entry points of the others push a
+; zero in its place so that every `TrapFrame` looks the same. The CPU aligns
+; the stack to 16 bytes before pushing its frame, with the error code and
+; our fifteen registers it's 8 bytes off for the call.
+
+section .text
+
+bits 64
+
+global breakpoint_entry
+global debug_entry
+global nmi_entry
+global divide_error_entry
+global overflow_entry
+global bound_range_exceeded_entry
+global invalid_opcode_entry
+global device_not_available_entry
+global double_fault_entry
+global invalid_tss_entry
+global segment_not_present_entry
+global stack_segment_fault_entry
+global general_protection_fault_entry
+global page_fault_entry
+global x87_floating_point_entry
+global alignment_check_entry
+global machine_check_entry
+global simd_floating_point_entry
+global virtualization_entry
+global security_exception_entry
+
+extern breakpoint_trap
+extern debug_trap
+extern nmi_trap
+extern divide_error_trap
+extern overflow_trap
+extern bound_range_exceeded_trap
+extern invalid_opcode_trap
+extern device_not_available_trap
+extern double_fault_trap
+extern invalid_tss_trap
+extern segment_not_present_trap
+extern stack_segment_fault_trap
+extern general_protection_fault_trap
+extern page_fault_trap
+extern x87_floating_point_trap
+extern alignment_check_trap
+extern machine_check_trap
+extern simd_floating_point_trap
+extern virtualization_trap
+extern security_exception_trap
+
+; Save the registers, call the handler and return (the error code is on the stack.)
+%macro TRAP_CALL 1
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    cld
+    mov rdi, rsp
+    sub rsp, 8
+    call %1
+    add rsp, 8
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+
+    ; The error code.
+    add rsp, 8
+
+    iretq
+%endmacro
+
+; An exception without an error code.
+%macro TRAP_ENTRY 2
+%1:
+    push 0
+    TRAP_CALL %2
+%endmacro
+
+; An exception the CPU pushes an error code for.
+%macro TRAP_ENTRY_ERROR 2
+%1:
+    TRAP_CALL %2
+%endmacro
+
+TRAP_ENTRY breakpoint_entry, breakpoint_trap
+TRAP_ENTRY debug_entry, debug_trap
+TRAP_ENTRY nmi_entry, nmi_trap
+TRAP_ENTRY divide_error_entry, divide_error_trap
+TRAP_ENTRY overflow_entry, overflow_trap
+TRAP_ENTRY bound_range_exceeded_entry, bound_range_exceeded_trap
+TRAP_ENTRY invalid_opcode_entry, invalid_opcode_trap
+TRAP_ENTRY device_not_available_entry, device_not_available_trap
+TRAP_ENTRY_ERROR double_fault_entry, double_fault_trap
+TRAP_ENTRY_ERROR invalid_tss_entry, invalid_tss_trap
+TRAP_ENTRY_ERROR segment_not_present_entry, segment_not_present_trap
+TRAP_ENTRY_ERROR stack_segment_fault_entry, stack_segment_fault_trap
+TRAP_ENTRY_ERROR general_protection_fault_entry, general_protection_fault_trap
+TRAP_ENTRY_ERROR page_fault_entry, page_fault_trap
+TRAP_ENTRY x87_floating_point_entry, x87_floating_point_trap
+TRAP_ENTRY_ERROR alignment_check_entry, alignment_check_trap
+TRAP_ENTRY machine_check_entry, machine_check_trap
+TRAP_ENTRY simd_floating_point_entry, simd_floating_point_trap
+TRAP_ENTRY virtualization_entry, virtualization_trap
+TRAP_ENTRY_ERROR security_exception_entry, security_exception_trap
@@ -0,0 +1,267 @@
+//! Per-target log filtering, configured like `env_logger`.
+//!
+//! A filter is a comma separated list of directives, e.g.
+//! `info,kernel::pci=trace,mem=debug`:
+//!
+//! * `<level>` sets the level of targets no other directive matches
+//! * `<target>=<level>` sets the level of `target` and the modules below it
+//! * `<target>` on its own enables every level of `target`
+//!
+//! The longest matching target wins. The filter is taken from the `log=`
+//! argument of the kernel command line and can be changed at runtime.
+
+use core::fmt;
+
+use log::{Level, LevelFilter};
+use spin::RwLock;
+
+/// The most directives with a target a filter holds.
+const MAX_DIRECTIVES: usize = 16;
+
+/// The longest target a directive can name.
+const MAX_TARGET: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterError<'a> {
+    UnknownLevel(&'a str),
+    TargetTooLong(&'a str),
+    TooManyDirectives,
+}
+
+impl fmt::Display for FilterError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterError::UnknownLevel(level) => write!(f, "unknown log level {:?}", level),
+            FilterError::TargetTooLong(target) => write!(f, "log target {:?} is too long", target),
+            FilterError::TooManyDirectives => write!(f, "more than {:?} log directives", MAX_DIRECTIVES),
+        }
+    }
+}
+
+fn parse_level(level: &str) -> Option<LevelFilter> {
+    let filters = [
+        ("off", LevelFilter::Off),
+        ("error", LevelFilter::Error),
+        ("warn", LevelFilter::Warn),
+        ("info", LevelFilter::Info),
+        ("debug", LevelFilter::Debug),
+        ("trace", LevelFilter::Trace),
+    ];
+
+    filters
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(level))
+        .map(|&(_, filter)| filter)
+}
+
+// -- Directive
+
+#[derive(Clone, Copy)]
+struct Directive {
+    target: [u8; MAX_TARGET],
+    length: usize,
+    level: LevelFilter,
+}
+
+impl Directive {
+    fn new(target: &str, level: LevelFilter) -> Result<Self, FilterError<'_>> {
+        if target.len() > MAX_TARGET {
+            return Err(FilterError::TargetTooLong(target));
+        }
+
+        let mut directive = Self {
+            target: [0; MAX_TARGET],
+            length: target.len(),
+            level,
+        };
+
+        directive.target[..target.len()].copy_from_slice(target.as_bytes());
+
+        Ok(directive)
+    }
+
+    #[inline]
+    fn target(&self) -> &str {
+        core::str::from_utf8(&self.target[..self.length]).unwrap_or("")
+    }
+
+    /// Is `target` the directive's target or a module below it?
+    fn matches(&self, target: &str) -> bool {
+        let name = &self.target[..self.length];
+        let target = target.as_bytes();
+
+        target.starts_with(name) && (target.len() == name.len() || target[name.len()..].starts_with(b"::"))
+    }
+}
+
+// -- LogFilter
+
+#[derive(Clone, Copy)]
+pub struct LogFilter {
+    default: LevelFilter,
+    directives: [Option<Directive>; MAX_DIRECTIVES],
+}
+
+impl LogFilter {
+    /// Let through everything up to `level`, whatever the target.
+    pub const fn new(level: LevelFilter) -> Self {
+        Self {
+            default: level,
+            directives: [None; MAX_DIRECTIVES],
+        }
+    }
+
+    /// Parse a filter, targets without a directive start at `default`.
+    pub fn parse(spec: &str, default: LevelFilter) -> Result<Self, FilterError<'_>> {
+        let mut filter = Self::new(default);
+
+        for directive in spec.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            match directive.find('=') {
+                Some(idx) => {
+                    let level = &directive[(idx + 1)..];
+                    let level = parse_level(level).ok_or(FilterError::UnknownLevel(level))?;
+
+                    filter.set_level(&directive[..idx], level)?;
+                }
+
+                None => match parse_level(directive) {
+                    Some(level) => filter.default = level,
+                    None => filter.set_level(directive, LevelFilter::Trace)?,
+                },
+            }
+        }
+
+        Ok(filter)
+    }
+
+    /// The level of targets no directive matches.
+    pub fn default_level(&self) -> LevelFilter {
+        self.default
+    }
+
+    pub fn set_default_level(&mut self, level: LevelFilter) {
+        self.default = level;
+    }
+
+    /// Set (or replace) the directive of `target`.
+    pub fn set_level<'a>(&mut self, target: &'a str, level: LevelFilter) -> Result<(), FilterError<'a>> {
+        let directive = Directive::new(target, level)?;
+
+        let existing = self.directives.iter().position(|slot| match slot {
+            Some(existing) => existing.target() == target,
+            None => false,
+        });
+
+        let slot = match existing.or_else(|| self.directives.iter().position(Option::is_none)) {
+            Some(idx) => &mut self.directives[idx],
+            None => return Err(FilterError::TooManyDirectives),
+        };
+
+        *slot = Some(directive);
+
+        Ok(())
+    }
+
+    /// Drop the directive of `target`, it goes back to the default level.
+    pub fn clear_level(&mut self, target: &str) {
+        for slot in self.directives.iter_mut() {
+            if slot.map_or(false, |directive| directive.target() == target) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// The most verbose level let through for `target`.
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .flatten()
+            .filter(|directive| directive.matches(target))
+            .max_by_key(|directive| directive.length)
+            .map_or(self.default, |directive| directive.level)
+    }
+
+    #[inline]
+    pub fn enabled(&self, level: Level, target: &str) -> bool {
+        level <= self.level(target)
+    }
+
+    /// The most verbose level any target gets, for `log::set_max_level`.
+    pub fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .flatten()
+            .map(|directive| directive.level)
+            .fold(self.default, LevelFilter::max)
+    }
+}
+
+/// Formats as a filter `LogFilter::parse` accepts.
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default)?;
+
+        for directive in self.directives.iter().flatten() {
+            write!(f, ",{}={}", directive.target(), directive.level)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("LogFilter").field(&format_args!("{}", self)).finish()
+    }
+}
+
+// -- Global filter
+
+static FILTER: RwLock<LogFilter> = RwLock::new(LogFilter::new(LevelFilter::Info));
+
+/// Is a record of `level` for `target` let through?
+///
+/// Records are let through while the filter is being changed.
+#[inline]
+pub(crate) fn enabled(level: Level, target: &str) -> bool {
+    FILTER.try_read().map_or(true, |filter| filter.enabled(level, target))
+}
+
+/// The filter in use.
+pub fn current() -> LogFilter {
+    *FILTER.read()
+}
+
+/// Change the filter, e.g. from a debug shell.
+pub fn update(f: impl FnOnce(&mut LogFilter)) {
+    let mut filter = FILTER.write();
+
+    f(&mut filter);
+
+    log::set_max_level(filter.max_level());
+}
+
+/// Replace the filter with `filter`.
+pub fn set(filter: LogFilter) {
+    update(|current| *current = filter)
+}
+
+/// Replace the filter with the one in the `log=` argument of `command_line`, if it has one.
+///
+/// Targets it doesn't mention stay at the current default level.
+pub fn configure_from_command_line(command_line: &str) -> Result<Option<LogFilter>, FilterError<'_>> {
+    let spec = match command_line
+        .split_whitespace()
+        .filter_map(|argument| argument.strip_prefix("log="))
+        .last()
+    {
+        Some(spec) => spec,
+        None => return Ok(None),
+    };
+
+    let filter = LogFilter::parse(spec, current().default_level())?;
+
+    set(filter);
+
+    Ok(Some(filter))
+}
@@ -0,0 +1,513 @@
+//! The kernel logger, every record goes into an in-memory ring buffer first.
+//!
+//! Logging never waits on a lock: a record is formatted on the stack and
+//! copied into the next slot of `RING`. Every slot carries a stamp (like the
+//! clock's `Timekeeper`) so a reader can tell a complete record from one that
+//! is still being written or was already overwritten.
+//!
+//! Sinks (serial, console) drain the ring at their own pace, each from its
+//! own cursor: inline while booting and from `DrainLog` once the executor is
+//! running. A sink that falls a whole ring behind is told how many records it
+//! missed, and the ring stays readable afterwards with `history` (`dmesg`.)
+
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Write},
+    future::Future,
+    pin::Pin,
+    sync::atomic::{fence, AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use log::{Level, Log, Metadata, Record};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{clock, log_filter, percpu};
+
+/// The most log sinks that can be installed.
+const MAX_SINKS: usize = 4;
+
+/// How many records the ring buffer keeps.
+const SLOTS: usize = 512;
+
+/// Bytes of target and message a record keeps, the rest is cut off.
+const TEXT_SIZE: usize = 192;
+
+/// The most of `TEXT_SIZE` the target may take up.
+const MAX_TARGET: usize = 48;
+
+// -- LogRecord
+
+/// A record as kept in the ring buffer.
+#[derive(Clone, Copy)]
+pub struct LogRecord {
+    sequence: u64,
+    nanos: u64,
+    cpu: u32,
+
+    /// A `Level` as `usize`, only plain data may be read from a slot.
+    level: u8,
+
+    /// Is the message cut off?
+    truncated: u8,
+
+    target_length: u8,
+    length: u16,
+    text: [u8; TEXT_SIZE],
+}
+
+/// Appends to a byte buffer, cutting off (on a character boundary) what doesn't fit.
+struct TextWriter<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+    truncated: bool,
+}
+
+impl<'a> Write for TextWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(self.buffer.len() - self.length);
+
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        self.buffer[self.length..self.length + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.length += end;
+        self.truncated |= end < s.len();
+
+        Ok(())
+    }
+}
+
+impl LogRecord {
+    const EMPTY: Self = Self {
+        sequence: 0,
+        nanos: 0,
+        cpu: 0,
+        level: Level::Trace as u8,
+        truncated: 0,
+        target_length: 0,
+        length: 0,
+        text: [0; TEXT_SIZE],
+    };
+
+    fn new(level: Level, target: &str, args: fmt::Arguments) -> Self {
+        let mut record = Self {
+            nanos: clock::monotonic_nanos(),
+            cpu: if percpu::is_ready() { percpu::current_cpu() as u32 } else { 0 },
+            level: level as u8,
+            ..Self::EMPTY
+        };
+
+        let mut writer = TextWriter {
+            buffer: &mut record.text[..MAX_TARGET],
+            length: 0,
+            truncated: false,
+        };
+
+        let _ = writer.write_str(target);
+        let target_length = writer.length;
+
+        let mut writer = TextWriter {
+            buffer: &mut record.text[target_length..],
+            length: 0,
+            truncated: false,
+        };
+
+        let _ = writer.write_fmt(args);
+        let (length, truncated) = (writer.length, writer.truncated);
+
+        record.target_length = target_length as u8;
+        record.length = (target_length + length) as u16;
+        record.truncated = truncated as u8;
+        record
+    }
+
+    /// The position of the record in the log, counting from 0 at boot.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// When it was logged, on the monotonic clock.
+    pub fn timestamp(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+
+    /// The processor that logged it.
+    pub fn cpu(&self) -> u32 {
+        self.cpu
+    }
+
+    pub fn level(&self) -> Level {
+        match self.level {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+
+    /// The target of the record, its module path unless it was given one.
+    pub fn target(&self) -> &str {
+        core::str::from_utf8(&self.text[..self.target_length as usize]).unwrap_or("?")
+    }
+
+    pub fn message(&self) -> &str {
+        core::str::from_utf8(&self.text[self.target_length as usize..self.length as usize]).unwrap_or("?")
+    }
+
+    /// Was the message too long to keep all of it?
+    pub fn is_truncated(&self) -> bool {
+        self.truncated != 0
+    }
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let timestamp = self.timestamp();
+
+        write!(
+            f,
+            "[{:>5}.{:06}] cpu{} {:<5} {}: {}",
+            timestamp.as_secs(),
+            timestamp.subsec_micros(),
+            self.cpu,
+            self.level(),
+            self.target(),
+            self.message()
+        )?;
+
+        if self.is_truncated() {
+            f.write_str(" [...]")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LogRecord")
+            .field("sequence", &self.sequence)
+            .field("timestamp", &self.timestamp())
+            .field("cpu", &self.cpu)
+            .field("level", &self.level())
+            .field("target", &self.target())
+            .field("message", &self.message())
+            .finish()
+    }
+}
+
+// -- Ring
+
+struct Slot {
+    /// 0 when never written, `2 * sequence + 1` while record `sequence` is
+    /// being written and `2 * sequence + 2` once it's complete.
+    stamp: AtomicU64,
+    record: UnsafeCell<LogRecord>,
+}
+
+const EMPTY_SLOT: Slot = Slot {
+    stamp: AtomicU64::new(0),
+    record: UnsafeCell::new(LogRecord::EMPTY),
+};
+
+enum Read {
+    Record(LogRecord),
+
+    /// Not written yet.
+    Pending,
+
+    /// Overwritten by a newer record.
+    Lost,
+}
+
+struct Ring {
+    /// The sequence number of the next record.
+    head: AtomicU64,
+    slots: [Slot; SLOTS],
+}
+
+unsafe impl Sync for Ring {}
+
+static RING: Ring = Ring {
+    head: AtomicU64::new(0),
+    slots: [EMPTY_SLOT; SLOTS],
+};
+
+impl Ring {
+    #[inline]
+    fn head(&self) -> u64 {
+        self.head.load(Ordering::Acquire)
+    }
+
+    /// The sequence number of the oldest record that can still be in the ring.
+    #[inline]
+    fn oldest(&self) -> u64 {
+        self.head().saturating_sub(SLOTS as u64)
+    }
+
+    fn push(&self, mut record: LogRecord) {
+        // Nothing on this processor may log into the ring while we hold a slot.
+        without_interrupts(|| {
+            let sequence = self.head.fetch_add(1, Ordering::AcqRel);
+            let slot = &self.slots[sequence as usize % SLOTS];
+            let writing = 2 * sequence + 1;
+
+            loop {
+                let stamp = slot.stamp.load(Ordering::Acquire);
+
+                // Lapped before we got here, readers count the record as lost.
+                if stamp >= writing {
+                    return;
+                }
+
+                // A record from the previous lap is still being written (on another processor.)
+                if stamp % 2 == 1 {
+                    core::sync::atomic::spin_loop_hint();
+                    continue;
+                }
+
+                if slot
+                    .stamp
+                    .compare_exchange_weak(stamp, writing, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    break;
+                }
+            }
+
+            record.sequence = sequence;
+
+            unsafe { slot.record.get().write_volatile(record) };
+            slot.stamp.store(writing + 1, Ordering::Release);
+        })
+    }
+
+    fn read(&self, sequence: u64) -> Read {
+        let slot = &self.slots[sequence as usize % SLOTS];
+        let complete = 2 * sequence + 2;
+        let stamp = slot.stamp.load(Ordering::Acquire);
+
+        if stamp < complete {
+            return Read::Pending;
+        }
+
+        if stamp > complete {
+            return Read::Lost;
+        }
+
+        let record = unsafe { slot.record.get().read_volatile() };
+
+        // The copy is only good if nobody started overwriting it meanwhile.
+        fence(Ordering::Acquire);
+
+        if slot.stamp.load(Ordering::Relaxed) == stamp {
+            Read::Record(record)
+        } else {
+            Read::Lost
+        }
+    }
+}
+
+/// Call `f` with every record still in the ring buffer, oldest first.
+pub fn history(mut f: impl FnMut(&LogRecord)) {
+    let head = RING.head();
+
+    for sequence in RING.oldest()..head {
+        if let Read::Record(record) = RING.read(sequence) {
+            f(&record);
+        }
+    }
+}
+
+/// How many records were logged since boot.
+pub fn records_logged() -> u64 {
+    RING.head()
+}
+
+// -- Sinks
+
+/// Somewhere records are drained to.
+pub(crate) trait Sink: Sync {
+    /// Does the sink want `record`? Records it doesn't want are skipped.
+    fn enabled(&self, record: &LogRecord) -> bool {
+        let _ = record;
+        true
+    }
+
+    /// Write `record`, `false` when the sink is busy: it's retried on the next drain.
+    fn write(&self, record: &LogRecord) -> bool;
+}
+
+#[derive(Clone, Copy)]
+struct SinkState {
+    sink: &'static dyn Sink,
+
+    /// The sequence number of the next record to hand to the sink.
+    next: u64,
+
+    /// Records the sink missed and hasn't been told about yet.
+    lost: u64,
+}
+
+static SINKS: Mutex<[Option<SinkState>; MAX_SINKS]> = Mutex::new([None; MAX_SINKS]);
+
+/// Once `DrainLog` runs records are no longer drained by whoever logged them.
+static DRAIN_TASK: AtomicBool = AtomicBool::new(false);
+
+impl SinkState {
+    fn drain(&mut self, head: u64) {
+        let oldest = head.saturating_sub(SLOTS as u64);
+
+        if self.next < oldest {
+            self.lost += oldest - self.next;
+            self.next = oldest;
+        }
+
+        while self.next < head {
+            let record = match RING.read(self.next) {
+                Read::Record(record) => record,
+
+                // Keep the order, pick it up on the next drain.
+                Read::Pending => break,
+
+                Read::Lost => {
+                    self.lost += 1;
+                    self.next += 1;
+                    continue;
+                }
+            };
+
+            if !self.report_lost() {
+                return;
+            }
+
+            if self.sink.enabled(&record) && !self.sink.write(&record) {
+                return;
+            }
+
+            self.next += 1;
+        }
+
+        self.report_lost();
+    }
+
+    /// Tell the sink how many records it missed, `false` while it's busy.
+    fn report_lost(&mut self) -> bool {
+        if self.lost == 0 {
+            return true;
+        }
+
+        let notice = LogRecord {
+            sequence: self.next,
+            ..LogRecord::new(
+                Level::Warn,
+                module_path!(),
+                format_args!("(LOG) {:?} records were lost before this sink drained them", self.lost),
+            )
+        };
+
+        let written = self.sink.write(&notice);
+
+        if written {
+            self.lost = 0;
+        }
+
+        written
+    }
+}
+
+/// Install another sink, returns `false` when there's no room for it.
+///
+/// The sink starts with the oldest record still in the ring buffer.
+pub(crate) fn add_sink(sink: &'static dyn Sink) -> bool {
+    let mut sinks = SINKS.lock();
+
+    match sinks.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(SinkState {
+                sink,
+                next: RING.oldest(),
+                lost: 0,
+            });
+
+            true
+        }
+
+        None => false,
+    }
+}
+
+/// Hand every sink the records it hasn't seen, `false` if somebody else is draining.
+pub(crate) fn drain() -> bool {
+    let mut sinks = match SINKS.try_lock() {
+        Some(sinks) => sinks,
+        None => return false,
+    };
+
+    let head = RING.head();
+
+    for state in sinks.iter_mut().flatten() {
+        state.drain(head);
+    }
+
+    true
+}
+
+/// Go back to draining records inline, e.g. when nothing else will run again.
+pub(crate) fn set_synchronous() {
+    DRAIN_TASK.store(false, Ordering::SeqCst);
+}
+
+// -- DrainLog
+
+/// An executor task that drains the ring buffer into the sinks.
+#[derive(Debug, Default)]
+pub(super) struct DrainLog;
+
+impl Future for DrainLog {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        DRAIN_TASK.store(true, Ordering::SeqCst);
+
+        drain();
+
+        cx.waker().wake_by_ref();
+
+        Poll::Pending
+    }
+}
+
+// -- Logger
+
+/// The global `log::Log`, puts every record the filter lets through in the ring buffer.
+pub(crate) struct Logger;
+
+pub(crate) static LOGGER: Logger = Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        log_filter::enabled(metadata.level(), metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        RING.push(LogRecord::new(record.level(), record.target(), *record.args()));
+
+        if !DRAIN_TASK.load(Ordering::SeqCst) {
+            drain();
+        }
+    }
+
+    fn flush(&self) {
+        drain();
+    }
+}
@@ -1,7 +1,7 @@
-use core::{intrinsics::transmute, marker::PhantomData, mem::MaybeUninit, ops::Range};
+use core::{future::Future, intrinsics::transmute, marker::PhantomData, mem::MaybeUninit, ops::Range, pin::Pin, task::{Context, Poll}};
 
 use macros::once;
-use mem::{PhysFrameAlloc, PhysFrameCursor, PhysicalMemory, boot_frame::PhysFrameIter, chunks::MemoryChunks};
+use mem::{BootMemory, BootMemoryMap, MemoryManager, PhysFrameAlloc, PhysFrameCursor, PhysicalMemory, ZeroedFrames, boot_frame::PhysFrameIter, chunks::MemoryChunks, fault::{self, FaultPoint}, free_list::FreeFrameList, memory_map::RegionKind, reclaim::{frames_within, BootMemoryKind}, zeroed::{zero_frame, ZeroPoolStats}};
 
 use multiboot2::BootInformation;
 use x86_64::{
@@ -35,6 +35,24 @@ static mut PDT_SPACE: AlignedHole<4096> = AlignedHole([0u8; 4096]);
 
 const STACK_SIZE: usize = 0x8000 * 4; // 32KiB
 
+/// The virtual address where physical memory is mapped (we're identity mapped.)
+pub(super) const PHYSICAL_MEMORY_OFFSET: u64 = 0x00;
+
+/// How many frames the idle refill task zeroes before yielding.
+const ZEROED_REFILL_BATCH: usize = 8;
+
+/// The page in low memory that application processors start executing in.
+pub(super) const AP_TRAMPOLINE: usize = 0x8000;
+
+/// Where the BIOS data area keeps the (real mode segment of the) EBDA.
+const BDA_EBDA_SEGMENT: usize = 0x40E;
+
+/// Everything below this is identity mapped by the bootstrap code.
+pub(super) const IDENTITY_MAPPED_LIMIT: usize = 0x4000_0000;
+
+const ONE_MIB: usize = 0x100000;
+const PAGE_SIZE: usize = 0x1000;
+
 /// Yes... this is the stack, no flash photography please.
 #[no_mangle]
 #[link_section = ".bss.stack"]
@@ -55,7 +73,7 @@ static mut KERNEL_STACK: AlignedHole<STACK_SIZE> = AlignedHole([0u8; STACK_SIZE]
 /// generating physical frames of a certain size (`0x1000` for instance)
 /// allowing us to avoid overwriting the space where our ELF sections are.
 pub(super) fn collect_elf_sections_into_chunks(info: &BootInformation) -> Option<(u64, u64)> {
-    let mut it = info.elf_sections_tag()?.sections();
+    let mut it = info.elf_sections_tag()?.sections().filter(|section| section.is_allocated());
 
     let (start, mut end) = {
         let first = it.next()?;
@@ -80,12 +98,147 @@ pub(super) fn collect_elf_sections_into_chunks(info: &BootInformation) -> Option
     Some((start, end))
 }
 
+/// Collect the parts of the first megabyte that are available and unneeded.
+///
+/// Low memory is kept out of the frame allocator while booting, afterwards
+/// only the AP trampoline, the EBDA and the first page (real mode IVT and the
+/// BIOS data area, also nice to keep as a null guard) have to stay put.
+fn collect_low_memory(available: &PhysicalMemory) -> PhysicalMemory {
+    let mut low = available.clone();
+
+    low.poke((ONE_MIB, usize::MAX));
+    low.poke((0usize, PAGE_SIZE));
+    low.poke((AP_TRAMPOLINE, AP_TRAMPOLINE + PAGE_SIZE));
+
+    // SAFETY: The BIOS data area is identity mapped and always present.
+    let ebda = (unsafe { *(BDA_EBDA_SEGMENT as *const u16) } as usize) << 4;
+
+    if (0x80000..0xA0000).contains(&ebda) {
+        low.poke((ebda & !(PAGE_SIZE - 1), 0xA0000usize));
+    } else {
+        log::warn!("(MEMORY) Suspicious EBDA address {:#x}, keeping 512KiB..640KiB", ebda);
+        low.poke((0x80000usize, 0xA0000usize));
+    }
+
+    low
+}
+
+/// Build the sanitised physical memory map.
+///
+/// On top of the firmware's areas the kernel image, the multiboot modules,
+/// the multiboot information itself and every ACPI table are carved out.
+fn sanitized_memory_map(info: &BootInformation) -> BootMemoryMap {
+    let mut map = BootMemoryMap::new();
+
+    map.add_boot_info(info);
+
+    let (start, end) = collect_elf_sections_into_chunks(&info).expect("No ELF sections found!");
+    map.add(start, end, RegionKind::Kernel);
+
+    // GRUB loads the unallocated sections (`.symtab`, `.strtab`...) wherever it likes.
+    if let Some(sections) = info.elf_sections_tag() {
+        for section in sections.sections().filter(|section| !section.is_allocated() && section.size() != 0) {
+            map.add(section.start_address(), section.end_address(), RegionKind::Kernel);
+        }
+    }
+
+    map.add(info.start_address() as u64, info.end_address() as u64, RegionKind::BootInfo);
+
+    collect_acpi_tables(info, |start, end| map.add(start, end, RegionKind::AcpiTables));
+
+    map.sanitize();
+    map
+}
+
+/// Walk the RSDT (or XSDT) from the multiboot RSDP tags calling `f(start, end)`
+/// for every table, including the DSDT.
+///
+/// Tables outside of the identity mapped region are skipped (with a warning.)
+fn collect_acpi_tables(info: &BootInformation, mut f: impl FnMut(u64, u64)) {
+    /// Offset of the `length` field in an SDT header.
+    const SDT_LENGTH: usize = 4;
+
+    /// Size of the SDT header, the RSDT/XSDT entries come right after it.
+    const SDT_HEADER_SIZE: usize = 36;
+
+    // SAFETY: Only ever called with addresses checked by `mapped` below.
+    unsafe fn read<T: Copy>(address: usize) -> T {
+        core::ptr::read_unaligned(address as *const T)
+    }
+
+    let mapped = |address: usize| {
+        let ok = address != 0 && address + SDT_HEADER_SIZE <= IDENTITY_MAPPED_LIMIT;
+
+        if !ok {
+            log::warn!("(MEMORY) ACPI table at {:#x} is not mapped, skipping it.", address);
+        }
+
+        ok
+    };
+
+    let (sdt, entry_size) = if let Some(rsdp) = info.rsdp_v2_tag() {
+        (rsdp.xsdt_address(), 8)
+    } else if let Some(rsdp) = info.rsdp_v1_tag() {
+        (rsdp.rsdt_address(), 4)
+    } else {
+        log::warn!("(MEMORY) No RSDP tag found, ACPI tables are not carved out.");
+        return;
+    };
+
+    if !mapped(sdt) {
+        return;
+    }
+
+    let mut table = |address: usize| -> Option<usize> {
+        if !mapped(address) {
+            return None;
+        }
+
+        let length = unsafe { read::<u32>(address + SDT_LENGTH) } as usize;
+        f(address as u64, (address + length) as u64);
+
+        Some(length)
+    };
+
+    let length = match table(sdt) {
+        Some(length) => length,
+        None => return,
+    };
+
+    for entry in ((sdt + SDT_HEADER_SIZE)..(sdt + length)).step_by(entry_size) {
+        let address = match entry_size {
+            8 => unsafe { read::<u64>(entry) as usize },
+            _ => unsafe { read::<u32>(entry) as usize },
+        };
+
+        let length = match table(address) {
+            Some(length) => length,
+            None => continue,
+        };
+
+        // The DSDT is only referenced through the FADT.
+        if unsafe { read::<[u8; 4]>(address) } == *b"FACP" {
+            let x_dsdt = if length >= 148 { unsafe { read::<u64>(address + 140) } } else { 0 };
+
+            let dsdt = match x_dsdt {
+                0 => unsafe { read::<u32>(address + 40) as usize },
+                x_dsdt => x_dsdt as usize,
+            };
+
+            table(dsdt);
+        }
+    }
+}
+
 /// Used to (de)allocate physframes and (un)map pages.
 #[derive(Debug, Default)]
 pub(super) struct VirtualMemoryManager {
     memory: PhysicalMemory,
     page_table: Option<OffsetPageTable<'static>>,
     physframe_cursor: PhysFrameCursor,
+    zeroed: ZeroedFrames,
+    free_frames: FreeFrameList,
+    boot_memory: BootMemory,
 }
 
 impl VirtualMemoryManager {
@@ -97,11 +250,14 @@ impl VirtualMemoryManager {
                 chunk_idx: 0,
                 section_idx: 0,
             },
+            zeroed: ZeroedFrames::new(),
+            free_frames: FreeFrameList::new(),
+            boot_memory: BootMemory::new(),
         }
     }
 }
 
-impl ::mem::MemoryManager for VirtualMemoryManager {
+impl MemoryManager for VirtualMemoryManager {
     fn identity_map(&mut self, address: usize, flags: u64) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>> {
         let virt = VirtAddr::new(address as u64);
         let phys = PhysAddr::new(address as u64);
@@ -109,14 +265,12 @@ impl ::mem::MemoryManager for VirtualMemoryManager {
         let page = Page::containing_address(virt);
         let frame = PhysFrame::containing_address(phys);
 
-        let mut frame_allocator = PhysFrameAlloc {
-            memory: self.memory.clone(),
-            physframe_cursor: PhysFrameCursor::default(),
-        };
+        let mut frame_allocator = self.frame_allocator();
 
         unsafe { self.map_to(page, frame, flags, &mut frame_allocator) }
     }
     
+    #[track_caller]
     fn map_to(
         &mut self,
         page: Page<Size4KiB>,
@@ -124,6 +278,10 @@ impl ::mem::MemoryManager for VirtualMemoryManager {
         flags: u64,
         frame_allocator: &mut PhysFrameAlloc,
     ) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>> {
+        if fault::should_fail(FaultPoint::MapTo) {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+
         let mut table = self.page_table.as_mut().unwrap();
 
         let flags = unsafe { PageTableFlags::from_bits_unchecked(flags) };
@@ -135,56 +293,170 @@ impl ::mem::MemoryManager for VirtualMemoryManager {
         result
     }
 
-    fn unmap(&mut self, page: Page<Size4KiB>) {
-        todo!()
+    fn unmap(&mut self, page: Page<Size4KiB>) -> Option<PhysFrame> {
+        let table = self.page_table.as_mut().unwrap();
+
+        let (frame, flush) = table.unmap(page).ok()?;
+        flush.flush();
+
+        Some(frame)
+    }
+
+    unsafe fn free_frame(&mut self, frame: PhysFrame) {
+        self.free_frames.push(frame, VirtAddr::new(PHYSICAL_MEMORY_OFFSET));
     }
 
-    fn map(
-        &mut self,
-        page: Page<Size4KiB>,
-        flags: u64,
-    ) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>> {
-        let mut frame_allocator = PhysFrameAlloc {
+    fn frame_allocator(&self) -> PhysFrameAlloc {
+        PhysFrameAlloc {
             memory: self.memory.clone(),
-            physframe_cursor: PhysFrameCursor::default(),
-        };
+            physframe_cursor: self.physframe_cursor.clone(),
+        }
+    }
+
+    fn alloc_frame(&mut self) -> Option<PhysFrame> {
+        if fault::should_fail(FaultPoint::FrameAlloc) {
+            return None;
+        }
+
+        // Recycle frames that have been handed back before touching fresh memory.
+        if let Some(frame) = unsafe { self.free_frames.pop(VirtAddr::new(PHYSICAL_MEMORY_OFFSET)) } {
+            return Some(frame);
+        }
+
+        let mut frame_allocator = self.frame_allocator();
+        let frame = frame_allocator.next_frame();
+
+        self.physframe_cursor = frame_allocator.physframe_cursor;
+
+        frame
+    }
+
+    fn alloc_zeroed_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.zeroed.take() {
+            return Some(frame);
+        }
+
+        let frame = self.alloc_frame()?;
+
+        // SAFETY: The frame was just allocated so nobody else is using it.
+        unsafe { zero_frame(frame, VirtAddr::new(PHYSICAL_MEMORY_OFFSET)) };
+
+        Some(frame)
+    }
+
+    fn refill_zeroed_frames(&mut self, budget: usize) -> usize {
+        let mut count = 0;
+
+        while count < budget && !self.zeroed.is_full() {
+            let frame = match self.alloc_frame() {
+                Some(frame) => frame,
+                None => break,
+            };
 
-        let mut frame = frame_allocator
-            .allocate_frame()
-            .expect("Failed to allocate a frame...");
+            // SAFETY: The frame was just allocated, zeroed, and is only
+            // reachable through the pool.
+            unsafe {
+                zero_frame(frame, VirtAddr::new(PHYSICAL_MEMORY_OFFSET));
+                self.zeroed.push(frame).expect("Zeroed frame pool overflowed.");
+            }
 
-        self.map_to(page, frame, flags, &mut frame_allocator)
+            count += 1;
+        }
+
+        count
+    }
+
+    fn zeroed_frame_stats(&self) -> ZeroPoolStats {
+        self.zeroed.stats()
+    }
+
+    fn reclaim(&mut self, kind: BootMemoryKind) -> usize {
+        let mut boot_memory = core::mem::take(&mut self.boot_memory);
+        let mut count = 0;
+
+        boot_memory.take(kind, |start, end| {
+            log::trace!("(MEMORY) Reclaiming {:?} memory {:#x}..{:#x}", kind, start, end);
+
+            for frame in frames_within(start, end) {
+                // SAFETY: The caller promises nothing lives here anymore.
+                unsafe { self.free_frame(frame) };
+                count += 1;
+            }
+        });
+
+        self.boot_memory = boot_memory;
+
+        count
     }
 
     #[once]
     fn initialize(&mut self, info: &BootInformation) {
-        let mut buf: PhysicalMemory = info
-            .memory_map_tag()
-            .expect("Memory map tag required.")
-            .memory_areas()
-            .collect();
+        let map = sanitized_memory_map(info);
 
-        // First megabyte of memory normally contains stuff we don't want to
-        // risk immedietly overwriting...
+        log::info!("(MEMORY) Physical memory map:");
 
-        log::trace!("\t{:?}", buf);
+        for region in map.iter() {
+            log::info!("(MEMORY) \t{}", region);
+        }
 
-        let hole = buf.poke((0 as usize, 0x100000 as usize));
-        log::trace!("1MIB:\t\t{:?}", hole);
+        for kind in RegionKind::ALL.iter() {
+            let total = map.total(*kind);
 
-        log::trace!("\t{:?}", buf);
+            if total > 0 {
+                log::info!("(MEMORY) \t{:>12}: {:>10} KiB", kind.name(), total / 1024);
+            }
+        }
+
+        let mut buf: PhysicalMemory = map.regions_of(RegionKind::Available).collect();
 
-        {
-            // Also, poke out the areas in memory where our ELF sections have been
-            // placed... we don't want to overwrite them (UB)
+        // Anything past the capacity of `PhysicalMemory` is left out.
+        let available = map.regions_of(RegionKind::Available).count();
 
-            let (start, end) =
-                collect_elf_sections_into_chunks(&info).expect("No ELF sections found!");
+        if available > buf.capacity() {
+            let dropped: usize = map
+                .regions_of(RegionKind::Available)
+                .skip(buf.capacity())
+                .map(|(start, end)| end - start)
+                .sum();
 
-            let hole = buf.poke((start as usize, end as usize));
-            log::trace!("ELF:\t\t{:?}", hole);
+            log::warn!(
+                "(MEMORY) Only {:?} of {:?} available regions fit, {:?} KiB go unused",
+                buf.capacity(),
+                available,
+                dropped / 1024
+            );
         }
 
+        // Remember ACPI reclaimable memory (and tables found outside of it),
+        // it's handed out once the tables have been parsed.
+        for (start, end) in map.regions_of(RegionKind::AcpiReclaimable) {
+            self.boot_memory.push(start, end, BootMemoryKind::Acpi);
+        }
+
+        for (start, end) in map.regions_of(RegionKind::AcpiTables) {
+            self.boot_memory.push(start, end, BootMemoryKind::Acpi);
+        }
+
+        // The multiboot information has to survive until the boot procedure
+        // is done with it.
+        for (start, end) in map.regions_of(RegionKind::BootInfo) {
+            self.boot_memory.push(start, end, BootMemoryKind::Bootloader);
+        }
+
+        // First megabyte of memory normally contains stuff we don't want to
+        // risk immedietly overwriting... most of it is reclaimed after boot.
+
+        let low_memory = collect_low_memory(&buf);
+
+        for idx in 0..low_memory.len() {
+            if let Some((start, end)) = low_memory.get(idx) {
+                self.boot_memory.push(start, end, BootMemoryKind::LowMemory);
+            }
+        }
+
+        let hole = buf.poke((0 as usize, ONE_MIB));
+        log::trace!("1MIB:\t\t{:?}", hole);
+
         log::trace!("\t{:?}", buf);
 
         // Update the internal buffer.
@@ -197,10 +469,57 @@ impl ::mem::MemoryManager for VirtualMemoryManager {
         let mut table = unsafe {
             OffsetPageTable::new(
                 transmute::<_, &mut PageTable>(&mut PML4_SPACE),
-                VirtAddr::new(0x00),
+                VirtAddr::new(PHYSICAL_MEMORY_OFFSET),
             )
         };
 
         self.page_table = Some(table);
     }
 }
+
+// -- RefillZeroedFrames
+
+/// An executor task that tops up the pre-zeroed frame pool while idle.
+///
+/// Every poll zeroes at most `ZEROED_REFILL_BATCH` frames and then yields so
+/// that other tasks get a turn, the task itself never completes.
+#[derive(Debug, Default)]
+pub(super) struct RefillZeroedFrames;
+
+impl Future for RefillZeroedFrames {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: Executor tasks are only ever polled from the boot processor.
+        let manager = unsafe { super::prelude::memory_manager_ref() };
+
+        if manager.refill_zeroed_frames(ZEROED_REFILL_BATCH) > 0 {
+            log::trace!("(MEMORY) Zeroed frame pool: {:?}", manager.zeroed_frame_stats());
+        }
+
+        cx.waker().wake_by_ref();
+
+        Poll::Pending
+    }
+}
+
+// -- Page attribute table
+
+const IA32_PAT: u32 = 0x277;
+
+/// The power-on PAT with PA1 (selected by `WRITE_THROUGH` alone) changed to
+/// write-combining, PA0..PA7 = WB, WC, UC-, UC, WB, WT, UC-, UC.
+const PAT_VALUE: u64 = 0x0007_0406_0007_0106;
+
+/// Page flags for a write-combined mapping (with `load_pat`.)
+pub(super) const WRITE_COMBINING: PageTableFlags = PageTableFlags::WRITE_THROUGH;
+
+/// Program the PAT of the executing processor, every processor has to agree.
+///
+/// # Safety
+///
+/// Mappings that used `WRITE_THROUGH` alone become write-combined.
+pub(super) unsafe fn load_pat() {
+    x86_64::registers::model_specific::Msr::new(IA32_PAT).write(PAT_VALUE);
+    x86_64::instructions::tlb::flush_all();
+}
@@ -1,11 +1,21 @@
 #![cfg(feature = "x86_64")]
 
+mod backtrace;
+mod clock;
+mod device;
+mod gdb;
 mod interrupts;
+mod log_filter;
+mod logger;
 mod serial_logger;
 
 pub type AllocatorT = usize;
 
 mod memory;
+mod percpu;
+mod smp;
+mod task;
+mod timer;
 
 pub mod prelude {
     use super::*;
@@ -32,27 +42,95 @@ pub mod prelude {
         VirtAddr,
     };
 
+    pub use super::backtrace::{symbolize, Backtrace};
+    pub use super::clock::realtime::{SystemTime, UNIX_EPOCH};
+    pub use super::clock::{
+        best_event, current_source, monotonic, set_event_handler, ClockError, ClockEvent, ClockEventFeatures, ClockSource,
+    };
+    pub use super::device::ps2::keyboard::{self, KeyboardConfig, KeyboardEvent, Layout, Leds, ScancodeSet, Typematic};
+    pub use super::device::ps2::mouse::{self, MouseButtons, MouseEvent};
+    pub use super::device::ps2::{self, Ps2Error};
+    pub use super::device::rtc::{self, DateTime};
+    pub use super::device::uart::{self, ComPort, FifoTrigger, Parity, StopBits, Uart, UartConfig, UartError};
+    pub use super::device::ioapic::{self, IoApicInfo, IsaOverride, Polarity, TriggerMode};
+    pub use super::interrupts::index::InterruptIndex;
+    pub use super::log_filter::{FilterError, LogFilter};
+    pub use super::logger::{records_logged, LogRecord};
+    pub use super::interrupts::irq::{self, IrqContext, IrqError, IrqHandle, IrqHandler, IrqReturn};
+    pub use super::percpu::{current_cpu, current_task, set_current_task, PerCpu};
+    pub use super::smp::{set_idle_handler, MAX_CPUS};
+    pub use super::task::{task_finished, task_spawned};
+
     /// Setup a logger and register it with `log::set_logger`.
+    ///
+    /// `level` is the default until `boot` reads the `log=` filter of the command line.
     pub fn install_logger(level: LevelFilter) {
-        let logger = serial_logger::SerialLogger::global_ref()
-            .expect("Failed to get a reference to the serial logger.");
+        device::uart::probe();
+        device::vga_text::initialize();
+
+        logger::add_sink(&serial_logger::SERIAL_LOGGER);
+        logger::add_sink(&device::console::CONSOLE_LOGGER);
+
+        log::set_logger(&logger::LOGGER).expect("Failed to set logger.");
+        log_filter::set(LogFilter::new(level));
+    }
+
+    /// Call `f` with every record still in the in-memory log, oldest first.
+    pub fn dmesg(f: impl FnMut(&LogRecord)) {
+        logger::history(f)
+    }
+
+    /// An executor task that drains the in-memory log into the serial line and console.
+    ///
+    /// Until it first runs records are drained by whoever logged them.
+    pub fn log_drain_task() -> impl core::future::Future<Output = ()> {
+        logger::DrainLog
+    }
+
+    /// The log filter in use, see `log_filter.rs` for the syntax.
+    pub fn log_filter() -> LogFilter {
+        log_filter::current()
+    }
 
-        log::set_logger(logger).expect("Failed to set logger.");
-        log::set_max_level(level);
+    /// Replace the log filter, e.g. `info,kernel::pci=trace,mem=debug`.
+    pub fn set_log_filter(spec: &str) -> Result<(), FilterError<'_>> {
+        let filter = LogFilter::parse(spec, log_filter::current().default_level())?;
+        log_filter::set(filter);
+        Ok(())
+    }
+
+    /// Change the level of one target (and the modules below it) in the log filter.
+    pub fn set_log_level<'a>(target: &'a str, level: LevelFilter) -> Result<(), FilterError<'a>> {
+        let mut result = Ok(());
+        log_filter::update(|filter| result = filter.set_level(target, level));
+        result
+    }
+
+    /// Write `args` to the console, see `kprint!`.
+    pub fn console_print(args: core::fmt::Arguments) {
+        device::console::print(args)
+    }
+
+    /// Only show records up to `level` on the console (`Info` by default.)
+    pub fn set_console_level(level: LevelFilter) {
+        device::console::set_level(level)
     }
 
     /// Panic handler stub for x86-64.
     pub fn panic_handler(info: &core::panic::PanicInfo) -> ! {
         x86_64::instructions::interrupts::disable();
 
+        // Nothing is going to run the drain task again.
+        logger::set_synchronous();
+
         log::error!("{:#?}\n", info);
 
-        use vga::colors::Color16;
-        use vga::writers::{Graphics640x480x16, GraphicsWriter};
-        let mode = Graphics640x480x16::new();
-        mode.set_mode();
-        mode.clear_screen(Color16::Red);
+        // Don't try again if unwinding is what panicked.
+        if backtrace::first_panic() {
+            backtrace::Backtrace::capture().log();
+        }
 
+        // Leave whatever the console shows alone, it may be all there is of the message.
         loop {
             x86_64::instructions::hlt()
         }
@@ -63,16 +141,207 @@ pub mod prelude {
         &mut MEMORY_MANAGER
     }
 
+    /// The backtrace of the caller, print it with `Backtrace::log` (or `Display`.)
+    #[inline(always)]
+    pub fn backtrace() -> Backtrace {
+        Backtrace::capture()
+    }
+
+    /// Can `current_cpu` and friends be used yet? Not before the per-CPU data is set up.
+    #[inline]
+    pub fn percpu_ready() -> bool {
+        percpu::is_ready()
+    }
+
+    /// The local APIC ID of the executing processor (`None` before the APIC is up.)
+    #[inline]
+    pub fn local_apic_id() -> Option<u32> {
+        device::apic::local_apic().map(|apic| apic.id())
+    }
+
+    /// Acknowledge the interrupt being serviced at the local APIC.
+    #[inline]
+    pub fn end_of_interrupt() {
+        if let Some(apic) = device::apic::local_apic() {
+            apic.end_of_interrupt();
+        }
+    }
+
+    /// Boot every application processor in `apic_ids` (the boot processor is skipped.)
+    ///
+    /// Returns how many of them came online, failures are logged.
+    pub fn start_application_processors(apic_ids: &[u32]) -> usize {
+        smp::start_application_processors(apic_ids)
+    }
+
+    /// How many processors are online.
+    pub fn cpus_online() -> usize {
+        smp::cpus_online()
+    }
+
+    /// Start the system tick at `freq` hertz (it only runs with interrupts enabled.)
+    pub fn start_timer(freq: usize) {
+        timer::initialize(freq)
+    }
+
+    /// Change the frequency of the system tick.
+    pub fn set_timer_frequency(freq: usize) {
+        timer::set_frequency(freq)
+    }
+
+    /// Call `hook` (in interrupt context) on every tick, e.g. for a scheduler.
+    pub fn set_tick_hook(hook: Option<fn()>) {
+        timer::set_tick_hook(hook)
+    }
+
+    /// Timer ticks since `start_timer`.
+    pub fn ticks() -> u64 {
+        timer::ticks()
+    }
+
+    /// Monotonic time since `start_timer`.
+    pub fn uptime() -> core::time::Duration {
+        timer::uptime()
+    }
+
+    /// Busy wait for (at least) `duration`.
+    pub fn delay(duration: core::time::Duration) {
+        timer::delay(duration)
+    }
+
+    /// Register the clock sources and events (needs `start_timer` first.)
+    ///
+    /// `hpet` is the physical address of the HPET registers, if ACPI has an HPET table.
+    pub fn initialize_clocks(hpet: Option<u64>) {
+        clock::initialize(hpet)
+    }
+
+    /// Monotonic nanoseconds from the best clock source, readable from any processor.
+    #[inline]
+    pub fn monotonic_nanos() -> u64 {
+        clock::monotonic_nanos()
+    }
+
+    /// Start keeping wall-clock time from the RTC (needs `initialize_clocks` first.)
+    ///
+    /// `century_register` is the CMOS index of the century from the FADT, if it has one.
+    pub fn initialize_wall_clock(century_register: Option<u8>) {
+        clock::realtime::initialize(century_register)
+    }
+
+    /// Bring up the PS/2 controller, the keyboard on its first port and the mouse on its second.
+    pub fn initialize_ps2(keyboard: KeyboardConfig) {
+        if let Err(err) = device::ps2::initialize() {
+            log::error!("(PS2) Controller initialisation failed: {:?}", err);
+            return;
+        }
+
+        if let Err(err) = device::ps2::keyboard::initialize(keyboard) {
+            log::warn!("(KEYBOARD) No keyboard: {:?}", err);
+        }
+
+        if let Err(err) = device::ps2::mouse::initialize() {
+            log::warn!("(MOUSE) No mouse: {:?}", err);
+        }
+    }
+
+    /// Serve the UARTs found at boot from their interrupts (needs interrupt routing set up.)
+    pub fn initialize_serial() {
+        device::uart::enable_interrupts()
+    }
+
+    /// Log to the UART on `port` (COM1 by default), or stop logging to serial.
+    pub fn set_serial_log_port(port: Option<ComPort>) {
+        serial_logger::SERIAL_LOGGER.set_port(port)
+    }
+
+    /// Run the GDB stub on `port` (see the `gdb` module), or stop running it.
+    pub fn set_gdb_port(port: Option<ComPort>) -> Result<(), UartError> {
+        match port {
+            Some(port) => gdb::enable(port),
+            None => {
+                gdb::disable();
+                Ok(())
+            }
+        }
+    }
+
+    /// Stop in the debugger (if the GDB stub is running), as if a breakpoint was hit.
+    #[inline(always)]
+    pub fn gdb_breakpoint() {
+        gdb::breakpoint()
+    }
+
+    /// Release boot-time memory of `kind` into the physical allocator.
+    ///
+    /// Nothing living in that memory may be used afterwards, i.e. only
+    /// reclaim `BootMemoryKind::Acpi` after the ACPI tables have been parsed.
+    pub fn reclaim_boot_memory(kind: mem::reclaim::BootMemoryKind) {
+        use mem::MemoryManager;
+
+        let frames = unsafe { memory_manager_ref().reclaim(kind) };
+
+        log::info!(
+            "(MEMORY) Reclaimed {:?} frames ({:?} KiB) of {:?} memory",
+            frames,
+            frames * 4,
+            kind
+        );
+    }
+
+    /// An executor task that runs the idle handler (see `set_idle_handler`) on the boot processor.
+    pub fn idle_task() -> impl core::future::Future<Output = ()> {
+        smp::IdleTask
+    }
+
+    /// An idle-time executor task that keeps the pre-zeroed frame pool topped up.
+    pub fn zeroed_frames_refill_task() -> impl core::future::Future<Output = ()> {
+        self::memory::RefillZeroedFrames
+    }
+
     /// Boot routine for x86_64 bit systems.
     #[once]
     pub fn boot(info: BootInformation) {
         log::debug!("Running boot procedure...");
 
+        // Per-CPU data has to be usable before anything else runs.
+        percpu::initialize_boot_cpu();
+
+        let mut wait_for_gdb = false;
+
+        if let Some(tag) = info.command_line_tag() {
+            match log_filter::configure_from_command_line(tag.command_line()) {
+                Ok(None) => {}
+                Ok(Some(filter)) => log::info!("(LOG) Filter: {}", filter),
+                Err(err) => log::error!("(LOG) Bad log filter argument: {}", err),
+            }
+
+            // Fault injection has to be armed before anything allocates.
+            match mem::fault::configure_from_command_line(tag.command_line()) {
+                Ok(0) => {}
+                Ok(count) => log::warn!("(FAULT) Armed {:?} fault injection points!", count),
+                Err(err) => log::error!("(FAULT) Bad fault injection argument: {}", err),
+            }
+
+            match gdb::configure_from_command_line(tag.command_line()) {
+                Ok(None) => {}
+                Ok(Some(options)) => wait_for_gdb = options.wait,
+                Err(err) => log::error!("(GDB) Bad gdb argument: {}", err),
+            }
+        }
+
         unsafe {
             use mem::MemoryManager;
             memory_manager_ref().initialize(&info);
         }
 
+        backtrace::initialize(&info);
+
+        // The framebuffer is mapped write-combining, which needs our PAT.
+        unsafe { memory::load_pat() };
+
+        device::framebuffer::initialize(&info);
+
         // IDT
         let ptr = DescriptorTablePointer {
             base: VirtAddr::new(&interrupts::INTERRUPT_DESCRIPTOR_TABLE as *const _ as u64),
@@ -91,6 +360,14 @@ pub mod prelude {
             load_tss(selectors.tss_selector);
         }
 
+        // Interrupt controllers.
+        device::apic::initialize();
+
+        if wait_for_gdb {
+            log::info!("(GDB) Waiting for GDB...");
+            gdb::breakpoint();
+        }
+
         log::debug!("Boot procedure completed!");
     }
 }
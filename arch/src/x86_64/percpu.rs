@@ -0,0 +1,253 @@
+//! Per-CPU data reached through the GS base.
+//!
+//! Variables declared with `percpu!` are placed in the `.percpu` section,
+//! which is never used in place: it's a template that every processor gets
+//! a copy of. The boot processor's copy lives in `BOOT_CPU_DATA`, APs get one
+//! in their region (see `smp.rs`.)
+//!
+//! The GS base of every processor points at its `CpuLocal` header, which
+//! records where its copy of the template is. While in the kernel GS base
+//! always holds the kernel's value, handlers that can be entered from user
+//! mode have to `swapgs` first (see `KernelGs`.)
+
+use core::{
+    cell::UnsafeCell,
+    ptr::{copy_nonoverlapping, null_mut},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+use x86_64::{registers::model_specific::Msr, structures::idt::InterruptStackFrame};
+
+use super::smp::MAX_CPUS;
+
+const IA32_GS_BASE: u32 = 0xC000_0101;
+const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+/// The most per-CPU data (i.e. the size of `.percpu`) we support.
+pub(crate) const PERCPU_DATA_SIZE: usize = 0x4000;
+
+extern "C" {
+    static __percpu_start: u8;
+    static __percpu_end: u8;
+}
+
+// -- CpuLocal
+
+/// Lives at the GS base of every processor.
+///
+/// The field offsets are used directly by the `gs:[..]` accessors below.
+#[repr(C)]
+pub(crate) struct CpuLocal {
+    /// Where this processor's copy of `.percpu` starts (`gs:[0]`.)
+    data: AtomicUsize,
+
+    /// The (dense) processor number, the boot processor is 0 (`gs:[8]`.)
+    cpu: AtomicUsize,
+
+    /// The task running on this processor, owned by the scheduler (`gs:[16]`.)
+    current_task: AtomicPtr<()>,
+}
+
+impl CpuLocal {
+    const EMPTY: Self = Self {
+        data: AtomicUsize::new(0),
+        cpu: AtomicUsize::new(0),
+        current_task: AtomicPtr::new(null_mut()),
+    };
+}
+
+static CPU_LOCALS: [CpuLocal; MAX_CPUS] = [CpuLocal::EMPTY; MAX_CPUS];
+
+#[repr(C, align(4096))]
+struct PerCpuData([u8; PERCPU_DATA_SIZE]);
+
+static mut BOOT_CPU_DATA: PerCpuData = PerCpuData([0; PERCPU_DATA_SIZE]);
+
+#[inline]
+fn template() -> (usize, usize) {
+    unsafe {
+        (
+            &__percpu_start as *const u8 as usize,
+            &__percpu_end as *const u8 as usize,
+        )
+    }
+}
+
+/// The size of the `.percpu` template.
+#[inline]
+pub(crate) fn data_size() -> usize {
+    let (start, end) = template();
+    end - start
+}
+
+/// Copy the template to `data` and fill in the header of `cpu`.
+///
+/// # Safety
+///
+/// `data` must be `data_size()` bytes of writable memory that outlives `cpu`.
+pub(crate) unsafe fn prepare(cpu: usize, data: usize) {
+    let (start, end) = template();
+
+    assert!(end - start <= PERCPU_DATA_SIZE, "(PERCPU) .percpu is too big!");
+
+    copy_nonoverlapping(start as *const u8, data as *mut u8, end - start);
+
+    let local = &CPU_LOCALS[cpu];
+
+    local.data.store(data, Ordering::SeqCst);
+    local.cpu.store(cpu, Ordering::SeqCst);
+    local.current_task.store(null_mut(), Ordering::SeqCst);
+}
+
+/// Point the GS base of the executing processor at the header of `cpu`.
+///
+/// # Safety
+///
+/// `prepare` must have been called for `cpu` and no other processor may be using it.
+pub(crate) unsafe fn load(cpu: usize) {
+    Msr::new(IA32_GS_BASE).write(&CPU_LOCALS[cpu] as *const CpuLocal as u64);
+
+    // User mode starts out without a GS base of its own.
+    Msr::new(IA32_KERNEL_GS_BASE).write(0);
+}
+
+/// Set once the boot processor's GS base is loaded.
+static BOOT_CPU_READY: AtomicBool = AtomicBool::new(false);
+
+/// Set up (and load) the per-CPU data of the boot processor.
+///
+/// Has to run before anything touches per-CPU data.
+pub(crate) fn initialize_boot_cpu() {
+    unsafe {
+        prepare(0, BOOT_CPU_DATA.0.as_mut_ptr() as usize);
+        load(0);
+    }
+
+    BOOT_CPU_READY.store(true, Ordering::Release);
+
+    log::info!("(PERCPU) {:?} bytes of per-CPU data", data_size());
+}
+
+/// Can `current_cpu` and friends be used yet (i.e. past `initialize_boot_cpu`)?
+#[inline]
+pub(crate) fn is_ready() -> bool {
+    BOOT_CPU_READY.load(Ordering::Acquire)
+}
+
+/// The number of the executing processor.
+#[inline]
+pub fn current_cpu() -> usize {
+    let cpu: usize;
+    unsafe { asm!("mov {}, gs:[8]", out(reg) cpu, options(nostack, readonly, preserves_flags)) };
+    cpu
+}
+
+/// The task running on the executing processor (null when idle.)
+#[inline]
+pub fn current_task() -> *mut () {
+    let task: *mut ();
+    unsafe { asm!("mov {}, gs:[16]", out(reg) task, options(nostack, readonly, preserves_flags)) };
+    task
+}
+
+/// The task running on processor `cpu` (null when idle.)
+#[inline]
+pub(crate) fn task_of(cpu: usize) -> *mut () {
+    CPU_LOCALS[cpu].current_task.load(Ordering::SeqCst)
+}
+
+/// Set the task running on the executing processor.
+#[inline]
+pub fn set_current_task(task: *mut ()) {
+    unsafe { asm!("mov gs:[16], {}", in(reg) task, options(nostack, preserves_flags)) };
+}
+
+// -- PerCpu
+
+/// A variable with a copy per processor, declare them with `percpu!`.
+///
+/// `get` hands out a shared reference to the executing processor's copy, use
+/// `Cell` or atomics for anything that changes (with interrupts disabled if
+/// handlers touch it too.)
+#[repr(transparent)]
+pub struct PerCpu<T> {
+    template: UnsafeCell<T>,
+}
+
+// SAFETY: Every processor only ever touches its own copy.
+unsafe impl<T> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(value: T) -> Self {
+        Self {
+            template: UnsafeCell::new(value),
+        }
+    }
+
+    /// The executing processor's copy.
+    #[inline]
+    pub fn get(&self) -> &T {
+        let (start, _) = template();
+        let offset = self.template.get() as usize - start;
+
+        let data: usize;
+        unsafe {
+            asm!("mov {}, gs:[0]", out(reg) data, options(nostack, readonly, preserves_flags));
+            &*((data + offset) as *const T)
+        }
+    }
+}
+
+/// Declare per-CPU variables, see `PerCpu`.
+///
+/// ```ignore
+/// percpu! {
+///     static TICKS: Cell<u64> = Cell::new(0);
+/// }
+///
+/// TICKS.get().set(TICKS.get().get() + 1);
+/// ```
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            #[link_section = ".percpu"]
+            $vis static $name: $crate::prelude::PerCpu<$ty> = $crate::prelude::PerCpu::new($init);
+        )*
+    };
+}
+
+// -- KernelGs
+
+/// Makes sure GS base holds the kernel's value for as long as it's alive.
+///
+/// Interrupt and exception handlers that can be entered from user mode
+/// create one first thing, it does a `swapgs` (and another one when dropped)
+/// if the interrupted code was running in ring 3.
+pub(crate) struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    #[inline]
+    pub(crate) fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        let swapped = stack_frame.code_segment & 0b11 != 0;
+
+        if swapped {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+
+        Self { swapped }
+    }
+}
+
+impl Drop for KernelGs {
+    #[inline]
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+    }
+}
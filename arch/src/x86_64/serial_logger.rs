@@ -1,46 +1,50 @@
-use core::fmt::Write;
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicU8, Ordering},
+};
 
-use uart_16550::SerialPort;
-use log::{Level, LevelFilter, Log, Metadata, Record};
-use once_cell::unsync::OnceCell;
-use spin::Mutex;
+use super::{
+    device::uart::{self, ComPort},
+    logger::{LogRecord, Sink},
+};
 
-// -- SerialLogger
+/// `SerialLogger::port` when logging to serial is turned off.
+const NO_PORT: u8 = u8::MAX;
 
-/// A newtype implementing `log::Log` for logging to a UART serial line.
-#[derive(Default)]
-pub(crate) struct SerialLogger(Mutex<OnceCell<SerialPort>>);
+// -- SerialLogger
 
-impl SerialLogger {
-    /// Attempt to get a static reference to the global logger instance.
-    pub fn global_ref() -> Option<&'static impl Log> {
-        static UART_LOGGER: SerialLogger = SerialLogger(Mutex::new(OnceCell::new()));
+/// A log sink writing every record to a UART serial line.
+pub(crate) struct SerialLogger {
+    /// A `ComPort` as `u8`, or `NO_PORT`.
+    port: AtomicU8,
+}
 
-        let mut cell = UART_LOGGER.0.try_lock()?;
-        let default = || {
-            let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-            serial_port.init();
-            serial_port
-        };
+pub(crate) static SERIAL_LOGGER: SerialLogger = SerialLogger {
+    port: AtomicU8::new(ComPort::Com1 as u8),
+};
 
-        cell.get_or_init(default);
+impl SerialLogger {
+    /// Log to `port` from now on, or nowhere.
+    pub(crate) fn set_port(&self, port: Option<ComPort>) {
+        self.port.store(port.map_or(NO_PORT, |port| port as u8), Ordering::SeqCst);
+    }
 
-        Some(&UART_LOGGER)
+    fn port(&self) -> Option<ComPort> {
+        ComPort::ALL.get(self.port.load(Ordering::SeqCst) as usize).copied()
     }
 }
 
-impl Log for SerialLogger {
-    fn enabled(&self, metadata: &Metadata) -> bool {
-        true
+impl Sink for SerialLogger {
+    fn enabled(&self, _record: &LogRecord) -> bool {
+        // Log lines would get in the way of the GDB stub.
+        self.port().and_then(uart::port).map_or(false, |uart| !uart.has_break_in())
     }
 
-    fn log(&self, record: &Record) {
-        if let Some(mut cell) = self.0.try_lock() {
-            if let Some(port) = cell.get_mut() {
-                let _ = port.write_fmt(format_args!("[{}] {}\n", record.level(), record.args()));
-            }
+    fn write(&self, record: &LogRecord) -> bool {
+        if let Some(uart) = self.port().and_then(uart::port) {
+            let _ = writeln!(uart.writer(), "{}", record);
         }
-    }
 
-    fn flush(&self) {}
+        true
+    }
 }
@@ -0,0 +1,371 @@
+//! Bringing up application processors (APs.)
+//!
+//! APs are started one at a time with the INIT-SIPI-SIPI sequence, each of
+//! them runs the real mode trampoline (see `bootstrap/trampoline.asm`) that
+//! was copied to `AP_TRAMPOLINE` and ends up in `ap_entry` on a stack of its
+//! own. Every AP gets a region of virtual memory at `AP_REGIONS_BASE`:
+//!
+//! ```text
+//! +0x0000 guard page
+//! +0x1000 kernel stack (32KiB)
+//! +0x9000 guard page
+//! +0xA000 double fault (IST) stack (20KiB)
+//! +0xF000 GDT, TSS and selectors
+//! +0x10000 per-CPU data (see `percpu.rs`, up to `PERCPU_DATA_SIZE`)
+//! +0x14000 guard page
+//! +0x15000 NMI (IST) stack (16KiB)
+//! +0x19000 guard page
+//! +0x1A000 machine check (IST) stack (16KiB)
+//! ```
+
+use core::{
+    future::Future,
+    mem::size_of,
+    pin::Pin,
+    ptr::{copy_nonoverlapping, write_volatile},
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use x86_64::{
+    instructions::{
+        interrupts,
+        segmentation::set_cs,
+        tables::load_tss,
+    },
+    registers::control::Cr3,
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        paging::{Page, PageTableFlags},
+        tss::TaskStateSegment,
+    },
+    PrivilegeLevel, VirtAddr,
+};
+
+use super::{
+    device::{
+        apic::{local_apic, IPI_INIT, IPI_STARTUP},
+        pic::ProgrammableIntervalTimer,
+    },
+    interrupts::{
+        cpu_reserved::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX},
+        Selectors, INTERRUPT_DESCRIPTOR_TABLE,
+    },
+    gdb,
+    memory::{load_pat, AP_TRAMPOLINE},
+    percpu,
+};
+
+/// The most processors (including the boot processor) we bring up.
+pub const MAX_CPUS: usize = 64;
+
+/// Where the per-AP regions start in virtual memory.
+const AP_REGIONS_BASE: u64 = 0x7777_0000_0000;
+
+/// The size of a per-AP region.
+const AP_REGION_SIZE: u64 = 0x20000;
+
+const STACK_BOTTOM: u64 = 0x1000;
+const STACK_TOP: u64 = 0x9000;
+const IST_BOTTOM: u64 = 0xA000;
+const IST_TOP: u64 = 0xF000;
+const DESCRIPTORS: u64 = 0xF000;
+const PERCPU_DATA: u64 = 0x10000;
+const NMI_BOTTOM: u64 = 0x15000;
+const NMI_TOP: u64 = 0x19000;
+const MACHINE_CHECK_BOTTOM: u64 = 0x1A000;
+const MACHINE_CHECK_TOP: u64 = 0x1E000;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Processors that made it into `ap_entry` (and the boot processor.)
+static CPUS_ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// The index of the last AP that checked in.
+static LAST_STARTED: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_params: u8;
+}
+
+/// Patched into the copied trampoline, must match `ap_trampoline_params`.
+#[repr(C)]
+struct TrampolineParams {
+    cr3: u64,
+    stack: u64,
+    entry: u64,
+    arg: u64,
+}
+
+/// The descriptor tables of an AP, they live in the AP's region.
+struct CpuDescriptors {
+    gdt: GlobalDescriptorTable,
+    tss: TaskStateSegment,
+    selectors: Selectors,
+}
+
+#[inline]
+fn region_of(cpu: usize) -> u64 {
+    AP_REGIONS_BASE + (cpu as u64) * AP_REGION_SIZE
+}
+
+#[inline]
+fn descriptors_of(cpu: usize) -> *mut CpuDescriptors {
+    (region_of(cpu) + DESCRIPTORS) as *mut CpuDescriptors
+}
+
+/// How many processors are online.
+#[inline]
+pub(crate) fn cpus_online() -> usize {
+    CPUS_ONLINE.load(Ordering::SeqCst)
+}
+
+/// Map the stacks, descriptor page and per-CPU data of `cpu`.
+fn map_region(cpu: usize) -> bool {
+    use mem::MemoryManager;
+
+    let base = region_of(cpu);
+    let flags = (PageTableFlags::PRESENT | PageTableFlags::WRITABLE).bits();
+
+    let pages = (STACK_BOTTOM..STACK_TOP)
+        .chain(IST_BOTTOM..IST_TOP)
+        .chain(DESCRIPTORS..(DESCRIPTORS + PAGE_SIZE))
+        .chain(NMI_BOTTOM..NMI_TOP)
+        .chain(MACHINE_CHECK_BOTTOM..MACHINE_CHECK_TOP)
+        .chain(PERCPU_DATA..(PERCPU_DATA + percpu::data_size() as u64))
+        .step_by(PAGE_SIZE as usize);
+
+    for offset in pages {
+        let page = Page::containing_address(VirtAddr::new(base + offset));
+
+        match unsafe { crate::prelude::memory_manager_ref() }.map(page, flags) {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                log::error!("(SMP) Failed to map the region of CPU {:?}: {:?}", cpu, err);
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+/// Fill in the GDT and TSS of `cpu` (its region must be mapped.)
+fn init_descriptors(cpu: usize) {
+    assert!(size_of::<CpuDescriptors>() <= PAGE_SIZE as usize);
+
+    let base = region_of(cpu);
+
+    unsafe {
+        let descriptors = descriptors_of(cpu);
+
+        descriptors.write(CpuDescriptors {
+            gdt: GlobalDescriptorTable::new(),
+            tss: TaskStateSegment::new(),
+            selectors: Selectors {
+                code_selector: SegmentSelector::new(0, PrivilegeLevel::Ring0),
+                tss_selector: SegmentSelector::new(0, PrivilegeLevel::Ring0),
+            },
+        });
+
+        let descriptors = &mut *descriptors;
+
+        let ist = &mut descriptors.tss.interrupt_stack_table;
+
+        ist[DOUBLE_FAULT_IST_INDEX as usize] = VirtAddr::new(base + IST_TOP);
+        ist[NMI_IST_INDEX as usize] = VirtAddr::new(base + NMI_TOP);
+        ist[MACHINE_CHECK_IST_INDEX as usize] = VirtAddr::new(base + MACHINE_CHECK_TOP);
+
+        // The TSS lives as long as the region (i.e. forever.)
+        let tss: &'static TaskStateSegment = &*(&descriptors.tss as *const _);
+
+        descriptors.selectors = Selectors {
+            code_selector: descriptors.gdt.add_entry(Descriptor::kernel_code_segment()),
+            tss_selector: descriptors.gdt.add_entry(Descriptor::tss_segment(tss)),
+        };
+    }
+}
+
+/// Copy the trampoline to low memory and point it at `cpu`'s stack.
+unsafe fn patch_trampoline(cpu: usize) {
+    let start = &ap_trampoline_start as *const u8 as usize;
+    let end = &ap_trampoline_end as *const u8 as usize;
+    let params = &ap_trampoline_params as *const u8 as usize;
+
+    copy_nonoverlapping(start as *const u8, AP_TRAMPOLINE as *mut u8, end - start);
+
+    let (pml4, _) = Cr3::read();
+
+    write_volatile(
+        (AP_TRAMPOLINE + (params - start)) as *mut TrampolineParams,
+        TrampolineParams {
+            cr3: pml4.start_address().as_u64(),
+            stack: region_of(cpu) + STACK_TOP,
+            entry: ap_entry as usize as u64,
+            arg: cpu as u64,
+        },
+    );
+}
+
+/// Poll for `cpu` checking in for up to `micros` microseconds.
+fn wait_for(cpu: usize, micros: u64) -> bool {
+    const POLL_INTERVAL: u64 = 50;
+
+    for _ in 0..=(micros / POLL_INTERVAL) {
+        if LAST_STARTED.load(Ordering::SeqCst) == cpu {
+            return true;
+        }
+
+        unsafe { ProgrammableIntervalTimer::spin_wait_micros(POLL_INTERVAL) };
+    }
+
+    LAST_STARTED.load(Ordering::SeqCst) == cpu
+}
+
+/// Start the AP with local APIC ID `apic_id` as processor `cpu`.
+fn start(cpu: usize, apic_id: u32) -> bool {
+    let apic = local_apic().expect("The local APIC must be enabled before starting APs.");
+
+    init_descriptors(cpu);
+
+    unsafe {
+        percpu::prepare(cpu, (region_of(cpu) + PERCPU_DATA) as usize);
+        patch_trampoline(cpu);
+    }
+
+    let vector = (AP_TRAMPOLINE >> 12) as u32;
+
+    apic.send_ipi(apic_id, IPI_INIT);
+    unsafe { ProgrammableIntervalTimer::spin_wait_micros(10_000) };
+
+    apic.send_ipi(apic_id, IPI_STARTUP | vector);
+
+    if wait_for(cpu, 200) {
+        return true;
+    }
+
+    apic.send_ipi(apic_id, IPI_STARTUP | vector);
+
+    if wait_for(cpu, 100_000) {
+        return true;
+    }
+
+    // Park it again so it doesn't wake up later on with somebody else's stack.
+    apic.send_ipi(apic_id, IPI_INIT);
+
+    false
+}
+
+/// Start every AP in `apic_ids`, returns how many came online.
+pub(crate) fn start_application_processors(apic_ids: &[u32]) -> usize {
+    let own = local_apic().map(|apic| apic.id());
+
+    let mut online = 0;
+    let mut cpu = 1;
+
+    // A failed start leaves its (mapped) region to the next AP.
+    let mut mapped = 0;
+
+    for apic_id in apic_ids.iter().cloned().filter(|id| Some(*id) != own) {
+        if cpu == MAX_CPUS {
+            log::warn!("(SMP) Ignoring APIC ID {:?} and up (MAX_CPUS is {:?})", apic_id, MAX_CPUS);
+            break;
+        }
+
+        if mapped < cpu {
+            if !map_region(cpu) {
+                break;
+            }
+
+            mapped = cpu;
+        }
+
+        if start(cpu, apic_id) {
+            log::info!("(SMP) CPU {:?} (APIC ID {:?}) is online", cpu, apic_id);
+            online += 1;
+            cpu += 1;
+        } else {
+            log::error!("(SMP) CPU with APIC ID {:?} failed to start", apic_id);
+        }
+    }
+
+    log::info!("(SMP) {:?}/{:?} processors online", cpus_online(), apic_ids.len() + 1);
+
+    online
+}
+
+/// Where APs end up after the trampoline.
+extern "C" fn ap_entry(cpu: usize) -> ! {
+    unsafe { percpu::load(cpu) };
+
+    // Same memory types as the boot processor.
+    unsafe { load_pat() };
+
+    let descriptors = unsafe { &*descriptors_of(cpu) };
+
+    descriptors.gdt.load();
+
+    unsafe {
+        set_cs(descriptors.selectors.code_selector);
+        load_tss(descriptors.selectors.tss_selector);
+    }
+
+    INTERRUPT_DESCRIPTOR_TABLE.load();
+
+    // Hardware breakpoints and watchpoints set before we came online.
+    gdb::load_debug_registers();
+
+    let apic = local_apic().expect("APs are started through the local APIC.");
+    unsafe { apic.enable() };
+
+    CPUS_ONLINE.fetch_add(1, Ordering::SeqCst);
+    LAST_STARTED.store(cpu, Ordering::SeqCst);
+
+    interrupts::enable();
+
+    loop {
+        idle();
+        x86_64::instructions::hlt();
+    }
+}
+
+// -- Idle
+
+/// The `fn()` set with `set_idle_handler`, 0 if there's none.
+static IDLE_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Call `handler` whenever a processor has nothing to do.
+///
+/// APs call it every time they wake up from `hlt`, the boot processor
+/// whenever its executor gets to `IdleTask`.
+pub fn set_idle_handler(handler: Option<fn()>) {
+    IDLE_HANDLER.store(handler.map_or(0, |handler| handler as usize), Ordering::Release);
+}
+
+/// Run the idle handler on the executing processor.
+fn idle() {
+    let handler = IDLE_HANDLER.load(Ordering::Acquire);
+
+    if handler != 0 {
+        // SAFETY: Only ever set from a `fn()` in `set_idle_handler`.
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+}
+
+/// An executor task that runs the idle handler every time it's polled.
+pub(super) struct IdleTask;
+
+impl Future for IdleTask {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        idle();
+
+        cx.waker().wake_by_ref();
+
+        Poll::Pending
+    }
+}
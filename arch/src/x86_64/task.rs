@@ -0,0 +1,48 @@
+//! The executor's tasks, as far as the architecture code cares (i.e. for the GDB stub.)
+//!
+//! The executor reports every task it spawns and finishes, a task is the
+//! address of its future. Which one is running on a processor is in its
+//! per-CPU data (`set_current_task`.)
+
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+/// The most tasks we keep track of, any more aren't shown to GDB.
+pub(crate) const MAX_TASKS: usize = 64;
+
+const NO_TASK: AtomicPtr<()> = AtomicPtr::new(null_mut());
+
+static TASKS: [AtomicPtr<()>; MAX_TASKS] = [NO_TASK; MAX_TASKS];
+
+/// Remember `task`, it was just spawned.
+pub fn task_spawned(task: *mut ()) {
+    // Past `MAX_TASKS` it just isn't listed.
+    let _ = TASKS
+        .iter()
+        .any(|slot| slot.compare_exchange(null_mut(), task, Ordering::AcqRel, Ordering::Relaxed).is_ok());
+}
+
+/// Forget `task`, it completed.
+pub fn task_finished(task: *mut ()) {
+    if let Some(slot) = TASKS.iter().find(|slot| slot.load(Ordering::Acquire) == task) {
+        slot.store(null_mut(), Ordering::Release);
+    }
+}
+
+/// The task in `slot` (null if there's none.)
+#[inline]
+pub(crate) fn task_in(slot: usize) -> *mut () {
+    TASKS.get(slot).map_or(null_mut(), |slot| slot.load(Ordering::Acquire))
+}
+
+/// The slot `task` is kept in.
+#[inline]
+pub(crate) fn slot_of(task: *mut ()) -> Option<usize> {
+    if task.is_null() {
+        return None;
+    }
+
+    TASKS.iter().position(|slot| slot.load(Ordering::Acquire) == task)
+}
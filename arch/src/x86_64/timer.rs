@@ -0,0 +1,105 @@
+//! The system tick, driven by channel 0 of the PIT.
+//!
+//! Every timer interrupt advances the uptime by the PIT's current period (so
+//! changing the frequency at runtime keeps it monotonic) and calls the tick
+//! hook, if a scheduler has installed one.
+
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use x86_64::instructions::interrupts;
+
+use super::{
+    clock,
+    device::pic::{timer_period_nanos, ProgrammableIntervalTimer, CHIP_8259},
+    interrupts::{
+        index::InterruptIndex,
+        irq::{self, IrqContext, IrqReturn},
+    },
+};
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static UPTIME_NANOS: AtomicU64 = AtomicU64::new(0);
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// The tick hook as a `fn()`, 0 when there isn't one.
+static TICK_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+fn on_tick(_context: &IrqContext) -> IrqReturn {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    UPTIME_NANOS.fetch_add(timer_period_nanos(), Ordering::Release);
+
+    clock::tick();
+    clock::pit::on_tick();
+
+    let hook = TICK_HOOK.load(Ordering::Acquire);
+
+    if hook != 0 {
+        // SAFETY: Only ever set from a `fn()` in `set_tick_hook`.
+        let hook: fn() = unsafe { core::mem::transmute(hook) };
+        hook();
+    }
+
+    IrqReturn::Handled
+}
+
+/// Program the PIT to `freq` hertz and start ticking (once interrupts are enabled.)
+pub(crate) fn initialize(freq: usize) {
+    CHIP_8259.set_timer_frequency(freq);
+
+    irq::request_irq(InterruptIndex::Timer, "PIT", on_tick, 0).expect("The timer IRQ is taken");
+
+    RUNNING.store(true, Ordering::SeqCst);
+
+    log::info!(
+        "(TIMER) Ticking at {:?}Hz ({:?}ns per tick)",
+        freq,
+        timer_period_nanos()
+    );
+}
+
+/// Change the tick frequency.
+#[inline]
+pub(crate) fn set_frequency(freq: usize) {
+    CHIP_8259.set_timer_frequency(freq);
+}
+
+/// Call `hook` on every tick (in interrupt context), replacing any previous hook.
+pub(crate) fn set_tick_hook(hook: Option<fn()>) {
+    TICK_HOOK.store(hook.map_or(0, |hook| hook as usize), Ordering::Release);
+}
+
+/// Timer interrupts since `initialize`.
+#[inline]
+pub(crate) fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Time since the timer was started (with tick granularity.)
+#[inline]
+pub(crate) fn uptime() -> Duration {
+    Duration::from_nanos(UPTIME_NANOS.load(Ordering::Acquire))
+}
+
+/// Busy wait for (at least) `duration`.
+///
+/// Waits on the uptime when the timer is ticking, otherwise (e.g. with
+/// interrupts disabled) polls channel 2 of the PIT.
+pub(crate) fn delay(duration: Duration) {
+    if !RUNNING.load(Ordering::SeqCst) || !interrupts::are_enabled() {
+        let micros = duration.as_nanos().saturating_add(999) / 1000;
+        unsafe { ProgrammableIntervalTimer::spin_wait_micros(micros.min(u64::MAX as u128) as u64) };
+        return;
+    }
+
+    // One extra tick, the current one may be about to end.
+    let deadline = uptime() + duration + Duration::from_nanos(timer_period_nanos());
+
+    // Not `hlt`, only the boot processor gets timer interrupts.
+    while uptime() < deadline {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
@@ -115,3 +115,77 @@ impl acpi::AcpiHandler for AcpiPassthrough {
         log::trace!("(ACPI) Unapping region {:#x}", region.physical_start);
     }
 }
+
+/// Hand the I/O APICs and ISA interrupt source overrides of the MADT to `arch`.
+pub(crate) fn configure_io_apics(apic: &acpi::platform::interrupt::Apic) {
+    use acpi::platform::interrupt::{Polarity, TriggerMode};
+    use arch::prelude::{ioapic, IoApicInfo, IsaOverride};
+
+    /// Matches the capacity of the I/O APIC driver, anything past it is ignored anyway.
+    const MAX_IO_APICS: usize = 8;
+
+    /// One per ISA IRQ is plenty.
+    const MAX_OVERRIDES: usize = 16;
+
+    let mut io_apics = [IoApicInfo { id: 0, address: 0, gsi_base: 0 }; MAX_IO_APICS];
+    let mut io_apics_length = 0;
+
+    for (slot, io_apic) in io_apics.iter_mut().zip(apic.io_apics.iter()) {
+        *slot = IoApicInfo {
+            id: io_apic.id,
+            address: io_apic.address,
+            gsi_base: io_apic.global_system_interrupt_base,
+        };
+
+        io_apics_length += 1;
+    }
+
+    let mut overrides = [IsaOverride { isa_source: 0, gsi: 0, polarity: None, trigger_mode: None }; MAX_OVERRIDES];
+    let mut overrides_length = 0;
+
+    for (slot, over) in overrides.iter_mut().zip(apic.interrupt_source_overrides.iter()) {
+        *slot = IsaOverride {
+            isa_source: over.isa_source,
+            gsi: over.global_system_interrupt,
+            polarity: match over.polarity {
+                Polarity::SameAsBus => None,
+                Polarity::ActiveHigh => Some(arch::prelude::Polarity::ActiveHigh),
+                Polarity::ActiveLow => Some(arch::prelude::Polarity::ActiveLow),
+            },
+            trigger_mode: match over.trigger_mode {
+                TriggerMode::SameAsBus => None,
+                TriggerMode::Edge => Some(arch::prelude::TriggerMode::Edge),
+                TriggerMode::Level => Some(arch::prelude::TriggerMode::Level),
+            },
+        };
+
+        overrides_length += 1;
+    }
+
+    log::info!(
+        "(ACPI) {:?} I/O APIC(s), {:?} interrupt source override(s), legacy PICs: {:?}",
+        apic.io_apics.len(),
+        apic.interrupt_source_overrides.len(),
+        apic.also_has_legacy_pics
+    );
+
+    ioapic::initialize(&io_apics[..io_apics_length], &overrides[..overrides_length]);
+}
+
+/// The CMOS index of the RTC's century register, if the FADT has one.
+pub(crate) fn century_register<H: acpi::AcpiHandler>(tables: &acpi::AcpiTables<H>) -> Option<u8> {
+    /// Offset of the `CENTURY` field in the FADT.
+    const FADT_CENTURY_OFFSET: usize = 108;
+
+    let fadt = tables.sdts.get(&acpi::sdt::Signature::FADT)?;
+
+    if (fadt.length as usize) <= FADT_CENTURY_OFFSET {
+        return None;
+    }
+
+    // The FADT is identity mapped (and not reclaimed until the tables are dropped.)
+    match unsafe { *((fadt.physical_address + FADT_CENTURY_OFFSET) as *const u8) } {
+        0 => None,
+        register => Some(register),
+    }
+}
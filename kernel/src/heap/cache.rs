@@ -0,0 +1,401 @@
+//! Per-CPU caches for small heap allocations.
+//!
+//! Small objects are carved out of `SLAB_SIZE` "slabs" that are owned by a
+//! single CPU. Each CPU keeps a magazine of free objects per size class that
+//! only it ever touches, so the fast paths don't take any locks (interrupts
+//! are disabled instead.)
+//!
+//! Objects freed on a CPU other than the owner are pushed onto the owner's
+//! remote-free queue, a lock-free stack that the owner empties whenever its
+//! magazine runs dry or the CPU goes idle.
+//!
+//! Entirely free slabs go back to the heap when an idle CPU drains its
+//! caches, which only happens once it picked up `DRAIN_WATERMARK` slabs
+//! since the last drain or `DRAIN_INTERVAL` has passed.
+
+use core::{
+    alloc::Layout,
+    cell::UnsafeCell,
+    cmp::max,
+    mem::size_of,
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::KernelHeap;
+
+/// The object sizes served by the per-CPU caches.
+const SIZE_CLASSES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+
+/// The size (and alignment) of the chunks objects are carved out of.
+const SLAB_SIZE: usize = 0x4000;
+
+/// How many objects a magazine holds.
+const MAGAZINE_SIZE: usize = 32;
+
+/// How many slabs a CPU picks up before its caches are drained on the next idle.
+const DRAIN_WATERMARK: usize = 16;
+
+/// How long an idle CPU goes without draining its caches.
+const DRAIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The most CPUs with their own caches.
+const MAX_CPUS: usize = arch::prelude::MAX_CPUS;
+
+/// Get the size class index for `layout`, `None` if it's too big to be cached.
+#[inline]
+pub(super) fn size_class(layout: &Layout) -> Option<usize> {
+    let size = max(layout.size(), layout.align());
+
+    SIZE_CLASSES.iter().position(|class| size <= *class)
+}
+
+/// The executing CPU, the boot processor allocates before its per-CPU data (GS base) is set up.
+#[inline]
+fn current_cpu() -> usize {
+    if !arch::prelude::percpu_ready() {
+        return 0;
+    }
+
+    let cpu = arch::prelude::current_cpu();
+
+    assert!(
+        cpu < MAX_CPUS,
+        "CPU {:?} is out of range for the allocation caches (MAX_CPUS is {:?})",
+        cpu,
+        MAX_CPUS
+    );
+
+    cpu
+}
+
+// -- SlabHeader
+
+/// Lives at the start of every slab, found by masking an object's address.
+#[repr(C)]
+struct SlabHeader {
+    owner: usize,
+    class: usize,
+
+    /// Objects currently handed out (only ever touched by the owner.)
+    in_use: usize,
+
+    /// The next slab owned by the same CPU and size class.
+    next: *mut SlabHeader,
+}
+
+impl SlabHeader {
+    #[inline]
+    fn of(ptr: *mut u8) -> *mut SlabHeader {
+        ((ptr as usize) & !(SLAB_SIZE - 1)) as *mut SlabHeader
+    }
+}
+
+/// A free object, the link is stored in the object itself.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+// -- Magazine
+
+struct Magazine {
+    rounds: [*mut u8; MAGAZINE_SIZE],
+    count: usize,
+
+    /// Free objects that didn't fit in `rounds`.
+    spare: *mut FreeObject,
+
+    /// Every slab owned by this magazine's CPU and size class.
+    slabs: *mut SlabHeader,
+}
+
+impl Magazine {
+    const EMPTY: Self = Self {
+        rounds: [null_mut(); MAGAZINE_SIZE],
+        count: 0,
+        spare: null_mut(),
+        slabs: null_mut(),
+    };
+
+    #[inline]
+    unsafe fn push_spare(&mut self, ptr: *mut u8) {
+        let object = ptr as *mut FreeObject;
+        (*object).next = self.spare;
+        self.spare = object;
+    }
+
+    /// Move spare objects into the magazine until it's full.
+    #[inline]
+    unsafe fn load_spares(&mut self) {
+        while self.count < MAGAZINE_SIZE && !self.spare.is_null() {
+            let object = self.spare;
+            self.spare = (*object).next;
+            self.rounds[self.count] = object as *mut u8;
+            self.count += 1;
+        }
+    }
+
+    /// Move every object in the magazine onto the spare list.
+    #[inline]
+    unsafe fn unload(&mut self) {
+        while self.count > 0 {
+            self.count -= 1;
+            self.push_spare(self.rounds[self.count]);
+        }
+    }
+}
+
+// -- ClassCache
+
+struct ClassCache {
+    local: UnsafeCell<Magazine>,
+    remote: AtomicPtr<FreeObject>,
+}
+
+impl ClassCache {
+    const EMPTY: Self = Self {
+        local: UnsafeCell::new(Magazine::EMPTY),
+        remote: AtomicPtr::new(null_mut()),
+    };
+
+    /// Push an object freed on another CPU onto the remote-free queue.
+    #[inline]
+    fn push_remote(&self, ptr: *mut u8) {
+        let object = ptr as *mut FreeObject;
+        let mut head = self.remote.load(Ordering::Relaxed);
+
+        loop {
+            // SAFETY: The object is free so we're allowed to scribble on it.
+            unsafe { (*object).next = head };
+
+            match self.remote.compare_exchange_weak(head, object, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Move everything on the remote-free queue onto the spare list.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called by the owning CPU with interrupts disabled.
+    #[inline]
+    unsafe fn reclaim_remote(&self, magazine: &mut Magazine) {
+        let mut object = self.remote.swap(null_mut(), Ordering::Acquire);
+
+        while !object.is_null() {
+            let next = (*object).next;
+
+            (*SlabHeader::of(object as *mut u8)).in_use -= 1;
+            magazine.push_spare(object as *mut u8);
+
+            object = next;
+        }
+    }
+}
+
+// -- CpuCache
+
+struct CpuCache {
+    classes: [ClassCache; SIZE_CLASSES.len()],
+
+    /// Slabs owned by this CPU.
+    slabs: AtomicUsize,
+
+    /// How many slabs the last drain left, see `drain_idle`.
+    retained: AtomicUsize,
+
+    /// When the last drain happened (in `monotonic_nanos`.)
+    drained_at: AtomicU64,
+}
+
+// SAFETY: The magazines are only touched by their own CPU with interrupts
+// disabled and the remote-free queues are atomic.
+unsafe impl Sync for CpuCache {}
+
+impl CpuCache {
+    const EMPTY: Self = Self {
+        classes: [ClassCache::EMPTY; SIZE_CLASSES.len()],
+        slabs: AtomicUsize::new(0),
+        retained: AtomicUsize::new(0),
+        drained_at: AtomicU64::new(0),
+    };
+}
+
+static CPU_CACHES: [CpuCache; MAX_CPUS] = [CpuCache::EMPTY; MAX_CPUS];
+
+/// Allocate an object of size class `class`.
+pub(super) unsafe fn alloc(heap: &KernelHeap, class: usize) -> *mut u8 {
+    let cpu = current_cpu();
+    let cache = &CPU_CACHES[cpu].classes[class];
+
+    without_interrupts(|| {
+        let magazine = &mut *cache.local.get();
+
+        if magazine.count == 0 && !refill(heap, cpu, class, cache, magazine) {
+            return null_mut();
+        }
+
+        magazine.count -= 1;
+        let ptr = magazine.rounds[magazine.count];
+
+        (*SlabHeader::of(ptr)).in_use += 1;
+
+        ptr
+    })
+}
+
+/// Free an object of size class `class`.
+pub(super) unsafe fn dealloc(ptr: *mut u8, class: usize) {
+    let cpu = current_cpu();
+    let owner = (*SlabHeader::of(ptr)).owner;
+
+    if owner != cpu {
+        CPU_CACHES[owner].classes[class].push_remote(ptr);
+        return;
+    }
+
+    let cache = &CPU_CACHES[cpu].classes[class];
+
+    without_interrupts(|| {
+        let magazine = &mut *cache.local.get();
+
+        (*SlabHeader::of(ptr)).in_use -= 1;
+
+        if magazine.count < MAGAZINE_SIZE {
+            magazine.rounds[magazine.count] = ptr;
+            magazine.count += 1;
+        } else {
+            magazine.push_spare(ptr);
+        }
+    })
+}
+
+/// Fill an empty magazine from the spare list, the remote-free queue or a new slab.
+unsafe fn refill(
+    heap: &KernelHeap,
+    cpu: usize,
+    class: usize,
+    cache: &ClassCache,
+    magazine: &mut Magazine,
+) -> bool {
+    magazine.load_spares();
+
+    if magazine.count == 0 {
+        cache.reclaim_remote(magazine);
+        magazine.load_spares();
+    }
+
+    if magazine.count == 0 {
+        let slab = heap.alloc_locked(Layout::from_size_align_unchecked(SLAB_SIZE, SLAB_SIZE));
+
+        if slab.is_null() {
+            return false;
+        }
+
+        let header = slab as *mut SlabHeader;
+
+        header.write(SlabHeader {
+            owner: cpu,
+            class,
+            in_use: 0,
+            next: magazine.slabs,
+        });
+
+        magazine.slabs = header;
+        CPU_CACHES[cpu].slabs.fetch_add(1, Ordering::Relaxed);
+
+        let object_size = SIZE_CLASSES[class];
+        let first = super::align_up(size_of::<SlabHeader>(), object_size);
+
+        for offset in (first..SLAB_SIZE).step_by(object_size).rev() {
+            magazine.push_spare(slab.add(offset));
+        }
+
+        magazine.load_spares();
+    }
+
+    magazine.count > 0
+}
+
+/// Drain the executing CPU's caches, handing entirely free slabs back to the heap.
+pub(super) fn drain(heap: &KernelHeap) {
+    let cpu = current_cpu();
+
+    for (class, cache) in CPU_CACHES[cpu].classes.iter().enumerate() {
+        without_interrupts(|| unsafe {
+            let magazine = &mut *cache.local.get();
+
+            magazine.unload();
+            cache.reclaim_remote(magazine);
+
+            // Drop every object living in a slab that is about to be freed.
+            let mut object = magazine.spare;
+            magazine.spare = null_mut();
+
+            while !object.is_null() {
+                let next = (*object).next;
+
+                if (*SlabHeader::of(object as *mut u8)).in_use != 0 {
+                    magazine.push_spare(object as *mut u8);
+                }
+
+                object = next;
+            }
+
+            // Unlink and free the empty slabs.
+            let mut link: *mut *mut SlabHeader = &mut magazine.slabs;
+            let mut freed = 0usize;
+
+            while !(*link).is_null() {
+                let slab = *link;
+
+                if (*slab).in_use == 0 {
+                    *link = (*slab).next;
+                    heap.dealloc_locked(slab as *mut u8, Layout::from_size_align_unchecked(SLAB_SIZE, SLAB_SIZE));
+                    freed += 1;
+                } else {
+                    link = &mut (*slab).next;
+                }
+            }
+
+            if freed > 0 {
+                CPU_CACHES[cpu].slabs.fetch_sub(freed, Ordering::Relaxed);
+
+                log::trace!(
+                    "(GLOBAL_ALLOCATOR) CPU {:?} released {:?} slabs of {:?} byte objects",
+                    cpu,
+                    freed,
+                    SIZE_CLASSES[class]
+                );
+            }
+
+            magazine.load_spares();
+        })
+    }
+}
+
+/// Drain the executing CPU's caches if it's due (see above), called whenever it's idle.
+pub(super) fn drain_idle(heap: &KernelHeap) {
+    let caches = &CPU_CACHES[current_cpu()];
+
+    let now = arch::prelude::monotonic_nanos();
+    let since = now.saturating_sub(caches.drained_at.load(Ordering::Relaxed));
+    let gained = caches
+        .slabs
+        .load(Ordering::Relaxed)
+        .saturating_sub(caches.retained.load(Ordering::Relaxed));
+
+    if gained < DRAIN_WATERMARK && since < DRAIN_INTERVAL.as_nanos() as u64 {
+        return;
+    }
+
+    drain(heap);
+
+    caches.retained.store(caches.slabs.load(Ordering::Relaxed), Ordering::Relaxed);
+    caches.drained_at.store(now, Ordering::Relaxed);
+}
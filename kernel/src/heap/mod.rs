@@ -0,0 +1,335 @@
+//! The kernel heap.
+//!
+//! The heap is made up of "segments", contiguous runs of virtual memory
+//! starting at `HEAP_BASE` that are each managed by their own buddy allocator.
+//! A new segment is mapped in whenever an allocation can't be satisfied and
+//! trailing segments that become entirely free are unmapped again.
+//!
+//! Small allocations don't touch the segments directly, they're served by
+//! per-CPU caches (see `cache`.)
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cmp::max,
+    mem::size_of,
+    ptr::{null_mut, NonNull},
+};
+
+use buddy_system_allocator::Heap;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+
+use mem::{
+    fault::{self, FaultPoint},
+    MemoryManager,
+};
+
+mod cache;
+
+/// Where the heap starts in virtual memory.
+const HEAP_BASE: usize = 0x6666_0000_0000;
+
+/// How the heap grows and when it shrinks.
+const HEAP_POLICY: GrowthPolicy = GrowthPolicy::DEFAULT;
+
+/// The most segments the heap can be split up into.
+const MAX_SEGMENTS: usize = 32;
+
+const PAGE_SIZE: usize = 0x1000;
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    panic!("allocation error: {:?}", layout)
+}
+
+#[global_allocator]
+static GLOBAL_ALLOCATOR: KernelHeap = KernelHeap::new(HEAP_POLICY);
+
+/// Idle handler (see `set_idle_handler`) that drains the executing CPU's
+/// allocation caches once they've grown or haven't been drained in a while.
+pub fn on_idle() {
+    cache::drain_idle(&GLOBAL_ALLOCATOR);
+}
+
+#[inline]
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+// -- GrowthPolicy
+
+/// Describes how the heap grows and when it shrinks.
+#[derive(Debug, Clone, Copy)]
+pub struct GrowthPolicy {
+    /// The smallest amount of bytes the heap will grow by.
+    pub min_extension: usize,
+
+    /// Every extension grows the heap to (at least) `growth_factor` times its
+    /// current size, `1` disables geometric growth.
+    pub growth_factor: usize,
+
+    /// The heap will never grow past this many bytes.
+    pub max_size: usize,
+
+    /// Fully free trailing segments are only unmapped while the rest of the
+    /// heap still has this many free bytes, to avoid map/unmap thrashing.
+    pub retain: usize,
+}
+
+impl GrowthPolicy {
+    pub const DEFAULT: Self = Self {
+        min_extension: 0x4000,
+        growth_factor: 2,
+        max_size: 0x1000_0000, // 256MiB
+        retain: 0x4000,
+    };
+}
+
+impl Default for GrowthPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+// -- HeapSegments
+
+struct HeapSegments {
+    /// `(start, end)` of every segment, the segment's `Heap` lives at `start`.
+    segments: [(usize, usize); MAX_SEGMENTS],
+    length: usize,
+    top: usize,
+    policy: GrowthPolicy,
+}
+
+impl HeapSegments {
+    /// Get the buddy allocator that manages the `idx` segment.
+    #[inline]
+    fn heap(&mut self, idx: usize) -> &mut Heap {
+        let (start, _) = self.segments[idx];
+
+        // SAFETY: Every segment starts with an initialized `Heap`.
+        unsafe { &mut *(start as *mut Heap) }
+    }
+
+    /// The amount of bytes currently mapped for the heap.
+    #[inline]
+    fn size(&self) -> usize {
+        self.top - HEAP_BASE
+    }
+
+    fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        // Older segments are tried first so that trailing ones drain out.
+        (0..self.length).find_map(|idx| self.heap(idx).alloc(layout).ok())
+    }
+
+    fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let address = ptr.as_ptr() as usize;
+
+        let idx = self.segments[..self.length]
+            .iter()
+            .position(|(start, end)| (*start..*end).contains(&address))
+            .expect("Tried to deallocate memory that is not part of the heap!");
+
+        self.heap(idx).dealloc(ptr, layout);
+    }
+
+    /// Map in a new segment large enough to satisfy `layout`.
+    fn grow(&mut self, layout: &Layout) -> bool {
+        if fault::should_fail(FaultPoint::HeapGrow) {
+            log::trace!("(GLOBAL_ALLOCATOR) Injected heap extension failure for {:?}", layout);
+            return false;
+        }
+
+        if self.length == MAX_SEGMENTS {
+            log::warn!("(GLOBAL_ALLOCATOR) Out of heap segments!");
+            return false;
+        }
+
+        // The buddy allocator hands out power of two sized blocks, a range
+        // twice as big as the block is guaranteed to contain an aligned one.
+        let block = max(
+            layout.size().next_power_of_two(),
+            max(layout.align(), size_of::<usize>()),
+        );
+
+        let needed = align_up(2 * block + size_of::<Heap>(), PAGE_SIZE);
+
+        let geometric = self.size() * self.policy.growth_factor.saturating_sub(1);
+        let preferred = align_up(max(needed, max(geometric, self.policy.min_extension)), PAGE_SIZE);
+
+        let remaining = self.policy.max_size.saturating_sub(self.size());
+
+        let extension = if preferred <= remaining {
+            preferred
+        } else if needed <= remaining {
+            remaining - (remaining % PAGE_SIZE)
+        } else {
+            log::warn!(
+                "(GLOBAL_ALLOCATOR) Refusing to grow the heap past {:#x} bytes for {:?}",
+                self.policy.max_size,
+                layout
+            );
+
+            return false;
+        };
+
+        let (start, end) = (self.top, self.top + extension);
+
+        // SAFETY: The heap is the only user of its virtual memory range.
+        let mapper = unsafe { arch::prelude::memory_manager_ref() };
+        let flags = (PageTableFlags::PRESENT | PageTableFlags::WRITABLE).bits();
+
+        for address in (start..end).step_by(PAGE_SIZE) {
+            let page = Page::containing_address(VirtAddr::new(address as u64));
+
+            match mapper.map(page, flags) {
+                Ok(flush) => flush.flush(),
+                Err(error) => {
+                    log::error!("(GLOBAL_ALLOCATOR) Failed to map heap page {:?}: {:?}", page, error);
+                    Self::release(mapper, start, address);
+                    return false;
+                }
+            }
+        }
+
+        log::debug!(
+            "(GLOBAL_ALLOCATOR) Mapping heap space {:#x}...{:#x} ({:?} bytes)",
+            start,
+            end,
+            extension,
+        );
+
+        unsafe {
+            let heap = start as *mut Heap;
+            heap.write(Heap::empty());
+            (*heap).add_to_heap(start + size_of::<Heap>(), end);
+        }
+
+        self.segments[self.length] = (start, end);
+        self.length += 1;
+        self.top = end;
+
+        true
+    }
+
+    /// Unmap trailing segments that have become entirely free.
+    fn shrink(&mut self) {
+        // The first segment is always kept around.
+        while self.length > 1 {
+            let last = self.length - 1;
+
+            if self.heap(last).stats_alloc_actual() != 0 {
+                break;
+            }
+
+            let free_elsewhere: usize = (0..last)
+                .map(|idx| {
+                    let heap = self.heap(idx);
+                    heap.stats_total_bytes() - heap.stats_alloc_actual()
+                })
+                .sum();
+
+            if free_elsewhere < self.policy.retain {
+                break;
+            }
+
+            let (start, end) = self.segments[last];
+
+            log::debug!(
+                "(GLOBAL_ALLOCATOR) Unmapping heap space {:#x}...{:#x} ({:?} bytes)",
+                start,
+                end,
+                end - start,
+            );
+
+            // SAFETY: Nothing is allocated in the segment anymore.
+            let mapper = unsafe { arch::prelude::memory_manager_ref() };
+            Self::release(mapper, start, end);
+
+            self.segments[last] = (0, 0);
+            self.length = last;
+            self.top = start;
+        }
+    }
+
+    /// Unmap `start..end` and hand the frames back to the memory manager.
+    fn release(mapper: &mut impl MemoryManager, start: usize, end: usize) {
+        for address in (start..end).step_by(PAGE_SIZE) {
+            let page = Page::containing_address(VirtAddr::new(address as u64));
+
+            if let Some(frame) = mapper.unmap(page) {
+                // SAFETY: The page has just been unmapped.
+                unsafe { mapper.free_frame(frame) };
+            }
+        }
+    }
+}
+
+// -- KernelHeap
+
+/// Per-CPU caches in front of a growable, and shrinkable, locked heap.
+pub struct KernelHeap(Mutex<HeapSegments>);
+
+impl KernelHeap {
+    pub const fn new(policy: GrowthPolicy) -> Self {
+        Self(Mutex::new(HeapSegments {
+            segments: [(0, 0); MAX_SEGMENTS],
+            length: 0,
+            top: HEAP_BASE,
+            policy,
+        }))
+    }
+
+    /// Allocate straight from the segments, bypassing the per-CPU caches.
+    unsafe fn alloc_locked(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| {
+            let mut segments = self.0.lock();
+
+            if let Some(ptr) = segments.alloc(layout) {
+                return ptr.as_ptr();
+            }
+
+            if !segments.grow(&layout) {
+                return null_mut();
+            }
+
+            segments
+                .alloc(layout)
+                .map_or(null_mut(), |ptr| ptr.as_ptr())
+        })
+    }
+
+    /// Free straight into the segments, bypassing the per-CPU caches.
+    unsafe fn dealloc_locked(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| {
+            let mut segments = self.0.lock();
+
+            segments.dealloc(NonNull::new_unchecked(ptr), layout);
+            segments.shrink();
+        })
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if fault::should_fail(FaultPoint::GlobalAlloc) {
+            return null_mut();
+        }
+
+        match cache::size_class(&layout) {
+            Some(class) => cache::alloc(self, class),
+            None => self.alloc_locked(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match cache::size_class(&layout) {
+            Some(class) => cache::dealloc(ptr, class),
+            None => self.dealloc_locked(ptr, layout),
+        }
+    }
+}
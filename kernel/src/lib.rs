@@ -6,10 +6,12 @@
 #![feature(type_ascription)]
 #![feature(llvm_asm)]
 #![feature(maybe_uninit_extra)]
+#![feature(const_in_array_repeat_expressions)]
 
 use alloc::boxed::Box;
 use alloc::format;
 use alloc::string::ToString;
+use alloc::vec::Vec;
 
 use core::{mem::MaybeUninit, panic};
 
@@ -19,6 +21,9 @@ mod acpi;
 mod heap;
 mod pci;
 
+/// How often (in hertz) the system timer ticks.
+const SYSTEM_TICK_FREQ: usize = 1000;
+
 #[macros::entry]
 unsafe fn kmain() {
     // -- ACPI
@@ -65,10 +70,67 @@ unsafe fn kmain() {
 
     log::info!("(ACPI) Boot processor is: {:#?}", pinfo.boot_processor);
 
-    for ap in pinfo.application_processors {
+    for ap in pinfo.application_processors.iter() {
         log::info!("    -> {:?}", ap);
     }
 
+    // -- Interrupt routing
+
+    match &info.interrupt_model {
+        ::acpi::platform::interrupt::InterruptModel::Apic(apic) => self::acpi::configure_io_apics(apic),
+        model => log::warn!("(ACPI) Unsupported interrupt model {:?}", model),
+    }
+
+    // -- SMP
+
+    arch::prelude::set_idle_handler(Some(heap::on_idle));
+
+    {
+        use ::acpi::platform::ProcessorState;
+
+        let apic_ids: Vec<u32> = pinfo
+            .application_processors
+            .iter()
+            .filter(|ap| ap.state != ProcessorState::Disabled)
+            .map(|ap| ap.local_apic_id as u32)
+            .collect();
+
+        arch::prelude::start_application_processors(&apic_ids);
+    }
+
+    // -- Timer
+
+    arch::prelude::start_timer(SYSTEM_TICK_FREQ);
+    x86_64::instructions::interrupts::enable();
+
+    // -- Clocks
+
+    let hpet = ::acpi::HpetInfo::new(&tables)
+        .ok()
+        .map(|hpet| hpet.base_address as u64);
+
+    arch::prelude::initialize_clocks(hpet);
+    arch::prelude::initialize_wall_clock(self::acpi::century_register(&tables));
+
+    // -- Input
+
+    arch::prelude::initialize_ps2(arch::prelude::KeyboardConfig::default());
+    arch::prelude::initialize_serial();
+
+    // -- Boot memory
+
+    // Everything we need from the ACPI tables has been copied out by now and
+    // the boot procedure is done with the multiboot information.
+    drop(tables);
+
+    {
+        use mem::reclaim::BootMemoryKind;
+
+        arch::prelude::reclaim_boot_memory(BootMemoryKind::Acpi);
+        arch::prelude::reclaim_boot_memory(BootMemoryKind::Bootloader);
+        arch::prelude::reclaim_boot_memory(BootMemoryKind::LowMemory);
+    }
+
     // -- PCI
 
     log::info!("(PCI Local Bus) Starting enumeration...");
@@ -98,4 +160,36 @@ unsafe fn kmain() {
     }
 
     log::info!("(PCI Local Bus) Completed enumeration!");
+
+    // -- Idle
+
+    let mut runtime = scheduler::Runtime::new();
+
+    // Lets the GDB stub show tasks as threads.
+    runtime.set_hooks(scheduler::TaskHooks {
+        spawned: arch::prelude::task_spawned,
+        polling: arch::prelude::set_current_task,
+        finished: arch::prelude::task_finished,
+    });
+
+    runtime.spawn(arch::prelude::log_drain_task());
+    runtime.spawn(arch::prelude::idle_task());
+    runtime.spawn(async {
+        loop {
+            let event = arch::prelude::keyboard::next_event().await;
+            log::debug!("(KEYBOARD) {:?}", event);
+
+            // Echo typed characters to the console.
+            if let Some(arch::prelude::keyboard::DecodedKey::Unicode(character)) = event.key {
+                arch::kprint!("{}", character);
+            }
+        }
+    });
+    runtime.spawn(async {
+        loop {
+            let event = arch::prelude::mouse::next_event().await;
+            log::trace!("(MOUSE) {:?}", event);
+        }
+    });
+    runtime.block_on(arch::prelude::zeroed_frames_refill_task());
 }
@@ -62,15 +62,16 @@ pub fn entry(_args: TokenStream, item: TokenStream) -> TokenStream {
         /// Automagically generated from the `entry` macro.
         #[no_mangle]
         pub unsafe extern "C" fn __kmain(multiboot_address: usize) {
+            // The default, per-target filters come from `log=` on the command line.
             let level = match ::core::option_env!("LOG_LEVEL") {
-                None => ::log::LevelFilter::Trace,
+                None => ::log::LevelFilter::Info,
                 Some(level) => match level {
                     "trace" => ::log::LevelFilter::Trace,
                     "debug" => ::log::LevelFilter::Debug,
                     "info" => ::log::LevelFilter::Info,
                     "warn" => ::log::LevelFilter::Warn,
                     "error" => ::log::LevelFilter::Error,
-                    _ => ::log::LevelFilter::Trace,
+                    _ => ::log::LevelFilter::Info,
                 }
             };
 
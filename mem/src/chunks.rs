@@ -120,6 +120,15 @@ impl<const N: usize> MemoryChunks<N> {
         N
     }
 
+    /// Get the amount of chunks currently being described.
+    #[inline]
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Contiguous { .. } => 1,
+            Self::Segregated { length, .. } => *length,
+        }
+    }
+
     #[inline]
     pub fn get(&self, idx: usize) -> Option<(usize, usize)> {
         match self {
@@ -206,9 +215,8 @@ impl<const N: usize> MemoryChunks<N> {
                 //   ^ {start} ^ {end}
                 //
                 // But its not intersecting.
-                if (*start > hole_start) && (*start > hole_end)
-                    || (*end < hole_start) && (*end < hole_end)
-                {
+                // (Chunks and holes are half-open, touching is not intersecting.)
+                if hole_end <= *start || hole_start >= *end || hole_start >= hole_end {
                     None
                 }
                 // The range is entirely within the hole!
@@ -219,7 +227,7 @@ impl<const N: usize> MemoryChunks<N> {
                 // __OOOOOXXXOOO__
                 //             ^ {hole_end}
                 //
-                else if *start >= hole_start && *end < hole_end {
+                else if *start >= hole_start && *end <= hole_end {
                     let mut body = [(0usize, 0usize); N];
 
                     *self = MemoryChunks::<{ N }>::Segregated { body, length: 0 };
@@ -239,7 +247,7 @@ impl<const N: usize> MemoryChunks<N> {
                 else if hole_start > *start && hole_end < *end {
                     let mut body = [(0usize, 0usize); N];
                     body[0] = (*start, hole_start);
-                    body[1] = (*end, hole_end);
+                    body[1] = (hole_end, *end);
 
                     *self = MemoryChunks::<{ N }>::Segregated { body, length: 2 };
 
@@ -294,48 +302,58 @@ impl<const N: usize> MemoryChunks<N> {
                 body: areas,
                 length,
             } => {
-                assert!(
-                    N > *length && (N - *length) >= 2,
-                    "Not enough remaining space to split! (length is {:?}, N is {:?})",
-                    length,
-                    N
-                );
-
-                let self_length = length;
-
-                for (idx, (start, end)) in areas.iter().cloned().enumerate() {
+                let mut hole = None;
+                let mut idx = 0;
+
+                // Every chunk intersecting the hole gets poked, not just the
+                // first, otherwise a hole spanning chunks would be left partly
+                // "available".
+                while idx < *length {
+                    let (start, end) = areas[idx];
                     let mut cursor = MemoryChunks::<{ 2 }>::Contiguous { start, end };
 
-                    match cursor.poke((hole_start, hole_end)) {
-                        Some(hole) => {
-                            let (left, right, length) = match cursor {
-                                MemoryChunks::Contiguous { .. } => unreachable!(),
-                                MemoryChunks::Segregated { body, length } => {
-                                    (body[0], body[1], length)
-                                }
-                            };
-
-                            if length == 1 {
-                                // The chunk was truncated.
-                                areas[idx] = left;
-                            } else if length == 0 {
-                                // The chunk was destroyed.
-                                areas[idx] = (0, 0);
-                            }else {
-                                // The chunk was split.
-                                areas[idx] = left;
-                                areas[idx + 1] = right;
-                                *self_length += 1;
-                            }
-
-                            return Some(hole);
+                    let poked = match cursor.poke((hole_start, hole_end)) {
+                        Some(poked) => poked,
+                        None => {
+                            idx += 1;
+                            continue;
                         }
-
-                        None => continue,
+                    };
+
+                    let (left, right, cursor_length) = match cursor {
+                        MemoryChunks::Contiguous { .. } => unreachable!(),
+                        MemoryChunks::Segregated { body, length } => (body[0], body[1], length),
+                    };
+
+                    if cursor_length == 0 {
+                        // The chunk was destroyed, shift the rest down over it.
+                        areas.copy_within((idx + 1)..*length, idx);
+                        *length -= 1;
+                        areas[*length] = (0, 0);
+                    } else if cursor_length == 1 {
+                        // The chunk was truncated.
+                        areas[idx] = left;
+                        idx += 1;
+                    } else {
+                        // The chunk was split, shift the rest up to make room.
+                        assert!(
+                            *length < N,
+                            "Not enough remaining space to split! (length is {:?}, N is {:?})",
+                            length,
+                            N
+                        );
+
+                        areas.copy_within((idx + 1)..*length, idx + 2);
+                        areas[idx] = left;
+                        areas[idx + 1] = right;
+                        *length += 1;
+                        idx += 2;
                     }
+
+                    hole = Some(poked);
                 }
 
-                None
+                hole
             }
         }
     }
@@ -0,0 +1,378 @@
+//! Fault injection for the allocation and mapping paths.
+//!
+//! Error paths like `MapToError::FrameAllocationFailed` or a failed heap
+//! extension basically never happen under QEMU with plenty of RAM, so instead
+//! of waiting for them we make them happen. Every `FaultPoint` can be given a
+//! `Schedule` (and optionally a call-site filter) that decides which calls to
+//! `should_fail` return `true`.
+//!
+//! Without the `fault-injection` feature `should_fail` is always `false` and
+//! compiles away, so injection points don't need to be `cfg`'d.
+//!
+//! Schedules are usually set from the kernel command line, see
+//! `configure_from_command_line`:
+//!
+//! ```text
+//! fault=map_to:every:50@heap/mod.rs,global_alloc:prob:1/1000 fault_seed=42
+//! ```
+//!
+//! Call-site filters only see through `#[track_caller]` functions (like
+//! `MemoryManager::map`), so `map_to` above only fails for the heap's own
+//! mappings. The global allocator is entered through `alloc::alloc`, its
+//! site is always the kernel's `heap/mod.rs`.
+
+use core::fmt;
+
+/// A named place that can be made to fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultPoint {
+    /// Handing out physical frames (`MemoryManager::alloc_frame` and `PhysFrameAlloc`.)
+    FrameAlloc,
+
+    /// `MemoryManager::map_to`, fails with `MapToError::FrameAllocationFailed`.
+    MapTo,
+
+    /// The global allocator.
+    GlobalAlloc,
+
+    /// Growing the kernel heap (i.e. the heap "rescue" path.)
+    HeapGrow,
+}
+
+impl FaultPoint {
+    pub const ALL: [FaultPoint; 4] = [
+        FaultPoint::FrameAlloc,
+        FaultPoint::MapTo,
+        FaultPoint::GlobalAlloc,
+        FaultPoint::HeapGrow,
+    ];
+
+    /// The name used on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FaultPoint::FrameAlloc => "frame_alloc",
+            FaultPoint::MapTo => "map_to",
+            FaultPoint::GlobalAlloc => "global_alloc",
+            FaultPoint::HeapGrow => "heap_grow",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().cloned().find(|point| point.name() == name)
+    }
+}
+
+/// Decides which (matching) calls to an injection point fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// Never fail (the default.)
+    Never,
+
+    /// Fail every call.
+    Always,
+
+    /// Fail every Nth call.
+    EveryNth(u64),
+
+    /// Fail a call with a probability of `numerator / denominator`.
+    Probability { numerator: u64, denominator: u64 },
+}
+
+/// How often an injection point has been hit and how often it failed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FaultStats {
+    pub calls: u64,
+    pub injected: u64,
+}
+
+/// Problems with a `fault=` command line argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError<'a> {
+    UnknownPoint(&'a str),
+    InvalidSchedule(&'a str),
+    SiteTooLong(&'a str),
+}
+
+impl fmt::Display for ParseError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnknownPoint(point) => write!(f, "unknown injection point {:?}", point),
+            ParseError::InvalidSchedule(schedule) => write!(f, "invalid schedule {:?}", schedule),
+            ParseError::SiteTooLong(site) => write!(f, "call-site filter {:?} is too long", site),
+        }
+    }
+}
+
+/// Parse a single `point:schedule[@site]` specification.
+///
+/// Schedules are `always`, `never`, `every:N` or `prob:N/D`, the site filter
+/// is matched against the caller's `file` (or `file:line` if it ends in a
+/// line number.)
+pub fn parse_spec(spec: &str) -> Result<(FaultPoint, Schedule, Option<&str>), ParseError<'_>> {
+    let (spec, site) = match spec.find('@') {
+        Some(idx) => (&spec[..idx], Some(&spec[(idx + 1)..])),
+        None => (spec, None),
+    };
+
+    let (point, schedule) = match spec.find(':') {
+        Some(idx) => (&spec[..idx], &spec[(idx + 1)..]),
+        None => (spec, "always"),
+    };
+
+    let point = FaultPoint::from_name(point).ok_or(ParseError::UnknownPoint(point))?;
+
+    let invalid = || ParseError::InvalidSchedule(schedule);
+
+    let schedule = if schedule == "always" {
+        Schedule::Always
+    } else if schedule == "never" {
+        Schedule::Never
+    } else if let Some(n) = schedule.strip_prefix("every:") {
+        match n.parse::<u64>() {
+            Ok(0) | Err(_) => return Err(invalid()),
+            Ok(n) => Schedule::EveryNth(n),
+        }
+    } else if let Some(ratio) = schedule.strip_prefix("prob:") {
+        let idx = ratio.find('/').ok_or_else(invalid)?;
+
+        let numerator = ratio[..idx].parse::<u64>().map_err(|_| invalid())?;
+        let denominator = ratio[(idx + 1)..].parse::<u64>().map_err(|_| invalid())?;
+
+        if denominator == 0 || numerator > denominator {
+            return Err(invalid());
+        }
+
+        Schedule::Probability { numerator, denominator }
+    } else {
+        return Err(invalid());
+    };
+
+    Ok((point, schedule, site.filter(|site| !site.is_empty())))
+}
+
+#[cfg(feature = "fault-injection")]
+pub use self::enabled::*;
+
+#[cfg(not(feature = "fault-injection"))]
+pub use self::disabled::*;
+
+#[cfg(feature = "fault-injection")]
+mod enabled {
+    use core::{
+        panic::Location,
+        sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+    };
+
+    use super::{parse_spec, FaultPoint, FaultStats, ParseError, Schedule};
+
+    /// The longest call-site filter that can be stored.
+    const MAX_SITE_LENGTH: usize = 64;
+
+    const MODE_NEVER: u8 = 0;
+    const MODE_ALWAYS: u8 = 1;
+    const MODE_EVERY: u8 = 2;
+    const MODE_PROBABILITY: u8 = 3;
+
+    const ZERO: AtomicU8 = AtomicU8::new(0);
+
+    /// Everything is atomic since injection points are hit from any CPU
+    /// (and from inside the global allocator, so no locks.)
+    struct PointState {
+        mode: AtomicU8,
+        a: AtomicU64,
+        b: AtomicU64,
+        calls: AtomicU64,
+        injected: AtomicU64,
+        site: [AtomicU8; MAX_SITE_LENGTH],
+        site_length: AtomicUsize,
+    }
+
+    impl PointState {
+        const NEW: Self = Self {
+            mode: AtomicU8::new(MODE_NEVER),
+            a: AtomicU64::new(0),
+            b: AtomicU64::new(0),
+            calls: AtomicU64::new(0),
+            injected: AtomicU64::new(0),
+            site: [ZERO; MAX_SITE_LENGTH],
+            site_length: AtomicUsize::new(0),
+        };
+
+        fn site_matches(&self, location: &Location<'_>) -> bool {
+            let length = self.site_length.load(Ordering::Acquire);
+
+            if length == 0 {
+                return true;
+            }
+
+            let mut buf = [0u8; MAX_SITE_LENGTH];
+
+            for (byte, site) in buf.iter_mut().zip(self.site.iter()).take(length) {
+                *byte = site.load(Ordering::Relaxed);
+            }
+
+            let site = match core::str::from_utf8(&buf[..length]) {
+                Ok(site) => site,
+                Err(_) => return false,
+            };
+
+            // `file:line` pins a single line, anything else is a path fragment.
+            match site.rfind(':').map(|idx| (&site[..idx], site[(idx + 1)..].parse::<u32>())) {
+                Some((file, Ok(line))) => location.file().ends_with(file) && location.line() == line,
+                _ => location.file().contains(site),
+            }
+        }
+    }
+
+    static POINTS: [PointState; FaultPoint::ALL.len()] = [PointState::NEW; FaultPoint::ALL.len()];
+
+    /// The xorshift state used for `Schedule::Probability`.
+    static RNG: AtomicU64 = AtomicU64::new(0x2545_f491_4f6c_dd1d);
+
+    fn next_random() -> u64 {
+        let mut state = RNG.load(Ordering::Relaxed);
+
+        loop {
+            let mut x = state;
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+
+            match RNG.compare_exchange_weak(state, x, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return x,
+                Err(current) => state = current,
+            }
+        }
+    }
+
+    /// Seed the generator behind probabilistic schedules (zero is ignored.)
+    pub fn set_seed(seed: u64) {
+        if seed != 0 {
+            RNG.store(seed, Ordering::Relaxed);
+        }
+    }
+
+    /// Set the schedule (and call-site filter) of `point`, resetting its stats.
+    pub fn set_schedule(point: FaultPoint, schedule: Schedule, site: Option<&str>) -> Result<(), ParseError<'_>> {
+        let state = &POINTS[point as usize];
+        let site = site.unwrap_or("");
+
+        if site.len() > MAX_SITE_LENGTH {
+            return Err(ParseError::SiteTooLong(site));
+        }
+
+        // Disarm the point while it's being changed.
+        state.mode.store(MODE_NEVER, Ordering::SeqCst);
+
+        for (slot, byte) in state.site.iter().zip(site.bytes()) {
+            slot.store(byte, Ordering::Relaxed);
+        }
+
+        state.site_length.store(site.len(), Ordering::Release);
+
+        let (mode, a, b) = match schedule {
+            Schedule::Never => (MODE_NEVER, 0, 0),
+            Schedule::Always => (MODE_ALWAYS, 0, 0),
+            Schedule::EveryNth(n) => (MODE_EVERY, n, 0),
+            Schedule::Probability { numerator, denominator } => (MODE_PROBABILITY, numerator, denominator),
+        };
+
+        state.a.store(a, Ordering::Relaxed);
+        state.b.store(b, Ordering::Relaxed);
+        state.calls.store(0, Ordering::Relaxed);
+        state.injected.store(0, Ordering::Relaxed);
+        state.mode.store(mode, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Apply every `fault=` (and `fault_seed=`) argument on a command line.
+    ///
+    /// Returns the first bad specification, the ones before it stay applied.
+    pub fn configure_from_command_line(command_line: &str) -> Result<usize, ParseError<'_>> {
+        let mut count = 0;
+
+        for argument in command_line.split_whitespace() {
+            if let Some(seed) = argument.strip_prefix("fault_seed=") {
+                set_seed(seed.parse().map_err(|_| ParseError::InvalidSchedule(seed))?);
+            } else if let Some(specs) = argument.strip_prefix("fault=") {
+                for spec in specs.split(',').filter(|spec| !spec.is_empty()) {
+                    let (point, schedule, site) = parse_spec(spec)?;
+                    set_schedule(point, schedule, site)?;
+                    count += 1;
+                }
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// The stats of `point` since its schedule was last set.
+    pub fn stats(point: FaultPoint) -> FaultStats {
+        let state = &POINTS[point as usize];
+
+        FaultStats {
+            calls: state.calls.load(Ordering::Relaxed),
+            injected: state.injected.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Should the call to `point` fail?
+    ///
+    /// The call-site filter is matched against the location of the caller,
+    /// `#[track_caller]` injection points pass their own caller along.
+    #[track_caller]
+    pub fn should_fail(point: FaultPoint) -> bool {
+        let state = &POINTS[point as usize];
+
+        let mode = state.mode.load(Ordering::SeqCst);
+
+        if mode == MODE_NEVER || !state.site_matches(Location::caller()) {
+            return false;
+        }
+
+        let call = state.calls.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let fail = match mode {
+            MODE_ALWAYS => true,
+            MODE_EVERY => call % state.a.load(Ordering::Relaxed) == 0,
+            MODE_PROBABILITY => {
+                let (numerator, denominator) =
+                    (state.a.load(Ordering::Relaxed), state.b.load(Ordering::Relaxed));
+
+                next_random() % denominator < numerator
+            }
+            _ => false,
+        };
+
+        if fail {
+            state.injected.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fail
+    }
+}
+
+#[cfg(not(feature = "fault-injection"))]
+mod disabled {
+    use super::{FaultPoint, FaultStats, ParseError, Schedule};
+
+    pub fn set_seed(_seed: u64) {}
+
+    pub fn set_schedule(_point: FaultPoint, _schedule: Schedule, _site: Option<&str>) -> Result<(), ParseError<'_>> {
+        Ok(())
+    }
+
+    pub fn configure_from_command_line(_command_line: &str) -> Result<usize, ParseError<'_>> {
+        Ok(0)
+    }
+
+    pub fn stats(_point: FaultPoint) -> FaultStats {
+        FaultStats::default()
+    }
+
+    #[inline(always)]
+    pub fn should_fail(_point: FaultPoint) -> bool {
+        false
+    }
+}
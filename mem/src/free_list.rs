@@ -0,0 +1,68 @@
+//! An intrusive list of physical frames that have been handed back.
+//!
+//! Free frames aren't used for anything so the list is threaded through the
+//! frames themselves, the first 8 bytes of every free frame hold the address
+//! of the next one.
+
+use x86_64::{structures::paging::PhysFrame, PhysAddr, VirtAddr};
+
+/// A LIFO stack of free physical frames.
+#[derive(Debug, Default)]
+pub struct FreeFrameList {
+    head: Option<u64>,
+    length: usize,
+}
+
+impl FreeFrameList {
+    /// Create a new, empty, list.
+    pub const fn new() -> Self {
+        Self {
+            head: None,
+            length: 0,
+        }
+    }
+
+    /// The amount of frames in the list.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Push a frame onto the list.
+    ///
+    /// # Safety
+    ///
+    /// The frame must be mapped at `physical_memory_offset` and must not be
+    /// in use by anything else (its first 8 bytes will be overwritten.)
+    #[inline]
+    pub unsafe fn push(&mut self, frame: PhysFrame, physical_memory_offset: VirtAddr) {
+        let address = frame.start_address().as_u64();
+        let link: *mut u64 = (physical_memory_offset + address).as_mut_ptr();
+
+        // `u64::MAX` is never a valid frame address so it marks the end.
+        link.write(self.head.unwrap_or(u64::MAX));
+
+        self.head = Some(address);
+        self.length += 1;
+    }
+
+    /// Pop the most recently pushed frame off the list.
+    ///
+    /// # Safety
+    ///
+    /// `physical_memory_offset` must be the same offset frames were pushed with.
+    #[inline]
+    pub unsafe fn pop(&mut self, physical_memory_offset: VirtAddr) -> Option<PhysFrame> {
+        let address = self.head?;
+        let link: *const u64 = (physical_memory_offset + address).as_ptr();
+
+        self.head = match link.read() {
+            u64::MAX => None,
+            next => Some(next),
+        };
+
+        self.length -= 1;
+
+        Some(PhysFrame::containing_address(PhysAddr::new(address)))
+    }
+}
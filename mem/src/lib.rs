@@ -1,9 +1,10 @@
 //! Kernel memory management utilities.
 
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 #![feature(allocator_api)]
 #![feature(min_const_generics)]
 #![feature(unchecked_math)]
+#![feature(const_in_array_repeat_expressions)]
 
 use chunks::MemoryChunks;
 use multiboot2::BootInformation;
@@ -18,6 +19,18 @@ use x86_64::{PhysAddr, structures::{self, paging::{FrameAllocator, Page, PageSiz
 
 pub mod boot_frame;
 pub mod chunks;
+pub mod fault;
+pub mod free_list;
+pub mod memory_map;
+pub mod reclaim;
+pub mod zeroed;
+
+#[cfg(feature = "std")]
+pub mod sim;
+
+use memory_map::MemoryMap;
+use reclaim::{BootMemoryKind, ReclaimableMemory};
+use zeroed::{ZeroPoolStats, ZeroedFramePool};
 
 /// Used as a buffer to store areas of memory market available.
 ///
@@ -27,7 +40,25 @@ pub mod chunks;
 /// I calculated `N` by launching QEMU with as much memory as I could `47GiB`
 /// and then counted the amount of available memory regions described by the
 /// multiboot memory map tag and doubled it (in my case it was `3` hence `6`)
-pub type PhysicalMemory = MemoryChunks<{ 6 }>;
+///
+/// Carving out the kernel image, modules and boot information splits those
+/// areas up some more, hence `16`. Areas past that are dropped (the kernel
+/// warns about them.)
+pub type PhysicalMemory = MemoryChunks<{ 16 }>;
+
+/// The sanitised boot memory map, see `memory_map::MemoryMap`.
+///
+/// Room for the firmware's areas, every carve-out and the splits between them.
+pub type BootMemoryMap = MemoryMap<{ 64 }>;
+
+/// The pool of pre-zeroed frames kept around by a `MemoryManager`.
+///
+/// 64 frames (256KiB) is enough to serve a handful of heap extensions
+/// without having to zero anything on the spot.
+pub type ZeroedFrames = ZeroedFramePool<{ 64 }>;
+
+/// Boot-time memory waiting to be reclaimed, see `MemoryManager::reclaim`.
+pub type BootMemory = ReclaimableMemory<{ 16 }>;
 
 /// A cursor for iterating over physframe chunks.
 #[derive(Debug, Default, Clone)]
@@ -48,6 +79,17 @@ pub struct PhysFrameAlloc {
 
 unsafe impl FrameAllocator<Size4KiB> for PhysFrameAlloc {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if fault::should_fail(fault::FaultPoint::FrameAlloc) {
+            return None;
+        }
+
+        self.next_frame()
+    }
+}
+
+impl PhysFrameAlloc {
+    /// Hand out the frame under the cursor (bypassing fault injection.)
+    pub fn next_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
         let frame_size = <Size4KiB as PageSize>::SIZE as usize;
 
         let PhysFrameCursor {
@@ -55,14 +97,13 @@ unsafe impl FrameAllocator<Size4KiB> for PhysFrameAlloc {
             mut section_idx,
         } = self.physframe_cursor;
 
-        chunk_idx %= self.memory.capacity();
-
         loop {
+            // Running off the last chunk means we're out of memory, wrapping
+            // around would hand out frames that are already in use.
             let chunk = self
                 .memory
                 .get(chunk_idx)
-                .map(|(a, b)| (a as u64)..(b as u64))
-                .expect("Out of bounds chunk read..."); // Should never actually be `None`
+                .map(|(a, b)| (a as u64)..(b as u64))?;
 
             let frame = chunk
                 .step_by(frame_size)
@@ -80,8 +121,8 @@ unsafe impl FrameAllocator<Size4KiB> for PhysFrameAlloc {
 
                 break Some(frame);
             } else {
-                // We're done with this chunk, wrap over to the next one.
-                chunk_idx = chunk_idx.wrapping_add(1) % self.memory.capacity();
+                // We're done with this chunk, move over to the next one.
+                chunk_idx += 1;
                 section_idx = 0;
             }
         }
@@ -103,13 +144,61 @@ pub trait MemoryManager {
         frame_allocator: &mut PhysFrameAlloc,
     ) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>>;
 
+    /// Map `page` to a freshly allocated (zeroed) frame.
+    ///
+    /// Running out of frames is `MapToError::FrameAllocationFailed`, the same
+    /// as running out of frames for the page tables themselves.
+    #[track_caller]
     fn map(
         &mut self,
         page: Page<Size4KiB>,
         flags: u64,
-    ) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>>;
+    ) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>> {
+        let frame = self
+            .alloc_zeroed_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        let mut frame_allocator = self.frame_allocator();
+
+        self.map_to(page, frame, flags, &mut frame_allocator)
+    }
 
-    fn unmap(&mut self, page: Page<Size4KiB>);
+    /// Construct a frame allocator that continues from where the last one stopped.
+    fn frame_allocator(&self) -> PhysFrameAlloc;
+
+    /// Unmap a page, returning the frame it was mapped to (if it was mapped.)
+    ///
+    /// The frame is **not** freed, see `free_frame`.
+    fn unmap(&mut self, page: Page<Size4KiB>) -> Option<PhysFrame>;
+
+    /// Hand a frame back so that it can be allocated again.
+    ///
+    /// # Safety
+    ///
+    /// The frame must no longer be mapped or otherwise in use.
+    unsafe fn free_frame(&mut self, frame: PhysFrame);
+
+    /// Allocate a physical frame, its contents are left untouched.
+    fn alloc_frame(&mut self) -> Option<PhysFrame>;
+
+    /// Allocate a physical frame that is guaranteed to be filled with zeroes.
+    ///
+    /// Frames are taken from the pre-zeroed pool when possible and zeroed on
+    /// the spot otherwise.
+    fn alloc_zeroed_frame(&mut self) -> Option<PhysFrame>;
+
+    /// Zero up to `budget` frames ahead of time and stash them in the pool,
+    /// returning how many were actually added.
+    fn refill_zeroed_frames(&mut self, budget: usize) -> usize;
+
+    /// Hit/miss statistics for the pre-zeroed frame pool.
+    fn zeroed_frame_stats(&self) -> ZeroPoolStats;
+
+    /// Release all boot-time memory of `kind` into the physical allocator,
+    /// returning the amount of frames that became available.
+    ///
+    /// Callers must be done with whatever lived in that memory.
+    fn reclaim(&mut self, kind: BootMemoryKind) -> usize;
 
     fn initialize(&mut self, info: &BootInformation);
 }
@@ -0,0 +1,267 @@
+//! Sanitising the (firmware provided) memory map.
+//!
+//! The multiboot2 memory map is passed along from the firmware more or less
+//! verbatim, areas may overlap, start or end in the middle of a page and
+//! happily claim that the memory our kernel image was loaded into is
+//! "available". `MemoryMap` normalises all of that:
+//!
+//! * Usable areas are shrunk inward to page boundaries, everything else is
+//!   grown outward.
+//! * Overlaps are resolved by `RegionKind` precedence (reserved wins.)
+//! * Carve-outs (kernel image, modules, boot information...) override the
+//!   usable memory they lie in.
+
+use core::{fmt, iter::once};
+
+use multiboot2::{BootInformation, MemoryAreaType};
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// What a region of physical memory is used for.
+///
+/// Variants are declared in order of precedence, when regions overlap the
+/// later variant wins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RegionKind {
+    /// Free to use.
+    Available,
+
+    /// Holds ACPI tables, usable once they've been parsed.
+    AcpiReclaimable,
+
+    /// The multiboot information structure.
+    BootInfo,
+
+    /// A multiboot module.
+    Module,
+
+    /// ACPI tables that were found outside of ACPI reclaimable memory.
+    AcpiTables,
+
+    /// The kernel image (ELF sections.)
+    Kernel,
+
+    /// ACPI non-volatile storage, must be preserved across sleep states.
+    AcpiNvs,
+
+    /// Reserved by the firmware or hardware.
+    Reserved,
+
+    /// Known bad memory.
+    Defective,
+}
+
+impl RegionKind {
+    /// Every kind, in order of precedence.
+    pub const ALL: [RegionKind; 9] = [
+        RegionKind::Available,
+        RegionKind::AcpiReclaimable,
+        RegionKind::BootInfo,
+        RegionKind::Module,
+        RegionKind::AcpiTables,
+        RegionKind::Kernel,
+        RegionKind::AcpiNvs,
+        RegionKind::Reserved,
+        RegionKind::Defective,
+    ];
+
+    /// Regions of usable kinds shrink to page boundaries instead of growing.
+    #[inline]
+    pub fn is_usable(&self) -> bool {
+        matches!(self, RegionKind::Available | RegionKind::AcpiReclaimable)
+    }
+
+    /// A short, e820-style, name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RegionKind::Available => "usable",
+            RegionKind::AcpiReclaimable => "ACPI data",
+            RegionKind::BootInfo => "boot info",
+            RegionKind::Module => "module",
+            RegionKind::AcpiTables => "ACPI tables",
+            RegionKind::Kernel => "kernel",
+            RegionKind::AcpiNvs => "ACPI NVS",
+            RegionKind::Reserved => "reserved",
+            RegionKind::Defective => "unusable",
+        }
+    }
+}
+
+impl From<MemoryAreaType> for RegionKind {
+    fn from(typ: MemoryAreaType) -> Self {
+        match typ {
+            MemoryAreaType::Available => RegionKind::Available,
+            MemoryAreaType::AcpiAvailable => RegionKind::AcpiReclaimable,
+            MemoryAreaType::ReservedHibernate => RegionKind::AcpiNvs,
+            MemoryAreaType::Defective => RegionKind::Defective,
+            _ => RegionKind::Reserved,
+        }
+    }
+}
+
+// -- MemoryRegion
+
+/// A `start..end` region of physical memory of some kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: RegionKind,
+}
+
+impl MemoryRegion {
+    const EMPTY: Self = Self {
+        start: 0,
+        end: 0,
+        kind: RegionKind::Reserved,
+    };
+
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+impl fmt::Display for MemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[mem {:#018x}-{:#018x}] {}",
+            self.start,
+            self.end.saturating_sub(1),
+            self.kind.name()
+        )
+    }
+}
+
+// -- MemoryMap
+
+/// A fixed capacity memory map.
+///
+/// Regions are added in any order with `add`, after `sanitize` they're page
+/// aligned, sorted and never overlap.
+#[derive(Debug, Clone)]
+pub struct MemoryMap<const N: usize> {
+    regions: [MemoryRegion; N],
+    length: usize,
+}
+
+impl<const N: usize> Default for MemoryMap<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> MemoryMap<N> {
+    pub const fn new() -> Self {
+        Self {
+            regions: [MemoryRegion::EMPTY; N],
+            length: 0,
+        }
+    }
+
+    /// Add a region, panics if the map is full.
+    pub fn add(&mut self, start: u64, end: u64, kind: RegionKind) {
+        assert!(
+            self.length < N,
+            "Memory map is full! (N is {:?}, adding {:#x}..{:#x} {:?})",
+            N,
+            start,
+            end,
+            kind
+        );
+
+        self.regions[self.length] = MemoryRegion { start, end, kind };
+        self.length += 1;
+    }
+
+    /// Add every area of the multiboot memory map and every module.
+    pub fn add_boot_info(&mut self, info: &BootInformation) {
+        let memory_map = info.memory_map_tag().expect("Memory map tag required.");
+
+        for area in memory_map.all_memory_areas() {
+            self.add(area.start_address(), area.end_address(), area.typ().into());
+        }
+
+        for module in info.module_tags() {
+            let (start, end) = (module.start_address() as u64, module.end_address() as u64);
+            self.add(start, end, RegionKind::Module);
+        }
+    }
+
+    /// Iterate over all regions.
+    pub fn iter(&self) -> impl Iterator<Item = &MemoryRegion> + '_ {
+        self.regions[..self.length].iter()
+    }
+
+    /// Iterate over the regions of `kind` as `(start, end)` tuples.
+    pub fn regions_of(&self, kind: RegionKind) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.iter()
+            .filter(move |region| region.kind == kind)
+            .map(|region| (region.start as usize, region.end as usize))
+    }
+
+    /// The total amount of bytes of `kind`.
+    pub fn total(&self, kind: RegionKind) -> u64 {
+        self.iter()
+            .filter(|region| region.kind == kind)
+            .map(MemoryRegion::size)
+            .sum()
+    }
+
+    /// Page align, sort and resolve overlaps.
+    pub fn sanitize(&mut self) {
+        // Alignment first, usable memory shrinks and everything else grows.
+        for region in self.regions[..self.length].iter_mut() {
+            if region.kind.is_usable() {
+                region.start = (region.start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+                region.end &= !(PAGE_SIZE - 1);
+            } else {
+                region.start &= !(PAGE_SIZE - 1);
+                region.end = (region.end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            }
+        }
+
+        let input = self.clone();
+
+        // There can be up to `2 * N` boundaries so rather than collecting
+        // and sorting them we walk upwards, always picking the smallest
+        // boundary that is larger than the current one.
+        let next_boundary = |after: Option<u64>| {
+            input
+                .iter()
+                .filter(|region| region.start < region.end)
+                .flat_map(|region| once(region.start).chain(once(region.end)))
+                .filter(|boundary| after.map_or(true, |after| *boundary > after))
+                .min()
+        };
+
+        self.length = 0;
+
+        let mut cursor = next_boundary(None);
+
+        while let Some(start) = cursor {
+            let end = match next_boundary(Some(start)) {
+                Some(end) => end,
+                None => break,
+            };
+
+            // The elementary interval `start..end` takes the kind of the
+            // region with the highest precedence covering it (if any.)
+            let kind = input
+                .iter()
+                .filter(|region| region.start <= start && end <= region.end)
+                .map(|region| region.kind)
+                .max();
+
+            if let Some(kind) = kind {
+                match self.regions[..self.length].last_mut() {
+                    Some(last) if last.kind == kind && last.end == start => last.end = end,
+                    _ => self.add(start, end, kind),
+                }
+            }
+
+            cursor = Some(end);
+        }
+    }
+}
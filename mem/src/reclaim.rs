@@ -0,0 +1,93 @@
+//! Bookkeeping for boot-time memory that can be handed back later on.
+//!
+//! Some physical memory is off limits while booting (ACPI tables, the
+//! multiboot information structure, low memory...) but turns into ordinary
+//! RAM once the kernel is done with it. Those areas are remembered, by kind,
+//! so they can be released into the physical allocator at the right time.
+
+use x86_64::{
+    structures::paging::{PageSize, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+/// The kinds of boot-time memory that can be reclaimed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMemoryKind {
+    /// Areas marked "ACPI reclaimable" in the memory map.
+    Acpi,
+
+    /// Structures the bootloader left behind (i.e. the multiboot information.)
+    Bootloader,
+
+    /// Conventional memory below 1MiB that nothing needs.
+    LowMemory,
+}
+
+/// A fixed capacity list of typed `(start, end)` areas.
+#[derive(Debug, Clone)]
+pub struct ReclaimableMemory<const N: usize> {
+    areas: [(usize, usize, BootMemoryKind); N],
+    length: usize,
+}
+
+impl<const N: usize> Default for ReclaimableMemory<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ReclaimableMemory<N> {
+    pub const fn new() -> Self {
+        Self {
+            areas: [(0, 0, BootMemoryKind::Acpi); N],
+            length: 0,
+        }
+    }
+
+    /// Remember an area, returns `false` (and forgets it) if we're out of space.
+    pub fn push(&mut self, start: usize, end: usize, kind: BootMemoryKind) -> bool {
+        if self.length == N || start >= end {
+            return false;
+        }
+
+        self.areas[self.length] = (start, end, kind);
+        self.length += 1;
+
+        true
+    }
+
+    /// Iterate over the remembered areas.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize, BootMemoryKind)> + '_ {
+        self.areas[..self.length].iter().cloned()
+    }
+
+    /// Forget every area of `kind`, calling `f(start, end)` for each of them.
+    pub fn take(&mut self, kind: BootMemoryKind, mut f: impl FnMut(usize, usize)) {
+        let mut idx = 0;
+
+        while idx < self.length {
+            let (start, end, area_kind) = self.areas[idx];
+
+            if area_kind == kind {
+                f(start, end);
+
+                self.areas.copy_within((idx + 1)..self.length, idx);
+                self.length -= 1;
+            } else {
+                idx += 1;
+            }
+        }
+    }
+}
+
+/// Iterate over every whole frame that lies within `start..end`.
+pub fn frames_within(start: usize, end: usize) -> impl Iterator<Item = PhysFrame> {
+    let size = Size4KiB::SIZE as usize;
+
+    let first = (start + size - 1) & !(size - 1);
+    let last = end & !(size - 1);
+
+    (first..last)
+        .step_by(size)
+        .map(|address| PhysFrame::containing_address(PhysAddr::new(address as u64)))
+}
@@ -0,0 +1,411 @@
+//! A simulated physical memory backend for exercising `mem` on the host.
+//!
+//! Only available with the `std` feature, the pieces are:
+//!
+//! * `SimulatedMemory` backs a fake physical address space with a heap buffer.
+//! * `FakeBootInfo` encodes a multiboot2 information structure containing a
+//!   made up memory map.
+//! * `SimMemoryManager` implements `MemoryManager` with page tables that live
+//!   entirely inside the simulated memory.
+//!
+//! There is no TLB to speak of, so callers are expected to `ignore()` any
+//! `MapperFlush` returned from the simulated manager.
+
+use multiboot2::BootInformation;
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, MapperFlush},
+        Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    fault::{self, FaultPoint},
+    free_list::FreeFrameList,
+    memory_map::RegionKind,
+    reclaim::{frames_within, BootMemoryKind},
+    zeroed::{zero_frame, ZeroPoolStats},
+    BootMemory, BootMemoryMap, MemoryManager, PhysFrameAlloc, PhysFrameCursor, PhysicalMemory, ZeroedFrames,
+};
+
+const FRAME_SIZE: usize = Size4KiB::SIZE as usize;
+
+// -- SimulatedMemory
+
+#[derive(Clone)]
+#[repr(C, align(4096))]
+struct SimFrame([u8; FRAME_SIZE]);
+
+/// A heap allocated stand-in for physical memory starting at address `0x0`.
+pub struct SimulatedMemory {
+    frames: Vec<SimFrame>,
+}
+
+impl SimulatedMemory {
+    /// Simulate `size` bytes of physical memory (rounded up to whole frames.)
+    ///
+    /// The memory is filled with `0xAA` so that stale contents stand out.
+    pub fn new(size: usize) -> Self {
+        let count = (size + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        Self {
+            frames: vec![SimFrame([0xAA; FRAME_SIZE]); count],
+        }
+    }
+
+    /// The amount of simulated bytes.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.frames.len() * FRAME_SIZE
+    }
+
+    /// The host address at which simulated physical address `0x0` lives.
+    #[inline]
+    pub fn offset(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.frames.as_ptr())
+    }
+
+    /// Get the contents of a simulated frame.
+    #[inline]
+    pub fn frame(&self, frame: PhysFrame) -> &[u8] {
+        &self.frames[Self::index_of(frame)].0
+    }
+
+    /// Get the contents of a simulated frame, mutably.
+    #[inline]
+    pub fn frame_mut(&mut self, frame: PhysFrame) -> &mut [u8] {
+        &mut self.frames[Self::index_of(frame)].0
+    }
+
+    #[inline]
+    fn index_of(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() as usize) / FRAME_SIZE
+    }
+}
+
+// -- FakeBootInfo
+
+/// Memory area types as they're encoded in a multiboot2 memory map.
+pub mod area_type {
+    pub const AVAILABLE: u32 = 1;
+    pub const RESERVED: u32 = 2;
+    pub const ACPI_AVAILABLE: u32 = 3;
+    pub const RESERVED_HIBERNATE: u32 = 4;
+    pub const DEFECTIVE: u32 = 5;
+}
+
+/// A builder for multiboot2 information structures with a made up memory map.
+#[derive(Debug, Default, Clone)]
+pub struct FakeBootInfo {
+    areas: Vec<(u64, u64, u32)>,
+}
+
+impl FakeBootInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a memory area (`start..end`) of some `area_type`.
+    pub fn area(mut self, start: u64, end: u64, typ: u32) -> Self {
+        assert!(start <= end, "Memory area ends before it starts!");
+        self.areas.push((start, end, typ));
+        self
+    }
+
+    /// Add an available memory area.
+    pub fn available(self, start: u64, end: u64) -> Self {
+        self.area(start, end, area_type::AVAILABLE)
+    }
+
+    /// Add a reserved memory area.
+    pub fn reserved(self, start: u64, end: u64) -> Self {
+        self.area(start, end, area_type::RESERVED)
+    }
+
+    /// Encode the information structure into a buffer `multiboot2` can load.
+    pub fn encode(&self) -> EncodedBootInfo {
+        fn push_u32(buf: &mut Vec<u8>, value: u32) {
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+
+        fn push_u64(buf: &mut Vec<u8>, value: u64) {
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+
+        const ENTRY_SIZE: u32 = 24;
+
+        let mut buf = Vec::new();
+
+        // Fixed part, `total_size` is patched in at the end.
+        push_u32(&mut buf, 0);
+        push_u32(&mut buf, 0);
+
+        // Memory map tag.
+        push_u32(&mut buf, 6);
+        push_u32(&mut buf, 16 + ENTRY_SIZE * self.areas.len() as u32);
+        push_u32(&mut buf, ENTRY_SIZE);
+        push_u32(&mut buf, 0);
+
+        for (start, end, typ) in self.areas.iter().cloned() {
+            push_u64(&mut buf, start);
+            push_u64(&mut buf, end - start);
+            push_u32(&mut buf, typ);
+            push_u32(&mut buf, 0);
+        }
+
+        // Tags are 8 byte aligned.
+        while buf.len() % 8 != 0 {
+            buf.push(0);
+        }
+
+        // End tag.
+        push_u32(&mut buf, 0);
+        push_u32(&mut buf, 8);
+
+        let total_size = buf.len() as u32;
+        buf[..4].copy_from_slice(&total_size.to_le_bytes());
+
+        let words = buf
+            .chunks(8)
+            .map(|chunk| {
+                let mut word = [0u8; 8];
+                word.copy_from_slice(chunk);
+                u64::from_le_bytes(word)
+            })
+            .collect();
+
+        EncodedBootInfo(words)
+    }
+}
+
+/// An 8 byte aligned, encoded, multiboot2 information structure.
+#[derive(Debug, Clone)]
+pub struct EncodedBootInfo(Vec<u64>);
+
+impl EncodedBootInfo {
+    /// Load the structure with `multiboot2`.
+    ///
+    /// The returned `BootInformation` points into `self`, it must not be
+    /// used after `self` has been dropped.
+    pub fn load(&self) -> BootInformation {
+        unsafe { multiboot2::load(self.0.as_ptr() as usize) }
+    }
+}
+
+// -- SimMemoryManager
+
+/// A `MemoryManager` operating on `SimulatedMemory` with software page tables.
+pub struct SimMemoryManager {
+    ram: SimulatedMemory,
+    memory: PhysicalMemory,
+    physframe_cursor: PhysFrameCursor,
+    pml4: Option<PhysFrame>,
+    zeroed: ZeroedFrames,
+    free_frames: FreeFrameList,
+    boot_memory: BootMemory,
+}
+
+impl SimMemoryManager {
+    pub fn new(ram: SimulatedMemory) -> Self {
+        Self {
+            ram,
+            memory: PhysicalMemory::default(),
+            physframe_cursor: PhysFrameCursor::default(),
+            pml4: None,
+            zeroed: ZeroedFrames::new(),
+            free_frames: FreeFrameList::new(),
+            boot_memory: BootMemory::new(),
+        }
+    }
+
+    /// The simulated physical memory.
+    #[inline]
+    pub fn ram(&self) -> &SimulatedMemory {
+        &self.ram
+    }
+
+    /// The simulated physical memory, mutably.
+    #[inline]
+    pub fn ram_mut(&mut self) -> &mut SimulatedMemory {
+        &mut self.ram
+    }
+
+    /// The chunks of physical memory frames are being allocated from.
+    #[inline]
+    pub fn memory(&self) -> &PhysicalMemory {
+        &self.memory
+    }
+
+    /// The frame the PML4 lives in, `None` before `initialize`.
+    #[inline]
+    pub fn pml4(&self) -> Option<PhysFrame> {
+        self.pml4
+    }
+
+    /// Walk the page tables to find which frame `page` is mapped to.
+    pub fn translate(&mut self, page: Page<Size4KiB>) -> Option<PhysFrame> {
+        self.mapper().translate_page(page).ok()
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let pml4 = self
+            .pml4
+            .expect("SimMemoryManager used before being initialized.");
+
+        let offset = self.ram.offset();
+        let table: *mut PageTable = (offset + pml4.start_address().as_u64()).as_mut_ptr();
+
+        // SAFETY: The PML4 frame is inside `self.ram` which is borrowed for
+        // as long as the mapper lives.
+        unsafe { OffsetPageTable::new(&mut *table, offset) }
+    }
+}
+
+impl MemoryManager for SimMemoryManager {
+    fn identity_map(
+        &mut self,
+        address: usize,
+        flags: u64,
+    ) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>> {
+        let page = Page::containing_address(VirtAddr::new(address as u64));
+        let frame = PhysFrame::containing_address(PhysAddr::new(address as u64));
+
+        let mut frame_allocator = self.frame_allocator();
+
+        self.map_to(page, frame, flags, &mut frame_allocator)
+    }
+
+    #[track_caller]
+    fn map_to(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame,
+        flags: u64,
+        frame_allocator: &mut PhysFrameAlloc,
+    ) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>> {
+        if fault::should_fail(FaultPoint::MapTo) {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+
+        let flags = unsafe { PageTableFlags::from_bits_unchecked(flags) };
+
+        let result = unsafe { self.mapper().map_to(page, frame, flags, frame_allocator) };
+
+        self.physframe_cursor = frame_allocator.physframe_cursor.clone();
+
+        result
+    }
+
+    fn unmap(&mut self, page: Page<Size4KiB>) -> Option<PhysFrame> {
+        let (frame, flush) = self.mapper().unmap(page).ok()?;
+        flush.ignore();
+        Some(frame)
+    }
+
+    unsafe fn free_frame(&mut self, frame: PhysFrame) {
+        self.free_frames.push(frame, self.ram.offset());
+    }
+
+    fn frame_allocator(&self) -> PhysFrameAlloc {
+        PhysFrameAlloc {
+            memory: self.memory.clone(),
+            physframe_cursor: self.physframe_cursor.clone(),
+        }
+    }
+
+    fn alloc_frame(&mut self) -> Option<PhysFrame> {
+        if fault::should_fail(FaultPoint::FrameAlloc) {
+            return None;
+        }
+
+        if let Some(frame) = unsafe { self.free_frames.pop(self.ram.offset()) } {
+            return Some(frame);
+        }
+
+        let mut frame_allocator = self.frame_allocator();
+        let frame = frame_allocator.next_frame();
+
+        self.physframe_cursor = frame_allocator.physframe_cursor;
+
+        frame
+    }
+
+    fn alloc_zeroed_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.zeroed.take() {
+            return Some(frame);
+        }
+
+        let frame = self.alloc_frame()?;
+
+        unsafe { zero_frame(frame, self.ram.offset()) };
+
+        Some(frame)
+    }
+
+    fn refill_zeroed_frames(&mut self, budget: usize) -> usize {
+        let mut count = 0;
+
+        while count < budget && !self.zeroed.is_full() {
+            let frame = match self.alloc_frame() {
+                Some(frame) => frame,
+                None => break,
+            };
+
+            unsafe {
+                zero_frame(frame, self.ram.offset());
+                self.zeroed.push(frame).expect("Zeroed frame pool overflowed.");
+            }
+
+            count += 1;
+        }
+
+        count
+    }
+
+    fn zeroed_frame_stats(&self) -> ZeroPoolStats {
+        self.zeroed.stats()
+    }
+
+    fn reclaim(&mut self, kind: BootMemoryKind) -> usize {
+        let mut boot_memory = core::mem::take(&mut self.boot_memory);
+        let mut count = 0;
+
+        boot_memory.take(kind, |start, end| {
+            for frame in frames_within(start, end) {
+                unsafe { self.free_frame(frame) };
+                count += 1;
+            }
+        });
+
+        self.boot_memory = boot_memory;
+
+        count
+    }
+
+    fn initialize(&mut self, info: &BootInformation) {
+        let mut map = BootMemoryMap::new();
+        map.add_boot_info(info);
+        map.sanitize();
+
+        let mut buf: PhysicalMemory = map.regions_of(RegionKind::Available).collect();
+
+        // Only whatever part of the ACPI areas is actually simulated can be reclaimed.
+        for (start, end) in map.regions_of(RegionKind::AcpiReclaimable) {
+            self.boot_memory.push(start, end.min(self.ram.size()), BootMemoryKind::Acpi);
+        }
+
+        // Anything past the end of the simulated memory can't be handed out.
+        buf.poke((self.ram.size(), usize::MAX));
+
+        self.memory.swap(&mut buf);
+        self.physframe_cursor = PhysFrameCursor::default();
+
+        let pml4 = self
+            .alloc_zeroed_frame()
+            .expect("No memory available for a PML4.");
+
+        self.pml4 = Some(pml4);
+    }
+}
@@ -0,0 +1,127 @@
+//! A pool of physical frames that have already been zeroed.
+//!
+//! Clearing a frame costs a full 4KiB write, so rather than doing it every
+//! time a page gets mapped we keep a small stash of frames that were zeroed
+//! ahead of time (normally by an idle task calling
+//! `MemoryManager::refill_zeroed_frames`.)
+
+use x86_64::{
+    structures::paging::{PageSize, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+/// Statistics describing how effective a `ZeroedFramePool` has been.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ZeroPoolStats {
+    /// Requests for a zeroed frame that were served straight from the pool.
+    pub hits: usize,
+
+    /// Requests for a zeroed frame that found the pool empty.
+    pub misses: usize,
+
+    /// Frames that have been zeroed ahead of time and pushed into the pool.
+    pub refilled: usize,
+}
+
+/// A fixed capacity stack of frames whose contents are known to be zero.
+#[derive(Debug)]
+pub struct ZeroedFramePool<const N: usize> {
+    frames: [u64; N],
+    length: usize,
+    stats: ZeroPoolStats,
+}
+
+impl<const N: usize> Default for ZeroedFramePool<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ZeroedFramePool<N> {
+    /// Create a new, empty, pool.
+    pub const fn new() -> Self {
+        Self {
+            frames: [0u64; N],
+            length: 0,
+            stats: ZeroPoolStats {
+                hits: 0,
+                misses: 0,
+                refilled: 0,
+            },
+        }
+    }
+
+    /// Get the const capacity of the pool.
+    #[inline]
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// The amount of zeroed frames currently stashed.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Returns `true` if the pool can not hold any more frames.
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.length == N
+    }
+
+    /// Get a snapshot of the hit/miss statistics.
+    #[inline]
+    pub fn stats(&self) -> ZeroPoolStats {
+        self.stats
+    }
+
+    /// Push a frame into the pool, handing it back if the pool is full.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the entire frame has been zeroed and that
+    /// nothing else holds on to it.
+    #[inline]
+    pub unsafe fn push(&mut self, frame: PhysFrame) -> Result<(), PhysFrame> {
+        if self.is_full() {
+            return Err(frame);
+        }
+
+        self.frames[self.length] = frame.start_address().as_u64();
+        self.length += 1;
+        self.stats.refilled += 1;
+
+        Ok(())
+    }
+
+    /// Take a zeroed frame out of the pool, recording a hit or a miss.
+    #[inline]
+    pub fn take(&mut self) -> Option<PhysFrame> {
+        if self.length == 0 {
+            self.stats.misses += 1;
+            return None;
+        }
+
+        self.length -= 1;
+        self.stats.hits += 1;
+
+        let address = PhysAddr::new(self.frames[self.length]);
+
+        Some(PhysFrame::containing_address(address))
+    }
+}
+
+/// Fill a physical frame with zeroes.
+///
+/// `physical_memory_offset` is the virtual address at which all of physical
+/// memory is mapped (which is `0x0` while we're identity mapped.)
+///
+/// # Safety
+///
+/// The frame must be mapped at `physical_memory_offset` and must not be in
+/// use by anything else.
+#[inline]
+pub unsafe fn zero_frame(frame: PhysFrame, physical_memory_offset: VirtAddr) {
+    let ptr: *mut u8 = (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr();
+    core::ptr::write_bytes(ptr, 0, Size4KiB::SIZE as usize);
+}
@@ -0,0 +1,176 @@
+//! Fault injection driven through `mem::sim` (run with `--features std,fault-injection`.)
+//!
+//! Schedules are global so tests are serialised through `Serial`, which
+//! disarms every point when dropped.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use mem::{
+    fault::{self, FaultPoint, ParseError, Schedule},
+    sim::{FakeBootInfo, SimMemoryManager, SimulatedMemory},
+    MemoryManager,
+};
+
+use x86_64::{
+    structures::paging::{mapper::MapToError, Page, PageTableFlags},
+    VirtAddr,
+};
+
+const MIB: u64 = 0x10_0000;
+
+static LOCKED: AtomicBool = AtomicBool::new(false);
+
+struct Serial;
+
+impl Serial {
+    fn lock() -> Self {
+        while LOCKED.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            std::thread::yield_now();
+        }
+
+        Serial
+    }
+}
+
+impl Drop for Serial {
+    fn drop(&mut self) {
+        disarm();
+        LOCKED.store(false, Ordering::Release);
+    }
+}
+
+fn flags() -> u64 {
+    (PageTableFlags::PRESENT | PageTableFlags::WRITABLE).bits()
+}
+
+fn manager() -> SimMemoryManager {
+    let encoded = FakeBootInfo::new().available(0, 4 * MIB).encode();
+    let mut manager = SimMemoryManager::new(SimulatedMemory::new(4 * MIB as usize));
+    manager.initialize(&encoded.load());
+    manager
+}
+
+fn page(idx: u64) -> Page {
+    Page::containing_address(VirtAddr::new(0x4000_0000 + idx * 0x1000))
+}
+
+fn disarm() {
+    for point in FaultPoint::ALL.iter() {
+        fault::set_schedule(*point, Schedule::Never, None).unwrap();
+    }
+}
+
+#[test]
+fn parse_specs() {
+    assert_eq!(
+        fault::parse_spec("map_to:every:3"),
+        Ok((FaultPoint::MapTo, Schedule::EveryNth(3), None))
+    );
+
+    assert_eq!(
+        fault::parse_spec("global_alloc:prob:1/100@heap/cache.rs"),
+        Ok((
+            FaultPoint::GlobalAlloc,
+            Schedule::Probability { numerator: 1, denominator: 100 },
+            Some("heap/cache.rs")
+        ))
+    );
+
+    assert_eq!(fault::parse_spec("frame_alloc"), Ok((FaultPoint::FrameAlloc, Schedule::Always, None)));
+
+    assert_eq!(fault::parse_spec("nope:always"), Err(ParseError::UnknownPoint("nope")));
+    assert_eq!(fault::parse_spec("map_to:every:0"), Err(ParseError::InvalidSchedule("every:0")));
+    assert_eq!(fault::parse_spec("map_to:prob:2/1"), Err(ParseError::InvalidSchedule("prob:2/1")));
+}
+
+#[test]
+fn every_nth_map_fails() {
+    let _serial = Serial::lock();
+    let mut manager = manager();
+
+    assert_eq!(fault::configure_from_command_line("quiet fault=map_to:every:3"), Ok(1));
+
+    let results: Vec<bool> = (0..6).map(|idx| manager.map(page(idx), flags()).is_ok()).collect();
+
+    assert_eq!(results, vec![true, true, false, true, true, false]);
+    assert_eq!(fault::stats(FaultPoint::MapTo).injected, 2);
+}
+
+#[test]
+fn frame_allocation_fails_with_frame_allocation_failed() {
+    let _serial = Serial::lock();
+    let mut manager = manager();
+
+    fault::set_schedule(FaultPoint::FrameAlloc, Schedule::Always, None).unwrap();
+
+    let frame = manager.alloc_frame();
+    let result = manager.map(page(0), flags());
+
+    let stats = fault::stats(FaultPoint::FrameAlloc);
+
+    disarm();
+
+    assert!(frame.is_none());
+    assert!(matches!(result, Err(MapToError::FrameAllocationFailed)));
+    assert_eq!(stats.calls, stats.injected);
+    assert!(stats.injected >= 2);
+
+    // Nothing sticks around once disarmed.
+    assert!(manager.alloc_frame().is_some());
+}
+
+#[test]
+fn call_site_filter() {
+    let _serial = Serial::lock();
+    let mut manager = manager();
+
+    // `map` and `map_to` are `#[track_caller]` so the site is this file.
+    fault::set_schedule(FaultPoint::MapTo, Schedule::Always, Some("somewhere/else.rs")).unwrap();
+    let elsewhere = manager.map(page(0), flags());
+
+    let here = format!("tests/fault.rs:{}", line!() + 2);
+    fault::set_schedule(FaultPoint::MapTo, Schedule::Always, Some(here.as_str())).unwrap();
+    let pinned = manager.map(page(1), flags());
+    let unpinned = manager.map(page(2), flags());
+
+    assert!(elsewhere.is_ok());
+    assert!(matches!(pinned, Err(MapToError::FrameAllocationFailed)));
+    assert!(unpinned.is_ok());
+}
+
+#[test]
+fn probability_is_seeded() {
+    let _serial = Serial::lock();
+
+    let run = || {
+        fault::set_seed(42);
+        fault::set_schedule(FaultPoint::HeapGrow, Schedule::Probability { numerator: 1, denominator: 4 }, None)
+            .unwrap();
+
+        (0..64).map(|_| fault::should_fail(FaultPoint::HeapGrow)).collect::<Vec<bool>>()
+    };
+
+    let (first, second) = (run(), run());
+
+    assert_eq!(first, second);
+
+    let count = first.iter().filter(|fail| **fail).count();
+    assert!(0 < count && count < 64, "{} of 64 failed", count);
+}
+
+#[test]
+fn failed_map_leaves_page_unmapped() {
+    let _serial = Serial::lock();
+    let mut manager = manager();
+
+    // `map` is the trait's own, shared by the kernel's manager and this one.
+    fault::set_schedule(FaultPoint::FrameAlloc, Schedule::Always, None).unwrap();
+
+    let result = manager.map(page(7), flags());
+
+    disarm();
+
+    assert!(matches!(result, Err(MapToError::FrameAllocationFailed)));
+    assert!(manager.translate(page(7)).is_none());
+    assert!(manager.map(page(7), flags()).is_ok());
+}
@@ -0,0 +1,350 @@
+//! Host-side tests driven through `mem::sim` (run with `--features std`.)
+
+use std::collections::HashSet;
+
+use mem::{
+    chunks::MemoryChunks,
+    memory_map::{MemoryMap, MemoryRegion, RegionKind},
+    reclaim::BootMemoryKind,
+    sim::{area_type, FakeBootInfo, SimMemoryManager, SimulatedMemory},
+    MemoryManager, PhysFrameAlloc, PhysFrameCursor, PhysicalMemory,
+};
+
+use proptest::prelude::*;
+use x86_64::{
+    structures::paging::{mapper::MapToError, Page, PageTableFlags},
+    VirtAddr,
+};
+
+const MIB: u64 = 0x10_0000;
+
+fn flags() -> u64 {
+    (PageTableFlags::PRESENT | PageTableFlags::WRITABLE).bits()
+}
+
+fn chunks_of<const N: usize>(memory: &MemoryChunks<N>) -> Vec<(usize, usize)> {
+    (0..memory.len()).filter_map(|idx| memory.get(idx)).collect()
+}
+
+fn intersects((a, b): (usize, usize), (c, d): (usize, usize)) -> bool {
+    a < d && c < b
+}
+
+fn manager(info: FakeBootInfo, size: u64) -> SimMemoryManager {
+    let encoded = info.encode();
+    let mut manager = SimMemoryManager::new(SimulatedMemory::new(size as usize));
+    manager.initialize(&encoded.load());
+    manager
+}
+
+// -- MemoryChunks::poke
+
+#[test]
+fn poke_hole_outside_range() {
+    let mut memory = MemoryChunks::<2>::Contiguous { start: 0x1000, end: 0x2000 };
+
+    assert!(memory.poke((0x0usize, 0x1000usize)).is_none());
+    assert!(memory.poke((0x2000usize, 0x3000usize)).is_none());
+    assert_eq!(chunks_of(&memory), vec![(0x1000, 0x2000)]);
+}
+
+#[test]
+fn poke_range_entirely_within_hole() {
+    let mut memory = MemoryChunks::<2>::Contiguous { start: 0x1000, end: 0x2000 };
+
+    assert!(memory.poke((0x1000usize, 0x2000usize)).is_some());
+    assert_eq!(chunks_of(&memory), vec![]);
+}
+
+#[test]
+fn poke_hole_entirely_within_range() {
+    let mut memory = MemoryChunks::<2>::Contiguous { start: 0x0, end: 0x2000 };
+
+    assert!(memory.poke((0x1800usize, 0x1900usize)).is_some());
+    assert_eq!(chunks_of(&memory), vec![(0x0, 0x1800), (0x1900, 0x2000)]);
+}
+
+#[test]
+fn poke_hole_clipping_either_side() {
+    let mut memory = MemoryChunks::<2>::Contiguous { start: 0x1000, end: 0x3000 };
+    assert!(memory.poke((0x0usize, 0x1800usize)).is_some());
+    assert_eq!(chunks_of(&memory), vec![(0x1800, 0x3000)]);
+
+    let mut memory = MemoryChunks::<2>::Contiguous { start: 0x1000, end: 0x3000 };
+    assert!(memory.poke((0x2800usize, 0x4000usize)).is_some());
+    assert_eq!(chunks_of(&memory), vec![(0x1000, 0x2800)]);
+}
+
+#[test]
+fn poke_hole_spanning_segregated_chunks() {
+    let mut memory: PhysicalMemory = vec![(0x0, 0x9_fc00), (0x10_0000, 0x20_0000), (0x30_0000, 0x40_0000)]
+        .into_iter()
+        .collect();
+
+    // The first megabyte, like `initialize` does.
+    assert!(memory.poke((0usize, 0x10_0000usize)).is_some());
+    assert_eq!(chunks_of(&memory), vec![(0x10_0000, 0x20_0000), (0x30_0000, 0x40_0000)]);
+
+    // Truncate the chunks on either side of the hole.
+    assert!(memory.poke((0x18_0000usize, 0x38_0000usize)).is_some());
+    assert_eq!(chunks_of(&memory), vec![(0x10_0000, 0x18_0000), (0x38_0000, 0x40_0000)]);
+}
+
+fn arb_chunks() -> impl Strategy<Value = Vec<(usize, usize)>> {
+    // Up to five disjoint, sorted, chunks leaving room for one split.
+    prop::collection::vec((0usize..0x1000, 1usize..0x1000), 1..=5).prop_map(|gaps| {
+        let mut cursor = 0;
+
+        gaps.into_iter()
+            .map(|(gap, size)| {
+                let start = cursor + gap;
+                cursor = start + size;
+                (start, cursor)
+            })
+            .collect()
+    })
+}
+
+proptest! {
+    #[test]
+    fn poke_removes_exactly_the_hole(
+        chunks in arb_chunks(),
+        hole_start in 0usize..0x5000,
+        hole_size in 0usize..0x2000,
+    ) {
+        let hole = (hole_start, hole_start + hole_size);
+
+        let mut memory: PhysicalMemory = chunks.iter().cloned().collect();
+        let poked = memory.poke(hole);
+
+        let after = chunks_of(&memory);
+
+        let expected_hit = hole_size > 0 && chunks.iter().any(|chunk| intersects(*chunk, hole));
+        prop_assert_eq!(poked.is_some(), expected_hit);
+
+        // Nothing left over may intersect the hole...
+        for chunk in after.iter() {
+            prop_assert!(chunk.0 < chunk.1, "empty chunk {:?} left behind", chunk);
+            prop_assert!(!intersects(*chunk, hole), "{:?} still intersects {:?}", chunk, hole);
+            prop_assert!(chunks.iter().any(|c| c.0 <= chunk.0 && chunk.1 <= c.1));
+        }
+
+        // ...and every byte outside of it must survive.
+        let covered = |chunks: &[(usize, usize)], address: usize| {
+            chunks.iter().any(|(start, end)| (*start..*end).contains(&address))
+        };
+
+        for address in (0..0x6000).step_by(0x40) {
+            let outside_hole = !(hole.0..hole.1).contains(&address);
+            prop_assert_eq!(covered(&after, address), covered(&chunks, address) && outside_hole);
+        }
+    }
+
+    #[test]
+    fn frames_are_unique_and_within_chunks(chunks in arb_chunks()) {
+        let chunks: Vec<(usize, usize)> = chunks
+            .into_iter()
+            .map(|(start, end)| (start * 0x1000, end * 0x1000))
+            .collect();
+
+        let mut allocator = PhysFrameAlloc {
+            memory: chunks.iter().cloned().collect(),
+            physframe_cursor: PhysFrameCursor::default(),
+        };
+
+        let expected: usize = chunks.iter().map(|(start, end)| (end - start) / 0x1000).sum();
+        let mut seen = HashSet::new();
+
+        while let Some(frame) = x86_64::structures::paging::FrameAllocator::allocate_frame(&mut allocator) {
+            let address = frame.start_address().as_u64() as usize;
+
+            prop_assert!(seen.insert(address), "frame {:#x} handed out twice", address);
+            prop_assert!(chunks.iter().any(|(start, end)| (*start..*end).contains(&address)));
+        }
+
+        prop_assert_eq!(seen.len(), expected);
+    }
+}
+
+// -- MemoryMap::sanitize
+
+fn sanitized(regions: &[(u64, u64, RegionKind)]) -> Vec<MemoryRegion> {
+    let mut map = MemoryMap::<16>::new();
+
+    for (start, end, kind) in regions.iter().cloned() {
+        map.add(start, end, kind);
+    }
+
+    map.sanitize();
+    map.iter().cloned().collect()
+}
+
+fn region(start: u64, end: u64, kind: RegionKind) -> MemoryRegion {
+    MemoryRegion { start, end, kind }
+}
+
+#[test]
+fn sanitize_aligns_usable_memory_inward_and_reserved_outward() {
+    let regions = sanitized(&[
+        (0x0, 0x9fc00, RegionKind::Available),
+        (0x9fc00, 0xa0000, RegionKind::Reserved),
+        (0x100800, 0x200800, RegionKind::Available),
+    ]);
+
+    assert_eq!(
+        regions,
+        vec![
+            region(0x0, 0x9f000, RegionKind::Available),
+            region(0x9f000, 0xa0000, RegionKind::Reserved),
+            region(0x101000, 0x200000, RegionKind::Available),
+        ]
+    );
+}
+
+#[test]
+fn sanitize_resolves_overlaps_with_reserved_winning() {
+    let regions = sanitized(&[
+        (0x100000, 0x400000, RegionKind::Available),
+        (0x200000, 0x300000, RegionKind::Reserved),
+        (0x280000, 0x500000, RegionKind::AcpiReclaimable),
+    ]);
+
+    assert_eq!(
+        regions,
+        vec![
+            region(0x100000, 0x200000, RegionKind::Available),
+            region(0x200000, 0x300000, RegionKind::Reserved),
+            region(0x300000, 0x500000, RegionKind::AcpiReclaimable),
+        ]
+    );
+}
+
+#[test]
+fn sanitize_carves_out_the_kernel_and_merges_neighbours() {
+    let regions = sanitized(&[
+        (0x100000, 0x180000, RegionKind::Available),
+        (0x180000, 0x800000, RegionKind::Available),
+        (0x200100, 0x280010, RegionKind::Kernel),
+        (0x300000, 0x301000, RegionKind::BootInfo),
+    ]);
+
+    assert_eq!(
+        regions,
+        vec![
+            region(0x100000, 0x200000, RegionKind::Available),
+            region(0x200000, 0x281000, RegionKind::Kernel),
+            region(0x281000, 0x300000, RegionKind::Available),
+            region(0x300000, 0x301000, RegionKind::BootInfo),
+            region(0x301000, 0x800000, RegionKind::Available),
+        ]
+    );
+
+    let available: u64 = regions
+        .iter()
+        .filter(|region| region.kind == RegionKind::Available)
+        .map(MemoryRegion::size)
+        .sum();
+
+    assert_eq!(available, 0x100000 + 0x7f000 + 0x4ff000);
+}
+
+// -- SimMemoryManager
+
+#[test]
+fn initialize_only_uses_available_memory() {
+    let mut manager = manager(
+        FakeBootInfo::new()
+            .available(0, 2 * MIB)
+            .reserved(2 * MIB, 3 * MIB)
+            .available(3 * MIB, 4 * MIB),
+        4 * MIB,
+    );
+
+    let pml4 = manager.pml4().unwrap().start_address().as_u64();
+    assert!(pml4 < 2 * MIB);
+
+    while let Some(frame) = manager.alloc_frame() {
+        let address = frame.start_address().as_u64();
+        assert!(!(2 * MIB..3 * MIB).contains(&address), "reserved frame {:#x}", address);
+    }
+}
+
+#[test]
+fn map_translates_to_a_zeroed_frame() {
+    let mut manager = manager(FakeBootInfo::new().available(0, MIB), MIB);
+
+    let page = Page::containing_address(VirtAddr::new(0x6666_0000_0000));
+    manager.map(page, flags()).unwrap().ignore();
+
+    let frame = manager.translate(page).expect("page was not mapped");
+    assert!(manager.ram().frame(frame).iter().all(|byte| *byte == 0));
+
+    manager.unmap(page);
+    assert!(manager.translate(page).is_none());
+}
+
+#[test]
+fn freed_frames_are_reused() {
+    let mut manager = manager(FakeBootInfo::new().available(0, MIB), MIB);
+
+    let page = Page::containing_address(VirtAddr::new(0x6666_0000_0000));
+    manager.map(page, flags()).unwrap().ignore();
+
+    let frame = manager.unmap(page).expect("page was not mapped");
+    unsafe { manager.free_frame(frame) };
+
+    assert_eq!(manager.alloc_frame(), Some(frame));
+}
+
+#[test]
+fn acpi_memory_is_only_used_once_reclaimed() {
+    let mut manager = manager(
+        FakeBootInfo::new()
+            .available(0, 0x4000)
+            .area(0x4000, 0x6000, area_type::ACPI_AVAILABLE),
+        0x8000,
+    );
+
+    // The PML4 took one frame already.
+    let mut frames: Vec<_> = std::iter::from_fn(|| manager.alloc_frame()).collect();
+    assert_eq!(frames.len(), 3);
+
+    assert_eq!(manager.reclaim(BootMemoryKind::Acpi), 2);
+    assert_eq!(manager.reclaim(BootMemoryKind::Acpi), 0);
+
+    frames.extend(std::iter::from_fn(|| manager.alloc_frame()));
+
+    let addresses: HashSet<u64> = frames.iter().map(|frame| frame.start_address().as_u64()).collect();
+    assert_eq!(addresses, [0x1000, 0x2000, 0x3000, 0x4000, 0x5000].iter().cloned().collect());
+}
+
+#[test]
+fn map_fails_once_memory_runs_out() {
+    let mut manager = manager(FakeBootInfo::new().available(0, 0x8000), 0x8000);
+
+    let result = (0..16u64)
+        .map(|idx| Page::containing_address(VirtAddr::new(0x6666_0000_0000 + idx * 0x1000)))
+        .map(|page| manager.map(page, flags()).map(|flush| flush.ignore()))
+        .find(Result::is_err);
+
+    assert!(matches!(result, Some(Err(MapToError::FrameAllocationFailed))));
+}
+
+#[test]
+fn zeroed_pool_hits_and_misses() {
+    let mut manager = manager(FakeBootInfo::new().available(0, MIB), MIB);
+
+    assert_eq!(manager.refill_zeroed_frames(4), 4);
+
+    for _ in 0..5 {
+        let frame = manager.alloc_zeroed_frame().unwrap();
+        assert!(manager.ram().frame(frame).iter().all(|byte| *byte == 0));
+    }
+
+    let stats = manager.zeroed_frame_stats();
+
+    assert_eq!(stats.refilled, 4);
+    assert_eq!(stats.hits, 4);
+    // One for the PML4 in `initialize` and one for the fifth request.
+    assert_eq!(stats.misses, 2);
+}
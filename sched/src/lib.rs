@@ -24,9 +24,27 @@ fn dummy_waker() -> Waker {
     unsafe { Waker::from_raw(dummy_raw_waker()) }
 }
 
+/// The address of a task's future, which identifies it to `TaskHooks`.
+#[inline]
+fn id_of(task: &Task<'_>) -> *mut () {
+    &**task as *const _ as *const () as *mut ()
+}
+
+/// Called as tasks come and go, for whoever wants to keep track of them (i.e. a debugger.)
+#[derive(Clone, Copy)]
+pub struct TaskHooks {
+    pub spawned: fn(*mut ()),
+
+    /// Before a task is polled, with null once it returned.
+    pub polling: fn(*mut ()),
+
+    pub finished: fn(*mut ()),
+}
+
 #[derive(Default)]
 pub struct Runtime<'a> {
     task_queue: VecDeque<Task<'a>>,
+    hooks: Option<TaskHooks>,
 }
 
 impl<'a> Runtime<'a> {
@@ -34,16 +52,44 @@ impl<'a> Runtime<'a> {
         Self::default()
     }
 
-    fn spawn(&mut self, fut: impl Future<Output = ()> + 'a) {
-        self.task_queue.push_back(Box::pin(fut))
+    /// Report tasks to `hooks` from now on, the ones already spawned included.
+    pub fn set_hooks(&mut self, hooks: TaskHooks) {
+        self.task_queue.iter().for_each(|task| (hooks.spawned)(id_of(task)));
+        self.hooks = Some(hooks);
     }
 
-    fn run(&mut self) {
+    pub fn spawn(&mut self, fut: impl Future<Output = ()> + 'a) {
+        let task: Task<'a> = Box::pin(fut);
+
+        if let Some(hooks) = self.hooks {
+            (hooks.spawned)(id_of(&task));
+        }
+
+        self.task_queue.push_back(task)
+    }
+
+    pub fn run(&mut self) {
         while let Some(mut task) = self.task_queue.pop_front() {
             let waker = dummy_waker();
             let mut context = Context::from_waker(&waker);
-            match task.as_mut().poll(&mut context) {
-                Poll::Ready(()) => {} // task done
+
+            if let Some(hooks) = self.hooks {
+                (hooks.polling)(id_of(&task));
+            }
+
+            let poll = task.as_mut().poll(&mut context);
+
+            if let Some(hooks) = self.hooks {
+                (hooks.polling)(core::ptr::null_mut());
+            }
+
+            match poll {
+                Poll::Ready(()) => {
+                    // task done
+                    if let Some(hooks) = self.hooks {
+                        (hooks.finished)(id_of(&task));
+                    }
+                }
                 Poll::Pending => self.task_queue.push_back(task),
             }
         }
@@ -20,6 +20,10 @@ parser.add_argument("--iso", action=BooleanOptionalAction)
 parser.add_argument("--qemu", action=BooleanOptionalAction)
 parser.add_argument("--qemu-nographic", action=BooleanOptionalAction)
 
+parser.add_argument("--features", default="", help="cargo features to enable for the kernel")
+parser.add_argument("--cmdline", default="", help="the kernel command line passed by GRUB")
+parser.add_argument("--frame-pointers", action=BooleanOptionalAction, help="force frame pointers (for backtraces)")
+
 
 args = parser.parse_args()
 
@@ -49,7 +53,9 @@ if args.build or args.iso or args.qemu:
 
     # Build the kernel.
     release = "--release" if args.release else ""
-    sh(f"cargo b {release} -vv --target common/target-x86_64.json")
+    features = f"-p kernel --features {args.features!r}" if args.features else ""
+    rustflags = "RUSTFLAGS='-C force-frame-pointers=yes'" if args.frame_pointers else ""
+    sh(f"{rustflags} cargo b {release} {features} -vv --target common/target-x86_64.json")
 
     assert libkernel_path.exists()
 
@@ -74,8 +80,14 @@ if args.iso or args.qemu:
     sh("mkdir -p build")
     sh("mkdir -p ./build/isofiles/boot/grub")
     sh(f"cp {kernel_blob} build/isofiles/boot/kernel.bin")
-    sh("strip build/isofiles/boot/kernel.bin")
+    # Keep the symbol table around for backtraces.
+    sh("strip --strip-debug build/isofiles/boot/kernel.bin")
     sh(f"cp {grub_cfg} build/isofiles/boot/grub")
+
+    if args.cmdline:
+        cfg = build_path.joinpath("isofiles/boot/grub/grub.cfg")
+        cfg.write_text(cfg.read_text().replace("/boot/kernel.bin", f"/boot/kernel.bin {args.cmdline}"))
+
     sh(f"grub-mkrescue -o {iso_path} build/isofiles")
     sh("rm -r build/isofiles")
 